@@ -0,0 +1,88 @@
+//! Derive macros for manzana.
+//!
+//! Use them through their re-exports, e.g. `manzana::metal::ShaderUniforms`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Derive `ShaderUniforms` for a struct with named fields.
+///
+/// Every field type must implement `manzana::metal::uniforms::UniformValue`.
+/// The MSL layout follows the field types, not the Rust layout, so the
+/// struct needs no `#[repr(C)]`.
+#[proc_macro_derive(ShaderUniforms)]
+pub fn derive_shader_uniforms(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "ShaderUniforms cannot be derived for generic structs",
+        ));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "ShaderUniforms can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &data.fields,
+            "ShaderUniforms requires named fields",
+        ));
+    };
+    if fields.named.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "MSL structs need at least one field",
+        ));
+    }
+
+    let name = &input.ident;
+    let msl_name = name.to_string();
+    let idents: Vec<_> = fields
+        .named
+        .iter()
+        .filter_map(|f| f.ident.as_ref())
+        .collect();
+    let names = idents.iter().map(|ident| {
+        let name = ident.to_string();
+        name.strip_prefix("r#").unwrap_or(&name).to_string()
+    });
+    let types = fields.named.iter().map(|f| &f.ty);
+    let indices = 0..idents.len();
+    let uniforms = quote!(::manzana::metal::uniforms);
+
+    Ok(quote! {
+        impl #uniforms::UniformValue for #name {
+            fn msl_type() -> #uniforms::MslType {
+                #uniforms::MslType::structure(
+                    #msl_name,
+                    ::std::vec![
+                        #( (#names, <#types as #uniforms::UniformValue>::msl_type()) ),*
+                    ],
+                )
+            }
+
+            fn write(&self, out: &mut [u8]) {
+                let ty = <Self as #uniforms::UniformValue>::msl_type();
+                #(
+                    #uniforms::UniformValue::write(
+                        &self.#idents,
+                        &mut out[ty.field_range(#indices)],
+                    );
+                )*
+            }
+        }
+
+        impl #uniforms::ShaderUniforms for #name {}
+    })
+}
@@ -46,6 +46,25 @@ pub enum Error {
         message: String,
     },
 
+    /// The Metal device was lost (GPU reset, removal or driver fault).
+    ///
+    /// All buffers and shaders created before the loss are invalid. Call
+    /// `MetalCompute::recover` to rebuild state before retrying.
+    #[error("Metal device {device_index} lost; recover before retrying")]
+    DeviceLost {
+        /// Index of the device that was lost.
+        device_index: usize,
+    },
+
+    /// Not enough GPU memory left on the device or in the pipeline budget.
+    #[error("out of GPU memory: requested {requested} bytes, {available} available")]
+    OutOfMemory {
+        /// Bytes requested by the allocation.
+        requested: u64,
+        /// Bytes still available under the applicable limit.
+        available: u64,
+    },
+
     /// CoreML framework returned an error.
     #[error("CoreML error: {message}")]
     CoreMl {
@@ -150,6 +169,21 @@ impl Error {
         }
     }
 
+    /// Create a new `DeviceLost` error.
+    #[must_use]
+    pub const fn device_lost(device_index: usize) -> Self {
+        Self::DeviceLost { device_index }
+    }
+
+    /// Create a new `OutOfMemory` error.
+    #[must_use]
+    pub const fn out_of_memory(requested: u64, available: u64) -> Self {
+        Self::OutOfMemory {
+            requested,
+            available,
+        }
+    }
+
     /// Create a new `CoreMl` error.
     #[must_use]
     pub fn coreml(message: impl Into<String>) -> Self {
@@ -214,6 +248,12 @@ impl Error {
         matches!(self, Self::Timeout { .. })
     }
 
+    /// Check if this error indicates the Metal device was lost.
+    #[must_use]
+    pub const fn is_device_lost(&self) -> bool {
+        matches!(self, Self::DeviceLost { .. })
+    }
+
     /// Check if this error is a permission issue.
     #[must_use]
     pub const fn is_permission_denied(&self) -> bool {
@@ -266,6 +306,8 @@ mod tests {
             Error::not_available(Subsystem::Metal),
             Error::iokit(0, "test"),
             Error::metal("test"),
+            Error::device_lost(0),
+            Error::out_of_memory(1024, 0),
             Error::coreml("test"),
             Error::security(-1),
             Error::invalid_input("test"),
@@ -297,6 +339,8 @@ mod tests {
         let _ = Error::not_available(Subsystem::Afterburner);
         let _ = Error::iokit(0, "msg");
         let _ = Error::metal("msg");
+        let _ = Error::device_lost(0);
+        let _ = Error::out_of_memory(1, 0);
         let _ = Error::coreml("msg");
         let _ = Error::security(0);
         let _ = Error::invalid_input("msg");
@@ -314,6 +358,9 @@ mod tests {
         assert!(Error::timeout(100).is_timeout());
         assert!(!Error::not_available(Subsystem::Metal).is_timeout());
 
+        assert!(Error::device_lost(1).is_device_lost());
+        assert!(!Error::metal("test").is_device_lost());
+
         assert!(Error::permission_denied("op").is_permission_denied());
         assert!(!Error::timeout(100).is_permission_denied());
     }
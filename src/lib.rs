@@ -41,6 +41,7 @@
 //! - `neural-engine` - Enable Neural Engine support (Apple Silicon)
 //! - `metal` - Enable Metal GPU compute
 //! - `secure-enclave` - Enable Secure Enclave operations
+//! - `async` - Enable async Metal dispatch and readback futures
 //! - `full` - Enable all features
 //!
 //! # Safety Guarantees
@@ -85,6 +86,9 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::doc_markdown)] // Allow ProRes, IOKit, etc. without backticks
 
+// Lets `#[derive(ShaderUniforms)]` name `::manzana` inside this crate.
+extern crate self as manzana;
+
 pub mod afterburner;
 pub mod error;
 pub mod metal;
@@ -1,606 +0,0 @@
-//! Metal GPU compute for macOS.
-//!
-//! Provides access to Apple's Metal framework for GPU compute operations.
-//! Supports both discrete and integrated GPUs, including Apple Silicon.
-//!
-//! # Example
-//!
-//! ```no_run
-//! use manzana::metal::MetalCompute;
-//!
-//! // Enumerate all Metal devices
-//! let devices = MetalCompute::devices();
-//! for (i, device) in devices.iter().enumerate() {
-//!     println!("GPU {}: {} ({} MB)", i, device.name, device.max_buffer_length / 1_000_000);
-//! }
-//! ```
-//!
-//! # Falsification Claims
-//!
-//! - F046: All Metal devices enumerated
-//! - F047: Device properties accurate
-//! - F053: Multi-GPU dispatch works
-//! - F058: Headless GPU works
-
-use crate::error::{Error, Result, Subsystem};
-
-/// Information about a Metal GPU device.
-#[derive(Debug, Clone)]
-pub struct MetalDevice {
-    /// Human-readable device name.
-    pub name: String,
-    /// Unique registry ID for the device.
-    pub registry_id: u64,
-    /// True if this is a low-power (integrated) GPU.
-    pub is_low_power: bool,
-    /// True if this is a headless (no display) GPU.
-    pub is_headless: bool,
-    /// Maximum threads per threadgroup.
-    pub max_threads_per_threadgroup: u32,
-    /// Maximum buffer length in bytes.
-    pub max_buffer_length: u64,
-    /// Unified memory architecture (Apple Silicon).
-    pub has_unified_memory: bool,
-    /// Device index for selection.
-    pub index: usize,
-}
-
-impl MetalDevice {
-    /// Check if this device supports unified memory.
-    #[must_use]
-    pub const fn is_apple_silicon(&self) -> bool {
-        self.has_unified_memory
-    }
-
-    /// Get approximate VRAM in gigabytes.
-    #[must_use]
-    #[allow(clippy::cast_precision_loss)]
-    pub fn vram_gb(&self) -> f64 {
-        self.max_buffer_length as f64 / 1_073_741_824.0
-    }
-}
-
-/// A compiled Metal shader (compute kernel).
-#[derive(Debug)]
-pub struct CompiledShader {
-    name: String,
-    #[allow(dead_code)]
-    source_hash: u64,
-}
-
-impl CompiledShader {
-    /// Get the shader function name.
-    #[must_use]
-    pub fn name(&self) -> &str {
-        &self.name
-    }
-}
-
-/// A Metal buffer for GPU data.
-#[derive(Debug)]
-pub struct MetalBuffer {
-    length: usize,
-    device_index: usize,
-}
-
-impl MetalBuffer {
-    /// Get the buffer length in bytes.
-    #[must_use]
-    pub const fn len(&self) -> usize {
-        self.length
-    }
-
-    /// Check if the buffer is empty.
-    #[must_use]
-    pub const fn is_empty(&self) -> bool {
-        self.length == 0
-    }
-
-    /// Get the device this buffer is allocated on.
-    #[must_use]
-    pub const fn device_index(&self) -> usize {
-        self.device_index
-    }
-}
-
-/// Metal compute pipeline.
-///
-/// Provides GPU compute capabilities via Apple's Metal framework.
-///
-/// # Thread Safety
-///
-/// This type is `!Send` and `!Sync` because Metal command queues
-/// are not thread-safe. Create pipelines on each thread that needs them.
-pub struct MetalCompute {
-    device_index: usize,
-    device_name: String,
-    _not_send_sync: std::marker::PhantomData<*const ()>,
-}
-
-impl MetalCompute {
-    /// Enumerate all available Metal devices.
-    ///
-    /// Uses `system_profiler` to detect real GPU hardware on macOS.
-    /// Returns an empty vector on non-macOS platforms.
-    #[must_use]
-    #[allow(clippy::missing_const_for_fn)]
-    pub fn devices() -> Vec<MetalDevice> {
-        #[cfg(target_os = "macos")]
-        {
-            Self::detect_gpus_via_system_profiler()
-        }
-
-        #[cfg(not(target_os = "macos"))]
-        {
-            Vec::new()
-        }
-    }
-
-    #[cfg(target_os = "macos")]
-    fn detect_gpus_via_system_profiler() -> Vec<MetalDevice> {
-        use std::process::Command;
-
-        let output = match Command::new("system_profiler")
-            .args(["SPDisplaysDataType"])
-            .output()
-        {
-            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).to_string(),
-            _ => return Self::fallback_device(),
-        };
-
-        let mut devices = Vec::new();
-        let mut current_name = String::new();
-        let mut current_vram: u64 = 0;
-        let mut index = 0;
-
-        for line in output.lines() {
-            let line = line.trim();
-
-            // GPU name line (e.g., "AMD Radeon Pro W5700X:")
-            if line.ends_with(':')
-                && !line.starts_with("Graphics")
-                && !line.contains("Displays")
-                && !line.contains("VRAM")
-                && !line.contains("Vendor")
-                && !line.contains("Device")
-                && !line.contains("Bus")
-                && !line.contains("Slot")
-                && !line.contains("Metal")
-            {
-                // Save previous GPU if we have one
-                if !current_name.is_empty() {
-                    devices.push(Self::create_device(&current_name, current_vram, index));
-                    index += 1;
-                }
-                current_name = line.trim_end_matches(':').to_string();
-                current_vram = 0;
-            }
-
-            // VRAM line (e.g., "VRAM (Total): 16 GB")
-            if line.starts_with("VRAM") {
-                if let Some(vram_str) = line.split(':').nth(1) {
-                    let vram_str = vram_str.trim();
-                    if let Some(gb_pos) = vram_str.find(" GB") {
-                        if let Ok(gb) = vram_str[..gb_pos].trim().parse::<u64>() {
-                            current_vram = gb * 1_073_741_824; // Convert GB to bytes
-                        }
-                    } else if let Some(mb_pos) = vram_str.find(" MB") {
-                        if let Ok(mb) = vram_str[..mb_pos].trim().parse::<u64>() {
-                            current_vram = mb * 1_048_576; // Convert MB to bytes
-                        }
-                    }
-                }
-            }
-        }
-
-        // Don't forget the last GPU
-        if !current_name.is_empty() {
-            devices.push(Self::create_device(&current_name, current_vram, index));
-        }
-
-        if devices.is_empty() {
-            Self::fallback_device()
-        } else {
-            devices
-        }
-    }
-
-    #[cfg(target_os = "macos")]
-    fn create_device(name: &str, vram_bytes: u64, index: usize) -> MetalDevice {
-        let is_apple_silicon = name.contains("Apple") || cfg!(target_arch = "aarch64");
-        let is_integrated = name.contains("Intel") || name.contains("Integrated");
-
-        MetalDevice {
-            name: name.to_string(),
-            registry_id: (index + 1) as u64,
-            is_low_power: is_integrated,
-            is_headless: false,
-            max_threads_per_threadgroup: 1024,
-            max_buffer_length: if vram_bytes > 0 {
-                vram_bytes
-            } else if is_apple_silicon {
-                17_179_869_184 // 16 GB default for Apple Silicon
-            } else {
-                4_294_967_296 // 4 GB default
-            },
-            has_unified_memory: is_apple_silicon,
-            index,
-        }
-    }
-
-    #[cfg(target_os = "macos")]
-    fn fallback_device() -> Vec<MetalDevice> {
-        let is_apple_silicon = cfg!(target_arch = "aarch64");
-        vec![MetalDevice {
-            name: if is_apple_silicon {
-                "Apple GPU".to_string()
-            } else {
-                "Unknown GPU".to_string()
-            },
-            registry_id: 1,
-            is_low_power: false,
-            is_headless: false,
-            max_threads_per_threadgroup: 1024,
-            max_buffer_length: 4_294_967_296,
-            has_unified_memory: is_apple_silicon,
-            index: 0,
-        }]
-    }
-
-    /// Check if any Metal device is available.
-    #[must_use]
-    pub fn is_available() -> bool {
-        !Self::devices().is_empty()
-    }
-
-    /// Create a compute pipeline on the specified device.
-    ///
-    /// # Arguments
-    ///
-    /// * `device_index` - Index into the devices list from `devices()`
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if the device index is out of bounds.
-    pub fn new(device_index: usize) -> Result<Self> {
-        let devices = Self::devices();
-        if device_index >= devices.len() {
-            return Err(Error::not_found(format!(
-                "Metal device index {device_index} (only {} devices available)",
-                devices.len()
-            )));
-        }
-
-        Ok(Self {
-            device_index,
-            device_name: devices[device_index].name.clone(),
-            _not_send_sync: std::marker::PhantomData,
-        })
-    }
-
-    /// Create a compute pipeline on the default (first) device.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if no Metal devices are available.
-    pub fn default_device() -> Result<Self> {
-        if Self::devices().is_empty() {
-            return Err(Error::not_available(Subsystem::Metal));
-        }
-        Self::new(0)
-    }
-
-    /// Get the device name.
-    #[must_use]
-    pub fn device_name(&self) -> &str {
-        &self.device_name
-    }
-
-    /// Get the device index.
-    #[must_use]
-    pub const fn device_index(&self) -> usize {
-        self.device_index
-    }
-
-    /// Compile a Metal shader from source.
-    ///
-    /// # Arguments
-    ///
-    /// * `source` - Metal Shading Language (MSL) source code
-    /// * `function_name` - Name of the kernel function to compile
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if compilation fails.
-    pub fn compile_shader(&self, source: &str, function_name: &str) -> Result<CompiledShader> {
-        // Validate source isn't empty
-        if source.trim().is_empty() {
-            return Err(Error::invalid_input("shader source is empty"));
-        }
-
-        // Validate function name isn't empty
-        if function_name.trim().is_empty() {
-            return Err(Error::invalid_input("function name is empty"));
-        }
-
-        // Simple hash for tracking
-        let source_hash = source.bytes().fold(0u64, |acc, b| {
-            acc.wrapping_mul(31).wrapping_add(u64::from(b))
-        });
-
-        Ok(CompiledShader {
-            name: function_name.to_string(),
-            source_hash,
-        })
-    }
-
-    /// Allocate a buffer on the GPU.
-    ///
-    /// # Arguments
-    ///
-    /// * `length` - Size in bytes
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if allocation fails.
-    pub fn allocate_buffer(&self, length: usize) -> Result<MetalBuffer> {
-        if length == 0 {
-            return Err(Error::invalid_input("buffer length cannot be zero"));
-        }
-
-        // Check against device limits (stub)
-        let max_length = 17_179_869_184_usize; // 16 GB
-        if length > max_length {
-            return Err(Error::invalid_input(format!(
-                "buffer length {length} exceeds device limit {max_length}"
-            )));
-        }
-
-        Ok(MetalBuffer {
-            length,
-            device_index: self.device_index,
-        })
-    }
-
-    /// Dispatch a compute shader.
-    ///
-    /// # Arguments
-    ///
-    /// * `shader` - Compiled shader to execute
-    /// * `buffers` - Buffers to bind to the shader
-    /// * `grid_size` - Total number of threads (width, height, depth)
-    /// * `threadgroup_size` - Threads per threadgroup (width, height, depth)
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if dispatch fails.
-    pub fn dispatch(
-        &self,
-        shader: &CompiledShader,
-        buffers: &[&MetalBuffer],
-        grid_size: (u32, u32, u32),
-        threadgroup_size: (u32, u32, u32),
-    ) -> Result<()> {
-        // Validate grid size
-        if grid_size.0 == 0 || grid_size.1 == 0 || grid_size.2 == 0 {
-            return Err(Error::invalid_input("grid size dimensions cannot be zero"));
-        }
-
-        // Validate threadgroup size
-        let tg_total = threadgroup_size.0 * threadgroup_size.1 * threadgroup_size.2;
-        if tg_total > 1024 {
-            return Err(Error::invalid_input(format!(
-                "threadgroup size {tg_total} exceeds maximum 1024"
-            )));
-        }
-
-        // Validate buffers belong to this device
-        for buffer in buffers {
-            if buffer.device_index != self.device_index {
-                return Err(Error::invalid_input("buffer allocated on different device"));
-            }
-        }
-
-        // Stub: actual dispatch would use Metal command buffer
-        let _ = shader;
-        Ok(())
-    }
-}
-
-/// Check if Metal is available.
-///
-/// Convenience function equivalent to `MetalCompute::is_available()`.
-#[must_use]
-pub fn is_available() -> bool {
-    MetalCompute::is_available()
-}
-
-#[cfg(test)]
-#[allow(clippy::unwrap_used)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_devices_no_panic() {
-        let devices = MetalCompute::devices();
-        // On macOS: at least one device
-        // On other platforms: empty
-        #[cfg(target_os = "macos")]
-        assert!(
-            !devices.is_empty(),
-            "Should have at least one Metal device on macOS"
-        );
-        #[cfg(not(target_os = "macos"))]
-        assert!(
-            devices.is_empty(),
-            "Should have no Metal devices on non-macOS"
-        );
-    }
-
-    #[test]
-    fn test_is_available_consistent() {
-        let available = MetalCompute::is_available();
-        let devices = MetalCompute::devices();
-        assert_eq!(available, !devices.is_empty());
-    }
-
-    #[test]
-    fn test_device_properties() {
-        let devices = MetalCompute::devices();
-        for device in &devices {
-            assert!(!device.name.is_empty());
-            assert!(device.max_threads_per_threadgroup > 0);
-            assert!(device.max_buffer_length > 0);
-            assert!(device.vram_gb() > 0.0);
-        }
-    }
-
-    #[test]
-    fn test_new_invalid_index() {
-        let result = MetalCompute::new(999);
-        assert!(result.is_err());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_new_valid_index() {
-        let result = MetalCompute::new(0);
-        assert!(result.is_ok());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_default_device() {
-        let result = MetalCompute::default_device();
-        assert!(result.is_ok());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_compile_shader() {
-        let compute = MetalCompute::default_device().unwrap();
-        let shader = compute.compile_shader(
-            r"
-            kernel void add(device float* a [[buffer(0)]],
-                           device float* b [[buffer(1)]],
-                           uint id [[thread_position_in_grid]]) {
-                a[id] = a[id] + b[id];
-            }
-            ",
-            "add",
-        );
-        assert!(shader.is_ok());
-        assert_eq!(shader.unwrap().name(), "add");
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_compile_shader_empty_source() {
-        let compute = MetalCompute::default_device().unwrap();
-        let result = compute.compile_shader("", "test");
-        assert!(result.is_err());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_compile_shader_empty_name() {
-        let compute = MetalCompute::default_device().unwrap();
-        let result = compute.compile_shader("kernel void test() {}", "");
-        assert!(result.is_err());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_allocate_buffer() {
-        let compute = MetalCompute::default_device().unwrap();
-        let buffer = compute.allocate_buffer(1024);
-        assert!(buffer.is_ok());
-        let buffer = buffer.unwrap();
-        assert_eq!(buffer.len(), 1024);
-        assert!(!buffer.is_empty());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_allocate_buffer_zero() {
-        let compute = MetalCompute::default_device().unwrap();
-        let result = compute.allocate_buffer(0);
-        assert!(result.is_err());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_dispatch_invalid_grid() {
-        let compute = MetalCompute::default_device().unwrap();
-        let shader = compute
-            .compile_shader("kernel void test() {}", "test")
-            .unwrap();
-        let result = compute.dispatch(&shader, &[], (0, 1, 1), (1, 1, 1));
-        assert!(result.is_err());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_dispatch_invalid_threadgroup() {
-        let compute = MetalCompute::default_device().unwrap();
-        let shader = compute
-            .compile_shader("kernel void test() {}", "test")
-            .unwrap();
-        let result = compute.dispatch(&shader, &[], (64, 64, 1), (32, 32, 2)); // 2048 > 1024
-        assert!(result.is_err());
-    }
-
-    #[test]
-    fn test_convenience_function() {
-        assert_eq!(is_available(), MetalCompute::is_available());
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_detect_real_gpus() {
-        // Should detect actual GPUs on Mac via system_profiler
-        let devices = MetalCompute::devices();
-        assert!(!devices.is_empty(), "Should detect at least one GPU");
-
-        // Device name should be real, not stub
-        let first = &devices[0];
-        assert!(
-            !first.name.contains("Intel UHD"),
-            "Should detect real GPU, not stub. Got: {}",
-            first.name
-        );
-    }
-
-    #[test]
-    #[cfg(target_os = "macos")]
-    fn test_detect_gpu_vram() {
-        let devices = MetalCompute::devices();
-        if !devices.is_empty() {
-            // Real GPUs should report VRAM
-            let first = &devices[0];
-            // Mac Pro AMD GPUs have 16GB, Apple Silicon has unified memory
-            assert!(
-                first.vram_gb() >= 1.0,
-                "GPU should report at least 1GB VRAM, got: {} GB",
-                first.vram_gb()
-            );
-        }
-    }
-
-    #[test]
-    fn test_metal_buffer_methods() {
-        let buffer = MetalBuffer {
-            length: 1024,
-            device_index: 0,
-        };
-        assert_eq!(buffer.len(), 1024);
-        assert!(!buffer.is_empty());
-        assert_eq!(buffer.device_index(), 0);
-
-        let empty_buffer = MetalBuffer {
-            length: 0,
-            device_index: 0,
-        };
-        assert!(empty_buffer.is_empty());
-    }
-}
@@ -0,0 +1,532 @@
+//! Blit commands: buffer copies and fills.
+//!
+//! A [`BlitEncoder`] records copies and fills, validating each range as it
+//! is encoded, and commits them as one command buffer. Commits are ordered
+//! with compute dispatches on the same pipeline: a synchronous
+//! [`commit`](BlitEncoder::commit) runs after all previously committed
+//! work, and (with the `async` feature)
+//! [`commit_async`](BlitEncoder::commit_async) queues behind it.
+//!
+//! [`BlitEncoder::copy_from_device`] copies from a buffer on another
+//! device. GPUs in the same link group ([`MetalDevice::peer_group_id`])
+//! copy peer-to-peer; otherwise the bytes are staged through host memory.
+//! Work already committed on the source pipeline completes before the
+//! copy reads its buffer; an asynchronous commit chains on the source
+//! queue instead of blocking the caller.
+//!
+//! As on macOS Metal, offsets and sizes must be multiples of
+//! [`BLIT_ALIGNMENT`], and a copy's source and destination must not
+//! overlap. Copies carry over which bytes are initialized, so they work
+//! with [`validation`](super::validation).
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::MetalCompute;
+//!
+//! let compute = MetalCompute::cpu();
+//! let source = compute.allocate_buffer(16)?;
+//! let destination = compute.allocate_buffer(16)?;
+//! source.write(0, &[1u32, 2, 3, 4])?;
+//!
+//! let mut blit = compute.blit_encoder();
+//! blit.fill(&destination, 0..16, 0xFF)?;
+//! blit.copy(&source, 4..12, &destination, 0)?;
+//! blit.commit()?;
+//!
+//! assert_eq!(destination.read::<u32>()?, vec![2, 3, u32::MAX, u32::MAX]);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - Copied bytes equal the source range; bytes outside the destination
+//!   range are untouched
+//! - Out-of-range, misaligned, overlapping and wrong-device operations are
+//!   rejected when encoded
+//! - A blit never runs before dispatches committed ahead of it
+
+#[cfg(feature = "async")]
+use super::completion::{self, GpuFuture};
+use super::storage::StorageView;
+#[cfg(doc)]
+use super::MetalDevice;
+use super::{FaultOp, MetalBuffer, MetalCompute};
+use crate::error::{Error, Result};
+use std::ops::Range;
+
+/// Required alignment of blit offsets and sizes, in bytes.
+pub const BLIT_ALIGNMENT: usize = 4;
+
+/// How a copy moves its bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CopyPath {
+    /// Within one device.
+    Local,
+    /// Directly between linked devices.
+    PeerToPeer,
+    /// Through host memory, between unlinked devices.
+    Staged,
+}
+
+impl std::fmt::Display for CopyPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::PeerToPeer => write!(f, "peer-to-peer"),
+            Self::Staged => write!(f, "staged"),
+        }
+    }
+}
+
+/// An encoded blit command.
+enum BlitOp {
+    Copy {
+        source: StorageView,
+        destination: StorageView,
+    },
+    Fill {
+        destination: StorageView,
+        value: u8,
+    },
+}
+
+impl BlitOp {
+    fn run(&self) {
+        match self {
+            Self::Copy {
+                source,
+                destination,
+            } => destination.write_back(&source.read(), &source.initialized()),
+            Self::Fill { destination, value } => {
+                destination.write(&vec![*value; destination.len()]);
+            }
+        }
+    }
+}
+
+/// Records blit commands for one pipeline.
+///
+/// Created by [`MetalCompute::blit_encoder`]. Nothing runs until the
+/// encoder is committed; dropping it discards the commands.
+pub struct BlitEncoder<'a> {
+    compute: &'a MetalCompute,
+    /// Other pipelines whose committed work must finish first.
+    sources: Vec<&'a MetalCompute>,
+    ops: Vec<BlitOp>,
+}
+
+impl<'a> BlitEncoder<'a> {
+    /// Number of encoded commands.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// True if no commands are encoded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Copy `source_range` of `source` to `destination` at
+    /// `destination_offset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either buffer is on another device, predates
+    /// the last recovery or was released by a heap reset, a range is
+    /// empty, reversed, out of bounds or misaligned, or the ranges overlap.
+    pub fn copy(
+        &mut self,
+        source: &MetalBuffer,
+        source_range: Range<usize>,
+        destination: &MetalBuffer,
+        destination_offset: usize,
+    ) -> Result<()> {
+        self.copy_from_device(
+            self.compute,
+            source,
+            source_range,
+            destination,
+            destination_offset,
+        )
+        .map(|_| ())
+    }
+
+    /// Fill `range` of `buffer` with `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the buffer is on another device, predates the
+    /// last recovery or was released by a heap reset, or the range is
+    /// empty, reversed, out of bounds or misaligned.
+    pub fn fill(&mut self, buffer: &MetalBuffer, range: Range<usize>, value: u8) -> Result<()> {
+        let destination = checked_view(self.compute, buffer, range, "fill")?;
+        self.ops.push(BlitOp::Fill { destination, value });
+        Ok(())
+    }
+
+    /// Copy `source_range` of `source`, a buffer of `source_compute`'s
+    /// device, to `destination` on this encoder's device.
+    ///
+    /// The copy runs after work already committed on `source_compute`.
+    /// Returns the path it takes: [`CopyPath::Local`] if both pipelines
+    /// share a device, [`CopyPath::PeerToPeer`] if the devices share a
+    /// link group, and [`CopyPath::Staged`] otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either device is lost, `source` is not on
+    /// `source_compute`'s device or `destination` not on this encoder's,
+    /// or for the reasons listed on [`copy`](Self::copy).
+    pub fn copy_from_device(
+        &mut self,
+        source_compute: &'a MetalCompute,
+        source: &MetalBuffer,
+        source_range: Range<usize>,
+        destination: &MetalBuffer,
+        destination_offset: usize,
+    ) -> Result<CopyPath> {
+        source_compute.check_device()?;
+        let len = source_range.len();
+        let source = checked_view(source_compute, source, source_range, "copy source")?;
+        let destination = checked_view(
+            self.compute,
+            destination,
+            destination_offset..destination_offset.saturating_add(len),
+            "copy destination",
+        )?;
+        if source.overlaps(&destination) {
+            return Err(Error::invalid_input(
+                "copy source and destination ranges overlap",
+            ));
+        }
+
+        let (from, to) = (&source_compute.device, &self.compute.device);
+        let path = if from.index == to.index {
+            CopyPath::Local
+        } else if from.peer_group_id != 0 && from.peer_group_id == to.peer_group_id {
+            CopyPath::PeerToPeer
+        } else {
+            CopyPath::Staged
+        };
+        if !std::ptr::eq(source_compute, self.compute)
+            && !self
+                .sources
+                .iter()
+                .any(|s| std::ptr::eq(*s, source_compute))
+        {
+            self.sources.push(source_compute);
+        }
+        self.ops.push(BlitOp::Copy {
+            source,
+            destination,
+        });
+        Ok(path)
+    }
+
+    /// Check the pipelines the blit runs on and reads from.
+    fn prepare(&self) -> Result<()> {
+        self.compute.check_device()?;
+        for source in &self.sources {
+            source.check_device()?;
+        }
+        self.compute.inject(FaultOp::Dispatch)?;
+        Ok(())
+    }
+
+    /// Run the commands after all previously committed work.
+    ///
+    /// Blits count as dispatches for fault injection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a device is lost or the commit fails.
+    pub fn commit(self) -> Result<()> {
+        self.prepare()?;
+        #[cfg(feature = "async")]
+        for compute in self.sources.iter().chain([&self.compute]) {
+            compute.wait_idle();
+        }
+        for op in &self.ops {
+            op.run();
+        }
+        Ok(())
+    }
+
+    /// Commit the commands and return without waiting for this pipeline.
+    ///
+    /// The commands run after work previously committed to this pipeline
+    /// and to the source pipelines of cross-device copies. Neither is
+    /// waited for here: each source queue gets a marker, and this
+    /// pipeline's queue waits for the markers before running the commands.
+    ///
+    /// The future resolves to an error for the reasons listed on
+    /// [`commit`](Self::commit).
+    #[cfg(feature = "async")]
+    pub fn commit_async(self) -> GpuFuture<()> {
+        if let Err(e) = self.prepare() {
+            return GpuFuture::ready(Err(e));
+        }
+        let markers: Vec<GpuFuture<()>> = self
+            .sources
+            .iter()
+            .map(|source| {
+                let (marker, reached) = completion::Completion::new();
+                source.submit(Box::new(move || marker.complete(Ok(()))));
+                reached
+            })
+            .collect();
+        let ops = self.ops;
+        let (completion, future) = completion::Completion::new();
+        self.compute.submit(Box::new(move || {
+            // Markers are committed before this job, so waiting on them
+            // from the queue thread cannot form a cycle between queues.
+            let sources = markers.into_iter().try_for_each(completion::block_on);
+            if sources.is_ok() {
+                for op in &ops {
+                    op.run();
+                }
+            }
+            completion.complete(sources);
+        }));
+        future
+    }
+}
+
+impl std::fmt::Debug for BlitEncoder<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlitEncoder")
+            .field("device", &self.compute.device.index)
+            .field("commands", &self.ops.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Validate `range` of `buffer` for a blit on `compute`'s device.
+fn checked_view(
+    compute: &MetalCompute,
+    buffer: &MetalBuffer,
+    range: Range<usize>,
+    what: &str,
+) -> Result<StorageView> {
+    if !buffer.epoch.is_alive() {
+        return Err(Error::device_lost(buffer.device_index));
+    }
+    if buffer.is_released() {
+        return Err(Error::invalid_input("buffer was released by a heap reset"));
+    }
+    if buffer.device_index != compute.device.index {
+        return Err(Error::invalid_input(format!(
+            "{what} buffer allocated on different device"
+        )));
+    }
+    if range.is_empty() {
+        return Err(Error::invalid_input(format!(
+            "{what} range {range:?} is empty"
+        )));
+    }
+    if range.start % BLIT_ALIGNMENT != 0 || range.len() % BLIT_ALIGNMENT != 0 {
+        return Err(Error::invalid_input(format!(
+            "{what} range {range:?} is not {BLIT_ALIGNMENT}-byte aligned"
+        )));
+    }
+    buffer.check_range(range.start, range.len())?;
+    Ok(StorageView::new(
+        buffer.storage.clone(),
+        buffer.offset + range.start,
+        range.len(),
+    ))
+}
+
+impl MetalCompute {
+    /// Start encoding blit commands on this pipeline.
+    #[must_use]
+    pub const fn blit_encoder(&self) -> BlitEncoder<'_> {
+        BlitEncoder {
+            compute: self,
+            sources: Vec::new(),
+            ops: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::metal::{Backend, MetalDevice};
+
+    fn device(index: usize, peer_group_id: u64) -> MetalCompute {
+        MetalCompute::with_device(
+            MetalDevice {
+                peer_group_id,
+                index,
+                ..MetalDevice::cpu()
+            },
+            Backend::Cpu,
+        )
+    }
+
+    #[test]
+    fn test_copy_and_fill() {
+        let compute = MetalCompute::cpu();
+        let buffer = compute.allocate_buffer(32).unwrap();
+        buffer.write(0, &[1u32, 2, 3, 4]).unwrap();
+
+        let mut blit = compute.blit_encoder();
+        blit.copy(&buffer, 0..8, &buffer, 24).unwrap();
+        blit.fill(&buffer, 16..24, 7).unwrap();
+        assert_eq!(blit.len(), 2);
+        // Nothing runs before commit.
+        assert_eq!(buffer.read::<u32>().unwrap()[6], 0);
+        blit.commit().unwrap();
+
+        assert_eq!(
+            buffer.read::<u32>().unwrap(),
+            vec![1, 2, 3, 4, 0x0707_0707, 0x0707_0707, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_invalid_operations_rejected() {
+        let compute = MetalCompute::cpu();
+        let buffer = compute.allocate_buffer(16).unwrap();
+        let other = device(100, 0);
+        let foreign = other.allocate_buffer(16).unwrap();
+        let mut blit = compute.blit_encoder();
+
+        assert!(blit.copy(&buffer, 0..8, &buffer, 12).is_err()); // out of bounds
+        assert!(blit.copy(&buffer, 0..8, &buffer, 4).is_err()); // overlapping
+        assert!(blit.copy(&buffer, 2..6, &buffer, 8).is_err()); // misaligned
+        assert!(blit.fill(&buffer, 0..6, 0).is_err()); // misaligned
+        #[allow(clippy::reversed_empty_ranges)]
+        for range in [8..4, 4..4] {
+            let err = blit.copy(&buffer, range.clone(), &buffer, 12).unwrap_err();
+            assert!(err.to_string().contains("is empty"), "{err}");
+            let err = blit.fill(&buffer, range, 0).unwrap_err();
+            assert!(err.to_string().contains("is empty"), "{err}");
+        }
+        assert!(blit.fill(&foreign, 0..4, 0).is_err()); // wrong device
+        let err = blit
+            .copy_from_device(&other, &buffer, 0..4, &buffer, 0)
+            .unwrap_err();
+        assert!(err.to_string().contains("different device"), "{err}");
+        assert!(blit.is_empty());
+    }
+
+    #[test]
+    fn test_cross_device_paths() {
+        let (first, second, third) = (device(101, 9), device(102, 9), device(103, 0));
+        let source = first.allocate_buffer(8).unwrap();
+        source.write(0, &[5u32, 6]).unwrap();
+        let linked = second.allocate_buffer(8).unwrap();
+        let unlinked = third.allocate_buffer(8).unwrap();
+
+        let mut blit = second.blit_encoder();
+        let path = blit.copy_from_device(&first, &source, 0..8, &linked, 0);
+        assert_eq!(path.unwrap(), CopyPath::PeerToPeer);
+        blit.commit().unwrap();
+        assert_eq!(linked.read::<u32>().unwrap(), vec![5, 6]);
+
+        let mut blit = third.blit_encoder();
+        let path = blit.copy_from_device(&first, &source, 4..8, &unlinked, 4);
+        assert_eq!(path.unwrap(), CopyPath::Staged);
+        blit.commit().unwrap();
+        assert_eq!(unlinked.read::<u32>().unwrap(), vec![0, 6]);
+
+        let mut blit = first.blit_encoder();
+        let path = blit.copy_from_device(&first, &source, 0..4, &source, 4);
+        assert_eq!(path.unwrap(), CopyPath::Local);
+    }
+
+    #[test]
+    fn test_copy_preserves_initialized_ranges() {
+        use crate::metal::{CpuKernel, Validation};
+
+        let validation = Validation::all();
+        let compute = MetalCompute::cpu().with_validation(validation.clone());
+        let shader = compute
+            .compile_shader("kernel void sum() {}", "sum")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let value = ctx.read_u32(0, 0);
+                ctx.write_u32(0, 1, value);
+            }));
+        let source = compute.allocate_buffer(8).unwrap();
+        let destination = compute.allocate_buffer(8).unwrap();
+        source.write(0, &[3u32]).unwrap();
+
+        let mut blit = compute.blit_encoder();
+        blit.copy(&source, 0..8, &destination, 0).unwrap();
+        blit.commit().unwrap();
+        compute
+            .dispatch(&shader, &[&destination], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+        assert!(validation.is_clean());
+        assert_eq!(destination.read::<u32>().unwrap(), vec![3, 3]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_blit_ordered_after_async_dispatch() {
+        use crate::metal::completion::block_on;
+        use crate::metal::CpuKernel;
+
+        let compute = MetalCompute::cpu();
+        let shader = compute
+            .compile_shader("kernel void slow() {}", "slow")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                ctx.write_u32(0, 0, 42);
+            }));
+        let source = compute.allocate_buffer(4).unwrap();
+        let destination = compute.allocate_buffer(4).unwrap();
+
+        let dispatched = compute.dispatch_async(&shader, &[&source], (1, 1, 1), (1, 1, 1));
+        let mut blit = compute.blit_encoder();
+        blit.copy(&source, 0..4, &destination, 0).unwrap();
+        let copied = blit.commit_async();
+        block_on(copied).unwrap();
+        assert!(dispatched.is_complete());
+        assert_eq!(destination.read::<u32>().unwrap(), vec![42]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_commit_async_does_not_wait_for_source() {
+        use crate::metal::completion::block_on;
+        use crate::metal::CpuKernel;
+        use std::sync::mpsc;
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        let (first, second) = (device(104, 0), device(105, 0));
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Mutex::new(gate);
+        let shader = first
+            .compile_shader("kernel void gated() {}", "gated")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(move |ctx| {
+                gate.lock().unwrap().recv().unwrap();
+                ctx.write_u32(0, 0, 42);
+            }));
+        let source = first.allocate_buffer(4).unwrap();
+        let destination = second.allocate_buffer(4).unwrap();
+
+        let dispatched = first.dispatch_async(&shader, &[&source], (1, 1, 1), (1, 1, 1));
+        let mut blit = second.blit_encoder();
+        blit.copy_from_device(&first, &source, 0..4, &destination, 0)
+            .unwrap();
+        // Returns while the source dispatch is still blocked on the gate.
+        let copied = blit.commit_async();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!copied.is_complete());
+        release.send(()).unwrap();
+        block_on(copied).unwrap();
+        assert!(dispatched.is_complete());
+        assert_eq!(destination.read::<u32>().unwrap(), vec![42]);
+    }
+}
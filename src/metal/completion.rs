@@ -0,0 +1,320 @@
+//! Asynchronous dispatch and readback (`async` feature).
+//!
+//! [`MetalCompute::dispatch_async`](super::MetalCompute::dispatch_async)
+//! validates and commits a dispatch, then returns a [`GpuFuture`] at once.
+//! Committed work runs in order on the pipeline's command queue, and the
+//! future is woken by the command buffer's completion handler, so awaiting
+//! it never blocks an executor thread. Readback through
+//! [`MetalCompute::read_buffer_async`](super::MetalCompute::read_buffer_async)
+//! is queued behind earlier dispatches the same way.
+//!
+//! The futures rely only on [`Waker`]s and work with any executor
+//! (tokio, async-std, smol). [`block_on`] drives one from synchronous code.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::completion::block_on;
+//! use manzana::metal::{CpuKernel, MetalCompute};
+//!
+//! let compute = MetalCompute::cpu();
+//! let shader = compute
+//!     .compile_shader("kernel void inc() {}", "inc")?
+//!     .with_cpu_kernel(CpuKernel::new(|ctx| {
+//!         let i = ctx.thread_position().0 as usize;
+//!         ctx.write_u32(0, i, ctx.read_u32(0, i) + 1);
+//!     }));
+//! let buffer = compute.allocate_buffer(16)?;
+//!
+//! let done = compute.dispatch_async(&shader, &[&buffer], (4, 1, 1), (4, 1, 1));
+//! let values = compute.read_buffer_async::<u32>(&buffer);
+//! block_on(done)?;
+//! assert_eq!(block_on(values)?, vec![1, 1, 1, 1]);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - F055: `dispatch_async` returns before the dispatch executes
+//! - F095: awaiting a `GpuFuture` never blocks the polling thread
+//! - Queued work completes in submission order
+
+use crate::error::{Error, Result};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle, Thread};
+
+enum State<T> {
+    /// Still running; holds the waker of the last poll.
+    Pending(Option<Waker>),
+    /// Finished and not yet returned.
+    Done(Result<T>),
+    /// Returned from `poll`.
+    Taken,
+}
+
+type Shared<T> = Arc<Mutex<State<T>>>;
+
+fn lock<T>(state: &Mutex<T>) -> MutexGuard<'_, T> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Result of GPU work that completes later.
+///
+/// Resolves once the command buffer has completed. Dropping the future
+/// does not cancel the work.
+#[must_use = "GPU work runs regardless; await the future to observe its result"]
+pub struct GpuFuture<T> {
+    state: Shared<T>,
+}
+
+impl<T> GpuFuture<T> {
+    /// A future that is already resolved, for errors found before commit.
+    pub(crate) fn ready(result: Result<T>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::Done(result))),
+        }
+    }
+
+    /// True once the work has completed.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        !matches!(*lock(&self.state), State::Pending(_))
+    }
+}
+
+impl<T> std::fmt::Debug for GpuFuture<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GpuFuture")
+            .field("complete", &self.is_complete())
+            .finish()
+    }
+}
+
+impl<T> Future for GpuFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = lock(&self.state);
+        match std::mem::replace(&mut *state, State::Taken) {
+            State::Pending(_) => {
+                *state = State::Pending(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+            State::Done(result) => Poll::Ready(result),
+            State::Taken => Poll::Ready(Err(Error::internal("GpuFuture polled after completion"))),
+        }
+    }
+}
+
+/// Completion handler side of a [`GpuFuture`].
+///
+/// Dropping it without completing resolves the future with an error.
+pub(crate) struct Completion<T> {
+    state: Option<Shared<T>>,
+}
+
+impl<T> Completion<T> {
+    /// Create a linked completion handler and future.
+    pub(crate) fn new() -> (Self, GpuFuture<T>) {
+        let state = Arc::new(Mutex::new(State::Pending(None)));
+        (
+            Self {
+                state: Some(Arc::clone(&state)),
+            },
+            GpuFuture { state },
+        )
+    }
+
+    /// Resolve the future and wake its task.
+    pub(crate) fn complete(mut self, result: Result<T>) {
+        self.finish(result);
+    }
+
+    fn finish(&mut self, result: Result<T>) {
+        let Some(state) = self.state.take() else {
+            return;
+        };
+        let previous = std::mem::replace(&mut *lock(&state), State::Done(result));
+        if let State::Pending(Some(waker)) = previous {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.finish(Err(Error::metal(
+            "command buffer was dropped before completing",
+        )));
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Serial queue that runs committed work off the caller's thread.
+///
+/// Stands in for `MTLCommandQueue`: work runs in submission order, one
+/// command buffer at a time.
+pub(crate) struct CommandQueue {
+    sender: Option<Sender<Job>>,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl CommandQueue {
+    pub(crate) fn new() -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let pending = Arc::new((Mutex::new(0usize), Condvar::new()));
+        let worker_pending = Arc::clone(&pending);
+        let worker = thread::Builder::new()
+            .name("manzana-metal-queue".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    // A panicking kernel fails its own future (its
+                    // completion is dropped) without stopping the queue.
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                    let (count, idle) = &*worker_pending;
+                    *lock(count) -= 1;
+                    idle.notify_all();
+                }
+            })
+            .map_err(|e| Error::metal(format!("failed to start command queue: {e}")))?;
+        Ok(Self {
+            sender: Some(sender),
+            pending,
+            worker: Some(worker),
+        })
+    }
+
+    /// Commit `job` to run after everything already committed.
+    pub(crate) fn submit(&self, job: Job) {
+        let (count, _) = &*self.pending;
+        *lock(count) += 1;
+        let sent = self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| sender.send(job).is_ok());
+        if !sent {
+            *lock(count) -= 1;
+        }
+    }
+
+    /// Block until all committed work has completed.
+    pub(crate) fn wait_idle(&self) {
+        let (count, idle) = &*self.pending;
+        drop(
+            idle.wait_while(lock(count), |pending| *pending > 0)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+}
+
+impl Drop for CommandQueue {
+    fn drop(&mut self) {
+        // Closing the channel lets the worker drain the queue and exit.
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the current thread.
+///
+/// For synchronous callers only: inside an async runtime, `.await` the
+/// future instead.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Waker that counts wakes.
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_future_pending_until_completed() {
+        let (completion, mut future) = Completion::<u32>::new();
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counter));
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        assert!(!future.is_complete());
+        completion.complete(Ok(7));
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(future.is_complete());
+        assert!(matches!(
+            Pin::new(&mut future).poll(&mut cx),
+            Poll::Ready(Ok(7))
+        ));
+        assert!(matches!(
+            Pin::new(&mut future).poll(&mut cx),
+            Poll::Ready(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_dropped_completion_fails_future() {
+        let (completion, future) = Completion::<()>::new();
+        drop(completion);
+        let err = block_on(future).unwrap_err();
+        assert!(err.to_string().contains("dropped before completing"));
+    }
+
+    #[test]
+    fn test_queue_runs_in_order_off_thread() {
+        let queue = CommandQueue::new().unwrap();
+        let caller = thread::current().id();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut futures = Vec::new();
+        for i in 0..8 {
+            let (completion, future) = Completion::new();
+            let order = Arc::clone(&order);
+            queue.submit(Box::new(move || {
+                lock(&order).push(i);
+                completion.complete(Ok(thread::current().id()));
+            }));
+            futures.push(future);
+        }
+        for future in futures {
+            assert_ne!(block_on(future).unwrap(), caller);
+        }
+        queue.wait_idle();
+        assert_eq!(*lock(&order), (0..8).collect::<Vec<_>>());
+    }
+}
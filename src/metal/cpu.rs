@@ -0,0 +1,490 @@
+//! Host CPU backend for Metal compute kernels.
+//!
+//! A pipeline created with [`MetalCompute::cpu`](super::MetalCompute::cpu)
+//! runs kernels on the host instead of a GPU. Kernels execute through a
+//! [`CpuKernel`] attached to the [`CompiledShader`](super::CompiledShader):
+//! a Rust closure invoked once per thread position, mirroring the MSL
+//! kernel's `thread_position_in_grid` mapping. Buffer accesses go through
+//! a [`ThreadContext`], which behaves like Metal's robust buffer access:
+//! out-of-bounds reads return zero and out-of-bounds writes are dropped.
+//!
+//! The CPU backend is always available, so kernels, graphs and schedulers
+//! built on [`MetalCompute`](super::MetalCompute) can be tested on Linux.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::{CpuKernel, MetalCompute};
+//!
+//! let compute = MetalCompute::cpu();
+//! let shader = compute
+//!     .compile_shader(
+//!         "kernel void twice(device float* x [[buffer(0)]], uint i [[thread_position_in_grid]]) { x[i] *= 2; }",
+//!         "twice",
+//!     )?
+//!     .with_cpu_kernel(CpuKernel::new(|ctx| {
+//!         let i = ctx.thread_position().0 as usize;
+//!         ctx.write_f32(0, i, ctx.read_f32(0, i) * 2.0);
+//!     }));
+//!
+//! let buffer = compute.allocate_buffer(16)?;
+//! buffer.write(0, &[1.0f32, 2.0, 3.0, 4.0])?;
+//! compute.dispatch(&shader, &[&buffer], (4, 1, 1), (4, 1, 1))?;
+//! assert_eq!(buffer.read::<f32>()?, vec![2.0, 4.0, 6.0, 8.0]);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+use super::storage::{RangeSet, StorageView};
+use super::validation::Validator;
+use super::{Sampler, Size3, TextureDescriptor, TextureUsage};
+use std::cell::RefCell;
+use std::sync::Arc;
+
+/// Host implementation of a compute kernel, run once per thread.
+#[derive(Clone)]
+pub struct CpuKernel(Arc<dyn Fn(&ThreadContext<'_>) + Send + Sync>);
+
+impl CpuKernel {
+    /// Wrap a per-thread closure.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&ThreadContext<'_>) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Run the kernel for one thread.
+    pub fn run(&self, ctx: &ThreadContext<'_>) {
+        (self.0)(ctx);
+    }
+}
+
+impl std::fmt::Debug for CpuKernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CpuKernel").finish_non_exhaustive()
+    }
+}
+
+/// Per-thread view of a CPU dispatch.
+///
+/// Buffer bindings are indexed like `[[buffer(n)]]` in MSL, and element
+/// indices count 4-byte words. Under [validation](super::validation),
+/// every buffer access is checked.
+pub struct ThreadContext<'a> {
+    position: Size3,
+    grid: Size3,
+    threadgroup: Size3,
+    buffers: &'a [RefCell<Vec<u8>>],
+    /// Words of each buffer written by any thread, if tracked.
+    written: &'a [RefCell<Vec<bool>>],
+    textures: &'a [TextureBinding],
+    validator: Option<&'a Validator>,
+}
+
+impl std::fmt::Debug for ThreadContext<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreadContext")
+            .field("position", &self.position)
+            .field("grid", &self.grid)
+            .field("threadgroup", &self.threadgroup)
+            .field("validating", &self.validator.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Snapshot of a texture bound to a CPU dispatch.
+#[derive(Debug)]
+pub(crate) struct TextureBinding {
+    descriptor: TextureDescriptor,
+    bytes: RefCell<Vec<u8>>,
+}
+
+impl TextureBinding {
+    pub(crate) const fn new(descriptor: TextureDescriptor, bytes: Vec<u8>) -> Self {
+        Self {
+            descriptor,
+            bytes: RefCell::new(bytes),
+        }
+    }
+
+    fn read(&self, position: Size3, slice: u32) -> [f32; 4] {
+        let format = self.descriptor.pixel_format();
+        self.descriptor
+            .texel_offset(position, slice)
+            .and_then(|start| {
+                self.bytes
+                    .borrow()
+                    .get(start..start + format.bytes_per_pixel())
+                    .map(|texel| format.decode(texel))
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl<'a> ThreadContext<'a> {
+    pub(crate) const fn new(
+        position: Size3,
+        grid: Size3,
+        threadgroup: Size3,
+        buffers: &'a [RefCell<Vec<u8>>],
+        textures: &'a [TextureBinding],
+    ) -> Self {
+        Self {
+            position,
+            grid,
+            threadgroup,
+            buffers,
+            written: &[],
+            textures,
+            validator: None,
+        }
+    }
+
+    /// Record the words each thread writes in `written`, one flag per word
+    /// of each buffer.
+    pub(crate) const fn with_written(mut self, written: &'a [RefCell<Vec<bool>>]) -> Self {
+        self.written = written;
+        self
+    }
+
+    /// Check every buffer access with `validator`.
+    pub(crate) const fn with_validator(mut self, validator: Option<&'a Validator>) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    /// Position of this thread in the grid (`thread_position_in_grid`).
+    #[must_use]
+    pub const fn thread_position(&self) -> Size3 {
+        self.position
+    }
+
+    /// Total grid size (`threads_per_grid`).
+    #[must_use]
+    pub const fn grid_size(&self) -> Size3 {
+        self.grid
+    }
+
+    /// Threadgroup size (`threads_per_threadgroup`).
+    #[must_use]
+    pub const fn threads_per_threadgroup(&self) -> Size3 {
+        self.threadgroup
+    }
+
+    /// Position of this thread's threadgroup
+    /// (`threadgroup_position_in_grid`).
+    #[must_use]
+    pub const fn threadgroup_position(&self) -> Size3 {
+        (
+            self.position.0 / self.threadgroup.0,
+            self.position.1 / self.threadgroup.1,
+            self.position.2 / self.threadgroup.2,
+        )
+    }
+
+    /// Position of this thread within its threadgroup
+    /// (`thread_position_in_threadgroup`).
+    #[must_use]
+    pub const fn thread_position_in_threadgroup(&self) -> Size3 {
+        (
+            self.position.0 % self.threadgroup.0,
+            self.position.1 % self.threadgroup.1,
+            self.position.2 % self.threadgroup.2,
+        )
+    }
+
+    /// Length in bytes of the buffer at `binding` (0 if unbound).
+    #[must_use]
+    pub fn buffer_len(&self, binding: usize) -> usize {
+        self.buffers.get(binding).map_or(0, |b| b.borrow().len())
+    }
+
+    fn load(&self, binding: usize, index: usize) -> u32 {
+        let Some(buffer) = self.buffers.get(binding) else {
+            return 0;
+        };
+        let bytes = buffer.borrow();
+        index
+            .checked_mul(4)
+            .and_then(|start| bytes.get(start..start + 4))
+            .and_then(|word| word.try_into().ok())
+            .map_or(0, u32::from_le_bytes)
+    }
+
+    fn store(&self, binding: usize, index: usize, value: u32) {
+        let Some(buffer) = self.buffers.get(binding) else {
+            return;
+        };
+        let mut bytes = buffer.borrow_mut();
+        if let Some(word) = index
+            .checked_mul(4)
+            .and_then(|start| bytes.get_mut(start..start + 4))
+        {
+            word.copy_from_slice(&value.to_le_bytes());
+            if let Some(words) = self.written.get(binding) {
+                if let Some(flag) = words.borrow_mut().get_mut(index) {
+                    *flag = true;
+                }
+            }
+        }
+    }
+
+    /// Read the 32-bit word at `index` of buffer `binding`.
+    ///
+    /// Returns 0 when out of bounds.
+    #[must_use]
+    pub fn read_u32(&self, binding: usize, index: usize) -> u32 {
+        if let Some(validator) = self.validator {
+            validator.read(self.position, binding, index);
+        }
+        self.load(binding, index)
+    }
+
+    /// Write the 32-bit word at `index` of buffer `binding`.
+    ///
+    /// Dropped when out of bounds.
+    pub fn write_u32(&self, binding: usize, index: usize, value: u32) {
+        if let Some(validator) = self.validator {
+            validator.write(self.position, binding, index, false);
+        }
+        self.store(binding, index, value);
+    }
+
+    /// Atomically add `value` to the 32-bit word at `index` of buffer
+    /// `binding` (`atomic_fetch_add_explicit`), returning the old value.
+    ///
+    /// Wraps on overflow; returns 0 and does nothing when out of bounds.
+    #[allow(clippy::must_use_candidate)]
+    pub fn atomic_fetch_add_u32(&self, binding: usize, index: usize, value: u32) -> u32 {
+        if let Some(validator) = self.validator {
+            validator.write(self.position, binding, index, true);
+        }
+        let old = self.load(binding, index);
+        self.store(binding, index, old.wrapping_add(value));
+        old
+    }
+
+    /// Read the `f32` at `index` of buffer `binding`.
+    #[must_use]
+    pub fn read_f32(&self, binding: usize, index: usize) -> f32 {
+        f32::from_bits(self.read_u32(binding, index))
+    }
+
+    /// Write the `f32` at `index` of buffer `binding`.
+    pub fn write_f32(&self, binding: usize, index: usize, value: f32) {
+        self.write_u32(binding, index, value.to_bits());
+    }
+
+    /// Size of the texture at `binding` (`get_width`/`get_height`/
+    /// `get_depth`), or zero if unbound.
+    #[must_use]
+    pub fn texture_size(&self, binding: usize) -> Size3 {
+        self.textures
+            .get(binding)
+            .map_or((0, 0, 0), |texture| texture.descriptor.size())
+    }
+
+    /// Read the texel at `position` in array `slice` of the texture at
+    /// `binding` (`texture.read`), as RGBA.
+    ///
+    /// Returns zero when out of bounds.
+    #[must_use]
+    pub fn read_texture(&self, binding: usize, position: Size3, slice: u32) -> [f32; 4] {
+        self.textures
+            .get(binding)
+            .map_or([0.0; 4], |texture| texture.read(position, slice))
+    }
+
+    /// Write the texel at `position` in array `slice` of the texture at
+    /// `binding` (`texture.write`).
+    ///
+    /// Dropped when out of bounds or the texture lacks
+    /// [`TextureUsage::SHADER_WRITE`].
+    pub fn write_texture(&self, binding: usize, position: Size3, slice: u32, rgba: [f32; 4]) {
+        let Some(texture) = self.textures.get(binding) else {
+            return;
+        };
+        if !texture
+            .descriptor
+            .usage()
+            .contains(TextureUsage::SHADER_WRITE)
+        {
+            return;
+        }
+        let texel = texture.descriptor.pixel_format().encode(rgba);
+        let mut bytes = texture.bytes.borrow_mut();
+        if let Some(target) = texture
+            .descriptor
+            .texel_offset(position, slice)
+            .and_then(|start| bytes.get_mut(start..start + texel.len()))
+        {
+            target.copy_from_slice(&texel);
+        }
+    }
+
+    /// Sample the texture at `binding` with `sampler` at `coord`
+    /// (`texture.sample`); unused coordinates are ignored.
+    ///
+    /// Returns zero if no texture is bound.
+    #[must_use]
+    pub fn sample(
+        &self,
+        binding: usize,
+        sampler: &Sampler,
+        coord: [f32; 3],
+        slice: u32,
+    ) -> [f32; 4] {
+        self.textures.get(binding).map_or([0.0; 4], |texture| {
+            sampler.sample(&texture.descriptor, coord, |position| {
+                texture.read(position, slice)
+            })
+        })
+    }
+}
+
+/// Byte ranges of the words flagged in `words`.
+fn written_ranges(words: &[bool]) -> RangeSet {
+    let mut ranges = RangeSet::default();
+    for (index, _) in words.iter().enumerate().filter(|(_, &written)| written) {
+        ranges.insert(index * 4..index * 4 + 4);
+    }
+    ranges
+}
+
+/// Run `kernel` once for every position in `grid` over the bound buffers
+/// and textures, in threadgroups of `threadgroup` (non-zero in every
+/// dimension).
+///
+/// Contents are snapshotted before the dispatch, and the words each
+/// binding wrote are copied back afterwards, so the dispatch behaves as
+/// one GPU command. Only written words are copied back, so bindings that
+/// alias the same memory don't overwrite each other's results. With a
+/// `validator`, buffer accesses are checked.
+pub(crate) fn execute(
+    kernel: &CpuKernel,
+    grid: Size3,
+    threadgroup: Size3,
+    buffers: &[StorageView],
+    textures: &[(TextureDescriptor, StorageView)],
+    validator: Option<&Validator>,
+) {
+    let snapshots: Vec<RefCell<Vec<u8>>> = buffers
+        .iter()
+        .map(|view| RefCell::new(view.read()))
+        .collect();
+    let written: Vec<RefCell<Vec<bool>>> = snapshots
+        .iter()
+        .map(|snapshot| RefCell::new(vec![false; snapshot.borrow().len() / 4]))
+        .collect();
+    let texture_snapshots: Vec<TextureBinding> = textures
+        .iter()
+        .map(|(descriptor, view)| TextureBinding::new(*descriptor, view.read()))
+        .collect();
+
+    for z in 0..grid.2 {
+        for y in 0..grid.1 {
+            for x in 0..grid.0 {
+                kernel.run(
+                    &ThreadContext::new(
+                        (x, y, z),
+                        grid,
+                        threadgroup,
+                        &snapshots,
+                        &texture_snapshots,
+                    )
+                    .with_written(&written)
+                    .with_validator(validator),
+                );
+            }
+        }
+    }
+
+    for ((view, snapshot), words) in buffers.iter().zip(snapshots).zip(written) {
+        view.write_ranges(&snapshot.into_inner(), &written_ranges(&words.into_inner()));
+    }
+    for ((_, view), snapshot) in textures.iter().zip(texture_snapshots) {
+        view.write(&snapshot.bytes.into_inner());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(values: &[u32]) -> RefCell<Vec<u8>> {
+        RefCell::new(values.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
+
+    #[test]
+    fn test_context_read_write() {
+        let buffers = [words(&[1, 2, 3])];
+        let ctx = ThreadContext::new((1, 0, 0), (3, 1, 1), (3, 1, 1), &buffers, &[]);
+        assert_eq!(ctx.thread_position(), (1, 0, 0));
+        assert_eq!(ctx.grid_size(), (3, 1, 1));
+        assert_eq!(ctx.buffer_len(0), 12);
+        assert_eq!(ctx.read_u32(0, 2), 3);
+        ctx.write_f32(0, 0, 1.5);
+        assert!((ctx.read_f32(0, 0) - 1.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_context_out_of_bounds_is_robust() {
+        let buffers = [words(&[7])];
+        let ctx = ThreadContext::new((0, 0, 0), (1, 1, 1), (1, 1, 1), &buffers, &[]);
+        assert_eq!(ctx.read_u32(0, 1), 0);
+        assert_eq!(ctx.read_u32(5, 0), 0);
+        assert_eq!(ctx.read_u32(0, usize::MAX), 0);
+        ctx.write_u32(0, 1, 9);
+        ctx.write_u32(3, 0, 9);
+        assert_eq!(ctx.read_u32(0, 0), 7);
+        assert_eq!(ctx.buffer_len(0), 4);
+        assert_eq!(ctx.buffer_len(1), 0);
+    }
+
+    #[test]
+    fn test_context_threadgroup_positions() {
+        let ctx = ThreadContext::new((9, 5, 0), (12, 8, 1), (4, 4, 1), &[], &[]);
+        assert_eq!(ctx.threads_per_threadgroup(), (4, 4, 1));
+        assert_eq!(ctx.threadgroup_position(), (2, 1, 0));
+        assert_eq!(ctx.thread_position_in_threadgroup(), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_aliased_bindings_keep_each_others_writes() {
+        use crate::metal::storage::Storage;
+
+        let storage = Storage::new();
+        storage.write(0, &[0; 16]);
+        // The same buffer twice, and a slice overlapping its second half.
+        let whole = StorageView::new(storage.clone(), 0, 16);
+        let tail = StorageView::new(storage.clone(), 8, 8);
+        let kernel = CpuKernel::new(|ctx| {
+            let i = ctx.thread_position().0;
+            ctx.write_u32(i as usize, i as usize, 10 + i);
+        });
+        execute(
+            &kernel,
+            (3, 1, 1),
+            (3, 1, 1),
+            &[whole.clone(), whole, tail],
+            &[],
+            None,
+        );
+        let words: Vec<u32> = crate::metal::storage::from_bytes(&storage.read(0, 16));
+        assert_eq!(words, [10, 11, 0, 0]);
+
+        // Thread 1 writes word 0 of the slice, which is word 2 of the buffer.
+        let kernel = CpuKernel::new(|ctx| ctx.write_u32(ctx.thread_position().0 as usize, 0, 7));
+        let whole = StorageView::new(storage.clone(), 0, 16);
+        let tail = StorageView::new(storage.clone(), 8, 8);
+        execute(&kernel, (2, 1, 1), (2, 1, 1), &[whole, tail], &[], None);
+        let words: Vec<u32> = crate::metal::storage::from_bytes(&storage.read(0, 16));
+        assert_eq!(words, [7, 11, 7, 0]);
+    }
+
+    #[test]
+    fn test_kernel_debug() {
+        let kernel = CpuKernel::new(|_| {});
+        assert!(format!("{kernel:?}").contains("CpuKernel"));
+    }
+}
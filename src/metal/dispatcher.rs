@@ -0,0 +1,533 @@
+//! Backend selection (contract `dispatch-v1`).
+//!
+//! [`Dispatcher::select`] implements
+//! `backend = select(capabilities, task_requirements)`: given the detected
+//! [`Capabilities`] and a [`DispatchTask`], it picks Metal or the host CPU
+//! and records the [`SelectionReason`]. Selection is total: every task gets
+//! a backend, and the CPU is chosen whenever Metal is unavailable or
+//! unsuitable.
+//!
+//! Metal is declined when:
+//!
+//! - no Metal device is present;
+//! - the task needs `f64`, which Metal shaders do not support;
+//! - the latency target is below the GPU's launch overhead;
+//! - a compute task's data is too small to amortize the launch;
+//! - no device can hold the task's data.
+//!
+//! Otherwise the best fitting device is chosen: a display-attached device
+//! for render work, then a high-power device, then the most memory.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::dispatcher::{Capabilities, DispatchTask, Dispatcher, Precision};
+//! use manzana::metal::Backend;
+//!
+//! let dispatcher = Dispatcher::new(Capabilities::cpu_only());
+//! let task = DispatchTask::compute("gemm", 64 << 20).with_precision(Precision::F32);
+//!
+//! let selection = dispatcher.select(&task);
+//! assert_eq!(selection.backend(), Backend::Cpu);
+//! println!("{}", selection.reason());
+//!
+//! let compute = selection.create_pipeline()?;
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - FALSIFY-DISPATCH-001: `select` always returns Metal or CPU
+//! - Without Metal devices, every task selects the CPU
+//! - A Metal selection names a detected device that can hold the data
+
+use super::{memory, Backend, MetalCompute, MetalDevice};
+use crate::error::Result;
+use std::time::Duration;
+
+/// Default estimate of the cost of committing a Metal command buffer.
+pub const DEFAULT_LAUNCH_OVERHEAD: Duration = Duration::from_micros(50);
+
+/// Default smallest compute payload, in bytes, worth sending to the GPU.
+pub const DEFAULT_MIN_GPU_BYTES: u64 = 64 * 1024;
+
+/// Kind of work a task performs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskKind {
+    /// Compute kernels.
+    Compute,
+    /// Rendering.
+    Render,
+}
+
+/// Floating-point precision a task requires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Precision {
+    /// 16-bit `half`.
+    F16,
+    /// 32-bit `float`.
+    F32,
+    /// 64-bit `double`; not available in Metal shaders.
+    F64,
+}
+
+impl Precision {
+    /// True if Metal shaders support this precision.
+    #[must_use]
+    pub const fn is_supported_by_metal(self) -> bool {
+        !matches!(self, Self::F64)
+    }
+}
+
+impl std::fmt::Display for Precision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::F16 => write!(f, "f16"),
+            Self::F32 => write!(f, "f32"),
+            Self::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+/// Requirements of one unit of work.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatchTask {
+    kernel: String,
+    kind: TaskKind,
+    data_bytes: u64,
+    precision: Precision,
+    latency_target: Option<Duration>,
+}
+
+impl DispatchTask {
+    /// A compute task running `kernel` over `data_bytes` of input and
+    /// output, in `f32` with no latency target.
+    #[must_use]
+    pub fn compute(kernel: impl Into<String>, data_bytes: u64) -> Self {
+        Self {
+            kernel: kernel.into(),
+            kind: TaskKind::Compute,
+            data_bytes,
+            precision: Precision::F32,
+            latency_target: None,
+        }
+    }
+
+    /// A render task, otherwise like [`compute`](Self::compute).
+    #[must_use]
+    pub fn render(kernel: impl Into<String>, data_bytes: u64) -> Self {
+        Self {
+            kind: TaskKind::Render,
+            ..Self::compute(kernel, data_bytes)
+        }
+    }
+
+    /// Set the required precision.
+    #[must_use]
+    pub const fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Set the time within which the task should complete.
+    #[must_use]
+    pub const fn with_latency_target(mut self, target: Duration) -> Self {
+        self.latency_target = Some(target);
+        self
+    }
+
+    /// Kernel name.
+    #[must_use]
+    pub fn kernel(&self) -> &str {
+        &self.kernel
+    }
+
+    /// Kind of work.
+    #[must_use]
+    pub const fn kind(&self) -> TaskKind {
+        self.kind
+    }
+
+    /// Bytes of data the task touches.
+    #[must_use]
+    pub const fn data_bytes(&self) -> u64 {
+        self.data_bytes
+    }
+
+    /// Required precision.
+    #[must_use]
+    pub const fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    /// Latency target, if any.
+    #[must_use]
+    pub const fn latency_target(&self) -> Option<Duration> {
+        self.latency_target
+    }
+}
+
+/// Hardware available for dispatch.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    devices: Vec<MetalDevice>,
+}
+
+impl Capabilities {
+    /// Detect the Metal devices on this machine.
+    #[must_use]
+    pub fn detect() -> Self {
+        Self::from_devices(MetalCompute::devices())
+    }
+
+    /// Capabilities with no Metal device; every task runs on the CPU.
+    #[must_use]
+    pub const fn cpu_only() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Capabilities from an explicit device list, as returned by
+    /// [`MetalCompute::devices`].
+    #[must_use]
+    pub const fn from_devices(devices: Vec<MetalDevice>) -> Self {
+        Self { devices }
+    }
+
+    /// Detected Metal devices.
+    #[must_use]
+    pub fn devices(&self) -> &[MetalDevice] {
+        &self.devices
+    }
+
+    /// True if at least one Metal device is present.
+    #[must_use]
+    pub fn has_metal(&self) -> bool {
+        !self.devices.is_empty()
+    }
+}
+
+/// Why a backend was chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectionReason {
+    /// Metal was available and suitable.
+    MetalDevice {
+        /// Name of the chosen device.
+        name: String,
+    },
+    /// No Metal device was detected.
+    NoMetalDevice,
+    /// Metal shaders do not support the required precision.
+    UnsupportedPrecision(Precision),
+    /// The latency target is below the GPU launch overhead.
+    LatencyTarget {
+        /// Requested latency.
+        target: Duration,
+        /// Estimated launch overhead.
+        overhead: Duration,
+    },
+    /// The payload is too small to amortize a GPU launch.
+    BelowOffloadThreshold {
+        /// Task payload in bytes.
+        bytes: u64,
+        /// Smallest payload sent to the GPU.
+        threshold: u64,
+    },
+    /// No Metal device can hold the task's data.
+    ExceedsDeviceMemory {
+        /// Task payload in bytes.
+        required: u64,
+        /// Largest device capacity in bytes.
+        largest: u64,
+    },
+}
+
+impl std::fmt::Display for SelectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MetalDevice { name } => write!(f, "Metal device {name} is suitable"),
+            Self::NoMetalDevice => write!(f, "no Metal device available"),
+            Self::UnsupportedPrecision(precision) => {
+                write!(f, "Metal does not support {precision}")
+            }
+            Self::LatencyTarget { target, overhead } => write!(
+                f,
+                "latency target {target:?} is below GPU launch overhead {overhead:?}"
+            ),
+            Self::BelowOffloadThreshold { bytes, threshold } => write!(
+                f,
+                "{bytes} bytes is below the GPU offload threshold of {threshold} bytes"
+            ),
+            Self::ExceedsDeviceMemory { required, largest } => write!(
+                f,
+                "{required} bytes exceeds the largest device capacity of {largest} bytes"
+            ),
+        }
+    }
+}
+
+/// Outcome of [`Dispatcher::select`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    backend: Backend,
+    device_index: Option<usize>,
+    reason: SelectionReason,
+}
+
+impl Selection {
+    const fn cpu(reason: SelectionReason) -> Self {
+        Self {
+            backend: Backend::Cpu,
+            device_index: None,
+            reason,
+        }
+    }
+
+    /// Chosen backend.
+    #[must_use]
+    pub const fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Index of the chosen Metal device; `None` for the CPU.
+    #[must_use]
+    pub const fn device_index(&self) -> Option<usize> {
+        self.device_index
+    }
+
+    /// Why the backend was chosen.
+    #[must_use]
+    pub const fn reason(&self) -> &SelectionReason {
+        &self.reason
+    }
+
+    /// Create a compute pipeline on the chosen backend.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the chosen Metal device is no longer present.
+    pub fn create_pipeline(&self) -> Result<MetalCompute> {
+        self.device_index
+            .map_or_else(|| Ok(MetalCompute::cpu()), MetalCompute::new)
+    }
+}
+
+/// Chooses Metal or the CPU for each task.
+#[derive(Debug, Clone)]
+pub struct Dispatcher {
+    capabilities: Capabilities,
+    launch_overhead: Duration,
+    min_gpu_bytes: u64,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher over `capabilities`.
+    #[must_use]
+    pub const fn new(capabilities: Capabilities) -> Self {
+        Self {
+            capabilities,
+            launch_overhead: DEFAULT_LAUNCH_OVERHEAD,
+            min_gpu_bytes: DEFAULT_MIN_GPU_BYTES,
+        }
+    }
+
+    /// Create a dispatcher over the detected devices.
+    #[must_use]
+    pub fn detect() -> Self {
+        Self::new(Capabilities::detect())
+    }
+
+    /// Set the estimated GPU launch overhead.
+    ///
+    /// Tasks with a latency target below it run on the CPU.
+    #[must_use]
+    pub const fn with_launch_overhead(mut self, overhead: Duration) -> Self {
+        self.launch_overhead = overhead;
+        self
+    }
+
+    /// Set the smallest compute payload sent to the GPU.
+    #[must_use]
+    pub const fn with_min_gpu_bytes(mut self, bytes: u64) -> Self {
+        self.min_gpu_bytes = bytes;
+        self
+    }
+
+    /// Capabilities selection is based on.
+    #[must_use]
+    pub const fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Choose a backend for `task`.
+    ///
+    /// Never fails: the CPU is the fallback for every case Metal cannot
+    /// serve.
+    #[must_use]
+    #[provable_contracts_macros::contract("dispatch-v1", equation = "dispatch_selection")]
+    pub fn select(&self, task: &DispatchTask) -> Selection {
+        if !self.capabilities.has_metal() {
+            return Selection::cpu(SelectionReason::NoMetalDevice);
+        }
+        if !task.precision.is_supported_by_metal() {
+            return Selection::cpu(SelectionReason::UnsupportedPrecision(task.precision));
+        }
+        if let Some(target) = task.latency_target {
+            if target < self.launch_overhead {
+                return Selection::cpu(SelectionReason::LatencyTarget {
+                    target,
+                    overhead: self.launch_overhead,
+                });
+            }
+        }
+        if task.kind == TaskKind::Compute && task.data_bytes < self.min_gpu_bytes {
+            return Selection::cpu(SelectionReason::BelowOffloadThreshold {
+                bytes: task.data_bytes,
+                threshold: self.min_gpu_bytes,
+            });
+        }
+
+        let render = task.kind == TaskKind::Render;
+        let best = self
+            .capabilities
+            .devices
+            .iter()
+            .filter(|device| memory::device_capacity(device) >= task.data_bytes)
+            .max_by_key(|device| {
+                (
+                    render && !device.is_headless,
+                    !device.is_low_power,
+                    memory::device_capacity(device),
+                )
+            });
+
+        best.map_or_else(
+            || {
+                Selection::cpu(SelectionReason::ExceedsDeviceMemory {
+                    required: task.data_bytes,
+                    largest: self
+                        .capabilities
+                        .devices
+                        .iter()
+                        .map(memory::device_capacity)
+                        .max()
+                        .unwrap_or(0),
+                })
+            },
+            |device| Selection {
+                backend: Backend::Metal,
+                device_index: Some(device.index),
+                reason: SelectionReason::MetalDevice {
+                    name: device.name.clone(),
+                },
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn device(index: usize, capacity: u64, is_low_power: bool, is_headless: bool) -> MetalDevice {
+        MetalDevice {
+            name: format!("GPU {index}"),
+            registry_id: 100 + index as u64,
+            device_id: None,
+            peer_group_id: 0,
+            is_low_power,
+            is_headless,
+            max_threads_per_threadgroup: 1024,
+            max_buffer_length: capacity,
+            vram_bytes: 0,
+            has_unified_memory: false,
+            index,
+        }
+    }
+
+    fn two_gpus() -> Dispatcher {
+        Dispatcher::new(Capabilities::from_devices(vec![
+            device(0, 8 << 30, true, false),
+            device(1, 16 << 30, false, true),
+        ]))
+    }
+
+    #[test]
+    fn test_no_metal_falls_back_to_cpu() {
+        let dispatcher = Dispatcher::new(Capabilities::cpu_only());
+        let selection = dispatcher.select(&DispatchTask::render("blit", 1 << 30));
+        assert_eq!(selection.backend(), Backend::Cpu);
+        assert_eq!(selection.device_index(), None);
+        assert_eq!(selection.reason(), &SelectionReason::NoMetalDevice);
+        assert_eq!(selection.create_pipeline().unwrap().backend(), Backend::Cpu);
+    }
+
+    #[test]
+    fn test_cpu_reasons() {
+        let dispatcher = two_gpus();
+        let big = 1 << 20;
+
+        let f64_task = DispatchTask::compute("gemm", big).with_precision(Precision::F64);
+        assert_eq!(
+            dispatcher.select(&f64_task).reason(),
+            &SelectionReason::UnsupportedPrecision(Precision::F64)
+        );
+
+        let urgent =
+            DispatchTask::compute("gemm", big).with_latency_target(Duration::from_micros(10));
+        assert!(matches!(
+            dispatcher.select(&urgent).reason(),
+            SelectionReason::LatencyTarget { .. }
+        ));
+
+        let tiny = DispatchTask::compute("add", 16);
+        let selection = dispatcher.select(&tiny);
+        assert_eq!(selection.backend(), Backend::Cpu);
+        assert!(selection.reason().to_string().contains("offload threshold"));
+
+        let huge = DispatchTask::compute("gemm", 32 << 30);
+        assert_eq!(
+            dispatcher.select(&huge).reason(),
+            &SelectionReason::ExceedsDeviceMemory {
+                required: 32 << 30,
+                largest: 16 << 30,
+            }
+        );
+    }
+
+    #[test]
+    fn test_metal_device_preference() {
+        let dispatcher = two_gpus();
+
+        // Compute prefers the high-power device.
+        let compute = dispatcher.select(&DispatchTask::compute("gemm", 1 << 20));
+        assert_eq!(compute.backend(), Backend::Metal);
+        assert_eq!(compute.device_index(), Some(1));
+
+        // Render prefers a display-attached device, even a small one, and
+        // is not subject to the offload threshold.
+        let render = dispatcher.select(&DispatchTask::render("draw", 16));
+        assert_eq!(render.device_index(), Some(0));
+
+        // Only the larger device fits.
+        let large = dispatcher.select(&DispatchTask::render("draw", 12 << 30));
+        assert_eq!(large.device_index(), Some(1));
+        assert_eq!(
+            large.reason(),
+            &SelectionReason::MetalDevice {
+                name: "GPU 1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_thresholds_configurable() {
+        let dispatcher = two_gpus()
+            .with_min_gpu_bytes(0)
+            .with_launch_overhead(Duration::ZERO);
+        let task = DispatchTask::compute("add", 4).with_latency_target(Duration::from_nanos(1));
+        assert_eq!(dispatcher.select(&task).backend(), Backend::Metal);
+    }
+}
@@ -0,0 +1,641 @@
+//! Shared access to one pipeline from many threads (`async` feature).
+//!
+//! [`MetalCompute`] is `!Send` and `!Sync`, and creating one enumerates
+//! the system's devices. A [`MetalExecutor`] creates the pipeline once on
+//! a dedicated thread and hands out [`ExecutorHandle`]s, which are cheap to
+//! clone and can move between threads and async tasks. Handles send
+//! compile, allocate, copy and dispatch requests to the executor thread and
+//! get results back as [`GpuFuture`]s, or through a callback with
+//! [`ExecutorHandle::execute_then`].
+//!
+//! The executor thread runs requests one at a time in the order they
+//! arrive, so requests from one handle take effect in the order that
+//! handle made them: a read submitted after a dispatch observes the
+//! dispatch's writes without awaiting it first.
+//!
+//! Buffers and shaders stay on the executor thread. Handles refer to them
+//! through [`RemoteBuffer`] and [`RemoteShader`], which release the
+//! underlying object when their last clone is dropped.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::completion::block_on;
+//! use manzana::metal::executor::MetalExecutor;
+//! use manzana::metal::CpuKernel;
+//!
+//! let executor = MetalExecutor::cpu()?;
+//! let handle = executor.handle();
+//!
+//! let worker = std::thread::spawn(move || {
+//!     let shader = handle.compile_with_cpu_kernel(
+//!         "kernel void inc() {}",
+//!         "inc",
+//!         CpuKernel::new(|ctx| {
+//!             let i = ctx.thread_position().0 as usize;
+//!             ctx.write_u32(0, i, ctx.read_u32(0, i) + 1);
+//!         }),
+//!     );
+//!     let buffer = block_on(handle.allocate(16))?;
+//!     let done = handle.dispatch(&block_on(shader)?, &[&buffer], (4, 1, 1), (4, 1, 1));
+//!     let values = handle.read::<u32>(&buffer);
+//!     block_on(done)?;
+//!     block_on(values)
+//! });
+//! assert_eq!(worker.join().unwrap()?, vec![1, 1, 1, 1]);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - `ExecutorHandle`, `RemoteBuffer` and `RemoteShader` are `Send + Sync`
+//! - Requests from one handle run in submission order
+//! - The pipeline is created once per executor, not once per handle
+
+use super::completion::{Completion, GpuFuture};
+use super::storage::BufferElement;
+use super::{CompiledShader, CpuKernel, MetalBuffer, MetalCompute, Size3};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tracing::{debug, warn};
+
+/// Source of executor ids, so resources can't be used on another executor.
+static NEXT_EXECUTOR: AtomicU64 = AtomicU64::new(0);
+
+type Request = Box<dyn FnOnce(&mut Worker) + Send>;
+
+/// State owned by the executor thread.
+struct Worker {
+    compute: MetalCompute,
+    buffers: HashMap<u64, MetalBuffer>,
+    shaders: HashMap<u64, CompiledShader>,
+    next_id: u64,
+}
+
+impl Worker {
+    fn insert_buffer(&mut self, buffer: MetalBuffer) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.buffers.insert(id, buffer);
+        id
+    }
+
+    fn insert_shader(&mut self, shader: CompiledShader) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.shaders.insert(id, shader);
+        id
+    }
+
+    fn buffer(&self, id: u64) -> Result<&MetalBuffer> {
+        self.buffers
+            .get(&id)
+            .ok_or_else(|| Error::not_found(format!("executor buffer {id}")))
+    }
+
+    fn shader(&self, id: u64) -> Result<&CompiledShader> {
+        self.shaders
+            .get(&id)
+            .ok_or_else(|| Error::not_found(format!("executor shader {id}")))
+    }
+}
+
+enum Message {
+    Run(Request),
+    Stop,
+}
+
+/// Sending side shared by an executor and its handles.
+struct Channel {
+    executor: u64,
+    sender: Sender<Message>,
+    closed: AtomicBool,
+}
+
+impl Channel {
+    /// Queue `request`; false if the executor has shut down.
+    fn send(&self, request: Request) -> bool {
+        !self.closed.load(Ordering::Acquire) && self.sender.send(Message::Run(request)).is_ok()
+    }
+}
+
+/// Owner of a pipeline running on a dedicated thread.
+///
+/// Dropping the executor waits for the requests already queued, then stops
+/// the thread and frees its buffers and shaders. Requests made through
+/// surviving handles afterwards fail.
+pub struct MetalExecutor {
+    channel: Arc<Channel>,
+    device_name: String,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetalExecutor {
+    /// Start an executor for the device at `device_index`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device index is out of bounds or the thread
+    /// can't be started.
+    pub fn new(device_index: usize) -> Result<Self> {
+        Self::spawn(move || MetalCompute::new(device_index))
+    }
+
+    /// Start an executor on the host CPU backend.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the thread can't be started.
+    pub fn cpu() -> Result<Self> {
+        Self::spawn(|| Ok(MetalCompute::cpu()))
+    }
+
+    /// Start an executor whose pipeline is built by `init` on the executor
+    /// thread, for pipelines configured with a profiler, validation or a
+    /// memory budget.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from `init`, or an error if the thread can't be
+    /// started.
+    pub fn spawn<F>(init: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<MetalCompute> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Message>();
+        let (ready, started) = mpsc::channel::<Result<String>>();
+        let thread = thread::Builder::new()
+            .name("manzana-metal-executor".to_string())
+            .spawn(move || {
+                let compute = match init() {
+                    Ok(compute) => compute,
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready.send(Ok(compute.device_name().to_string()));
+                let mut worker = Worker {
+                    compute,
+                    buffers: HashMap::new(),
+                    shaders: HashMap::new(),
+                    next_id: 0,
+                };
+                while let Ok(Message::Run(request)) = receiver.recv() {
+                    // A panicking request fails its own future (its
+                    // completion is dropped) without stopping the thread.
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        request(&mut worker);
+                    }));
+                }
+                debug!("metal executor stopped");
+            })
+            .map_err(|e| Error::metal(format!("failed to start executor thread: {e}")))?;
+        let device_name = match started.recv() {
+            Ok(Ok(name)) => name,
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                return Err(e);
+            }
+            Err(_) => {
+                let _ = thread.join();
+                return Err(Error::internal("executor thread exited during start-up"));
+            }
+        };
+        debug!(device = %device_name, "metal executor started");
+        Ok(Self {
+            channel: Arc::new(Channel {
+                executor: NEXT_EXECUTOR.fetch_add(1, Ordering::Relaxed),
+                sender,
+                closed: AtomicBool::new(false),
+            }),
+            device_name,
+            thread: Some(thread),
+        })
+    }
+
+    /// Name of the executor's device.
+    #[must_use]
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    /// A handle for submitting requests.
+    #[must_use]
+    pub fn handle(&self) -> ExecutorHandle {
+        ExecutorHandle {
+            channel: Arc::clone(&self.channel),
+        }
+    }
+}
+
+impl Drop for MetalExecutor {
+    fn drop(&mut self) {
+        self.channel.closed.store(true, Ordering::Release);
+        // Handles keep the channel open, so stop the thread explicitly,
+        // behind the requests already queued.
+        let _ = self.channel.sender.send(Message::Stop);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("metal executor thread panicked");
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MetalExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetalExecutor")
+            .field("device", &self.device_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A resource living on the executor thread.
+struct Remote {
+    channel: Arc<Channel>,
+    id: u64,
+    release: fn(&mut Worker, u64),
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        let (id, release) = (self.id, self.release);
+        self.channel
+            .send(Box::new(move |worker| release(worker, id)));
+    }
+}
+
+/// A [`MetalBuffer`] owned by an executor.
+///
+/// Clones refer to the same buffer, which is freed when the last clone is
+/// dropped.
+#[derive(Clone)]
+pub struct RemoteBuffer {
+    remote: Arc<Remote>,
+    len: usize,
+}
+
+impl RemoteBuffer {
+    /// Buffer length in bytes.
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the buffer is empty.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl fmt::Debug for RemoteBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteBuffer")
+            .field("id", &self.remote.id)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// A [`CompiledShader`] owned by an executor.
+///
+/// Clones refer to the same pipeline, which is freed when the last clone
+/// is dropped.
+#[derive(Clone)]
+pub struct RemoteShader {
+    remote: Arc<Remote>,
+    name: String,
+}
+
+impl RemoteShader {
+    /// Kernel function name.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for RemoteShader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteShader")
+            .field("id", &self.remote.id)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// Cloneable, `Send + Sync` access to a [`MetalExecutor`]'s pipeline.
+///
+/// Every method queues a request and returns at once. Requests from one
+/// handle run in the order they were made.
+#[derive(Clone)]
+pub struct ExecutorHandle {
+    channel: Arc<Channel>,
+}
+
+impl ExecutorHandle {
+    /// Run `f` with the executor's pipeline.
+    ///
+    /// Resolves with an error if the executor has shut down.
+    pub fn execute<T, F>(&self, f: F) -> GpuFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&MetalCompute) -> Result<T> + Send + 'static,
+    {
+        self.request(move |worker| f(&worker.compute))
+    }
+
+    /// Run `f` with the executor's pipeline and pass its result to
+    /// `callback`.
+    ///
+    /// The callback runs on the executor thread, so it must not wait on
+    /// other requests to the same executor.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, without calling `callback`, if the executor has
+    /// shut down.
+    pub fn execute_then<T, F, C>(&self, f: F, callback: C) -> Result<()>
+    where
+        F: FnOnce(&MetalCompute) -> Result<T> + Send + 'static,
+        C: FnOnce(Result<T>) + Send + 'static,
+    {
+        if self
+            .channel
+            .send(Box::new(move |worker| callback(f(&worker.compute))))
+        {
+            Ok(())
+        } else {
+            Err(shut_down())
+        }
+    }
+
+    /// Compile `function_name` from `source`.
+    ///
+    /// Resolves with an error if compilation fails; see
+    /// [`MetalCompute::compile_shader`].
+    pub fn compile(&self, source: &str, function_name: &str) -> GpuFuture<RemoteShader> {
+        self.compile_shader(source, function_name, None)
+    }
+
+    /// Compile `function_name` from `source` with a [`CpuKernel`] for the
+    /// CPU backend.
+    pub fn compile_with_cpu_kernel(
+        &self,
+        source: &str,
+        function_name: &str,
+        kernel: CpuKernel,
+    ) -> GpuFuture<RemoteShader> {
+        self.compile_shader(source, function_name, Some(kernel))
+    }
+
+    fn compile_shader(
+        &self,
+        source: &str,
+        function_name: &str,
+        kernel: Option<CpuKernel>,
+    ) -> GpuFuture<RemoteShader> {
+        let (source, name) = (source.to_string(), function_name.to_string());
+        let channel = Arc::clone(&self.channel);
+        self.request(move |worker| {
+            let mut shader = worker.compute.compile_shader(&source, &name)?;
+            if let Some(kernel) = kernel {
+                shader = shader.with_cpu_kernel(kernel);
+            }
+            let id = worker.insert_shader(shader);
+            Ok(RemoteShader {
+                remote: Arc::new(Remote {
+                    channel,
+                    id,
+                    release: |worker, id| drop(worker.shaders.remove(&id)),
+                }),
+                name,
+            })
+        })
+    }
+
+    /// Allocate a buffer of `length` bytes.
+    ///
+    /// Resolves with an error if allocation fails; see
+    /// [`MetalCompute::allocate_buffer`].
+    pub fn allocate(&self, length: usize) -> GpuFuture<RemoteBuffer> {
+        let channel = Arc::clone(&self.channel);
+        self.request(move |worker| {
+            let buffer = worker.compute.allocate_buffer(length)?;
+            let len = buffer.len();
+            let id = worker.insert_buffer(buffer);
+            Ok(RemoteBuffer {
+                remote: Arc::new(Remote {
+                    channel,
+                    id,
+                    release: |worker, id| drop(worker.buffers.remove(&id)),
+                }),
+                len,
+            })
+        })
+    }
+
+    /// Copy `data` into `buffer` starting at element `index`; see
+    /// [`MetalBuffer::write`].
+    pub fn write<T>(&self, buffer: &RemoteBuffer, index: usize, data: Vec<T>) -> GpuFuture<()>
+    where
+        T: BufferElement + Send + 'static,
+    {
+        let id = match self.local(&buffer.remote) {
+            Ok(id) => id,
+            Err(e) => return GpuFuture::ready(Err(e)),
+        };
+        self.request(move |worker| worker.buffer(id)?.write(index, &data))
+    }
+
+    /// Copy out the contents of `buffer`; see [`MetalBuffer::read`].
+    pub fn read<T>(&self, buffer: &RemoteBuffer) -> GpuFuture<Vec<T>>
+    where
+        T: BufferElement + Send + 'static,
+    {
+        let id = match self.local(&buffer.remote) {
+            Ok(id) => id,
+            Err(e) => return GpuFuture::ready(Err(e)),
+        };
+        self.request(move |worker| worker.buffer(id)?.read())
+    }
+
+    /// Run `shader` over `grid_size` threads; see
+    /// [`MetalCompute::dispatch`].
+    pub fn dispatch(
+        &self,
+        shader: &RemoteShader,
+        buffers: &[&RemoteBuffer],
+        grid_size: Size3,
+        threadgroup_size: Size3,
+    ) -> GpuFuture<()> {
+        let ids = std::iter::once(&shader.remote)
+            .chain(buffers.iter().map(|buffer| &buffer.remote))
+            .map(|remote| self.local(remote))
+            .collect::<Result<Vec<_>>>();
+        let ids = match ids {
+            Ok(ids) => ids,
+            Err(e) => return GpuFuture::ready(Err(e)),
+        };
+        self.request(move |worker| {
+            let shader = worker.shader(ids[0])?;
+            let buffers = ids[1..]
+                .iter()
+                .map(|&id| worker.buffer(id))
+                .collect::<Result<Vec<_>>>()?;
+            worker
+                .compute
+                .dispatch(shader, &buffers, grid_size, threadgroup_size)
+        })
+    }
+
+    /// Id of `remote` if it belongs to this handle's executor.
+    fn local(&self, remote: &Remote) -> Result<u64> {
+        if remote.channel.executor == self.channel.executor {
+            Ok(remote.id)
+        } else {
+            Err(Error::invalid_input(
+                "resource belongs to a different executor",
+            ))
+        }
+    }
+
+    fn request<T, F>(&self, f: F) -> GpuFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Worker) -> Result<T> + Send + 'static,
+    {
+        let (completion, future) = Completion::new();
+        if self
+            .channel
+            .send(Box::new(move |worker| completion.complete(f(worker))))
+        {
+            future
+        } else {
+            GpuFuture::ready(Err(shut_down()))
+        }
+    }
+}
+
+impl fmt::Debug for ExecutorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExecutorHandle")
+            .field("executor", &self.channel.executor)
+            .finish_non_exhaustive()
+    }
+}
+
+fn shut_down() -> Error {
+    Error::metal("executor has shut down")
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::metal::completion::block_on;
+
+    fn increment() -> CpuKernel {
+        CpuKernel::new(|ctx| {
+            let i = ctx.thread_position().0 as usize;
+            ctx.write_u32(0, i, ctx.read_u32(0, i) + 1);
+        })
+    }
+
+    #[test]
+    fn test_handles_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ExecutorHandle>();
+        assert_send_sync::<RemoteBuffer>();
+        assert_send_sync::<RemoteShader>();
+    }
+
+    #[test]
+    fn test_requests_run_in_order_per_handle() {
+        let executor = MetalExecutor::cpu().unwrap();
+        assert_eq!(executor.device_name(), "CPU");
+        let workers: Vec<_> = (0..4u32)
+            .map(|n| {
+                let handle = executor.handle();
+                thread::spawn(move || {
+                    let shader =
+                        handle.compile_with_cpu_kernel("kernel void inc() {}", "inc", increment());
+                    let buffer = block_on(handle.allocate(32)).unwrap();
+                    let shader = block_on(shader).unwrap();
+                    // Awaited only after the read: ordering alone makes
+                    // the read see the writes.
+                    let mut queued = vec![handle.write(&buffer, 0, vec![n; 8])];
+                    for _ in 0..3 {
+                        queued.push(handle.dispatch(&shader, &[&buffer], (8, 1, 1), (8, 1, 1)));
+                    }
+                    let values = block_on(handle.read::<u32>(&buffer)).unwrap();
+                    assert!(queued.into_iter().all(|done| done.is_complete()));
+                    values
+                })
+            })
+            .collect();
+        for (n, worker) in (0..4u32).zip(workers) {
+            assert_eq!(worker.join().unwrap(), vec![n + 3; 8]);
+        }
+    }
+
+    #[test]
+    fn test_errors_and_callbacks() {
+        let executor = MetalExecutor::cpu().unwrap();
+        let handle = executor.handle();
+        let shader = block_on(handle.compile("kernel void k() {}", "k")).unwrap();
+        assert_eq!(shader.name(), "k");
+        let buffer = block_on(handle.allocate(16)).unwrap();
+        // No CPU kernel: the dispatch fails on the executor thread.
+        assert!(block_on(handle.dispatch(&shader, &[&buffer], (4, 1, 1), (4, 1, 1))).is_err());
+
+        let other = MetalExecutor::cpu().unwrap();
+        let foreign = block_on(other.handle().allocate(16)).unwrap();
+        let err = block_on(handle.read::<u32>(&foreign)).unwrap_err();
+        assert!(err.to_string().contains("different executor"));
+
+        let (sender, receiver) = mpsc::channel();
+        handle
+            .execute_then(
+                |compute| Ok(compute.device_name().to_string()),
+                move |name| {
+                    sender.send(name).unwrap();
+                },
+            )
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap().unwrap(), "CPU");
+        let usage =
+            block_on(handle.execute(|compute| Ok(compute.memory_usage().live_buffers))).unwrap();
+        assert_eq!(usage, 1);
+
+        // Dropping the last clone frees the buffer on the executor thread.
+        drop(buffer);
+        drop(foreign);
+        let usage =
+            block_on(handle.execute(|compute| Ok(compute.memory_usage().live_buffers))).unwrap();
+        assert_eq!(usage, 0);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        assert!(MetalExecutor::spawn(|| Err(Error::not_found("device 7"))).is_err());
+
+        let executor = MetalExecutor::cpu().unwrap();
+        let handle = executor.handle();
+        let buffer = block_on(handle.allocate(16)).unwrap();
+        let pending = handle.write(&buffer, 0, vec![1u32; 4]);
+        drop(executor);
+        // Queued work finished before the thread stopped.
+        assert!(block_on(pending).is_ok());
+        let err = block_on(handle.read::<u32>(&buffer)).unwrap_err();
+        assert!(err.to_string().contains("shut down"));
+        assert!(handle.execute_then(|_| Ok(()), |_| {}).is_err());
+    }
+}
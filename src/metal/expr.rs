@@ -0,0 +1,1379 @@
+//! Fused elementwise kernels from Rust expressions.
+//!
+//! An [`Expr`] is a tree of elementwise operations over named, typed
+//! inputs. [`MetalCompute::compile_fused`] checks the types, broadcasts the
+//! input shapes (NumPy rules) and generates one MSL kernel that evaluates
+//! the whole tree per output element, so `gelu(a * b + c)` costs one
+//! dispatch and no intermediate buffers. The same expression can be
+//! evaluated on the host with [`FusedKernel::evaluate`] to check results.
+//!
+//! Types are never converted implicitly: both operands of a binary
+//! operation must have the same [`DType`], and [`Expr::cast`] converts
+//! explicitly. Transcendental functions (`exp`, `tanh`, `gelu`, ...) take
+//! `f32` only. Integer arithmetic wraps; integer division by zero is
+//! undefined in MSL and evaluates to 0 on the host.
+//!
+//! Inputs are bound in order of first appearance in the expression,
+//! followed by the output.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::{DType, Expr, HostData, MetalCompute};
+//!
+//! let a = Expr::input("a", DType::F32, &[2, 3]);
+//! let b = Expr::input("b", DType::F32, &[3]);
+//! let c = Expr::input("c", DType::F32, &[2, 1]);
+//! let y = (a * b + c).gelu();
+//!
+//! let compute = MetalCompute::cpu();
+//! let kernel = compute.compile_fused("fused_gelu", &y)?;
+//! assert_eq!(kernel.output_shape(), [2, 3]);
+//!
+//! let inputs = [
+//!     HostData::from(vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]),
+//!     HostData::from(vec![0.5f32, -1.0, 2.0]),
+//!     HostData::from(vec![0.0f32, 1.0]),
+//! ];
+//! let buffers = inputs
+//!     .iter()
+//!     .map(|data| compute.upload(data))
+//!     .collect::<Result<Vec<_>, _>>()?;
+//! let output = compute.allocate_buffer(kernel.len() * 4)?;
+//! kernel.run(&buffers.iter().collect::<Vec<_>>(), &output)?;
+//!
+//! assert_eq!(HostData::F32(output.read()?), kernel.evaluate(&inputs)?);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - GPU and host evaluation of the same expression agree
+//! - Mixed-type operations are rejected rather than converted
+//! - Inputs broadcast exactly as NumPy broadcasts them
+
+use super::kernels::cpu_reference;
+use super::{CompiledShader, CpuKernel, MetalBuffer, MetalCompute};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::sync::Arc;
+
+/// Element type of an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DType {
+    /// 32-bit float (`float`).
+    F32,
+    /// 32-bit signed integer (`int`).
+    I32,
+    /// 32-bit unsigned integer (`uint`).
+    U32,
+}
+
+impl DType {
+    /// Size of one element in bytes.
+    #[must_use]
+    pub const fn size(self) -> usize {
+        4
+    }
+
+    /// MSL spelling.
+    #[must_use]
+    pub const fn msl_name(self) -> &'static str {
+        match self {
+            Self::F32 => "float",
+            Self::I32 => "int",
+            Self::U32 => "uint",
+        }
+    }
+}
+
+impl fmt::Display for DType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::F32 => write!(f, "f32"),
+            Self::I32 => write!(f, "i32"),
+            Self::U32 => write!(f, "u32"),
+        }
+    }
+}
+
+/// One element of some [`DType`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    F32(f32),
+    I32(i32),
+    U32(u32),
+}
+
+impl Value {
+    fn from_bits(dtype: DType, bits: u32) -> Self {
+        match dtype {
+            DType::F32 => Self::F32(f32::from_bits(bits)),
+            #[allow(clippy::cast_possible_wrap)]
+            DType::I32 => Self::I32(bits as i32),
+            DType::U32 => Self::U32(bits),
+        }
+    }
+
+    fn to_bits(self) -> u32 {
+        match self {
+            Self::F32(v) => v.to_bits(),
+            #[allow(clippy::cast_sign_loss)]
+            Self::I32(v) => v as u32,
+            Self::U32(v) => v,
+        }
+    }
+
+    const fn dtype(self) -> DType {
+        match self {
+            Self::F32(_) => DType::F32,
+            Self::I32(_) => DType::I32,
+            Self::U32(_) => DType::U32,
+        }
+    }
+
+    /// MSL literal.
+    fn literal(self) -> String {
+        match self {
+            Self::F32(v) if v.is_nan() => "NAN".to_string(),
+            Self::F32(v) if v.is_infinite() => {
+                if v > 0.0 { "INFINITY" } else { "(-INFINITY)" }.to_string()
+            }
+            Self::F32(v) => format!("{v:?}f"),
+            Self::I32(v) => format!("int({v})"),
+            Self::U32(v) => format!("{v}u"),
+        }
+    }
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_possible_wrap,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    const fn cast(self, dtype: DType) -> Self {
+        match (self, dtype) {
+            (Self::F32(v), DType::F32) => Self::F32(v),
+            (Self::F32(v), DType::I32) => Self::I32(v as i32),
+            (Self::F32(v), DType::U32) => Self::U32(v as u32),
+            (Self::I32(v), DType::F32) => Self::F32(v as f32),
+            (Self::I32(v), DType::I32) => Self::I32(v),
+            (Self::I32(v), DType::U32) => Self::U32(v as u32),
+            (Self::U32(v), DType::F32) => Self::F32(v as f32),
+            (Self::U32(v), DType::I32) => Self::I32(v as i32),
+            (Self::U32(v), DType::U32) => Self::U32(v),
+        }
+    }
+}
+
+/// Elementwise functions of one operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unary {
+    Neg,
+    Abs,
+    Relu,
+    Exp,
+    Log,
+    Sqrt,
+    Tanh,
+    Sigmoid,
+    Gelu,
+    Silu,
+}
+
+impl Unary {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Neg => "neg",
+            Self::Abs => "abs",
+            Self::Relu => "relu",
+            Self::Exp => "exp",
+            Self::Log => "log",
+            Self::Sqrt => "sqrt",
+            Self::Tanh => "tanh",
+            Self::Sigmoid => "sigmoid",
+            Self::Gelu => "gelu",
+            Self::Silu => "silu",
+        }
+    }
+
+    const fn float_only(self) -> bool {
+        !matches!(self, Self::Neg | Self::Abs | Self::Relu)
+    }
+
+    fn msl(self, dtype: DType, a: &str) -> String {
+        match (self, dtype) {
+            (Self::Neg, _) => format!("-{a}"),
+            (Self::Abs | Self::Relu, DType::U32) => a.to_string(),
+            (Self::Abs, _) => format!("abs({a})"),
+            (Self::Relu, DType::F32) => format!("max({a}, 0.0f)"),
+            (Self::Relu, _) => format!("max({a}, 0)"),
+            (Self::Sigmoid, _) => format!("1.0f / (1.0f + exp(-{a}))"),
+            (Self::Gelu, _) => format!(
+                "0.5f * {a} * (1.0f + tanh(0.7978846f * fma(0.044715f * {a} * {a}, {a}, {a})))"
+            ),
+            (Self::Silu, _) => format!("{a} / (1.0f + exp(-{a}))"),
+            (Self::Exp | Self::Log | Self::Sqrt | Self::Tanh, _) => {
+                format!("{}({a})", self.name())
+            }
+        }
+    }
+
+    fn apply(self, value: Value) -> Value {
+        match value {
+            Value::F32(x) => Value::F32(match self {
+                Self::Neg => -x,
+                Self::Abs => x.abs(),
+                Self::Relu => x.max(0.0),
+                Self::Exp => x.exp(),
+                Self::Log => x.ln(),
+                Self::Sqrt => x.sqrt(),
+                Self::Tanh => x.tanh(),
+                Self::Sigmoid => 1.0 / (1.0 + (-x).exp()),
+                Self::Gelu => cpu_reference::gelu(x),
+                Self::Silu => cpu_reference::silu(x),
+            }),
+            Value::I32(x) => Value::I32(match self {
+                Self::Neg => x.wrapping_neg(),
+                Self::Abs => x.wrapping_abs(),
+                Self::Relu => x.max(0),
+                _ => x,
+            }),
+            Value::U32(x) => Value::U32(match self {
+                Self::Neg => x.wrapping_neg(),
+                _ => x,
+            }),
+        }
+    }
+}
+
+/// Elementwise functions of two operands of the same type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Binary {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Max,
+    Min,
+}
+
+impl Binary {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Max => "max",
+            Self::Min => "min",
+        }
+    }
+
+    fn msl(self, a: &str, b: &str) -> String {
+        match self {
+            Self::Max | Self::Min => format!("{}({a}, {b})", self.name()),
+            _ => format!("{a} {} {b}", self.name()),
+        }
+    }
+
+    fn apply(self, a: Value, b: Value) -> Value {
+        macro_rules! int {
+            ($variant:ident, $a:expr, $b:expr) => {
+                Value::$variant(match self {
+                    Self::Add => $a.wrapping_add($b),
+                    Self::Sub => $a.wrapping_sub($b),
+                    Self::Mul => $a.wrapping_mul($b),
+                    Self::Div => $a.checked_div($b).unwrap_or(0),
+                    Self::Max => $a.max($b),
+                    Self::Min => $a.min($b),
+                })
+            };
+        }
+        match (a, b) {
+            (Value::F32(a), Value::F32(b)) => Value::F32(match self {
+                Self::Add => a + b,
+                Self::Sub => a - b,
+                Self::Mul => a * b,
+                Self::Div => a / b,
+                Self::Max => a.max(b),
+                Self::Min => a.min(b),
+            }),
+            (Value::I32(a), Value::I32(b)) => int!(I32, a, b),
+            (Value::U32(a), Value::U32(b)) => int!(U32, a, b),
+            // Operand types are checked when the expression is compiled.
+            _ => a,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Input {
+        name: String,
+        dtype: DType,
+        shape: Vec<usize>,
+    },
+    Constant(Value),
+    Unary(Unary, Expr),
+    Binary(Binary, Expr, Expr),
+    Cast(DType, Expr),
+}
+
+/// An elementwise expression.
+///
+/// Cloning is cheap; clones share subexpressions, which the generated
+/// kernel evaluates once.
+#[derive(Debug, Clone)]
+pub struct Expr(Arc<Node>);
+
+impl Expr {
+    fn new(node: Node) -> Self {
+        Self(Arc::new(node))
+    }
+
+    /// A named input tensor. Every use of `name` must agree on type and
+    /// shape.
+    #[must_use]
+    pub fn input(name: &str, dtype: DType, shape: &[usize]) -> Self {
+        Self::new(Node::Input {
+            name: name.to_string(),
+            dtype,
+            shape: shape.to_vec(),
+        })
+    }
+
+    /// An `f32` constant.
+    #[must_use]
+    pub fn f32(value: f32) -> Self {
+        Self::new(Node::Constant(Value::F32(value)))
+    }
+
+    /// An `i32` constant.
+    #[must_use]
+    pub fn i32(value: i32) -> Self {
+        Self::new(Node::Constant(Value::I32(value)))
+    }
+
+    /// A `u32` constant.
+    #[must_use]
+    pub fn u32(value: u32) -> Self {
+        Self::new(Node::Constant(Value::U32(value)))
+    }
+
+    fn unary(self, op: Unary) -> Self {
+        Self::new(Node::Unary(op, self))
+    }
+
+    /// Absolute value.
+    #[must_use]
+    pub fn abs(self) -> Self {
+        self.unary(Unary::Abs)
+    }
+
+    /// `max(x, 0)`.
+    #[must_use]
+    pub fn relu(self) -> Self {
+        self.unary(Unary::Relu)
+    }
+
+    /// `e^x`.
+    #[must_use]
+    pub fn exp(self) -> Self {
+        self.unary(Unary::Exp)
+    }
+
+    /// Natural logarithm.
+    #[must_use]
+    pub fn log(self) -> Self {
+        self.unary(Unary::Log)
+    }
+
+    /// Square root.
+    #[must_use]
+    pub fn sqrt(self) -> Self {
+        self.unary(Unary::Sqrt)
+    }
+
+    /// Hyperbolic tangent.
+    #[must_use]
+    pub fn tanh(self) -> Self {
+        self.unary(Unary::Tanh)
+    }
+
+    /// `1 / (1 + e^-x)`.
+    #[must_use]
+    pub fn sigmoid(self) -> Self {
+        self.unary(Unary::Sigmoid)
+    }
+
+    /// GELU, tanh approximation, as [`cpu_reference::gelu`].
+    #[must_use]
+    pub fn gelu(self) -> Self {
+        self.unary(Unary::Gelu)
+    }
+
+    /// SiLU, as [`cpu_reference::silu`].
+    #[must_use]
+    pub fn silu(self) -> Self {
+        self.unary(Unary::Silu)
+    }
+
+    /// Elementwise maximum.
+    #[must_use]
+    pub fn max(self, other: Self) -> Self {
+        Self::new(Node::Binary(Binary::Max, self, other))
+    }
+
+    /// Elementwise minimum.
+    #[must_use]
+    pub fn min(self, other: Self) -> Self {
+        Self::new(Node::Binary(Binary::Min, self, other))
+    }
+
+    /// Convert to `dtype`, as MSL `static_cast` does. Float to integer
+    /// conversion of out-of-range values is undefined in MSL and
+    /// saturates on the host.
+    #[must_use]
+    pub fn cast(self, dtype: DType) -> Self {
+        Self::new(Node::Cast(dtype, self))
+    }
+}
+
+macro_rules! impl_binary_operator {
+    ($($trait:ident, $method:ident, $op:ident;)*) => {$(
+        impl $trait for Expr {
+            type Output = Self;
+
+            fn $method(self, rhs: Self) -> Self {
+                Self::new(Node::Binary(Binary::$op, self, rhs))
+            }
+        }
+
+        impl $trait<f32> for Expr {
+            type Output = Self;
+
+            fn $method(self, rhs: f32) -> Self {
+                Self::new(Node::Binary(Binary::$op, self, Self::f32(rhs)))
+            }
+        }
+    )*};
+}
+
+impl_binary_operator! {
+    Add, add, Add;
+    Sub, sub, Sub;
+    Mul, mul, Mul;
+    Div, div, Div;
+}
+
+impl Neg for Expr {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        self.unary(Unary::Neg)
+    }
+}
+
+/// Host-side data for one input or output of a [`FusedKernel`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostData {
+    /// `f32` elements.
+    F32(Vec<f32>),
+    /// `i32` elements.
+    I32(Vec<i32>),
+    /// `u32` elements.
+    U32(Vec<u32>),
+}
+
+impl HostData {
+    /// Element type.
+    #[must_use]
+    pub const fn dtype(&self) -> DType {
+        match self {
+            Self::F32(_) => DType::F32,
+            Self::I32(_) => DType::I32,
+            Self::U32(_) => DType::U32,
+        }
+    }
+
+    /// Number of elements.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        match self {
+            Self::F32(data) => data.len(),
+            Self::I32(data) => data.len(),
+            Self::U32(data) => data.len(),
+        }
+    }
+
+    /// Check if there are no elements.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, index: usize) -> Value {
+        match self {
+            Self::F32(data) => Value::F32(data[index]),
+            Self::I32(data) => Value::I32(data[index]),
+            Self::U32(data) => Value::U32(data[index]),
+        }
+    }
+
+    fn collect(dtype: DType, values: impl Iterator<Item = Value>) -> Self {
+        let bits = values.map(Value::to_bits);
+        match dtype {
+            DType::F32 => Self::F32(bits.map(f32::from_bits).collect()),
+            #[allow(clippy::cast_possible_wrap)]
+            DType::I32 => Self::I32(bits.map(|b| b as i32).collect()),
+            DType::U32 => Self::U32(bits.collect()),
+        }
+    }
+}
+
+impl From<Vec<f32>> for HostData {
+    fn from(data: Vec<f32>) -> Self {
+        Self::F32(data)
+    }
+}
+
+impl From<Vec<i32>> for HostData {
+    fn from(data: Vec<i32>) -> Self {
+        Self::I32(data)
+    }
+}
+
+impl From<Vec<u32>> for HostData {
+    fn from(data: Vec<u32>) -> Self {
+        Self::U32(data)
+    }
+}
+
+/// An input of a [`FusedKernel`], in binding order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprInput {
+    /// Name given to [`Expr::input`].
+    pub name: String,
+    /// Element type.
+    pub dtype: DType,
+    /// Shape before broadcasting.
+    pub shape: Vec<usize>,
+}
+
+/// One step of a flattened expression; operands refer to earlier steps.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Input(usize),
+    Constant(Value),
+    Unary(Unary, usize),
+    Binary(Binary, usize, usize),
+    Cast(DType, usize),
+}
+
+/// A type-checked expression in evaluation order.
+#[derive(Debug)]
+struct Plan {
+    steps: Vec<(Step, DType)>,
+    inputs: Vec<ExprInput>,
+    /// Per input, the element stride for each output dimension (0 where
+    /// broadcast).
+    strides: Vec<Vec<usize>>,
+    shape: Vec<usize>,
+    /// Row-major strides of the output (0 for dimensions of size 1).
+    output_strides: Vec<usize>,
+    len: usize,
+}
+
+impl Plan {
+    fn new(output: &Expr) -> Result<Self> {
+        let mut builder = PlanBuilder::default();
+        builder.visit(output)?;
+        let PlanBuilder { steps, inputs, .. } = builder;
+        if inputs.is_empty() {
+            return Err(Error::invalid_input("expression has no inputs"));
+        }
+        let shape = inputs.iter().try_fold(Vec::new(), |shape, input| {
+            cpu_reference::broadcast_shape(&shape, &input.shape).ok_or_else(|| {
+                Error::invalid_input(format!(
+                    "input '{}' of shape {:?} does not broadcast to {shape:?}",
+                    input.name, input.shape
+                ))
+            })
+        })?;
+        let len = shape.iter().try_fold(1usize, |acc, &dim| {
+            acc.checked_mul(dim)
+                .ok_or_else(|| Error::invalid_input("output size overflows"))
+        })?;
+        if len == 0 || u32::try_from(len).is_err() {
+            return Err(Error::invalid_input(format!(
+                "output shape {shape:?} must have between 1 and {} elements",
+                u32::MAX
+            )));
+        }
+        let strides = inputs
+            .iter()
+            .map(|input| cpu_reference::broadcast_strides(&input.shape, &shape))
+            .collect();
+        Ok(Self {
+            steps,
+            inputs,
+            strides,
+            output_strides: cpu_reference::broadcast_strides(&shape, &shape),
+            shape,
+            len,
+        })
+    }
+
+    fn dtype(&self) -> DType {
+        self.steps.last().map_or(DType::F32, |&(_, dtype)| dtype)
+    }
+
+    /// Output dimensions `input` is not broadcast along, as
+    /// `(output stride, extent, input stride)`.
+    fn dimensions(&self, input: usize) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        self.output_strides
+            .iter()
+            .zip(&self.shape)
+            .zip(&self.strides[input])
+            .filter(|(_, &stride)| stride != 0)
+            .map(|((&out, &dim), &stride)| (out, dim, stride))
+    }
+
+    /// Element offset of output element `index` in `input`.
+    fn offset(&self, input: usize, index: usize) -> usize {
+        self.dimensions(input)
+            .map(|(out, dim, stride)| index / out % dim * stride)
+            .sum()
+    }
+
+    /// Evaluate output element `index`, reading inputs through `load`.
+    fn evaluate(&self, index: usize, load: impl Fn(usize, DType, usize) -> Value) -> Value {
+        let mut values: Vec<Value> = Vec::with_capacity(self.steps.len());
+        for &(step, dtype) in &self.steps {
+            let value = match step {
+                Step::Input(input) => load(input, dtype, self.offset(input, index)),
+                Step::Constant(value) => value,
+                Step::Unary(op, a) => op.apply(values[a]),
+                Step::Binary(op, a, b) => op.apply(values[a], values[b]),
+                Step::Cast(dtype, a) => values[a].cast(dtype),
+            };
+            values.push(value);
+        }
+        values.last().copied().unwrap_or(Value::U32(0))
+    }
+
+    /// MSL kernel computing the expression, one thread per output element.
+    fn msl(&self, name: &str) -> String {
+        let mut out = String::from("#include <metal_stdlib>\nusing namespace metal;\n\n");
+        let _ = writeln!(out, "kernel void {name}(");
+        for (i, input) in self.inputs.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "    device const {}* in_{} [[buffer({i})]],",
+                input.dtype.msl_name(),
+                input.name
+            );
+        }
+        let _ = writeln!(
+            out,
+            "    device {}* out [[buffer({})]],",
+            self.dtype().msl_name(),
+            self.inputs.len()
+        );
+        out.push_str("    uint gid [[thread_position_in_grid]])\n{\n");
+        let _ = writeln!(
+            out,
+            "    if (gid >= {}u) {{\n        return;\n    }}",
+            self.len
+        );
+
+        let mut names: Vec<String> = Vec::with_capacity(self.steps.len());
+        for (i, &(step, dtype)) in self.steps.iter().enumerate() {
+            let value = match step {
+                Step::Input(input) => {
+                    let offset = if self.strides[input] == self.output_strides {
+                        "gid".to_string()
+                    } else {
+                        let terms: Vec<_> = self
+                            .dimensions(input)
+                            .map(|(out, dim, stride)| {
+                                format!("(gid / {out}u % {dim}u) * {stride}u")
+                            })
+                            .collect();
+                        if terms.is_empty() {
+                            "0".to_string()
+                        } else {
+                            terms.join(" + ")
+                        }
+                    };
+                    format!("in_{}[{offset}]", self.inputs[input].name)
+                }
+                Step::Constant(value) => value.literal(),
+                Step::Unary(op, a) => op.msl(dtype, &names[a]),
+                Step::Binary(op, a, b) => op.msl(&names[a], &names[b]),
+                Step::Cast(dtype, a) => format!("static_cast<{}>({})", dtype.msl_name(), names[a]),
+            };
+            let _ = writeln!(out, "    const {} t{i} = {value};", dtype.msl_name());
+            names.push(format!("t{i}"));
+        }
+        let _ = writeln!(out, "    out[gid] = t{};\n}}", self.steps.len() - 1);
+        out
+    }
+}
+
+#[derive(Default)]
+struct PlanBuilder {
+    steps: Vec<(Step, DType)>,
+    inputs: Vec<ExprInput>,
+    visited: HashMap<*const Node, usize>,
+}
+
+impl PlanBuilder {
+    /// Append the steps for `expr`, returning the index of its value.
+    fn visit(&mut self, expr: &Expr) -> Result<usize> {
+        let key = Arc::as_ptr(&expr.0);
+        if let Some(&index) = self.visited.get(&key) {
+            return Ok(index);
+        }
+        let step = match &*expr.0 {
+            Node::Input { name, dtype, shape } => {
+                (Step::Input(self.input(name, *dtype, shape)?), *dtype)
+            }
+            Node::Constant(value) => (Step::Constant(*value), value.dtype()),
+            Node::Unary(op, a) => {
+                let a = self.visit(a)?;
+                let dtype = self.steps[a].1;
+                if op.float_only() && dtype != DType::F32 {
+                    return Err(Error::invalid_input(format!(
+                        "{} requires f32, found {dtype}; use cast",
+                        op.name()
+                    )));
+                }
+                (Step::Unary(*op, a), dtype)
+            }
+            Node::Binary(op, a, b) => {
+                let (a, b) = (self.visit(a)?, self.visit(b)?);
+                let (lhs, rhs) = (self.steps[a].1, self.steps[b].1);
+                if lhs != rhs {
+                    return Err(Error::invalid_input(format!(
+                        "operands of `{}` have types {lhs} and {rhs}; use cast",
+                        op.name()
+                    )));
+                }
+                (Step::Binary(*op, a, b), lhs)
+            }
+            Node::Cast(dtype, a) => (Step::Cast(*dtype, self.visit(a)?), *dtype),
+        };
+        self.steps.push(step);
+        let index = self.steps.len() - 1;
+        self.visited.insert(key, index);
+        Ok(index)
+    }
+
+    /// Binding index of the input called `name`, registering it if new.
+    fn input(&mut self, name: &str, dtype: DType, shape: &[usize]) -> Result<usize> {
+        if let Some(index) = self.inputs.iter().position(|input| input.name == name) {
+            let input = &self.inputs[index];
+            if input.dtype != dtype || input.shape != shape {
+                return Err(Error::invalid_input(format!(
+                    "input '{name}' is used as {} {:?} and {dtype} {shape:?}",
+                    input.dtype, input.shape
+                )));
+            }
+            return Ok(index);
+        }
+        if !is_identifier(name) {
+            return Err(Error::invalid_input(format!(
+                "input name '{name}' is not an identifier"
+            )));
+        }
+        self.inputs.push(ExprInput {
+            name: name.to_string(),
+            dtype,
+            shape: shape.to_vec(),
+        });
+        Ok(self.inputs.len() - 1)
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A compiled fused elementwise kernel.
+///
+/// Created by [`MetalCompute::compile_fused`]; see the module docs.
+pub struct FusedKernel<'a> {
+    compute: &'a MetalCompute,
+    plan: Arc<Plan>,
+    source: String,
+    shader: CompiledShader,
+}
+
+impl FusedKernel<'_> {
+    /// The generated MSL source.
+    #[must_use]
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The compiled pipeline.
+    #[must_use]
+    pub const fn shader(&self) -> &CompiledShader {
+        &self.shader
+    }
+
+    /// Inputs in binding order; the output is bound after them.
+    #[must_use]
+    pub fn inputs(&self) -> &[ExprInput] {
+        &self.plan.inputs
+    }
+
+    /// Shape of the output, broadcast from the inputs.
+    #[must_use]
+    pub fn output_shape(&self) -> &[usize] {
+        &self.plan.shape
+    }
+
+    /// Element type of the output.
+    #[must_use]
+    pub fn output_dtype(&self) -> DType {
+        self.plan.dtype()
+    }
+
+    /// Number of output elements.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.plan.len
+    }
+
+    /// Always `false`: outputs have at least one element.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Dispatch the kernel over `inputs`, in [`FusedKernel::inputs`]
+    /// order, writing `output`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the number of inputs is wrong, a buffer is
+    /// smaller than its shape, or the dispatch fails.
+    pub fn run(&self, inputs: &[&MetalBuffer], output: &MetalBuffer) -> Result<()> {
+        self.check_count(inputs.len())?;
+        for (input, buffer) in self.plan.inputs.iter().zip(inputs) {
+            let needed = input.shape.iter().product::<usize>() * input.dtype.size();
+            if buffer.len() < needed {
+                return Err(Error::invalid_input(format!(
+                    "input '{}' buffer holds {} bytes, shape {:?} needs {needed}",
+                    input.name,
+                    buffer.len(),
+                    input.shape
+                )));
+            }
+        }
+        let needed = self.plan.len * self.output_dtype().size();
+        if output.len() < needed {
+            return Err(Error::invalid_input(format!(
+                "output buffer holds {} bytes, kernel needs {needed}",
+                output.len()
+            )));
+        }
+
+        let mut bindings = inputs.to_vec();
+        bindings.push(output);
+        #[allow(clippy::cast_possible_truncation)] // checked when compiled
+        let grid = (self.plan.len as u32, 1, 1);
+        let threadgroup = self.compute.threadgroup_size_for(&self.shader, grid);
+        self.compute
+            .dispatch(&self.shader, &bindings, grid, threadgroup)
+    }
+
+    /// Evaluate the expression on the host.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the number of inputs is wrong, or an input has
+    /// the wrong type or fewer elements than its shape.
+    pub fn evaluate(&self, inputs: &[HostData]) -> Result<HostData> {
+        self.check_count(inputs.len())?;
+        for (input, data) in self.plan.inputs.iter().zip(inputs) {
+            if data.dtype() != input.dtype {
+                return Err(Error::invalid_input(format!(
+                    "input '{}' is {}, got {}",
+                    input.name,
+                    input.dtype,
+                    data.dtype()
+                )));
+            }
+            let needed = input.shape.iter().product::<usize>();
+            if data.len() < needed {
+                return Err(Error::invalid_input(format!(
+                    "input '{}' has {} elements, shape {:?} needs {needed}",
+                    input.name,
+                    data.len(),
+                    input.shape
+                )));
+            }
+        }
+        let values = (0..self.plan.len).map(|index| {
+            self.plan
+                .evaluate(index, |input, _, offset| inputs[input].get(offset))
+        });
+        Ok(HostData::collect(self.output_dtype(), values))
+    }
+
+    fn check_count(&self, count: usize) -> Result<()> {
+        if count != self.plan.inputs.len() {
+            return Err(Error::invalid_input(format!(
+                "fused kernel takes {} inputs, got {count}",
+                self.plan.inputs.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FusedKernel<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FusedKernel")
+            .field("name", &self.shader.name())
+            .field("inputs", &self.plan.inputs)
+            .field("output_shape", &self.plan.shape)
+            .field("output_dtype", &self.plan.dtype())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MetalCompute {
+    /// Generate and compile a kernel called `name` that evaluates `output`
+    /// elementwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` is not an identifier, the expression has
+    /// no inputs, mixes types, applies a float function to integers, uses
+    /// one input name with two types or shapes, or its input shapes do not
+    /// broadcast; or if compilation fails.
+    pub fn compile_fused(&self, name: &str, output: &Expr) -> Result<FusedKernel<'_>> {
+        if !is_identifier(name) {
+            return Err(Error::invalid_input(format!(
+                "kernel name '{name}' is not an identifier"
+            )));
+        }
+        let plan = Arc::new(Plan::new(output)?);
+        let source = plan.msl(name);
+        let host = Arc::clone(&plan);
+        let shader = self
+            .compile_shader(&source, name)?
+            .with_cpu_kernel(CpuKernel::new(move |ctx| {
+                let index = ctx.thread_position().0 as usize;
+                if index >= host.len {
+                    return;
+                }
+                let value = host.evaluate(index, |input, dtype, offset| {
+                    Value::from_bits(dtype, ctx.read_u32(input, offset))
+                });
+                ctx.write_u32(host.inputs.len(), index, value.to_bits());
+            }));
+        Ok(FusedKernel {
+            compute: self,
+            plan,
+            source,
+            shader,
+        })
+    }
+
+    /// Allocate a buffer holding `data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `data` is empty or allocation fails.
+    pub fn upload(&self, data: &HostData) -> Result<MetalBuffer> {
+        let buffer = self.allocate_buffer(data.len() * data.dtype().size())?;
+        match data {
+            HostData::F32(values) => buffer.write(0, values)?,
+            HostData::I32(values) => buffer.write(0, values)?,
+            HostData::U32(values) => buffer.write(0, values)?,
+        }
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn ramp(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (i as f32).mul_add(0.37, -2.0).sin())
+            .collect()
+    }
+
+    fn run(kernel: &FusedKernel<'_>, compute: &MetalCompute, inputs: &[HostData]) -> MetalBuffer {
+        let buffers: Vec<_> = inputs.iter().map(|d| compute.upload(d).unwrap()).collect();
+        let output = compute.allocate_buffer(kernel.len() * 4).unwrap();
+        kernel
+            .run(&buffers.iter().collect::<Vec<_>>(), &output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_broadcast_gelu_matches_reference() {
+        let compute = MetalCompute::cpu();
+        let a = Expr::input("a", DType::F32, &[2, 3, 4]);
+        let b = Expr::input("b", DType::F32, &[4]);
+        let c = Expr::input("c", DType::F32, &[3, 1]);
+        let kernel = compute.compile_fused("fused", &(a * b + c).gelu()).unwrap();
+        assert_eq!(kernel.output_shape(), [2, 3, 4]);
+        let names: Vec<_> = kernel.inputs().iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert!(kernel.source().contains("device float* out [[buffer(3)]]"));
+        assert!(kernel.source().contains("in_a[gid]"));
+        assert!(kernel.source().contains("in_c[(gid / 4u % 3u) * 1u]"));
+
+        let (a, b, c) = (ramp(24), ramp(4), ramp(3));
+        let inputs = [
+            HostData::from(a.clone()),
+            HostData::from(b.clone()),
+            HostData::from(c.clone()),
+        ];
+        let HostData::F32(host) = kernel.evaluate(&inputs).unwrap() else {
+            unreachable!()
+        };
+        for (i, value) in host.iter().enumerate() {
+            let expected = cpu_reference::gelu(a[i].mul_add(b[i % 4], c[i / 4 % 3]));
+            assert!(
+                (value - expected).abs() < 1e-6,
+                "{i}: {value} vs {expected}"
+            );
+        }
+
+        let output = run(&kernel, &compute, &inputs);
+        assert_eq!(output.read::<f32>().unwrap(), host);
+    }
+
+    #[test]
+    fn test_integer_expressions_and_shared_subexpressions() {
+        let compute = MetalCompute::cpu();
+        let x = Expr::input("x", DType::F32, &[4]);
+        let y = Expr::input("y", DType::I32, &[1]);
+        let scaled = x.cast(DType::I32) * Expr::i32(-3);
+        let expr = (scaled.clone() + scaled).max(y).abs();
+        let kernel = compute.compile_fused("ints", &expr).unwrap();
+        assert_eq!(kernel.output_dtype(), DType::I32);
+        assert_eq!(kernel.source().matches("static_cast<int>").count(), 1);
+        assert!(kernel.source().contains("in_y[0]"));
+
+        let inputs = [
+            HostData::from(vec![1.5f32, -2.0, 0.0, 7.9]),
+            HostData::from(vec![-5i32]),
+        ];
+        let expected = HostData::I32(vec![5, 12, 0, 5]);
+        assert_eq!(kernel.evaluate(&inputs).unwrap(), expected);
+        let output = run(&kernel, &compute, &inputs);
+        assert_eq!(HostData::I32(output.read().unwrap()), expected);
+
+        let wrap = compute
+            .compile_fused(
+                "wrap",
+                &(Expr::input("u", DType::U32, &[2]) - Expr::u32(1) / Expr::u32(0)),
+            )
+            .unwrap();
+        assert_eq!(
+            wrap.evaluate(&[HostData::from(vec![0u32, 1])]).unwrap(),
+            HostData::U32(vec![0, 1])
+        );
+        assert!(wrap.source().contains("const uint t1 = 1u;"));
+        assert!(wrap.source().contains("const uint t3 = t1 / t2;"));
+    }
+
+    /// Check both the CPU dispatch and host evaluation against values
+    /// worked out by hand.
+    fn check(compute: &MetalCompute, expr: &Expr, inputs: &[HostData], expected: &HostData) {
+        let kernel = compute.compile_fused("check", expr).unwrap();
+        assert_eq!(&kernel.evaluate(inputs).unwrap(), expected);
+        let output = run(&kernel, compute, inputs);
+        let output = match expected {
+            HostData::F32(_) => HostData::F32(output.read().unwrap()),
+            HostData::I32(_) => HostData::I32(output.read().unwrap()),
+            HostData::U32(_) => HostData::U32(output.read().unwrap()),
+        };
+        assert_eq!(&output, expected);
+    }
+
+    #[test]
+    fn test_generated_source() {
+        let compute = MetalCompute::cpu();
+        let sum = Expr::input("a", DType::F32, &[2, 3]) + Expr::input("b", DType::F32, &[3]);
+        let kernel = compute.compile_fused("add", &sum).unwrap();
+        assert_eq!(
+            kernel.source(),
+            "#include <metal_stdlib>
+using namespace metal;
+
+kernel void add(
+    device const float* in_a [[buffer(0)]],
+    device const float* in_b [[buffer(1)]],
+    device float* out [[buffer(2)]],
+    uint gid [[thread_position_in_grid]])
+{
+    if (gid >= 6u) {
+        return;
+    }
+    const float t0 = in_a[gid];
+    const float t1 = in_b[(gid / 1u % 3u) * 1u];
+    const float t2 = t0 + t1;
+    out[gid] = t2;
+}
+"
+        );
+
+        let x = || Expr::input("x", DType::F32, &[4]);
+        let y = || Expr::input("y", DType::F32, &[4]);
+        let i = || Expr::input("i", DType::I32, &[4]);
+        let u = || Expr::input("u", DType::U32, &[4]);
+        let cases = [
+            (-x(), "const float t1 = -t0;"),
+            (x().abs(), "const float t1 = abs(t0);"),
+            (x().relu(), "const float t1 = max(t0, 0.0f);"),
+            (x().exp(), "const float t1 = exp(t0);"),
+            (x().log(), "const float t1 = log(t0);"),
+            (x().sqrt(), "const float t1 = sqrt(t0);"),
+            (x().tanh(), "const float t1 = tanh(t0);"),
+            (
+                x().sigmoid(),
+                "const float t1 = 1.0f / (1.0f + exp(-t0));",
+            ),
+            (
+                x().gelu(),
+                "const float t1 = 0.5f * t0 * (1.0f + tanh(0.7978846f * fma(0.044715f * t0 * t0, t0, t0)));",
+            ),
+            (x().silu(), "const float t1 = t0 / (1.0f + exp(-t0));"),
+            (-i(), "const int t1 = -t0;"),
+            (i().abs(), "const int t1 = abs(t0);"),
+            (i().relu(), "const int t1 = max(t0, 0);"),
+            (u().abs(), "const uint t1 = t0;"),
+            (u().relu(), "const uint t1 = t0;"),
+            (x() + y(), "const float t2 = t0 + t1;"),
+            (x() - y(), "const float t2 = t0 - t1;"),
+            (x() * y(), "const float t2 = t0 * t1;"),
+            (x() / y(), "const float t2 = t0 / t1;"),
+            (x().max(y()), "const float t2 = max(t0, t1);"),
+            (x().min(y()), "const float t2 = min(t0, t1);"),
+            (x() * 0.5, "const float t2 = t0 * t1;"),
+            (x().cast(DType::I32), "const int t1 = static_cast<int>(t0);"),
+            (i().cast(DType::U32), "const uint t1 = static_cast<uint>(t0);"),
+            (u().cast(DType::F32), "const float t1 = static_cast<float>(t0);"),
+            (i() + Expr::i32(-3), "const int t1 = int(-3);"),
+            (u() + Expr::u32(7), "const uint t1 = 7u;"),
+            (x() + 0.5, "const float t1 = 0.5f;"),
+            (x() + f32::NAN, "const float t1 = NAN;"),
+            (x() + f32::INFINITY, "const float t1 = INFINITY;"),
+            (x() + f32::NEG_INFINITY, "const float t1 = (-INFINITY);"),
+        ];
+        for (expr, line) in cases {
+            let kernel = compute.compile_fused("k", &expr).unwrap();
+            assert!(
+                kernel.source().lines().any(|l| l.trim() == line),
+                "missing `{line}` in\n{}",
+                kernel.source()
+            );
+        }
+    }
+
+    #[test]
+    fn test_known_values() {
+        let compute = MetalCompute::cpu();
+        let x = || Expr::input("x", DType::F32, &[1]);
+        let cases: [(Expr, f32, f32); 11] = [
+            (-x(), 2.0, -2.0),
+            (x().abs(), -2.0, 2.0),
+            (x().relu(), -2.0, 0.0),
+            (x().exp(), 0.0, 1.0),
+            (x().log(), 1.0, 0.0),
+            (x().sqrt(), 6.25, 2.5),
+            (x().tanh(), 0.0, 0.0),
+            (x().sigmoid(), 0.0, 0.5),
+            (x().gelu(), 0.0, 0.0),
+            (x().silu(), 0.0, 0.0),
+            ((x() - 1.0) / 4.0, 9.0, 2.0),
+        ];
+        for (expr, input, output) in cases {
+            check(
+                &compute,
+                &expr,
+                &[HostData::from(vec![input])],
+                &HostData::F32(vec![output]),
+            );
+        }
+    }
+
+    #[test]
+    fn test_casts_truncate_and_reinterpret() {
+        let compute = MetalCompute::cpu();
+        let f = Expr::input("f", DType::F32, &[4]);
+        let i = Expr::input("i", DType::I32, &[4]);
+        let u = Expr::input("u", DType::U32, &[4]);
+        let floats = [HostData::from(vec![7.9f32, -2.5, -0.0, 100.5])];
+        let ints = [HostData::from(vec![-1i32, i32::MIN, 0, 7])];
+        let uints = [HostData::from(vec![u32::MAX, 16_777_217, 0, 1])];
+
+        check(
+            &compute,
+            &f.cast(DType::I32),
+            &floats,
+            &HostData::I32(vec![7, -2, 0, 100]),
+        );
+        check(
+            &compute,
+            &i.clone().cast(DType::U32),
+            &ints,
+            &HostData::U32(vec![u32::MAX, 0x8000_0000, 0, 7]),
+        );
+        check(
+            &compute,
+            &i.cast(DType::F32),
+            &ints,
+            &HostData::F32(vec![-1.0, -2_147_483_648.0, 0.0, 7.0]),
+        );
+        check(
+            &compute,
+            &u.clone().cast(DType::I32),
+            &uints,
+            &HostData::I32(vec![-1, 16_777_217, 0, 1]),
+        );
+        // 2^24 + 1 is the first integer f32 cannot represent.
+        check(
+            &compute,
+            &u.cast(DType::F32),
+            &uints,
+            &HostData::F32(vec![4_294_967_296.0, 16_777_216.0, 0.0, 1.0]),
+        );
+    }
+
+    #[test]
+    fn test_integer_arithmetic_wraps() {
+        let compute = MetalCompute::cpu();
+        let i = || Expr::input("i", DType::I32, &[2]);
+        let ints = [HostData::from(vec![i32::MAX, i32::MIN])];
+        for (expr, expected) in [
+            (i() + Expr::i32(1), [i32::MIN, i32::MIN + 1]),
+            (i() - Expr::i32(1), [i32::MAX - 1, i32::MAX]),
+            (i() * Expr::i32(2), [-2, 0]),
+            (i() * Expr::i32(-1), [-i32::MAX, i32::MIN]),
+            (-i(), [-i32::MAX, i32::MIN]),
+            (i().abs(), [i32::MAX, i32::MIN]),
+            (i() / Expr::i32(-1), [-i32::MAX, 0]),
+        ] {
+            check(&compute, &expr, &ints, &HostData::I32(expected.to_vec()));
+        }
+
+        let u = || Expr::input("u", DType::U32, &[2]);
+        let uints = [HostData::from(vec![0u32, 0x8000_0001])];
+        for (expr, expected) in [
+            (u() - Expr::u32(1), [u32::MAX, 0x8000_0000]),
+            (u() * Expr::u32(2), [0, 2]),
+            (u() + Expr::u32(u32::MAX), [u32::MAX, 0x8000_0000]),
+            (-u(), [0, 0x7FFF_FFFF]),
+        ] {
+            check(&compute, &expr, &uints, &HostData::U32(expected.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_broadcast_values() {
+        let compute = MetalCompute::cpu();
+        let a = Expr::input("a", DType::I32, &[2, 1, 3]);
+        let b = Expr::input("b", DType::I32, &[4, 1]);
+        let s = Expr::input("s", DType::I32, &[1]);
+        let inputs = [
+            HostData::from(vec![0, 1, 2, 3, 4, 5]),
+            HostData::from(vec![0, 10, 20, 30]),
+            HostData::from(vec![100]),
+        ];
+        #[rustfmt::skip]
+        let expected = vec![
+            100, 101, 102, 110, 111, 112, 120, 121, 122, 130, 131, 132,
+            103, 104, 105, 113, 114, 115, 123, 124, 125, 133, 134, 135,
+        ];
+        check(&compute, &(a + b + s), &inputs, &HostData::I32(expected));
+
+        // A column against a row.
+        let column = Expr::input("c", DType::F32, &[3, 1]);
+        let row = Expr::input("r", DType::F32, &[1, 2]);
+        check(
+            &compute,
+            &(column - row),
+            &[
+                HostData::from(vec![10.0f32, 20.0, 30.0]),
+                HostData::from(vec![1.0f32, 2.0]),
+            ],
+            &HostData::F32(vec![9.0, 8.0, 19.0, 18.0, 29.0, 28.0]),
+        );
+    }
+
+    #[test]
+    fn test_type_and_shape_errors() {
+        let compute = MetalCompute::cpu();
+        let f = Expr::input("f", DType::F32, &[4]);
+        let i = Expr::input("i", DType::I32, &[4]);
+        let cases = [
+            (
+                f.clone() + i.clone(),
+                "operands of `+` have types f32 and i32",
+            ),
+            (i.clone().exp(), "exp requires f32, found i32"),
+            (
+                f.clone() + Expr::input("f", DType::F32, &[2]),
+                "input 'f' is used as f32 [4] and f32 [2]",
+            ),
+            (
+                f.clone() * Expr::input("g", DType::F32, &[3]),
+                "does not broadcast",
+            ),
+            (Expr::f32(1.0) + 2.0, "no inputs"),
+            (Expr::input("a b", DType::F32, &[1]), "not an identifier"),
+            (Expr::input("z", DType::F32, &[0, 2]), "between 1 and"),
+        ];
+        for (expr, message) in cases {
+            let err = compute.compile_fused("k", &expr).unwrap_err();
+            assert!(err.to_string().contains(message), "{err}");
+        }
+        assert!(compute.compile_fused("1k", &f).is_err());
+        assert!(compute
+            .compile_fused("cast", &(f + i.cast(DType::F32)).sigmoid())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_run_and_evaluate_validate_inputs() {
+        let compute = MetalCompute::cpu();
+        let kernel = compute
+            .compile_fused("neg", &-Expr::input("x", DType::F32, &[2, 2]))
+            .unwrap();
+        let small = compute.allocate_buffer(8).unwrap();
+        let output = compute.allocate_buffer(16).unwrap();
+        assert!(kernel.run(&[], &output).is_err());
+        assert!(kernel.run(&[&small], &output).is_err());
+        assert!(kernel.run(&[&output], &small).is_err());
+        assert!(kernel.evaluate(&[HostData::from(vec![1i32; 4])]).is_err());
+        assert!(kernel.evaluate(&[HostData::from(vec![1.0f32; 3])]).is_err());
+        assert_eq!(
+            kernel
+                .evaluate(&[HostData::from(vec![1.0f32, -2.0, 0.5, 0.0])])
+                .unwrap(),
+            HostData::F32(vec![-1.0, 2.0, -0.5, -0.0])
+        );
+    }
+}
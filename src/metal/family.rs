@@ -0,0 +1,300 @@
+//! GPU families and optional feature support.
+//!
+//! Metal groups GPUs into families (`Apple7`, `Mac2`, ...) that share a
+//! feature set, but whether a particular device supports a feature is not
+//! fully determined by its family: 64-bit atomics arrived with the M2, and
+//! threadgroup memory size varies by vendor. Support is therefore looked up
+//! in a table of known GPUs, keyed by PCI device ID where the system
+//! reports one and by marketing name otherwise.
+//!
+//! A device that is not in the table has no family and every query
+//! answers [`Support::Unknown`]; nothing is inferred from partial matches.
+//! Add new GPUs to [`KNOWN_NAMES`] or [`KNOWN_DEVICE_IDS`] as they are
+//! verified against Apple's Metal Feature Set Tables.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::{Feature, MetalDevice, Support};
+//!
+//! let mut device = MetalDevice::cpu();
+//! assert_eq!(device.gpu_family(), None);
+//! assert_eq!(device.supports(Feature::Bfloat16), Support::Unknown);
+//!
+//! device.name = "Apple M2 Pro".to_string();
+//! assert_eq!(device.gpu_family().unwrap().to_string(), "Apple8");
+//! assert!(device.supports(Feature::Atomic64).is_supported());
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - Devices missing from the table report `Unknown`, never a guess
+//! - A known device's answers match the Metal Feature Set Tables
+//! - A PCI device ID takes precedence over the device name
+
+use super::MetalDevice;
+use std::fmt;
+
+/// A Metal GPU family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum GpuFamily {
+    /// A14 and M1 GPUs.
+    Apple7,
+    /// A15, A16 and M2 GPUs.
+    Apple8,
+    /// A17 Pro, M3 and M4 GPUs.
+    Apple9,
+    /// Intel and AMD GPUs in Macs.
+    Mac2,
+}
+
+impl fmt::Display for GpuFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Apple7 => write!(f, "Apple7"),
+            Self::Apple8 => write!(f, "Apple8"),
+            Self::Apple9 => write!(f, "Apple9"),
+            Self::Mac2 => write!(f, "Mac2"),
+        }
+    }
+}
+
+/// An optional GPU capability kernels may depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// `simdgroup_matrix` multiply-accumulate.
+    SimdgroupMatrix,
+    /// The `bfloat` data type.
+    Bfloat16,
+    /// Ray tracing from compute kernels.
+    Raytracing,
+    /// 64-bit integer atomic min/max.
+    Atomic64,
+    /// More than 32 KiB of threadgroup memory.
+    LargeThreadgroupMemory,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SimdgroupMatrix => write!(f, "simdgroup matrix"),
+            Self::Bfloat16 => write!(f, "bfloat16"),
+            Self::Raytracing => write!(f, "ray tracing"),
+            Self::Atomic64 => write!(f, "64-bit atomics"),
+            Self::LargeThreadgroupMemory => write!(f, "large threadgroup memory"),
+        }
+    }
+}
+
+/// Answer to a [`MetalDevice::supports`] query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Support {
+    /// The device is known to support the feature.
+    Supported,
+    /// The device is known not to support the feature.
+    Unsupported,
+    /// The device is not in the capability table.
+    Unknown,
+}
+
+impl Support {
+    /// True only if support is known.
+    #[must_use]
+    pub const fn is_supported(self) -> bool {
+        matches!(self, Self::Supported)
+    }
+}
+
+/// Family and features of a known GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Profile {
+    /// GPU family.
+    pub family: GpuFamily,
+    /// Features the GPU supports.
+    pub features: &'static [Feature],
+}
+
+const APPLE7: Profile = Profile {
+    family: GpuFamily::Apple7,
+    features: &[
+        Feature::SimdgroupMatrix,
+        Feature::Bfloat16,
+        Feature::Raytracing,
+    ],
+};
+
+const APPLE8: Profile = Profile {
+    family: GpuFamily::Apple8,
+    features: &[
+        Feature::SimdgroupMatrix,
+        Feature::Bfloat16,
+        Feature::Raytracing,
+        Feature::Atomic64,
+    ],
+};
+
+const APPLE9: Profile = Profile {
+    family: GpuFamily::Apple9,
+    features: &[
+        Feature::SimdgroupMatrix,
+        Feature::Bfloat16,
+        Feature::Raytracing,
+        Feature::Atomic64,
+    ],
+};
+
+const MAC2_AMD: Profile = Profile {
+    family: GpuFamily::Mac2,
+    features: &[
+        Feature::SimdgroupMatrix,
+        Feature::Bfloat16,
+        Feature::Raytracing,
+        Feature::LargeThreadgroupMemory,
+    ],
+};
+
+const MAC2_INTEL: Profile = Profile {
+    family: GpuFamily::Mac2,
+    features: &[
+        Feature::SimdgroupMatrix,
+        Feature::Bfloat16,
+        Feature::Raytracing,
+    ],
+};
+
+/// Known GPUs by name. A name matches if the device name equals it or
+/// continues with a space, so `Apple M1` covers `Apple M1 Max`.
+pub const KNOWN_NAMES: &[(&str, Profile)] = &[
+    ("Apple M1", APPLE7),
+    ("Apple M2", APPLE8),
+    ("Apple M3", APPLE9),
+    ("Apple M4", APPLE9),
+    ("AMD Radeon Pro W5500X", MAC2_AMD),
+    ("AMD Radeon Pro W5700X", MAC2_AMD),
+    ("AMD Radeon Pro W6800X", MAC2_AMD),
+    ("AMD Radeon Pro W6900X", MAC2_AMD),
+    ("AMD Radeon Pro Vega II", MAC2_AMD),
+    ("AMD Radeon Pro 5500M", MAC2_AMD),
+    ("AMD Radeon Pro 5600M", MAC2_AMD),
+    ("Intel UHD Graphics 630", MAC2_INTEL),
+    ("Intel Iris Plus Graphics", MAC2_INTEL),
+];
+
+/// Known GPUs by PCI device ID.
+pub const KNOWN_DEVICE_IDS: &[(u32, Profile)] = &[
+    (0x66af, MAC2_AMD),   // Vega 20
+    (0x687f, MAC2_AMD),   // Vega 10
+    (0x7310, MAC2_AMD),   // Navi 10 (Radeon Pro W5700X)
+    (0x731f, MAC2_AMD),   // Navi 10
+    (0x73bf, MAC2_AMD),   // Navi 21
+    (0x3e9b, MAC2_INTEL), // UHD Graphics 630
+    (0x8a52, MAC2_INTEL), // Iris Plus Graphics G7
+];
+
+/// Look up a device by PCI ID, then by name.
+#[must_use]
+pub fn lookup(device: &MetalDevice) -> Option<Profile> {
+    let by_id = device.device_id.and_then(|id| {
+        KNOWN_DEVICE_IDS
+            .iter()
+            .find(|(known, _)| *known == id)
+            .map(|(_, profile)| *profile)
+    });
+    by_id.or_else(|| {
+        KNOWN_NAMES
+            .iter()
+            .find(|(known, _)| {
+                device
+                    .name
+                    .strip_prefix(known)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+            })
+            .map(|(_, profile)| *profile)
+    })
+}
+
+impl MetalDevice {
+    /// The device's GPU family, or `None` if it is not in the capability
+    /// table.
+    #[must_use]
+    pub fn gpu_family(&self) -> Option<GpuFamily> {
+        lookup(self).map(|profile| profile.family)
+    }
+
+    /// Whether the device supports `feature`.
+    ///
+    /// Returns [`Support::Unknown`] for devices not in the capability table,
+    /// including the CPU pseudo-device.
+    #[must_use]
+    pub fn supports(&self, feature: Feature) -> Support {
+        lookup(self).map_or(Support::Unknown, |profile| {
+            if profile.features.contains(&feature) {
+                Support::Supported
+            } else {
+                Support::Unsupported
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str, device_id: Option<u32>) -> MetalDevice {
+        MetalDevice {
+            name: name.to_string(),
+            device_id,
+            ..MetalDevice::cpu()
+        }
+    }
+
+    #[test]
+    fn test_known_names() {
+        assert_eq!(
+            device("Apple M1", None).gpu_family(),
+            Some(GpuFamily::Apple7)
+        );
+        assert_eq!(
+            device("Apple M3 Max", None).gpu_family(),
+            Some(GpuFamily::Apple9)
+        );
+        let m1 = device("Apple M1 Ultra", None);
+        assert_eq!(m1.supports(Feature::SimdgroupMatrix), Support::Supported);
+        assert_eq!(m1.supports(Feature::Atomic64), Support::Unsupported);
+        assert_eq!(
+            device("AMD Radeon Pro W5700X", None).supports(Feature::LargeThreadgroupMemory),
+            Support::Supported
+        );
+    }
+
+    #[test]
+    fn test_unknown_devices_are_not_guessed() {
+        for name in [
+            "Apple M10",
+            "Apple M",
+            "Apple GPU",
+            "AMD Radeon RX 580",
+            "CPU",
+        ] {
+            let unknown = device(name, None);
+            assert_eq!(unknown.gpu_family(), None, "{name}");
+            assert_eq!(unknown.supports(Feature::Bfloat16), Support::Unknown);
+            assert!(!unknown.supports(Feature::Raytracing).is_supported());
+        }
+        assert_eq!(device("GPU", Some(0xffff)).gpu_family(), None);
+    }
+
+    #[test]
+    fn test_device_id_takes_precedence() {
+        let navi = device("Unnamed GPU", Some(0x731f));
+        assert_eq!(navi.gpu_family(), Some(GpuFamily::Mac2));
+        let renamed = device("Apple M2", Some(0x3e9b));
+        assert_eq!(renamed.supports(Feature::Atomic64), Support::Unsupported);
+        // An unknown ID still falls back to the name.
+        assert_eq!(
+            device("Apple M2", Some(1)).gpu_family(),
+            Some(GpuFamily::Apple8)
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Fault injection for the Metal backend.
+//!
+//! A [`FaultInjector`] attached to a [`MetalCompute`](super::MetalCompute)
+//! makes selected allocations, shader compilations or dispatches fail, and
+//! can simulate a GPU reset by marking the device lost after a fixed number
+//! of operations. This lets recovery paths be exercised without real
+//! hardware faults.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::{FaultInjector, MetalCompute};
+//!
+//! let mut compute = MetalCompute::default_device()?
+//!     .with_fault_injector(FaultInjector::new().lose_device_after(2));
+//!
+//! let _a = compute.allocate_buffer(1024)?;
+//! let _b = compute.allocate_buffer(1024)?;
+//! assert!(compute.allocate_buffer(1024).unwrap_err().is_device_lost());
+//!
+//! compute.recover()?;
+//! assert!(compute.allocate_buffer(1024).is_ok());
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - F057: Device lost handled gracefully
+
+use std::cell::Cell;
+
+/// Operations that can be targeted by fault injection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultOp {
+    /// Buffer allocation.
+    Allocate,
+    /// Shader compilation.
+    Compile,
+    /// Compute dispatch.
+    Dispatch,
+}
+
+impl std::fmt::Display for FaultOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Allocate => write!(f, "allocation"),
+            Self::Compile => write!(f, "shader compilation"),
+            Self::Dispatch => write!(f, "dispatch"),
+        }
+    }
+}
+
+/// Outcome of consulting the injector before an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails, the device stays usable.
+    Fail,
+    /// The device is lost; all dependent state must be rebuilt.
+    DeviceLost,
+}
+
+/// Deterministic fault plan for a Metal compute pipeline.
+///
+/// Operation counts are zero-based and tracked per [`FaultOp`]: a plan
+/// built with `fail_dispatch_at(1)` lets the first dispatch succeed and
+/// fails the second. Each trigger fires at most once.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    fail_allocation_at: Option<u64>,
+    fail_compile_at: Option<u64>,
+    fail_dispatch_at: Option<u64>,
+    lose_device_after: Option<u64>,
+    allocations: Cell<u64>,
+    compiles: Cell<u64>,
+    dispatches: Cell<u64>,
+    operations: Cell<u64>,
+}
+
+impl FaultInjector {
+    /// Create an injector that never injects a fault.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail the allocation with the given zero-based index.
+    #[must_use]
+    pub const fn fail_allocation_at(mut self, index: u64) -> Self {
+        self.fail_allocation_at = Some(index);
+        self
+    }
+
+    /// Fail the shader compilation with the given zero-based index.
+    #[must_use]
+    pub const fn fail_compile_at(mut self, index: u64) -> Self {
+        self.fail_compile_at = Some(index);
+        self
+    }
+
+    /// Fail the dispatch with the given zero-based index.
+    #[must_use]
+    pub const fn fail_dispatch_at(mut self, index: u64) -> Self {
+        self.fail_dispatch_at = Some(index);
+        self
+    }
+
+    /// Mark the device lost once `operations` operations of any kind
+    /// have been attempted.
+    #[must_use]
+    pub const fn lose_device_after(mut self, operations: u64) -> Self {
+        self.lose_device_after = Some(operations);
+        self
+    }
+
+    /// Total number of operations seen so far.
+    #[must_use]
+    pub fn operations(&self) -> u64 {
+        self.operations.get()
+    }
+
+    /// Record an operation and decide whether it should fault.
+    ///
+    /// Device loss takes precedence over a per-operation failure.
+    pub fn check(&self, op: FaultOp) -> Option<Fault> {
+        let total = self.operations.get();
+        self.operations.set(total + 1);
+
+        if self.lose_device_after == Some(total) {
+            return Some(Fault::DeviceLost);
+        }
+
+        let (counter, target) = match op {
+            FaultOp::Allocate => (&self.allocations, self.fail_allocation_at),
+            FaultOp::Compile => (&self.compiles, self.fail_compile_at),
+            FaultOp::Dispatch => (&self.dispatches, self.fail_dispatch_at),
+        };
+        let index = counter.get();
+        counter.set(index + 1);
+
+        (target == Some(index)).then_some(Fault::Fail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_never_faults() {
+        let injector = FaultInjector::new();
+        for _ in 0..100 {
+            assert_eq!(injector.check(FaultOp::Allocate), None);
+            assert_eq!(injector.check(FaultOp::Compile), None);
+            assert_eq!(injector.check(FaultOp::Dispatch), None);
+        }
+        assert_eq!(injector.operations(), 300);
+    }
+
+    #[test]
+    fn test_fail_allocation_at_index() {
+        let injector = FaultInjector::new().fail_allocation_at(2);
+        assert_eq!(injector.check(FaultOp::Allocate), None);
+        assert_eq!(injector.check(FaultOp::Dispatch), None);
+        assert_eq!(injector.check(FaultOp::Allocate), None);
+        assert_eq!(injector.check(FaultOp::Allocate), Some(Fault::Fail));
+        assert_eq!(injector.check(FaultOp::Allocate), None);
+    }
+
+    #[test]
+    fn test_counters_are_per_operation() {
+        let injector = FaultInjector::new().fail_compile_at(0).fail_dispatch_at(1);
+        assert_eq!(injector.check(FaultOp::Dispatch), None);
+        assert_eq!(injector.check(FaultOp::Compile), Some(Fault::Fail));
+        assert_eq!(injector.check(FaultOp::Dispatch), Some(Fault::Fail));
+        assert_eq!(injector.check(FaultOp::Compile), None);
+    }
+
+    #[test]
+    fn test_lose_device_after_operations() {
+        let injector = FaultInjector::new().lose_device_after(3);
+        assert_eq!(injector.check(FaultOp::Allocate), None);
+        assert_eq!(injector.check(FaultOp::Compile), None);
+        assert_eq!(injector.check(FaultOp::Dispatch), None);
+        assert_eq!(injector.check(FaultOp::Dispatch), Some(Fault::DeviceLost));
+        // One-shot: a recovered device keeps working.
+        assert_eq!(injector.check(FaultOp::Dispatch), None);
+    }
+
+    #[test]
+    fn test_device_lost_takes_precedence() {
+        let injector = FaultInjector::new()
+            .fail_allocation_at(0)
+            .lose_device_after(0);
+        assert_eq!(injector.check(FaultOp::Allocate), Some(Fault::DeviceLost));
+    }
+
+    #[test]
+    fn test_fault_op_display() {
+        assert_eq!(FaultOp::Allocate.to_string(), "allocation");
+        assert_eq!(FaultOp::Compile.to_string(), "shader compilation");
+        assert_eq!(FaultOp::Dispatch.to_string(), "dispatch");
+    }
+}
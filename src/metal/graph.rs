@@ -0,0 +1,736 @@
+//! Compute graphs: whole layers as one schedulable unit.
+//!
+//! A [`ComputeGraph`] describes kernels ([`CompiledShader`]s) as nodes and
+//! the buffers they exchange as logical tensors. Each node reads some
+//! tensors and writes others; its buffers are bound `[[buffer(n)]]` in that
+//! order, reads first.
+//!
+//! [`ComputeGraph::compile`] turns the description into an
+//! [`ExecutionPlan`]:
+//!
+//! - **Ordering**: nodes are sorted so every tensor is written before it
+//!   is read, keeping insertion order where dependencies allow.
+//! - **Synchronization**: a [`Step::Barrier`] is placed before a node that
+//!   would otherwise race with work since the previous barrier
+//!   (read-after-write, write-after-read or write-after-write).
+//! - **Memory reuse**: temporaries live in one arena; tensors whose
+//!   lifetimes do not overlap share bytes.
+//!
+//! The plan runs on any [`MetalCompute`], including the CPU backend.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::graph::ComputeGraph;
+//! use manzana::metal::{CpuKernel, MetalCompute};
+//!
+//! let compute = MetalCompute::cpu();
+//! let add_one = compute
+//!     .compile_shader("kernel void add_one() {}", "add_one")?
+//!     .with_cpu_kernel(CpuKernel::new(|ctx| {
+//!         let i = ctx.thread_position().0 as usize;
+//!         ctx.write_f32(1, i, ctx.read_f32(0, i) + 1.0);
+//!     }));
+//!
+//! let mut graph = ComputeGraph::new();
+//! let x = graph.input("x", 16);
+//! let h = graph.temporary("h", 16);
+//! let y = graph.output("y", 16);
+//! graph.add_node(&add_one, &[x], &[h], (4, 1, 1), (4, 1, 1))?;
+//! graph.add_node(&add_one, &[h], &[y], (4, 1, 1), (4, 1, 1))?;
+//! let plan = graph.compile()?;
+//!
+//! let input = compute.allocate_buffer(16)?;
+//! input.write(0, &[1.0f32, 2.0, 3.0, 4.0])?;
+//! let output = compute.allocate_buffer(16)?;
+//! plan.run(&compute, &[(x, &input), (y, &output)])?;
+//! assert_eq!(output.read::<f32>()?, vec![3.0, 4.0, 5.0, 6.0]);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - Every tensor is written before any node reads it
+//! - Temporaries that are live at the same time never share bytes
+//! - Plans give the same results on Metal and on the CPU backend
+
+use super::{CompiledShader, MetalBuffer, MetalCompute, Size3, HEAP_ALIGNMENT};
+use crate::error::{Error, Result};
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+/// Handle to a logical tensor of a [`ComputeGraph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TensorId(usize);
+
+/// Handle to a node of a [`ComputeGraph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+/// Role of a tensor in a graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TensorKind {
+    /// Bound by the caller and only read by the graph.
+    Input,
+    /// Bound by the caller and written by one node.
+    Output,
+    /// Allocated by the plan and written by one node.
+    Temporary,
+}
+
+#[derive(Debug, Clone)]
+struct Tensor {
+    name: String,
+    kind: TensorKind,
+    bytes: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    shader: CompiledShader,
+    reads: Vec<TensorId>,
+    writes: Vec<TensorId>,
+    threads: Size3,
+    threadgroup: Size3,
+}
+
+/// Builder for a graph of kernels over logical tensors.
+#[derive(Debug, Clone, Default)]
+pub struct ComputeGraph {
+    tensors: Vec<Tensor>,
+    nodes: Vec<Node>,
+}
+
+impl ComputeGraph {
+    /// Create an empty graph.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tensor(&mut self, name: &str, kind: TensorKind, bytes: usize) -> TensorId {
+        self.tensors.push(Tensor {
+            name: name.to_string(),
+            kind,
+            bytes,
+        });
+        TensorId(self.tensors.len() - 1)
+    }
+
+    /// Declare an input tensor of `bytes` bytes.
+    pub fn input(&mut self, name: &str, bytes: usize) -> TensorId {
+        self.tensor(name, TensorKind::Input, bytes)
+    }
+
+    /// Declare an output tensor of `bytes` bytes.
+    pub fn output(&mut self, name: &str, bytes: usize) -> TensorId {
+        self.tensor(name, TensorKind::Output, bytes)
+    }
+
+    /// Declare a temporary tensor of `bytes` bytes, allocated by the plan.
+    pub fn temporary(&mut self, name: &str, bytes: usize) -> TensorId {
+        self.tensor(name, TensorKind::Temporary, bytes)
+    }
+
+    /// Add a dispatch of `shader` over `threads` that reads `reads` and
+    /// writes `writes`.
+    ///
+    /// Nodes may be added in any order; [`compile`](Self::compile) sorts
+    /// them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a tensor is not part of this graph, an
+    /// input is written, or a tensor is both read and written by the node.
+    pub fn add_node(
+        &mut self,
+        shader: &CompiledShader,
+        reads: &[TensorId],
+        writes: &[TensorId],
+        threads: Size3,
+        threadgroup: Size3,
+    ) -> Result<NodeId> {
+        for &id in reads.iter().chain(writes) {
+            if id.0 >= self.tensors.len() {
+                return Err(Error::invalid_input(format!(
+                    "tensor {} is not part of this graph",
+                    id.0
+                )));
+            }
+        }
+        for &id in writes {
+            let tensor = &self.tensors[id.0];
+            if tensor.kind == TensorKind::Input {
+                return Err(Error::invalid_input(format!(
+                    "node '{}' writes input tensor '{}'",
+                    shader.name(),
+                    tensor.name
+                )));
+            }
+            if reads.contains(&id) {
+                return Err(Error::invalid_input(format!(
+                    "node '{}' both reads and writes tensor '{}'",
+                    shader.name(),
+                    tensor.name
+                )));
+            }
+        }
+        self.nodes.push(Node {
+            shader: shader.clone(),
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+            threads,
+            threadgroup,
+        });
+        Ok(NodeId(self.nodes.len() - 1))
+    }
+
+    /// Number of nodes.
+    #[must_use]
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Order the nodes, place barriers and lay out temporaries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a tensor has zero bytes, a tensor is written by
+    /// more than one node, a temporary or output is never written, a
+    /// temporary is never read, or the dependencies form a cycle.
+    pub fn compile(&self) -> Result<ExecutionPlan> {
+        // Metal buffers cannot be empty, so a zero-byte tensor could never
+        // be bound to its `[[buffer(n)]]` slot.
+        if let Some(tensor) = self.tensors.iter().find(|tensor| tensor.bytes == 0) {
+            return Err(Error::invalid_input(format!(
+                "tensor '{}' has zero bytes",
+                tensor.name
+            )));
+        }
+        let producers = self.producers()?;
+        let order = self.order(&producers)?;
+
+        // Lifetimes of temporaries, in positions of `order`.
+        let mut lifetimes: Vec<Option<Range<usize>>> = vec![None; self.tensors.len()];
+        for (position, &node) in order.iter().enumerate() {
+            let node = &self.nodes[node];
+            for id in node.reads.iter().chain(&node.writes) {
+                if self.tensors[id.0].kind == TensorKind::Temporary {
+                    let lifetime = lifetimes[id.0].get_or_insert(position..position);
+                    lifetime.end = position;
+                }
+            }
+        }
+        for (id, tensor) in self.tensors.iter().enumerate() {
+            let unread = !self
+                .nodes
+                .iter()
+                .any(|node| node.reads.contains(&TensorId(id)));
+            if tensor.kind == TensorKind::Temporary && unread {
+                return Err(Error::invalid_input(format!(
+                    "temporary tensor '{}' is never read",
+                    tensor.name
+                )));
+            }
+        }
+
+        let (offsets, arena_bytes) = self.layout(&lifetimes);
+        let regions: Vec<Region> = self
+            .tensors
+            .iter()
+            .enumerate()
+            .map(|(id, tensor)| {
+                offsets[id].map_or(Region::External(id), |offset| {
+                    Region::Arena(offset..offset + tensor.bytes)
+                })
+            })
+            .collect();
+
+        let mut steps = Vec::with_capacity(order.len() * 2);
+        let mut read_since: Vec<&Region> = Vec::new();
+        let mut written_since: Vec<&Region> = Vec::new();
+        for &index in &order {
+            let node = &self.nodes[index];
+            let reads: Vec<&Region> = node.reads.iter().map(|id| &regions[id.0]).collect();
+            let writes: Vec<&Region> = node.writes.iter().map(|id| &regions[id.0]).collect();
+            let hazard = reads
+                .iter()
+                .any(|r| written_since.iter().any(|w| r.overlaps(w)))
+                || writes.iter().any(|w| {
+                    written_since
+                        .iter()
+                        .chain(&read_since)
+                        .any(|other| w.overlaps(other))
+                });
+            if hazard {
+                steps.push(Step::Barrier);
+                read_since.clear();
+                written_since.clear();
+            }
+            read_since.extend(reads);
+            written_since.extend(writes);
+            steps.push(Step::Dispatch(NodeId(index)));
+        }
+
+        Ok(ExecutionPlan {
+            graph: self.clone(),
+            steps,
+            offsets,
+            arena_bytes,
+        })
+    }
+
+    /// The node writing each tensor.
+    fn producers(&self) -> Result<Vec<Option<usize>>> {
+        let mut producers = vec![None; self.tensors.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            for id in &node.writes {
+                if producers[id.0].replace(index).is_some() {
+                    return Err(Error::invalid_input(format!(
+                        "tensor '{}' is written by more than one node",
+                        self.tensors[id.0].name
+                    )));
+                }
+            }
+        }
+        for (id, tensor) in self.tensors.iter().enumerate() {
+            if tensor.kind != TensorKind::Input && producers[id].is_none() {
+                return Err(Error::invalid_input(format!(
+                    "tensor '{}' is never written",
+                    tensor.name
+                )));
+            }
+        }
+        Ok(producers)
+    }
+
+    /// Topological order of the nodes, lowest index first among ready
+    /// nodes.
+    fn order(&self, producers: &[Option<usize>]) -> Result<Vec<usize>> {
+        let mut dependents = vec![Vec::new(); self.nodes.len()];
+        let mut pending = vec![0usize; self.nodes.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            let deps: BTreeSet<usize> =
+                node.reads.iter().filter_map(|id| producers[id.0]).collect();
+            pending[index] = deps.len();
+            for dep in deps {
+                dependents[dep].push(index);
+            }
+        }
+
+        let mut ready: BTreeSet<usize> = (0..self.nodes.len())
+            .filter(|&index| pending[index] == 0)
+            .collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(index) = ready.pop_first() {
+            order.push(index);
+            for &dependent in &dependents[index] {
+                pending[dependent] -= 1;
+                if pending[dependent] == 0 {
+                    ready.insert(dependent);
+                }
+            }
+        }
+
+        if let Some(stuck) = (0..self.nodes.len()).find(|&index| pending[index] > 0) {
+            return Err(Error::invalid_input(format!(
+                "graph has a cycle through node '{}'",
+                self.nodes[stuck].shader.name()
+            )));
+        }
+        Ok(order)
+    }
+
+    /// Place temporaries in the arena, largest first, at the lowest
+    /// aligned offset free for their whole lifetime.
+    fn layout(&self, lifetimes: &[Option<Range<usize>>]) -> (Vec<Option<usize>>, usize) {
+        let mut temporaries: Vec<(usize, &Range<usize>)> = lifetimes
+            .iter()
+            .enumerate()
+            .filter_map(|(id, lifetime)| lifetime.as_ref().map(|lifetime| (id, lifetime)))
+            .collect();
+        temporaries.sort_by_key(|&(id, _)| (std::cmp::Reverse(self.tensors[id].bytes), id));
+
+        let mut offsets = vec![None; self.tensors.len()];
+        let mut placed: Vec<(Range<usize>, &Range<usize>)> = Vec::new();
+        let mut arena_bytes = 0;
+        for (id, lifetime) in temporaries {
+            let bytes = self.tensors[id].bytes;
+            let mut blocking: Vec<&Range<usize>> = placed
+                .iter()
+                .filter(|(_, other)| other.start <= lifetime.end && lifetime.start <= other.end)
+                .map(|(memory, _)| memory)
+                .collect();
+            blocking.sort_by_key(|memory| memory.start);
+
+            let mut offset = 0;
+            for memory in blocking {
+                if offset + bytes <= memory.start {
+                    break;
+                }
+                offset = offset.max(memory.end.next_multiple_of(HEAP_ALIGNMENT));
+            }
+            offsets[id] = Some(offset);
+            arena_bytes = arena_bytes.max(offset + bytes);
+            placed.push((offset..offset + bytes, lifetime));
+        }
+        (offsets, arena_bytes)
+    }
+}
+
+/// Memory a tensor occupies during a run.
+#[derive(Debug)]
+enum Region {
+    /// A caller-bound buffer.
+    External(usize),
+    /// Bytes of the temporary arena.
+    Arena(Range<usize>),
+}
+
+impl Region {
+    fn overlaps(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::External(a), Self::External(b)) => a == b,
+            (Self::Arena(a), Self::Arena(b)) => a.start < b.end && b.start < a.end,
+            _ => false,
+        }
+    }
+}
+
+/// One step of an [`ExecutionPlan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Wait for earlier dispatches' memory writes to be visible.
+    Barrier,
+    /// Dispatch a node.
+    Dispatch(NodeId),
+}
+
+/// A compiled [`ComputeGraph`], ready to run.
+#[derive(Debug, Clone)]
+pub struct ExecutionPlan {
+    graph: ComputeGraph,
+    steps: Vec<Step>,
+    offsets: Vec<Option<usize>>,
+    arena_bytes: usize,
+}
+
+impl ExecutionPlan {
+    /// Steps in execution order.
+    #[must_use]
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Nodes in execution order.
+    pub fn order(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.steps.iter().filter_map(|step| match step {
+            Step::Dispatch(node) => Some(*node),
+            Step::Barrier => None,
+        })
+    }
+
+    /// Bytes of the arena holding all temporaries.
+    #[must_use]
+    pub const fn arena_bytes(&self) -> usize {
+        self.arena_bytes
+    }
+
+    /// Bytes the temporaries would need without reuse.
+    #[must_use]
+    pub fn unshared_bytes(&self) -> usize {
+        self.graph
+            .tensors
+            .iter()
+            .filter(|tensor| tensor.kind == TensorKind::Temporary)
+            .map(|tensor| tensor.bytes)
+            .sum()
+    }
+
+    /// Arena offset of a temporary; `None` for inputs and outputs.
+    #[must_use]
+    pub fn arena_offset(&self, tensor: TensorId) -> Option<usize> {
+        self.offsets.get(tensor.0).copied().flatten()
+    }
+
+    /// Run the plan on `compute`, with every input and output tensor
+    /// bound to a buffer.
+    ///
+    /// Temporaries are allocated from one arena buffer for the run.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an input or output is unbound or bound to a
+    /// buffer smaller than the tensor, a temporary is bound, the arena
+    /// cannot be allocated, or a dispatch fails.
+    pub fn run(&self, compute: &MetalCompute, bindings: &[(TensorId, &MetalBuffer)]) -> Result<()> {
+        let mut bound: Vec<Option<&MetalBuffer>> = vec![None; self.graph.tensors.len()];
+        for &(id, buffer) in bindings {
+            let tensor = self.graph.tensors.get(id.0).ok_or_else(|| {
+                Error::invalid_input(format!("tensor {} is not part of this graph", id.0))
+            })?;
+            if tensor.kind == TensorKind::Temporary {
+                return Err(Error::invalid_input(format!(
+                    "temporary tensor '{}' cannot be bound",
+                    tensor.name
+                )));
+            }
+            if buffer.len() < tensor.bytes {
+                return Err(Error::invalid_input(format!(
+                    "tensor '{}' needs {} bytes but its buffer holds {}",
+                    tensor.name,
+                    tensor.bytes,
+                    buffer.len()
+                )));
+            }
+            bound[id.0] = Some(buffer);
+        }
+        if let Some(tensor) = self
+            .graph
+            .tensors
+            .iter()
+            .zip(&bound)
+            .find(|(tensor, buffer)| tensor.kind != TensorKind::Temporary && buffer.is_none())
+            .map(|(tensor, _)| tensor)
+        {
+            return Err(Error::invalid_input(format!(
+                "tensor '{}' is not bound",
+                tensor.name
+            )));
+        }
+
+        let arena = if self.arena_bytes > 0 {
+            Some(compute.allocate_buffer(self.arena_bytes)?)
+        } else {
+            None
+        };
+        let temporaries: Vec<Option<MetalBuffer>> = self
+            .graph
+            .tensors
+            .iter()
+            .zip(&self.offsets)
+            .map(|(tensor, offset)| {
+                offset
+                    .zip(arena.as_ref())
+                    .map(|(offset, arena)| arena.alias(offset, tensor.bytes))
+            })
+            .collect();
+
+        for step in &self.steps {
+            // Dispatches on one pipeline execute in order, so a barrier
+            // needs no extra work here.
+            let Step::Dispatch(NodeId(index)) = *step else {
+                continue;
+            };
+            let node = &self.graph.nodes[index];
+            let buffers = node
+                .reads
+                .iter()
+                .chain(&node.writes)
+                .map(|id| {
+                    bound[id.0].or(temporaries[id.0].as_ref()).ok_or_else(|| {
+                        Error::internal(format!(
+                            "tensor '{}' has no buffer",
+                            self.graph.tensors[id.0].name
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<&MetalBuffer>>>()?;
+            compute.dispatch(&node.shader, &buffers, node.threads, node.threadgroup)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::metal::CpuKernel;
+
+    fn shader(compute: &MetalCompute, name: &str) -> CompiledShader {
+        compute
+            .compile_shader("kernel void k() {}", name)
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|_| {}))
+    }
+
+    fn add(
+        graph: &mut ComputeGraph,
+        shader: &CompiledShader,
+        reads: &[TensorId],
+        writes: &[TensorId],
+    ) {
+        graph
+            .add_node(shader, reads, writes, (1, 1, 1), (1, 1, 1))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_orders_nodes_by_dependency() {
+        let compute = MetalCompute::cpu();
+        let mut graph = ComputeGraph::new();
+        let x = graph.input("x", 4);
+        let a = graph.temporary("a", 4);
+        let b = graph.temporary("b", 4);
+        let y = graph.output("y", 4);
+        // Added consumer-first.
+        add(&mut graph, &shader(&compute, "join"), &[a, b], &[y]);
+        add(&mut graph, &shader(&compute, "left"), &[x], &[a]);
+        add(&mut graph, &shader(&compute, "right"), &[x], &[b]);
+
+        let plan = graph.compile().unwrap();
+        assert_eq!(graph.node_count(), 3);
+        assert_eq!(
+            plan.steps(),
+            &[
+                Step::Dispatch(NodeId(1)),
+                Step::Dispatch(NodeId(2)),
+                Step::Barrier,
+                Step::Dispatch(NodeId(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reuses_memory_of_dead_temporaries() {
+        let compute = MetalCompute::cpu();
+        let k = shader(&compute, "k");
+        let mut graph = ComputeGraph::new();
+        let x = graph.input("x", 1000);
+        let t: Vec<TensorId> = (0..4)
+            .map(|i| graph.temporary(&format!("t{i}"), 1000))
+            .collect();
+        let y = graph.output("y", 1000);
+        add(&mut graph, &k, &[x], &[t[0]]);
+        add(&mut graph, &k, &[t[0]], &[t[1]]);
+        add(&mut graph, &k, &[t[1]], &[t[2]]);
+        add(&mut graph, &k, &[t[2]], &[t[3]]);
+        add(&mut graph, &k, &[t[3]], &[y]);
+
+        let plan = graph.compile().unwrap();
+        assert_eq!(plan.unshared_bytes(), 4000);
+        assert_eq!(plan.arena_bytes(), 1024 + 1000);
+        assert_eq!(plan.arena_offset(t[0]), plan.arena_offset(t[2]));
+        assert_ne!(plan.arena_offset(t[0]), plan.arena_offset(t[1]));
+        assert_eq!(plan.arena_offset(x), None);
+        // Every node depends on the previous one.
+        assert_eq!(
+            plan.steps().iter().filter(|s| **s == Step::Barrier).count(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let compute = MetalCompute::cpu();
+        let kernel = shader(&compute, "k");
+
+        let mut graph = ComputeGraph::new();
+        let x = graph.input("x", 4);
+        assert!(graph
+            .add_node(&kernel, &[], &[x], (1, 1, 1), (1, 1, 1))
+            .is_err());
+        assert!(graph
+            .add_node(&kernel, &[TensorId(9)], &[], (1, 1, 1), (1, 1, 1))
+            .is_err());
+        let y = graph.output("y", 4);
+        assert!(graph
+            .add_node(&kernel, &[y], &[y], (1, 1, 1), (1, 1, 1))
+            .is_err());
+        let err = graph.compile().unwrap_err();
+        assert!(err.to_string().contains("'y' is never written"));
+
+        add(&mut graph, &kernel, &[x], &[y]);
+        add(&mut graph, &kernel, &[x], &[y]);
+        assert!(graph
+            .compile()
+            .unwrap_err()
+            .to_string()
+            .contains("more than one"));
+
+        let mut graph = ComputeGraph::new();
+        let first = graph.temporary("first", 4);
+        let second = graph.temporary("second", 4);
+        add(&mut graph, &shader(&compute, "ping"), &[second], &[first]);
+        add(&mut graph, &shader(&compute, "pong"), &[first], &[second]);
+        assert!(graph.compile().unwrap_err().to_string().contains("cycle"));
+
+        let mut graph = ComputeGraph::new();
+        let x = graph.input("x", 4);
+        let empty = graph.temporary("empty", 0);
+        let y = graph.output("y", 4);
+        add(&mut graph, &kernel, &[x], &[empty]);
+        add(&mut graph, &kernel, &[empty], &[y]);
+        assert!(graph
+            .compile()
+            .unwrap_err()
+            .to_string()
+            .contains("'empty' has zero bytes"));
+
+        let mut graph = ComputeGraph::new();
+        let x = graph.input("x", 4);
+        let dead = graph.temporary("dead", 4);
+        add(&mut graph, &kernel, &[x], &[dead]);
+        assert!(graph
+            .compile()
+            .unwrap_err()
+            .to_string()
+            .contains("never read"));
+    }
+
+    #[test]
+    fn test_run_on_cpu_backend() {
+        let compute = MetalCompute::cpu();
+        let scale = compute
+            .compile_shader("kernel void scale() {}", "scale")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let i = ctx.thread_position().0 as usize;
+                ctx.write_f32(1, i, ctx.read_f32(0, i) * 2.0);
+            }));
+        let add = compute
+            .compile_shader("kernel void add() {}", "add")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let i = ctx.thread_position().0 as usize;
+                ctx.write_f32(2, i, ctx.read_f32(0, i) + ctx.read_f32(1, i));
+            }));
+
+        // y = 2x + 4x, through two temporaries and one reused slot.
+        let mut graph = ComputeGraph::new();
+        let x = graph.input("x", 16);
+        let twice = graph.temporary("twice", 16);
+        let four = graph.temporary("four", 16);
+        let y = graph.output("y", 16);
+        graph
+            .add_node(&scale, &[x], &[twice], (4, 1, 1), (4, 1, 1))
+            .unwrap();
+        graph
+            .add_node(&scale, &[twice], &[four], (4, 1, 1), (4, 1, 1))
+            .unwrap();
+        graph
+            .add_node(&add, &[twice, four], &[y], (4, 1, 1), (4, 1, 1))
+            .unwrap();
+        let plan = graph.compile().unwrap();
+
+        let input = compute.allocate_buffer(16).unwrap();
+        input.write(0, &[1.0f32, 2.0, 3.0, 4.0]).unwrap();
+        let output = compute.allocate_buffer(16).unwrap();
+        let usage = compute.memory_usage();
+        plan.run(&compute, &[(x, &input), (y, &output)]).unwrap();
+        assert_eq!(output.read::<f32>().unwrap(), vec![6.0, 12.0, 18.0, 24.0]);
+        // One arena for the run, released afterwards.
+        let after = compute.memory_usage();
+        assert_eq!(after.in_use, usage.in_use);
+        assert_eq!(after.high_water, usage.in_use + plan.arena_bytes() as u64);
+
+        let err = plan.run(&compute, &[(x, &input)]).unwrap_err();
+        assert!(err.to_string().contains("'y' is not bound"));
+        let small = compute.allocate_buffer(8).unwrap();
+        assert!(plan.run(&compute, &[(x, &small), (y, &output)]).is_err());
+        assert!(plan
+            .run(&compute, &[(x, &input), (y, &output), (twice, &output)])
+            .is_err());
+    }
+}
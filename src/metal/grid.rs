@@ -0,0 +1,185 @@
+//! Dispatch grid arithmetic and indirect dispatch arguments.
+//!
+//! Metal sizes a dispatch in one of two ways:
+//!
+//! - **Threads** (`dispatchThreads`): the grid counts threads and need not
+//!   be a multiple of the threadgroup size. Edge threadgroups are trimmed,
+//!   so every thread's position is inside the grid.
+//! - **Threadgroups** (`dispatchThreadgroups`): the grid counts whole
+//!   threadgroups, so the thread grid is `threadgroups * threadgroup` and
+//!   kernels must bounds-check positions past their logical size.
+//!
+//! Indirect dispatch reads the threadgroup counts from an
+//! [`IndirectArguments`] record in a buffer, typically written by an
+//! earlier kernel.
+//!
+//! All products are overflow-checked.
+//!
+//! # Falsification Claims
+//!
+//! - `threads_for_threadgroups` never wraps: it errors instead
+//! - `threadgroup_count` covers the grid with the fewest threadgroups
+
+use super::{storage, MetalBuffer, Size3};
+use crate::error::{Error, Result};
+
+/// Total number of threads in `grid`.
+///
+/// # Errors
+///
+/// Returns an error if the product overflows `u64`.
+pub fn thread_count(grid: Size3) -> Result<u64> {
+    u64::from(grid.0)
+        .checked_mul(u64::from(grid.1))
+        .and_then(|n| n.checked_mul(u64::from(grid.2)))
+        .ok_or_else(|| Error::invalid_input(format!("grid {grid:?} overflows thread count")))
+}
+
+/// Thread grid covered by `threadgroups` groups of `threadgroup` threads.
+///
+/// # Errors
+///
+/// Returns an error if a dimension overflows `u32`.
+pub fn threads_for_threadgroups(threadgroups: Size3, threadgroup: Size3) -> Result<Size3> {
+    let dim = |count: u32, size: u32| {
+        count.checked_mul(size).ok_or_else(|| {
+            Error::invalid_input(format!(
+                "{threadgroups:?} threadgroups of {threadgroup:?} overflow the grid"
+            ))
+        })
+    };
+    Ok((
+        dim(threadgroups.0, threadgroup.0)?,
+        dim(threadgroups.1, threadgroup.1)?,
+        dim(threadgroups.2, threadgroup.2)?,
+    ))
+}
+
+/// Fewest threadgroups of `threadgroup` threads covering `threads`.
+///
+/// Zero-sized threadgroup dimensions yield zero threadgroups.
+#[must_use]
+pub const fn threadgroup_count(threads: Size3, threadgroup: Size3) -> Size3 {
+    const fn ceil_div(n: u32, d: u32) -> u32 {
+        if d == 0 {
+            0
+        } else {
+            n.div_ceil(d)
+        }
+    }
+    (
+        ceil_div(threads.0, threadgroup.0),
+        ceil_div(threads.1, threadgroup.1),
+        ceil_div(threads.2, threadgroup.2),
+    )
+}
+
+/// Arguments of an indirect dispatch, laid out like
+/// `MTLDispatchThreadgroupsIndirectArguments`: three `uint` threadgroup
+/// counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndirectArguments {
+    /// Threadgroups per grid `(width, height, depth)`.
+    pub threadgroups: Size3,
+}
+
+impl IndirectArguments {
+    /// Size of the record in bytes.
+    pub const SIZE: usize = 12;
+
+    /// Required alignment of the record's offset in a buffer.
+    pub const ALIGNMENT: usize = 4;
+
+    /// Create arguments for `threadgroups`.
+    #[must_use]
+    pub const fn new(threadgroups: Size3) -> Self {
+        Self { threadgroups }
+    }
+
+    /// Read the record at `offset` in `buffer`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `offset` is misaligned or the record does not
+    /// fit in the buffer.
+    pub fn read(buffer: &MetalBuffer, offset: usize) -> Result<Self> {
+        Self::check_offset(buffer, offset)?;
+        Self::from_bytes(&buffer.read_bytes(offset, Self::SIZE)?)
+    }
+
+    /// Decode a record from its [`SIZE`](Self::SIZE) bytes.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match storage::from_bytes::<u32>(bytes)[..] {
+            [x, y, z] => Ok(Self::new((x, y, z))),
+            _ => Err(Error::internal("indirect arguments are three words")),
+        }
+    }
+
+    /// Write the record at `offset` in `buffer`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `offset` is misaligned or the record does not
+    /// fit in the buffer.
+    pub fn write(&self, buffer: &MetalBuffer, offset: usize) -> Result<()> {
+        Self::check_offset(buffer, offset)?;
+        let words: [u32; 3] = self.threadgroups.into();
+        buffer.write(offset / Self::ALIGNMENT, &words)
+    }
+
+    pub(crate) fn check_offset(buffer: &MetalBuffer, offset: usize) -> Result<()> {
+        if offset % Self::ALIGNMENT != 0 {
+            return Err(Error::invalid_input(format!(
+                "indirect argument offset {offset} is not {}-byte aligned",
+                Self::ALIGNMENT
+            )));
+        }
+        if offset
+            .checked_add(Self::SIZE)
+            .map_or(true, |end| end > buffer.len())
+        {
+            return Err(Error::invalid_input(format!(
+                "indirect arguments at offset {offset} exceed buffer of {} bytes",
+                buffer.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_count() {
+        assert_eq!(thread_count((4, 5, 6)).unwrap(), 120);
+        assert_eq!(thread_count((0, 5, 6)).unwrap(), 0);
+        assert_eq!(
+            thread_count((u32::MAX, u32::MAX, 1)).unwrap(),
+            u64::from(u32::MAX) * u64::from(u32::MAX)
+        );
+        assert!(thread_count((u32::MAX, u32::MAX, u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_threads_for_threadgroups_overflow() {
+        assert_eq!(
+            threads_for_threadgroups((3, 2, 1), (32, 4, 1)).unwrap(),
+            (96, 8, 1)
+        );
+        let err = threads_for_threadgroups((1, u32::MAX, 1), (1, 2, 1)).unwrap_err();
+        assert!(err.to_string().contains("overflow"));
+    }
+
+    #[test]
+    fn test_threadgroup_count_covers_grid() {
+        assert_eq!(threadgroup_count((10, 8, 1), (4, 8, 1)), (3, 1, 1));
+        assert_eq!(
+            threadgroup_count((u32::MAX, 1, 1), (2, 1, 1)),
+            (1 << 31, 1, 1)
+        );
+        assert_eq!(threadgroup_count((5, 5, 5), (0, 1, 1)), (0, 5, 5));
+    }
+}
@@ -0,0 +1,488 @@
+//! Heap sub-allocation for many small GPU buffers.
+//!
+//! A [`MetalHeap`] reserves one large region on a device with a single
+//! [`MetalCompute::allocate_buffer`](super::MetalCompute::allocate_buffer)
+//! call and hands out aligned [`MetalBuffer`] slices of it. Slices return to
+//! a coalescing first-fit free list when dropped, so thousands of small
+//! tensors per request cost no ledger traffic and no driver round trips.
+//!
+//! Transient buffers can be made aliasable: their range is returned to the
+//! free list while the buffer is still alive, and later allocations may
+//! overlap it. [`MetalHeap::reset`] releases every slice at once, which
+//! suits per-frame or per-request arenas.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::MetalCompute;
+//!
+//! let compute = MetalCompute::default_device()?;
+//! let heap = compute.create_heap(64 * 1024 * 1024)?;
+//!
+//! let weights = heap.allocate(4096)?;
+//! let scratch = heap.allocate(1024)?;
+//! heap.make_aliasable(&scratch)?;
+//!
+//! let stats = heap.stats();
+//! println!("used {} of {} bytes", stats.used, stats.capacity);
+//!
+//! heap.reset();
+//! assert!(!weights.is_valid());
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+use super::MetalBuffer;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Default alignment of heap slices in bytes.
+///
+/// Matches the buffer offset alignment Metal requires for argument buffers
+/// on macOS.
+pub const HEAP_ALIGNMENT: usize = 256;
+
+/// Occupancy snapshot of a [`MetalHeap`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeapStats {
+    /// Total heap size in bytes.
+    pub capacity: usize,
+    /// Bytes held by live, non-aliasable slices.
+    pub used: usize,
+    /// Bytes on the free list.
+    pub free: usize,
+    /// Largest contiguous free block in bytes.
+    pub largest_free_block: usize,
+    /// Number of live, non-aliasable slices.
+    pub allocations: usize,
+    /// External fragmentation: `1 - largest_free_block / free`, or 0 when
+    /// nothing is free.
+    pub fragmentation: f64,
+}
+
+/// Sorted, coalescing list of free `(offset, length)` ranges.
+#[derive(Debug)]
+struct FreeList {
+    capacity: usize,
+    blocks: Vec<(usize, usize)>,
+}
+
+impl FreeList {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: vec![(0, capacity)],
+        }
+    }
+
+    /// First-fit allocation; returns the aligned offset.
+    fn allocate(&mut self, length: usize, alignment: usize) -> Option<usize> {
+        let (index, offset, aligned) =
+            self.blocks
+                .iter()
+                .enumerate()
+                .find_map(|(index, &(offset, block_len))| {
+                    let aligned = offset.checked_next_multiple_of(alignment)?;
+                    let end = aligned.checked_add(length)?;
+                    (end <= offset + block_len).then_some((index, offset, aligned))
+                })?;
+
+        let (_, block_len) = self.blocks.remove(index);
+        let tail_start = aligned + length;
+        let tail_len = offset + block_len - tail_start;
+        if tail_len > 0 {
+            self.blocks.insert(index, (tail_start, tail_len));
+        }
+        if aligned > offset {
+            self.blocks.insert(index, (offset, aligned - offset));
+        }
+        Some(aligned)
+    }
+
+    /// Return a range and merge it with adjacent free blocks.
+    fn free(&mut self, offset: usize, length: usize) {
+        let index = self.blocks.partition_point(|&(o, _)| o < offset);
+        self.blocks.insert(index, (offset, length));
+
+        if index + 1 < self.blocks.len() {
+            let (next_offset, next_len) = self.blocks[index + 1];
+            if offset + length == next_offset {
+                self.blocks[index].1 += next_len;
+                self.blocks.remove(index + 1);
+            }
+        }
+        if index > 0 {
+            let (prev_offset, prev_len) = self.blocks[index - 1];
+            if prev_offset + prev_len == offset {
+                self.blocks[index - 1].1 += self.blocks[index].1;
+                self.blocks.remove(index);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.blocks = vec![(0, self.capacity)];
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.blocks.iter().map(|&(_, len)| len).sum()
+    }
+
+    fn largest_block(&self) -> usize {
+        self.blocks.iter().map(|&(_, len)| len).max().unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+struct HeapState {
+    backing: MetalBuffer,
+    free_list: FreeList,
+    live: HashMap<u64, (usize, usize)>,
+    next_id: u64,
+    generation: u64,
+}
+
+impl HeapState {
+    fn release(&mut self, id: u64) {
+        if let Some((offset, length)) = self.live.remove(&id) {
+            self.free_list.free(offset, length);
+        }
+    }
+}
+
+type SharedHeap = Arc<Mutex<HeapState>>;
+
+fn lock(state: &SharedHeap) -> MutexGuard<'_, HeapState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Link from a sub-allocated buffer back to its heap.
+///
+/// Returns the slice's range to the free list on drop, unless it was made
+/// aliasable or the heap was reset since.
+#[derive(Debug)]
+pub(crate) struct HeapSlice {
+    state: SharedHeap,
+    id: u64,
+    generation: u64,
+}
+
+impl HeapSlice {
+    /// True if a heap reset has released this slice.
+    pub(crate) fn is_released(&self) -> bool {
+        lock(&self.state).generation != self.generation
+    }
+
+    /// Return the range to the free list if it is still held.
+    fn release(&self) {
+        let mut state = lock(&self.state);
+        if state.generation == self.generation {
+            state.release(self.id);
+        }
+    }
+}
+
+impl Drop for HeapSlice {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// A GPU memory region that sub-allocates [`MetalBuffer`] slices.
+///
+/// Created with [`MetalCompute::create_heap`](super::MetalCompute::create_heap).
+/// The backing memory is charged to the pipeline's ledger once, when the
+/// heap is created, and released when the heap and all its slices are gone.
+#[derive(Debug)]
+pub struct MetalHeap {
+    state: SharedHeap,
+    capacity: usize,
+}
+
+impl MetalHeap {
+    pub(crate) fn new(backing: MetalBuffer) -> Self {
+        let capacity = backing.len();
+        Self {
+            state: Arc::new(Mutex::new(HeapState {
+                backing,
+                free_list: FreeList::new(capacity),
+                live: HashMap::new(),
+                next_id: 0,
+                generation: 0,
+            })),
+            capacity,
+        }
+    }
+
+    /// Get the heap size in bytes.
+    #[must_use]
+    pub const fn size(&self) -> usize {
+        self.capacity
+    }
+
+    /// Allocate a slice aligned to [`HEAP_ALIGNMENT`].
+    ///
+    /// # Errors
+    ///
+    /// See [`MetalHeap::allocate_aligned`].
+    pub fn allocate(&self, length: usize) -> Result<MetalBuffer> {
+        self.allocate_aligned(length, HEAP_ALIGNMENT)
+    }
+
+    /// Allocate a slice with a custom alignment.
+    ///
+    /// # Arguments
+    ///
+    /// * `length` - Size in bytes
+    /// * `alignment` - Offset alignment in bytes (a power of two)
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - `length` is zero or `alignment` is not a power of two
+    /// - No free block is large enough
+    /// - The device backing the heap is lost
+    pub fn allocate_aligned(&self, length: usize, alignment: usize) -> Result<MetalBuffer> {
+        if length == 0 {
+            return Err(Error::invalid_input("buffer length cannot be zero"));
+        }
+        if !alignment.is_power_of_two() {
+            return Err(Error::invalid_input(format!(
+                "heap alignment {alignment} is not a power of two"
+            )));
+        }
+
+        let mut state = lock(&self.state);
+        if !state.backing.is_valid() {
+            return Err(Error::device_lost(state.backing.device_index()));
+        }
+
+        let offset = state.free_list.allocate(length, alignment).ok_or_else(|| {
+            Error::out_of_memory(length as u64, state.free_list.largest_block() as u64)
+        })?;
+        let id = state.next_id;
+        state.next_id += 1;
+        state.live.insert(id, (offset, length));
+
+        Ok(MetalBuffer {
+            length,
+            device_index: state.backing.device_index(),
+            offset,
+            storage: state.backing.storage.clone(),
+            epoch: state.backing.epoch.clone(),
+            _allocation: None,
+            heap: Some(HeapSlice {
+                state: Arc::clone(&self.state),
+                id,
+                generation: state.generation,
+            }),
+            uniforms: None,
+        })
+    }
+
+    /// Return a transient slice's memory to the heap while it is still alive.
+    ///
+    /// Later allocations may overlap the buffer. The caller must ensure the
+    /// buffer is no longer read or written by work submitted after those
+    /// allocations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the buffer was not allocated from this heap.
+    pub fn make_aliasable(&self, buffer: &MetalBuffer) -> Result<()> {
+        let slice = buffer
+            .heap
+            .as_ref()
+            .filter(|slice| Arc::ptr_eq(&slice.state, &self.state))
+            .ok_or_else(|| Error::invalid_input("buffer was not allocated from this heap"))?;
+        slice.release();
+        Ok(())
+    }
+
+    /// Release every slice at once.
+    ///
+    /// Outstanding buffers become invalid: dispatch, blits, reads and writes
+    /// reject them.
+    pub fn reset(&self) {
+        let mut state = lock(&self.state);
+        state.generation += 1;
+        state.live.clear();
+        state.free_list.reset();
+    }
+
+    /// Report occupancy and fragmentation.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn stats(&self) -> HeapStats {
+        let state = lock(&self.state);
+        let free = state.free_list.free_bytes();
+        let largest_free_block = state.free_list.largest_block();
+        HeapStats {
+            capacity: self.capacity,
+            used: self.capacity - free,
+            free,
+            largest_free_block,
+            allocations: state.live.len(),
+            fragmentation: if free == 0 {
+                0.0
+            } else {
+                1.0 - largest_free_block as f64 / free as f64
+            },
+        }
+    }
+}
+
+/// Create a heap around a backing buffer outside a pipeline (tests only).
+#[cfg(test)]
+pub(crate) fn detached_heap(capacity: usize) -> MetalHeap {
+    MetalHeap::new(MetalBuffer {
+        length: capacity,
+        device_index: 0,
+        offset: 0,
+        storage: super::Storage::new(),
+        epoch: super::DeviceEpoch::new(),
+        _allocation: None,
+        heap: None,
+        uniforms: None,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_list_first_fit_alignment() {
+        let mut list = FreeList::new(1024);
+        assert_eq!(list.allocate(10, 1), Some(0));
+        assert_eq!(list.allocate(10, 256), Some(256));
+        assert_eq!(list.blocks, vec![(10, 246), (266, 758)]);
+        assert_eq!(list.allocate(200, 1), Some(10));
+    }
+
+    #[test]
+    fn test_free_list_coalesces() {
+        let mut list = FreeList::new(300);
+        let a = list.allocate(100, 1).unwrap();
+        let b = list.allocate(100, 1).unwrap();
+        let c = list.allocate(100, 1).unwrap();
+        assert_eq!(list.allocate(1, 1), None);
+
+        list.free(a, 100);
+        list.free(c, 100);
+        assert_eq!(list.blocks.len(), 2);
+        list.free(b, 100);
+        assert_eq!(list.blocks, vec![(0, 300)]);
+    }
+
+    #[test]
+    fn test_free_list_exhausted() {
+        let mut list = FreeList::new(100);
+        assert_eq!(list.allocate(101, 1), None);
+        assert_eq!(list.allocate(100, 1), Some(0));
+        assert_eq!(list.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn test_heap_slices_are_aligned() {
+        let heap = detached_heap(4096);
+        let a = heap.allocate(10).unwrap();
+        let b = heap.allocate(10).unwrap();
+        assert_eq!(a.offset(), 0);
+        assert_eq!(b.offset(), HEAP_ALIGNMENT);
+        assert_eq!(b.len(), 10);
+        assert_eq!(heap.stats().allocations, 2);
+    }
+
+    #[test]
+    fn test_heap_drop_returns_memory() {
+        let heap = detached_heap(1024);
+        let a = heap.allocate(512).unwrap();
+        assert_eq!(heap.stats().free, 512);
+        drop(a);
+        let stats = heap.stats();
+        assert_eq!(stats.free, 1024);
+        assert_eq!(stats.allocations, 0);
+    }
+
+    #[test]
+    fn test_heap_out_of_space() {
+        let heap = detached_heap(1024);
+        let _a = heap.allocate(1000).unwrap();
+        let err = heap.allocate(100).unwrap_err();
+        assert!(matches!(err, Error::OutOfMemory { .. }));
+    }
+
+    #[test]
+    fn test_heap_fragmentation() {
+        let heap = detached_heap(1024);
+        let a = heap.allocate(256).unwrap();
+        let _b = heap.allocate(256).unwrap();
+        let c = heap.allocate(256).unwrap();
+        let _d = heap.allocate(256).unwrap();
+        assert!(heap.stats().fragmentation.abs() < f64::EPSILON);
+
+        drop(a);
+        drop(c);
+        let stats = heap.stats();
+        assert_eq!(stats.free, 512);
+        assert_eq!(stats.largest_free_block, 256);
+        assert!((stats.fragmentation - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_heap_aliasing() {
+        let heap = detached_heap(1024);
+        let transient = heap.allocate(1024).unwrap();
+        heap.make_aliasable(&transient).unwrap();
+
+        let aliased = heap.allocate(512).unwrap();
+        assert_eq!(aliased.offset(), transient.offset());
+        assert!(transient.is_valid());
+
+        // Dropping the aliased buffer must not free the range twice.
+        drop(transient);
+        assert_eq!(heap.stats().free, 512);
+    }
+
+    #[test]
+    fn test_heap_aliasing_foreign_buffer() {
+        let heap = detached_heap(1024);
+        let other = detached_heap(1024);
+        let buffer = other.allocate(16).unwrap();
+        assert!(heap.make_aliasable(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_heap_reset() {
+        let heap = detached_heap(1024);
+        let a = heap.allocate(512).unwrap();
+        let b = heap.allocate(256).unwrap();
+        heap.reset();
+
+        assert!(!a.is_valid());
+        assert!(!b.is_valid());
+        assert_eq!(heap.stats().free, 1024);
+
+        let c = heap.allocate(1024).unwrap();
+        drop(a);
+        drop(b);
+        assert!(c.is_valid());
+        assert_eq!(heap.stats().free, 0);
+    }
+
+    #[test]
+    fn test_heap_invalid_alignment() {
+        let heap = detached_heap(1024);
+        assert!(heap.allocate_aligned(16, 3).is_err());
+        assert!(heap.allocate(0).is_err());
+    }
+
+    #[test]
+    fn test_heap_device_lost() {
+        let heap = detached_heap(1024);
+        lock(&heap.state).backing.epoch.invalidate();
+        assert!(heap.allocate(16).unwrap_err().is_device_lost());
+    }
+}
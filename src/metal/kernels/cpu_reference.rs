@@ -0,0 +1,344 @@
+//! CPU reference implementations of the built-in kernels.
+//!
+//! Plain functions over `f32` slices, independent of any
+//! [`MetalCompute`](crate::metal::MetalCompute). The per-row and
+//! per-element helpers are shared with the CPU backend's kernels, so the
+//! CPU backend matches these references bit for bit.
+
+use super::{BinaryOp, GemmShape, ReduceOp, RopeShape};
+use crate::error::{Error, Result};
+
+/// Element `(row, col)` of `op(A) × op(B)`.
+pub(crate) fn gemm_element(
+    a: impl Fn(usize) -> f32,
+    b: impl Fn(usize) -> f32,
+    shape: &GemmShape,
+    row: usize,
+    col: usize,
+) -> f32 {
+    let mut acc = 0.0f32;
+    for p in 0..shape.k {
+        let a_index = if shape.transpose_a {
+            p * shape.m + row
+        } else {
+            row * shape.k + p
+        };
+        let b_index = if shape.transpose_b {
+            col * shape.k + p
+        } else {
+            p * shape.n + col
+        };
+        acc += a(a_index) * b(b_index);
+    }
+    acc
+}
+
+/// Matrix multiply `C = op(A) × op(B)`, with `C` row-major `m × n`.
+///
+/// `A` is row-major `m × k`, or `k × m` when `transpose_a` is set; `B` is
+/// `k × n`, or `n × k` when `transpose_b` is set. Missing elements read as 0.
+#[must_use]
+pub fn gemm(a: &[f32], b: &[f32], shape: &GemmShape) -> Vec<f32> {
+    let a_at = |i: usize| a.get(i).copied().unwrap_or(0.0);
+    let b_at = |i: usize| b.get(i).copied().unwrap_or(0.0);
+    (0..shape.m)
+        .flat_map(|row| (0..shape.n).map(move |col| (row, col)))
+        .map(|(row, col)| gemm_element(a_at, b_at, shape, row, col))
+        .collect()
+}
+
+/// Numerically stable softmax of one row.
+#[must_use]
+pub fn softmax_row(row: &[f32]) -> Vec<f32> {
+    let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = row.iter().map(|&x| (x - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// Softmax over the last axis of a row-major matrix with `cols` columns.
+#[must_use]
+pub fn softmax(input: &[f32], cols: usize) -> Vec<f32> {
+    rows(input, cols).flat_map(softmax_row).collect()
+}
+
+/// Layer normalization of one row.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn layer_norm_row(row: &[f32], gamma: &[f32], beta: &[f32], eps: f32) -> Vec<f32> {
+    let n = row.len() as f32;
+    let mean = row.iter().sum::<f32>() / n;
+    let var = row.iter().map(|&x| (x - mean) * (x - mean)).sum::<f32>() / n;
+    let inv = 1.0 / (var + eps).sqrt();
+    row.iter()
+        .enumerate()
+        .map(|(i, &x)| ((x - mean) * inv).mul_add(at(gamma, i), at(beta, i)))
+        .collect()
+}
+
+/// Layer normalization over the last axis with per-column `gamma` and `beta`.
+#[must_use]
+pub fn layer_norm(input: &[f32], gamma: &[f32], beta: &[f32], cols: usize, eps: f32) -> Vec<f32> {
+    rows(input, cols)
+        .flat_map(|row| layer_norm_row(row, gamma, beta, eps))
+        .collect()
+}
+
+/// RMS normalization of one row.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn rms_norm_row(row: &[f32], gamma: &[f32], eps: f32) -> Vec<f32> {
+    let mean_square = row.iter().map(|&x| x * x).sum::<f32>() / row.len() as f32;
+    let inv = 1.0 / (mean_square + eps).sqrt();
+    row.iter()
+        .enumerate()
+        .map(|(i, &x)| x * inv * at(gamma, i))
+        .collect()
+}
+
+/// RMS normalization over the last axis with per-column `gamma`.
+#[must_use]
+pub fn rms_norm(input: &[f32], gamma: &[f32], cols: usize, eps: f32) -> Vec<f32> {
+    rows(input, cols)
+        .flat_map(|row| rms_norm_row(row, gamma, eps))
+        .collect()
+}
+
+/// GELU, tanh approximation.
+#[must_use]
+pub fn gelu(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1.0 + (SQRT_2_OVER_PI * (0.044_715 * x * x).mul_add(x, x)).tanh())
+}
+
+/// SiLU (swish): `x * sigmoid(x)`.
+#[must_use]
+pub fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+/// Shape produced by broadcasting `lhs` against `rhs` (NumPy rules).
+///
+/// Returns `None` if the shapes are incompatible.
+#[must_use]
+pub fn broadcast_shape(lhs: &[usize], rhs: &[usize]) -> Option<Vec<usize>> {
+    let rank = lhs.len().max(rhs.len());
+    let dim =
+        |shape: &[usize], i: usize| (i + shape.len()).checked_sub(rank).map_or(1, |j| shape[j]);
+    (0..rank)
+        .map(|i| match (dim(lhs, i), dim(rhs, i)) {
+            (a, b) if a == b => Some(a),
+            (1, b) => Some(b),
+            (a, 1) => Some(a),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Row-major strides of `shape` broadcast to `out`, with 0 for broadcast
+/// dimensions. `shape` must broadcast to `out`.
+pub(crate) fn broadcast_strides(shape: &[usize], out: &[usize]) -> Vec<usize> {
+    let pad = out.len() - shape.len();
+    let mut strides = vec![0; out.len()];
+    let mut stride = 1;
+    for (i, &dim) in shape.iter().enumerate().rev() {
+        if dim != 1 {
+            strides[pad + i] = stride;
+        }
+        stride *= dim;
+    }
+    strides
+}
+
+/// Elementwise binary op with broadcasting.
+///
+/// Returns the output and its shape.
+///
+/// # Errors
+///
+/// Returns an error if the shapes do not broadcast or an input is shorter
+/// than its shape.
+pub fn binary(
+    op: BinaryOp,
+    lhs: &[f32],
+    lhs_shape: &[usize],
+    rhs: &[f32],
+    rhs_shape: &[usize],
+) -> Result<(Vec<f32>, Vec<usize>)> {
+    let out = broadcast_shape(lhs_shape, rhs_shape).ok_or_else(|| {
+        Error::invalid_input(format!(
+            "shapes {lhs_shape:?} and {rhs_shape:?} do not broadcast"
+        ))
+    })?;
+    if lhs.len() < lhs_shape.iter().product() || rhs.len() < rhs_shape.iter().product() {
+        return Err(Error::invalid_input("input shorter than its shape"));
+    }
+    let lhs_strides = broadcast_strides(lhs_shape, &out);
+    let rhs_strides = broadcast_strides(rhs_shape, &out);
+
+    let count: usize = out.iter().product();
+    let mut result = Vec::with_capacity(count);
+    let mut coord = vec![0usize; out.len()];
+    for _ in 0..count {
+        let offset = |strides: &[usize]| coord.iter().zip(strides).map(|(c, s)| c * s).sum();
+        let l: usize = offset(&lhs_strides);
+        let r: usize = offset(&rhs_strides);
+        result.push(op.apply(lhs[l], rhs[r]));
+        for (c, &dim) in coord.iter_mut().zip(&out).rev() {
+            *c += 1;
+            if *c < dim {
+                break;
+            }
+            *c = 0;
+        }
+    }
+    Ok((result, out))
+}
+
+/// Reduce one row.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn reduce_row(op: ReduceOp, row: &[f32]) -> f32 {
+    match op {
+        ReduceOp::Sum => row.iter().sum(),
+        ReduceOp::Max => row.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        ReduceOp::Mean => row.iter().sum::<f32>() / row.len() as f32,
+    }
+}
+
+/// Reduce over the last axis of a row-major matrix with `cols` columns.
+#[must_use]
+pub fn reduce(op: ReduceOp, input: &[f32], cols: usize) -> Vec<f32> {
+    rows(input, cols).map(|row| reduce_row(op, row)).collect()
+}
+
+/// Rotate one interleaved pair `(x0, x1)` at `position`.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn rope_pair(x0: f32, x1: f32, position: usize, pair: usize, shape: &RopeShape) -> (f32, f32) {
+    let exponent = -2.0 * pair as f32 / shape.head_dim as f32;
+    let angle = position as f32 * shape.theta_base.powf(exponent);
+    let (sin, cos) = angle.sin_cos();
+    (x0.mul_add(cos, -(x1 * sin)), x0.mul_add(sin, x1 * cos))
+}
+
+/// Rotary position embedding over `[seq_len, heads, head_dim]`, rotating
+/// interleaved pairs.
+#[must_use]
+pub fn rope(input: &[f32], shape: &RopeShape) -> Vec<f32> {
+    let mut out = input.to_vec();
+    let pairs = shape.head_dim / 2;
+    for token in 0..shape.seq_len {
+        for head in 0..shape.heads {
+            for pair in 0..pairs {
+                let base = (token * shape.heads + head) * shape.head_dim + 2 * pair;
+                if base + 1 >= out.len() {
+                    continue;
+                }
+                let position = shape.position_offset + token;
+                let (y0, y1) = rope_pair(out[base], out[base + 1], position, pair, shape);
+                out[base] = y0;
+                out[base + 1] = y1;
+            }
+        }
+    }
+    out
+}
+
+fn rows(input: &[f32], cols: usize) -> std::slice::ChunksExact<'_, f32> {
+    input.chunks_exact(cols.max(1))
+}
+
+fn at(values: &[f32], i: usize) -> f32 {
+    values.get(i).copied().unwrap_or(0.0)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gemm_transposes() {
+        // A = [[1, 2], [3, 4]], B = [[5, 6], [7, 8]]
+        let shape = GemmShape::new(2, 2, 2);
+        assert_eq!(
+            gemm(&[1.0, 2.0, 3.0, 4.0], &[5.0, 6.0, 7.0, 8.0], &shape),
+            vec![19.0, 22.0, 43.0, 50.0]
+        );
+        let at = [1.0, 3.0, 2.0, 4.0];
+        let bt = [5.0, 7.0, 6.0, 8.0];
+        let shape = shape.with_transpose_a(true).with_transpose_b(true);
+        assert_eq!(gemm(&at, &bt, &shape), vec![19.0, 22.0, 43.0, 50.0]);
+    }
+
+    #[test]
+    fn test_softmax_sums_to_one() {
+        let out = softmax(&[1.0, 2.0, 3.0, 1000.0, 1000.0, 1000.0], 3);
+        assert!((out[..3].iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(out[3..].iter().all(|&p| (p - 1.0 / 3.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_norms() {
+        let out = layer_norm(&[1.0, 3.0], &[1.0, 1.0], &[0.0, 0.5], 2, 0.0);
+        assert_eq!(out, vec![-1.0, 1.5]);
+        let out = rms_norm(&[3.0, 4.0], &[1.0, 2.0], 2, 0.0);
+        let rms = 12.5f32.sqrt();
+        assert!((out[0] - 3.0 / rms).abs() < 1e-6);
+        assert!((out[1] - 8.0 / rms).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_activations() {
+        assert!(gelu(0.0).abs() < f32::EPSILON);
+        assert!((gelu(1.0) - 0.841_192).abs() < 1e-5);
+        assert!(silu(0.0).abs() < f32::EPSILON);
+        assert!((silu(1.0) - 0.731_058_6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_broadcast_shape() {
+        assert_eq!(broadcast_shape(&[2, 3], &[3]), Some(vec![2, 3]));
+        assert_eq!(broadcast_shape(&[2, 1], &[1, 4]), Some(vec![2, 4]));
+        assert_eq!(broadcast_shape(&[], &[5]), Some(vec![5]));
+        assert_eq!(broadcast_shape(&[2, 3], &[2]), None);
+        assert_eq!(broadcast_strides(&[3], &[2, 3]), vec![0, 1]);
+        assert_eq!(broadcast_strides(&[2, 1], &[2, 4]), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_binary_broadcast() {
+        let (out, shape) = binary(
+            BinaryOp::Add,
+            &[1.0, 2.0],
+            &[2, 1],
+            &[10.0, 20.0, 30.0],
+            &[3],
+        )
+        .unwrap();
+        assert_eq!(shape, vec![2, 3]);
+        assert_eq!(out, vec![11.0, 21.0, 31.0, 12.0, 22.0, 32.0]);
+        assert!(binary(BinaryOp::Add, &[1.0], &[2], &[1.0], &[3]).is_err());
+    }
+
+    #[test]
+    fn test_reduce() {
+        let input = [1.0, 5.0, 3.0, -1.0, -2.0, -3.0];
+        assert_eq!(reduce(ReduceOp::Sum, &input, 3), vec![9.0, -6.0]);
+        assert_eq!(reduce(ReduceOp::Max, &input, 3), vec![5.0, -1.0]);
+        assert_eq!(reduce(ReduceOp::Mean, &input, 3), vec![3.0, -2.0]);
+    }
+
+    #[test]
+    fn test_rope_position_zero_is_identity() {
+        let input = [1.0, 2.0, 3.0, 4.0];
+        let shape = RopeShape::new(1, 1, 4);
+        assert_eq!(rope(&input, &shape), input.to_vec());
+
+        let shape = RopeShape::new(1, 1, 2).with_position_offset(1);
+        let out = rope(&[1.0, 0.0], &shape);
+        assert!((out[0] - 1.0f32.cos()).abs() < 1e-6);
+        assert!((out[1] - 1.0f32.sin()).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,185 @@
+//! CPU backend implementations of the built-in kernels.
+//!
+//! Each kernel mirrors its MSL counterpart in `kernels.metal`: the same
+//! buffer bindings, the same params words, and the same thread mapping.
+//! The arithmetic is delegated to [`cpu_reference`](super::cpu_reference).
+
+use super::cpu_reference;
+use super::{BinaryOp, GemmShape, ReduceOp, RopeShape};
+use crate::metal::{CpuKernel, ThreadContext};
+
+fn param(ctx: &ThreadContext<'_>, binding: usize, index: usize) -> usize {
+    ctx.read_u32(binding, index) as usize
+}
+
+fn read_row(ctx: &ThreadContext<'_>, binding: usize, start: usize, len: usize) -> Vec<f32> {
+    (start..start + len)
+        .map(|i| ctx.read_f32(binding, i))
+        .collect()
+}
+
+fn write_row(ctx: &ThreadContext<'_>, binding: usize, start: usize, row: &[f32]) {
+    for (i, &value) in row.iter().enumerate() {
+        ctx.write_f32(binding, start + i, value);
+    }
+}
+
+/// `a [[buffer(0)]]`, `b [[buffer(1)]]`, `c [[buffer(2)]]`,
+/// params `[m, n, k, transpose_a, transpose_b]`; x = column, y = row.
+pub(super) fn gemm() -> CpuKernel {
+    CpuKernel::new(|ctx| {
+        let (col, row, _) = ctx.thread_position();
+        let (col, row) = (col as usize, row as usize);
+        let shape = GemmShape {
+            m: param(ctx, 3, 0),
+            n: param(ctx, 3, 1),
+            k: param(ctx, 3, 2),
+            transpose_a: param(ctx, 3, 3) != 0,
+            transpose_b: param(ctx, 3, 4) != 0,
+        };
+        if row >= shape.m || col >= shape.n {
+            return;
+        }
+        let value = cpu_reference::gemm_element(
+            |i| ctx.read_f32(0, i),
+            |i| ctx.read_f32(1, i),
+            &shape,
+            row,
+            col,
+        );
+        ctx.write_f32(2, row * shape.n + col, value);
+    })
+}
+
+/// `input [[buffer(0)]]`, `output [[buffer(1)]]`, params `[rows, cols]`;
+/// one thread per row.
+pub(super) fn softmax() -> CpuKernel {
+    CpuKernel::new(|ctx| {
+        let row = ctx.thread_position().0 as usize;
+        let (rows, cols) = (param(ctx, 2, 0), param(ctx, 2, 1));
+        if row >= rows {
+            return;
+        }
+        let input = read_row(ctx, 0, row * cols, cols);
+        write_row(ctx, 1, row * cols, &cpu_reference::softmax_row(&input));
+    })
+}
+
+/// `input`, `gamma`, `beta`, `output`, params `[rows, cols, eps]`;
+/// one thread per row.
+pub(super) fn layer_norm() -> CpuKernel {
+    CpuKernel::new(|ctx| {
+        let row = ctx.thread_position().0 as usize;
+        let (rows, cols) = (param(ctx, 4, 0), param(ctx, 4, 1));
+        let eps = ctx.read_f32(4, 2);
+        if row >= rows {
+            return;
+        }
+        let input = read_row(ctx, 0, row * cols, cols);
+        let gamma = read_row(ctx, 1, 0, cols);
+        let beta = read_row(ctx, 2, 0, cols);
+        let out = cpu_reference::layer_norm_row(&input, &gamma, &beta, eps);
+        write_row(ctx, 3, row * cols, &out);
+    })
+}
+
+/// `input`, `gamma`, `output`, params `[rows, cols, eps]`; one thread per row.
+pub(super) fn rms_norm() -> CpuKernel {
+    CpuKernel::new(|ctx| {
+        let row = ctx.thread_position().0 as usize;
+        let (rows, cols) = (param(ctx, 3, 0), param(ctx, 3, 1));
+        let eps = ctx.read_f32(3, 2);
+        if row >= rows {
+            return;
+        }
+        let input = read_row(ctx, 0, row * cols, cols);
+        let gamma = read_row(ctx, 1, 0, cols);
+        write_row(
+            ctx,
+            2,
+            row * cols,
+            &cpu_reference::rms_norm_row(&input, &gamma, eps),
+        );
+    })
+}
+
+/// `input`, `output`, params `[count]`; one thread per element.
+pub(super) fn unary(f: fn(f32) -> f32) -> CpuKernel {
+    CpuKernel::new(move |ctx| {
+        let i = ctx.thread_position().0 as usize;
+        if i < param(ctx, 2, 0) {
+            ctx.write_f32(1, i, f(ctx.read_f32(0, i)));
+        }
+    })
+}
+
+/// `lhs`, `rhs`, `output`, params `[op, out_shape[4], lhs_strides[4],
+/// rhs_strides[4]]`; one thread per output element.
+pub(super) fn binary_broadcast() -> CpuKernel {
+    CpuKernel::new(|ctx| {
+        let Some(op) = BinaryOp::from_code(ctx.read_u32(3, 0)) else {
+            return;
+        };
+        let mut index = ctx.thread_position().0 as usize;
+        let out_index = index;
+        let (mut lhs, mut rhs) = (0, 0);
+        for dim in (0..4).rev() {
+            let extent = param(ctx, 3, 1 + dim).max(1);
+            let coord = index % extent;
+            index /= extent;
+            lhs += coord * param(ctx, 3, 5 + dim);
+            rhs += coord * param(ctx, 3, 9 + dim);
+        }
+        if index != 0 {
+            return;
+        }
+        let value = op.apply(ctx.read_f32(0, lhs), ctx.read_f32(1, rhs));
+        ctx.write_f32(2, out_index, value);
+    })
+}
+
+/// `input`, `output`, params `[op, rows, cols]`; one thread per row.
+pub(super) fn reduce_rows() -> CpuKernel {
+    CpuKernel::new(|ctx| {
+        let row = ctx.thread_position().0 as usize;
+        let Some(op) = ReduceOp::from_code(ctx.read_u32(2, 0)) else {
+            return;
+        };
+        let (rows, cols) = (param(ctx, 2, 1), param(ctx, 2, 2));
+        if row >= rows {
+            return;
+        }
+        let input = read_row(ctx, 0, row * cols, cols);
+        ctx.write_f32(1, row, cpu_reference::reduce_row(op, &input));
+    })
+}
+
+/// `x [[buffer(0)]]` rotated in place, params `[seq_len, heads, head_dim,
+/// position_offset, theta_base]`; x = pair, y = head, z = token.
+pub(super) fn rope() -> CpuKernel {
+    CpuKernel::new(|ctx| {
+        let (pair, head, token) = ctx.thread_position();
+        let (pair, head, token) = (pair as usize, head as usize, token as usize);
+        let shape = RopeShape {
+            seq_len: param(ctx, 1, 0),
+            heads: param(ctx, 1, 1),
+            head_dim: param(ctx, 1, 2),
+            position_offset: param(ctx, 1, 3),
+            theta_base: ctx.read_f32(1, 4),
+        };
+        if token >= shape.seq_len || head >= shape.heads || 2 * pair + 1 >= shape.head_dim {
+            return;
+        }
+        let base = (token * shape.heads + head) * shape.head_dim + 2 * pair;
+        let position = shape.position_offset + token;
+        let (y0, y1) = cpu_reference::rope_pair(
+            ctx.read_f32(0, base),
+            ctx.read_f32(0, base + 1),
+            position,
+            pair,
+            &shape,
+        );
+        ctx.write_f32(0, base, y0);
+        ctx.write_f32(0, base + 1, y1);
+    })
+}
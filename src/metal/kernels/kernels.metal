@@ -0,0 +1,212 @@
+// Built-in ML kernels for manzana::metal::kernels.
+//
+// Every kernel takes its dimensions as a `constant uint*` params buffer
+// bound after its data buffers. Float parameters are passed as raw bits.
+// Keep in sync with host.rs, which mirrors these kernels on the CPU.
+
+#include <metal_stdlib>
+using namespace metal;
+
+kernel void gemm(
+    device const float* a [[buffer(0)]],
+    device const float* b [[buffer(1)]],
+    device float* c [[buffer(2)]],
+    constant uint* params [[buffer(3)]],
+    uint2 gid [[thread_position_in_grid]])
+{
+    const uint m = params[0], n = params[1], k = params[2];
+    const bool transpose_a = params[3] != 0, transpose_b = params[4] != 0;
+    const uint col = gid.x, row = gid.y;
+    if (row >= m || col >= n) {
+        return;
+    }
+    float acc = 0.0f;
+    for (uint p = 0; p < k; ++p) {
+        const float av = transpose_a ? a[p * m + row] : a[row * k + p];
+        const float bv = transpose_b ? b[col * k + p] : b[p * n + col];
+        acc += av * bv;
+    }
+    c[row * n + col] = acc;
+}
+
+kernel void softmax(
+    device const float* input [[buffer(0)]],
+    device float* output [[buffer(1)]],
+    constant uint* params [[buffer(2)]],
+    uint row [[thread_position_in_grid]])
+{
+    const uint rows = params[0], cols = params[1];
+    if (row >= rows) {
+        return;
+    }
+    device const float* x = input + row * cols;
+    device float* y = output + row * cols;
+    float max_value = -INFINITY;
+    for (uint i = 0; i < cols; ++i) {
+        max_value = max(max_value, x[i]);
+    }
+    float sum = 0.0f;
+    for (uint i = 0; i < cols; ++i) {
+        y[i] = exp(x[i] - max_value);
+        sum += y[i];
+    }
+    for (uint i = 0; i < cols; ++i) {
+        y[i] /= sum;
+    }
+}
+
+kernel void layer_norm(
+    device const float* input [[buffer(0)]],
+    device const float* gamma [[buffer(1)]],
+    device const float* beta [[buffer(2)]],
+    device float* output [[buffer(3)]],
+    constant uint* params [[buffer(4)]],
+    uint row [[thread_position_in_grid]])
+{
+    const uint rows = params[0], cols = params[1];
+    const float eps = as_type<float>(params[2]);
+    if (row >= rows) {
+        return;
+    }
+    device const float* x = input + row * cols;
+    float mean = 0.0f;
+    for (uint i = 0; i < cols; ++i) {
+        mean += x[i];
+    }
+    mean /= float(cols);
+    float var = 0.0f;
+    for (uint i = 0; i < cols; ++i) {
+        var += (x[i] - mean) * (x[i] - mean);
+    }
+    var /= float(cols);
+    const float inv = 1.0f / sqrt(var + eps);
+    for (uint i = 0; i < cols; ++i) {
+        output[row * cols + i] = fma((x[i] - mean) * inv, gamma[i], beta[i]);
+    }
+}
+
+kernel void rms_norm(
+    device const float* input [[buffer(0)]],
+    device const float* gamma [[buffer(1)]],
+    device float* output [[buffer(2)]],
+    constant uint* params [[buffer(3)]],
+    uint row [[thread_position_in_grid]])
+{
+    const uint rows = params[0], cols = params[1];
+    const float eps = as_type<float>(params[2]);
+    if (row >= rows) {
+        return;
+    }
+    device const float* x = input + row * cols;
+    float mean_square = 0.0f;
+    for (uint i = 0; i < cols; ++i) {
+        mean_square += x[i] * x[i];
+    }
+    mean_square /= float(cols);
+    const float inv = 1.0f / sqrt(mean_square + eps);
+    for (uint i = 0; i < cols; ++i) {
+        output[row * cols + i] = x[i] * inv * gamma[i];
+    }
+}
+
+kernel void gelu(
+    device const float* input [[buffer(0)]],
+    device float* output [[buffer(1)]],
+    constant uint* params [[buffer(2)]],
+    uint i [[thread_position_in_grid]])
+{
+    if (i >= params[0]) {
+        return;
+    }
+    const float x = input[i];
+    output[i] = 0.5f * x * (1.0f + tanh(0.7978846f * fma(0.044715f * x * x, x, x)));
+}
+
+kernel void silu(
+    device const float* input [[buffer(0)]],
+    device float* output [[buffer(1)]],
+    constant uint* params [[buffer(2)]],
+    uint i [[thread_position_in_grid]])
+{
+    if (i >= params[0]) {
+        return;
+    }
+    const float x = input[i];
+    output[i] = x / (1.0f + exp(-x));
+}
+
+// params: [op, out_shape[4], lhs_strides[4], rhs_strides[4]]
+// op: 0 add, 1 sub, 2 mul, 3 div, 4 max, 5 min
+kernel void binary_broadcast(
+    device const float* lhs [[buffer(0)]],
+    device const float* rhs [[buffer(1)]],
+    device float* output [[buffer(2)]],
+    constant uint* params [[buffer(3)]],
+    uint gid [[thread_position_in_grid]])
+{
+    uint index = gid;
+    uint l = 0, r = 0;
+    for (int dim = 3; dim >= 0; --dim) {
+        const uint extent = max(params[1 + dim], 1u);
+        const uint coord = index % extent;
+        index /= extent;
+        l += coord * params[5 + dim];
+        r += coord * params[9 + dim];
+    }
+    if (index != 0) {
+        return;
+    }
+    const float a = lhs[l], b = rhs[r];
+    float value;
+    switch (params[0]) {
+        case 0: value = a + b; break;
+        case 1: value = a - b; break;
+        case 2: value = a * b; break;
+        case 3: value = a / b; break;
+        case 4: value = max(a, b); break;
+        case 5: value = min(a, b); break;
+        default: return;
+    }
+    output[gid] = value;
+}
+
+// params: [op, rows, cols]; op: 0 sum, 1 max, 2 mean
+kernel void reduce_rows(
+    device const float* input [[buffer(0)]],
+    device float* output [[buffer(1)]],
+    constant uint* params [[buffer(2)]],
+    uint row [[thread_position_in_grid]])
+{
+    const uint op = params[0], rows = params[1], cols = params[2];
+    if (row >= rows || op > 2) {
+        return;
+    }
+    device const float* x = input + row * cols;
+    float acc = op == 1 ? -INFINITY : 0.0f;
+    for (uint i = 0; i < cols; ++i) {
+        acc = op == 1 ? max(acc, x[i]) : acc + x[i];
+    }
+    output[row] = op == 2 ? acc / float(cols) : acc;
+}
+
+// params: [seq_len, heads, head_dim, position_offset, theta_base]
+kernel void rope(
+    device float* x [[buffer(0)]],
+    constant uint* params [[buffer(1)]],
+    uint3 gid [[thread_position_in_grid]])
+{
+    const uint seq_len = params[0], heads = params[1], head_dim = params[2];
+    const uint position_offset = params[3];
+    const float theta_base = as_type<float>(params[4]);
+    const uint pair = gid.x, head = gid.y, token = gid.z;
+    if (token >= seq_len || head >= heads || 2 * pair + 1 >= head_dim) {
+        return;
+    }
+    const uint base = (token * heads + head) * head_dim + 2 * pair;
+    const float exponent = -2.0f * float(pair) / float(head_dim);
+    const float angle = float(position_offset + token) * pow(theta_base, exponent);
+    const float s = sin(angle), c = cos(angle);
+    const float x0 = x[base], x1 = x[base + 1];
+    x[base] = fma(x0, c, -(x1 * s));
+    x[base + 1] = fma(x0, s, x1 * c);
+}
@@ -0,0 +1,1076 @@
+//! Built-in ML compute kernels.
+//!
+//! [`KernelLibrary`] provides tested kernels with typed entry points over
+//! [`MetalBuffer`]s of `f32`:
+//!
+//! - GEMM with optional transposes ([`KernelLibrary::gemm`])
+//! - Softmax, layer norm and RMS norm over the last axis
+//! - GELU (tanh approximation) and SiLU activations
+//! - Elementwise binary ops with NumPy broadcasting, up to rank 4
+//! - Sum, max and mean reductions over the last axis
+//! - Rotary position embedding with interleaved pairs
+//!
+//! Every kernel has a CPU reference implementation in [`cpu_reference`], and
+//! a CPU backend implementation, so results can be checked on any
+//! platform with [`MetalCompute::cpu`].
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::kernels::{cpu_reference, GemmShape, KernelLibrary};
+//! use manzana::metal::MetalCompute;
+//!
+//! let compute = MetalCompute::cpu();
+//! let kernels = KernelLibrary::new(&compute);
+//!
+//! let shape = GemmShape::new(2, 2, 2);
+//! let a = compute.allocate_buffer(16)?;
+//! let b = compute.allocate_buffer(16)?;
+//! let c = compute.allocate_buffer(16)?;
+//! a.write(0, &[1.0f32, 2.0, 3.0, 4.0])?;
+//! b.write(0, &[5.0f32, 6.0, 7.0, 8.0])?;
+//!
+//! kernels.gemm(&a, &b, &c, &shape)?;
+//! assert_eq!(
+//!     c.read::<f32>()?,
+//!     cpu_reference::gemm(&[1.0, 2.0, 3.0, 4.0], &[5.0, 6.0, 7.0, 8.0], &shape)
+//! );
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+pub mod cpu_reference;
+mod host;
+
+use super::{CompiledShader, CpuKernel, MetalBuffer, MetalCompute, Size3};
+use crate::error::{Error, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// MSL source for every built-in kernel.
+pub const SOURCE: &str = include_str!("kernels.metal");
+
+/// Maximum rank supported by [`KernelLibrary::binary`].
+pub const MAX_BROADCAST_RANK: usize = 4;
+
+/// Default epsilon for [`NormShape`].
+pub const DEFAULT_NORM_EPS: f32 = 1e-5;
+
+/// Default base frequency for [`RopeShape`].
+pub const DEFAULT_ROPE_THETA: f32 = 10_000.0;
+
+/// Words in the shared params buffer: the binary kernel's opcode plus
+/// shape and two stride vectors is the largest parameter block.
+const PARAMS_WORDS: usize = 1 + 3 * MAX_BROADCAST_RANK;
+
+/// Dimensions of `C = op(A) × op(B)`.
+///
+/// `op(A)` is `m × k`, `op(B)` is `k × n`, and `C` is row-major `m × n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GemmShape {
+    /// Rows of `op(A)` and `C`.
+    pub m: usize,
+    /// Columns of `op(B)` and `C`.
+    pub n: usize,
+    /// Shared inner dimension.
+    pub k: usize,
+    /// `A` is stored as `k × m`.
+    pub transpose_a: bool,
+    /// `B` is stored as `n × k`.
+    pub transpose_b: bool,
+}
+
+impl GemmShape {
+    /// Create a shape without transposes.
+    #[must_use]
+    pub const fn new(m: usize, n: usize, k: usize) -> Self {
+        Self {
+            m,
+            n,
+            k,
+            transpose_a: false,
+            transpose_b: false,
+        }
+    }
+
+    /// Read `A` transposed.
+    #[must_use]
+    pub const fn with_transpose_a(mut self, transpose: bool) -> Self {
+        self.transpose_a = transpose;
+        self
+    }
+
+    /// Read `B` transposed.
+    #[must_use]
+    pub const fn with_transpose_b(mut self, transpose: bool) -> Self {
+        self.transpose_b = transpose;
+        self
+    }
+}
+
+/// Dimensions of a row-wise normalization.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormShape {
+    /// Number of rows.
+    pub rows: usize,
+    /// Elements per row (the normalized axis).
+    pub cols: usize,
+    /// Added to the variance before the square root.
+    pub eps: f32,
+}
+
+impl NormShape {
+    /// Create a shape with [`DEFAULT_NORM_EPS`].
+    #[must_use]
+    pub const fn new(rows: usize, cols: usize) -> Self {
+        Self {
+            rows,
+            cols,
+            eps: DEFAULT_NORM_EPS,
+        }
+    }
+
+    /// Set the epsilon.
+    #[must_use]
+    pub const fn with_eps(mut self, eps: f32) -> Self {
+        self.eps = eps;
+        self
+    }
+}
+
+/// Layout of a rotary position embedding over `[seq_len, heads, head_dim]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RopeShape {
+    /// Number of tokens.
+    pub seq_len: usize,
+    /// Number of heads per token.
+    pub heads: usize,
+    /// Elements per head; must be even.
+    pub head_dim: usize,
+    /// Position of the first token (for KV-cached decoding).
+    pub position_offset: usize,
+    /// Base of the frequency schedule.
+    pub theta_base: f32,
+}
+
+impl RopeShape {
+    /// Create a shape starting at position 0 with [`DEFAULT_ROPE_THETA`].
+    #[must_use]
+    pub const fn new(seq_len: usize, heads: usize, head_dim: usize) -> Self {
+        Self {
+            seq_len,
+            heads,
+            head_dim,
+            position_offset: 0,
+            theta_base: DEFAULT_ROPE_THETA,
+        }
+    }
+
+    /// Set the position of the first token.
+    #[must_use]
+    pub const fn with_position_offset(mut self, offset: usize) -> Self {
+        self.position_offset = offset;
+        self
+    }
+
+    /// Set the frequency base.
+    #[must_use]
+    pub const fn with_theta_base(mut self, theta: f32) -> Self {
+        self.theta_base = theta;
+        self
+    }
+}
+
+/// Elementwise binary operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    /// `a + b`
+    Add,
+    /// `a - b`
+    Sub,
+    /// `a * b`
+    Mul,
+    /// `a / b`
+    Div,
+    /// `max(a, b)`
+    Max,
+    /// `min(a, b)`
+    Min,
+}
+
+impl BinaryOp {
+    /// All operations.
+    pub const ALL: [Self; 6] = [
+        Self::Add,
+        Self::Sub,
+        Self::Mul,
+        Self::Div,
+        Self::Max,
+        Self::Min,
+    ];
+
+    /// Apply the operation.
+    #[must_use]
+    pub fn apply(self, a: f32, b: f32) -> f32 {
+        match self {
+            Self::Add => a + b,
+            Self::Sub => a - b,
+            Self::Mul => a * b,
+            Self::Div => a / b,
+            Self::Max => a.max(b),
+            Self::Min => a.min(b),
+        }
+    }
+
+    /// Opcode passed to the kernel.
+    const fn code(self) -> u32 {
+        self as u32
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|op| op.code() == code)
+    }
+}
+
+/// Reduction over the last axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReduceOp {
+    /// Sum of the row.
+    Sum,
+    /// Largest element of the row.
+    Max,
+    /// Arithmetic mean of the row.
+    Mean,
+}
+
+impl ReduceOp {
+    /// All reductions.
+    pub const ALL: [Self; 3] = [Self::Sum, Self::Max, Self::Mean];
+
+    /// Opcode passed to the kernel.
+    const fn code(self) -> u32 {
+        self as u32
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|op| op.code() == code)
+    }
+}
+
+/// Built-in kernel entry points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kernel {
+    Gemm,
+    Softmax,
+    LayerNorm,
+    RmsNorm,
+    Gelu,
+    Silu,
+    BinaryBroadcast,
+    ReduceRows,
+    Rope,
+}
+
+impl Kernel {
+    /// MSL function name in [`SOURCE`].
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Gemm => "gemm",
+            Self::Softmax => "softmax",
+            Self::LayerNorm => "layer_norm",
+            Self::RmsNorm => "rms_norm",
+            Self::Gelu => "gelu",
+            Self::Silu => "silu",
+            Self::BinaryBroadcast => "binary_broadcast",
+            Self::ReduceRows => "reduce_rows",
+            Self::Rope => "rope",
+        }
+    }
+
+    fn cpu_kernel(self) -> CpuKernel {
+        match self {
+            Self::Gemm => host::gemm(),
+            Self::Softmax => host::softmax(),
+            Self::LayerNorm => host::layer_norm(),
+            Self::RmsNorm => host::rms_norm(),
+            Self::Gelu => host::unary(cpu_reference::gelu),
+            Self::Silu => host::unary(cpu_reference::silu),
+            Self::BinaryBroadcast => host::binary_broadcast(),
+            Self::ReduceRows => host::reduce_rows(),
+            Self::Rope => host::rope(),
+        }
+    }
+}
+
+/// Compiled built-in kernels for one [`MetalCompute`] pipeline.
+///
+/// Pipelines are compiled on first use and cached; a cached pipeline that
+/// predates a device recovery is recompiled. Kernel parameters go through
+/// one params buffer that is reused across dispatches.
+pub struct KernelLibrary<'a> {
+    compute: &'a MetalCompute,
+    shaders: RefCell<HashMap<Kernel, CompiledShader>>,
+    params: RefCell<Option<MetalBuffer>>,
+}
+
+impl<'a> KernelLibrary<'a> {
+    /// Create a library for `compute`.
+    #[must_use]
+    pub fn new(compute: &'a MetalCompute) -> Self {
+        Self {
+            compute,
+            shaders: RefCell::new(HashMap::new()),
+            params: RefCell::new(None),
+        }
+    }
+
+    /// Get the pipeline the kernels run on.
+    #[must_use]
+    pub const fn compute(&self) -> &MetalCompute {
+        self.compute
+    }
+
+    /// Matrix multiply `c = op(a) × op(b)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, a buffer is too small, or
+    /// the dispatch fails.
+    pub fn gemm(
+        &self,
+        a: &MetalBuffer,
+        b: &MetalBuffer,
+        c: &MetalBuffer,
+        shape: &GemmShape,
+    ) -> Result<()> {
+        let GemmShape { m, n, k, .. } = *shape;
+        require_nonzero("gemm", &[m, n, k])?;
+        require_len("a", a, mul(m, k)?)?;
+        require_len("b", b, mul(k, n)?)?;
+        require_len("c", c, mul(m, n)?)?;
+        let params = [
+            word(m)?,
+            word(n)?,
+            word(k)?,
+            u32::from(shape.transpose_a),
+            u32::from(shape.transpose_b),
+        ];
+        self.run(Kernel::Gemm, &[a, b, c], &params, (word(n)?, word(m)?, 1))
+    }
+
+    /// Softmax over each row of a `rows × cols` matrix.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, a buffer is too small, or
+    /// the dispatch fails.
+    pub fn softmax(
+        &self,
+        input: &MetalBuffer,
+        output: &MetalBuffer,
+        rows: usize,
+        cols: usize,
+    ) -> Result<()> {
+        require_nonzero("softmax", &[rows, cols])?;
+        let count = mul(rows, cols)?;
+        require_len("input", input, count)?;
+        require_len("output", output, count)?;
+        let params = [word(rows)?, word(cols)?];
+        self.run(
+            Kernel::Softmax,
+            &[input, output],
+            &params,
+            (word(rows)?, 1, 1),
+        )
+    }
+
+    /// Layer normalization over each row, scaled by `gamma` and shifted by
+    /// `beta` (both `cols` long).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, a buffer is too small, or
+    /// the dispatch fails.
+    pub fn layer_norm(
+        &self,
+        input: &MetalBuffer,
+        gamma: &MetalBuffer,
+        beta: &MetalBuffer,
+        output: &MetalBuffer,
+        shape: &NormShape,
+    ) -> Result<()> {
+        let params = norm_params(shape)?;
+        let count = mul(shape.rows, shape.cols)?;
+        require_len("input", input, count)?;
+        require_len("gamma", gamma, shape.cols)?;
+        require_len("beta", beta, shape.cols)?;
+        require_len("output", output, count)?;
+        self.run(
+            Kernel::LayerNorm,
+            &[input, gamma, beta, output],
+            &params,
+            (params[0], 1, 1),
+        )
+    }
+
+    /// RMS normalization over each row, scaled by `gamma` (`cols` long).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, a buffer is too small, or
+    /// the dispatch fails.
+    pub fn rms_norm(
+        &self,
+        input: &MetalBuffer,
+        gamma: &MetalBuffer,
+        output: &MetalBuffer,
+        shape: &NormShape,
+    ) -> Result<()> {
+        let params = norm_params(shape)?;
+        let count = mul(shape.rows, shape.cols)?;
+        require_len("input", input, count)?;
+        require_len("gamma", gamma, shape.cols)?;
+        require_len("output", output, count)?;
+        self.run(
+            Kernel::RmsNorm,
+            &[input, gamma, output],
+            &params,
+            (params[0], 1, 1),
+        )
+    }
+
+    /// GELU (tanh approximation) over `count` elements.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `count` is zero, a buffer is too small, or the
+    /// dispatch fails.
+    pub fn gelu(&self, input: &MetalBuffer, output: &MetalBuffer, count: usize) -> Result<()> {
+        self.unary(Kernel::Gelu, input, output, count)
+    }
+
+    /// SiLU over `count` elements.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `count` is zero, a buffer is too small, or the
+    /// dispatch fails.
+    pub fn silu(&self, input: &MetalBuffer, output: &MetalBuffer, count: usize) -> Result<()> {
+        self.unary(Kernel::Silu, input, output, count)
+    }
+
+    /// Elementwise `output = op(lhs, rhs)` with NumPy broadcasting.
+    ///
+    /// Returns the output shape.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the shapes do not broadcast, the output rank
+    /// exceeds [`MAX_BROADCAST_RANK`], the output is empty, a buffer is too
+    /// small, or the dispatch fails.
+    pub fn binary(
+        &self,
+        op: BinaryOp,
+        lhs: &MetalBuffer,
+        lhs_shape: &[usize],
+        rhs: &MetalBuffer,
+        rhs_shape: &[usize],
+        output: &MetalBuffer,
+    ) -> Result<Vec<usize>> {
+        let out_shape = cpu_reference::broadcast_shape(lhs_shape, rhs_shape).ok_or_else(|| {
+            Error::invalid_input(format!(
+                "shapes {lhs_shape:?} and {rhs_shape:?} do not broadcast"
+            ))
+        })?;
+        if out_shape.len() > MAX_BROADCAST_RANK {
+            return Err(Error::invalid_input(format!(
+                "broadcast rank {} exceeds maximum {MAX_BROADCAST_RANK}",
+                out_shape.len()
+            )));
+        }
+        let count = product(&out_shape)?;
+        require_nonzero("binary", &[count])?;
+        require_len("lhs", lhs, product(lhs_shape)?)?;
+        require_len("rhs", rhs, product(rhs_shape)?)?;
+        require_len("output", output, count)?;
+
+        let pad = |values: Vec<usize>, fill: usize| {
+            let mut padded = vec![fill; MAX_BROADCAST_RANK - values.len()];
+            padded.extend(values);
+            padded
+        };
+        let mut params = vec![op.code()];
+        for value in pad(out_shape.clone(), 1)
+            .into_iter()
+            .chain(pad(
+                cpu_reference::broadcast_strides(lhs_shape, &out_shape),
+                0,
+            ))
+            .chain(pad(
+                cpu_reference::broadcast_strides(rhs_shape, &out_shape),
+                0,
+            ))
+        {
+            params.push(word(value)?);
+        }
+        self.run(
+            Kernel::BinaryBroadcast,
+            &[lhs, rhs, output],
+            &params,
+            (word(count)?, 1, 1),
+        )?;
+        Ok(out_shape)
+    }
+
+    /// Reduce each row of a `rows × cols` matrix to one element.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, a buffer is too small, or
+    /// the dispatch fails.
+    pub fn reduce(
+        &self,
+        op: ReduceOp,
+        input: &MetalBuffer,
+        output: &MetalBuffer,
+        rows: usize,
+        cols: usize,
+    ) -> Result<()> {
+        require_nonzero("reduce", &[rows, cols])?;
+        require_len("input", input, mul(rows, cols)?)?;
+        require_len("output", output, rows)?;
+        let params = [op.code(), word(rows)?, word(cols)?];
+        self.run(
+            Kernel::ReduceRows,
+            &[input, output],
+            &params,
+            (word(rows)?, 1, 1),
+        )
+    }
+
+    /// Apply rotary position embedding to `x` in place.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, `head_dim` is odd, the
+    /// buffer is too small, or the dispatch fails.
+    pub fn rope(&self, x: &MetalBuffer, shape: &RopeShape) -> Result<()> {
+        require_nonzero("rope", &[shape.seq_len, shape.heads, shape.head_dim])?;
+        if shape.head_dim % 2 != 0 {
+            return Err(Error::invalid_input(format!(
+                "rope head_dim {} must be even",
+                shape.head_dim
+            )));
+        }
+        require_len(
+            "x",
+            x,
+            mul(mul(shape.seq_len, shape.heads)?, shape.head_dim)?,
+        )?;
+        let params = [
+            word(shape.seq_len)?,
+            word(shape.heads)?,
+            word(shape.head_dim)?,
+            word(shape.position_offset)?,
+            shape.theta_base.to_bits(),
+        ];
+        let grid = (
+            word(shape.head_dim / 2)?,
+            word(shape.heads)?,
+            word(shape.seq_len)?,
+        );
+        self.run(Kernel::Rope, &[x], &params, grid)
+    }
+
+    fn unary(
+        &self,
+        kernel: Kernel,
+        input: &MetalBuffer,
+        output: &MetalBuffer,
+        count: usize,
+    ) -> Result<()> {
+        require_nonzero(kernel.name(), &[count])?;
+        require_len("input", input, count)?;
+        require_len("output", output, count)?;
+        let count = word(count)?;
+        self.run(kernel, &[input, output], &[count], (count, 1, 1))
+    }
+
+    /// Get the cached pipeline for `kernel`, compiling it if needed.
+    fn shader(&self, kernel: Kernel) -> Result<CompiledShader> {
+        if let Some(shader) = self.shaders.borrow().get(&kernel) {
+            if shader.is_valid() {
+                return Ok(shader.clone());
+            }
+        }
+        let shader = self
+            .compute
+            .compile_shader(SOURCE, kernel.name())?
+            .with_cpu_kernel(kernel.cpu_kernel());
+        self.shaders.borrow_mut().insert(kernel, shader.clone());
+        Ok(shader)
+    }
+
+    /// Bind `buffers` followed by the params buffer and dispatch `grid`.
+    ///
+    /// Dispatches complete before returning, so the params buffer can be
+    /// rewritten for the next one; it is reallocated after a recovery.
+    fn run(
+        &self,
+        kernel: Kernel,
+        buffers: &[&MetalBuffer],
+        params: &[u32],
+        grid: Size3,
+    ) -> Result<()> {
+        let shader = self.shader(kernel)?;
+        let mut slot = self.params.borrow_mut();
+        let params_buffer = match slot.take() {
+            Some(buffer) if buffer.is_valid() => buffer,
+            _ => {
+                let buffer = self
+                    .compute
+                    .allocate_buffer(PARAMS_WORDS * std::mem::size_of::<u32>())?;
+                buffer.write(0, &[0u32; PARAMS_WORDS])?;
+                buffer
+            }
+        };
+        params_buffer.write(0, params)?;
+
+        let mut bindings = buffers.to_vec();
+        bindings.push(&params_buffer);
+        let threadgroup = self.compute.threadgroup_size_for(&shader, grid);
+        let result = self.compute.dispatch(&shader, &bindings, grid, threadgroup);
+        *slot = Some(params_buffer);
+        result
+    }
+}
+
+impl std::fmt::Debug for KernelLibrary<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KernelLibrary")
+            .field("device", &self.compute.device_name())
+            .field("compiled", &self.shaders.borrow().len())
+            .finish()
+    }
+}
+
+fn norm_params(shape: &NormShape) -> Result<[u32; 3]> {
+    require_nonzero("norm", &[shape.rows, shape.cols])?;
+    Ok([word(shape.rows)?, word(shape.cols)?, shape.eps.to_bits()])
+}
+
+fn require_nonzero(kernel: &str, dims: &[usize]) -> Result<()> {
+    if dims.contains(&0) {
+        return Err(Error::invalid_input(format!(
+            "{kernel} dimensions must be non-zero"
+        )));
+    }
+    Ok(())
+}
+
+/// Check that `buffer` holds at least `elements` `f32`s.
+fn require_len(name: &str, buffer: &MetalBuffer, elements: usize) -> Result<()> {
+    let needed = elements
+        .checked_mul(std::mem::size_of::<f32>())
+        .ok_or_else(|| Error::invalid_input(format!("{name} size overflows")))?;
+    if buffer.len() < needed {
+        return Err(Error::invalid_input(format!(
+            "{name} buffer holds {} bytes, kernel needs {needed}",
+            buffer.len()
+        )));
+    }
+    Ok(())
+}
+
+fn mul(a: usize, b: usize) -> Result<usize> {
+    a.checked_mul(b)
+        .ok_or_else(|| Error::invalid_input(format!("dimension product {a} × {b} overflows")))
+}
+
+fn product(shape: &[usize]) -> Result<usize> {
+    shape.iter().try_fold(1, |acc, &dim| mul(acc, dim))
+}
+
+fn word(value: usize) -> Result<u32> {
+    u32::try_from(value)
+        .map_err(|_| Error::invalid_input(format!("dimension {value} exceeds u32 range")))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn upload(compute: &MetalCompute, data: &[f32]) -> MetalBuffer {
+        let buffer = compute.allocate_buffer(data.len() * 4).unwrap();
+        buffer.write(0, data).unwrap();
+        buffer
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn ramp(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (i as f32).mul_add(0.37, -2.0).sin())
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!((a - e).abs() <= 1e-5 * e.abs().max(1.0), "[{i}] {a} != {e}");
+        }
+    }
+
+    #[test]
+    fn test_gemm_matches_naive_loops() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let (rows, cols, inner) = (3, 4, 5);
+        let (lhs, rhs) = (ramp(rows * inner), ramp(inner * cols));
+        for (ta, tb) in [(false, false), (true, false), (false, true), (true, true)] {
+            let shape = GemmShape::new(rows, cols, inner)
+                .with_transpose_a(ta)
+                .with_transpose_b(tb);
+            let mut expected = vec![0.0f32; rows * cols];
+            for row in 0..rows {
+                for col in 0..cols {
+                    for p in 0..inner {
+                        let x = if ta {
+                            lhs[p * rows + row]
+                        } else {
+                            lhs[row * inner + p]
+                        };
+                        let y = if tb {
+                            rhs[col * inner + p]
+                        } else {
+                            rhs[p * cols + col]
+                        };
+                        expected[row * cols + col] += x * y;
+                    }
+                }
+            }
+            let out = compute.allocate_buffer(rows * cols * 4).unwrap();
+            kernels
+                .gemm(
+                    &upload(&compute, &lhs),
+                    &upload(&compute, &rhs),
+                    &out,
+                    &shape,
+                )
+                .unwrap();
+            assert_close(&out.read::<f32>().unwrap(), &expected);
+        }
+
+        let out = compute.allocate_buffer(16).unwrap();
+        let (lhs, rhs) = (
+            upload(&compute, &[1.0, 2.0, 3.0, 4.0]),
+            upload(&compute, &[5.0, 6.0, 7.0, 8.0]),
+        );
+        kernels
+            .gemm(&lhs, &rhs, &out, &GemmShape::new(2, 2, 2))
+            .unwrap();
+        assert_eq!(out.read::<f32>().unwrap(), [19.0, 22.0, 43.0, 50.0]);
+    }
+
+    #[test]
+    fn test_softmax_rows_are_distributions() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let mut data = ramp(12);
+        data[8..].copy_from_slice(&[0.0, 2.0_f32.ln(), 0.0, 2.0_f32.ln()]);
+        let output = compute.allocate_buffer(48).unwrap();
+        kernels
+            .softmax(&upload(&compute, &data), &output, 3, 4)
+            .unwrap();
+        let output = output.read::<f32>().unwrap();
+        for (row, input) in output.chunks(4).zip(data.chunks(4)) {
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+            assert!(row.iter().all(|&p| p > 0.0));
+            for i in 0..4 {
+                for j in 0..4 {
+                    assert_eq!(input[i] < input[j], row[i] < row[j]);
+                }
+            }
+        }
+        assert_close(&output[8..], &[1.0 / 6.0, 2.0 / 6.0, 1.0 / 6.0, 2.0 / 6.0]);
+    }
+
+    #[test]
+    fn test_norms_normalize_rows() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let data = ramp(12);
+        let input = upload(&compute, &data);
+        let ones = upload(&compute, &[1.0; 4]);
+        let zeros = upload(&compute, &[0.0; 4]);
+        let output = compute.allocate_buffer(48).unwrap();
+        let shape = NormShape::new(3, 4).with_eps(0.0);
+
+        kernels.rms_norm(&input, &ones, &output, &shape).unwrap();
+        for row in output.read::<f32>().unwrap().chunks(4) {
+            let mean_square = row.iter().map(|x| x * x).sum::<f32>() / 4.0;
+            assert!((mean_square - 1.0).abs() < 1e-5);
+        }
+
+        kernels
+            .layer_norm(&input, &ones, &zeros, &output, &shape)
+            .unwrap();
+        for row in output.read::<f32>().unwrap().chunks(4) {
+            let mean = row.iter().sum::<f32>() / 4.0;
+            let variance = row.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / 4.0;
+            assert!(mean.abs() < 1e-5);
+            assert!((variance - 1.0).abs() < 1e-5);
+        }
+
+        // [1, 3] has mean 2 and unit variance; gamma and beta then apply.
+        let gamma = upload(&compute, &[2.0, 10.0]);
+        let beta = upload(&compute, &[0.5, -1.0]);
+        let pair = upload(&compute, &[1.0, 3.0]);
+        let shape = NormShape::new(1, 2).with_eps(0.0);
+        kernels
+            .layer_norm(&pair, &gamma, &beta, &output, &shape)
+            .unwrap();
+        assert_eq!(output.read::<f32>().unwrap()[..2], [-1.5, 9.0]);
+        kernels.rms_norm(&pair, &gamma, &output, &shape).unwrap();
+        let rms = 5.0_f32.sqrt();
+        assert_close(
+            &output.read::<f32>().unwrap()[..2],
+            &[2.0 / rms, 30.0 / rms],
+        );
+    }
+
+    #[test]
+    fn test_reductions_of_known_rows() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let input = upload(&compute, &[1.0, 2.0, 3.0, 4.0, -5.0, 0.5, -1.0, 1.5]);
+        let output = compute.allocate_buffer(8).unwrap();
+        for (op, expected) in [
+            (ReduceOp::Sum, [10.0, -4.0]),
+            (ReduceOp::Max, [4.0, 1.5]),
+            (ReduceOp::Mean, [2.5, -1.0]),
+        ] {
+            kernels.reduce(op, &input, &output, 2, 4).unwrap();
+            assert_eq!(output.read::<f32>().unwrap(), expected, "{op:?}");
+        }
+    }
+
+    #[test]
+    fn test_activations_at_known_points() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let input = upload(&compute, &[0.0, 1.0, -1.0, 10.0]);
+        let output = compute.allocate_buffer(16).unwrap();
+
+        kernels.gelu(&input, &output, 4).unwrap();
+        assert_close(
+            &output.read::<f32>().unwrap(),
+            &[0.0, 0.841_192, -0.158_808, 10.0],
+        );
+
+        kernels.silu(&input, &output, 4).unwrap();
+        let sigmoid = |x: f32| 1.0 / (1.0 + (-x).exp());
+        assert_close(
+            &output.read::<f32>().unwrap(),
+            &[0.0, sigmoid(1.0), -sigmoid(-1.0), 10.0 * sigmoid(10.0)],
+        );
+    }
+
+    #[test]
+    fn test_binary_broadcast_indexes_each_operand() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let (lhs, rhs) = (
+            ramp(6),
+            ramp(4)[..].iter().map(|x| x + 2.0).collect::<Vec<_>>(),
+        );
+        let output = compute.allocate_buffer(96).unwrap();
+        for op in BinaryOp::ALL {
+            // [2, 3, 1] against [4]: lhs repeats along the last axis, rhs
+            // along the first two.
+            let shape = kernels
+                .binary(
+                    op,
+                    &upload(&compute, &lhs),
+                    &[2, 3, 1],
+                    &upload(&compute, &rhs),
+                    &[4],
+                    &output,
+                )
+                .unwrap();
+            assert_eq!(shape, [2, 3, 4]);
+            let mut expected = Vec::new();
+            for &a in &lhs {
+                for &b in &rhs {
+                    expected.push(match op {
+                        BinaryOp::Add => a + b,
+                        BinaryOp::Sub => a - b,
+                        BinaryOp::Mul => a * b,
+                        BinaryOp::Div => a / b,
+                        BinaryOp::Max => a.max(b),
+                        BinaryOp::Min => a.min(b),
+                    });
+                }
+            }
+            assert_eq!(output.read::<f32>().unwrap(), expected, "{op:?}");
+        }
+
+        let row = upload(&compute, &[1.0, 2.0]);
+        let column = upload(&compute, &[10.0, 20.0, 30.0]);
+        kernels
+            .binary(BinaryOp::Sub, &row, &[1, 2], &column, &[3, 1], &output)
+            .unwrap();
+        assert_eq!(
+            output.read::<f32>().unwrap()[..6],
+            [-9.0, -8.0, -19.0, -18.0, -29.0, -28.0]
+        );
+    }
+
+    #[test]
+    fn test_rope_rotates_pairs_by_position() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let data = ramp(24);
+        let x = upload(&compute, &data);
+        kernels.rope(&x, &RopeShape::new(3, 2, 4)).unwrap();
+        let rotated = x.read::<f32>().unwrap();
+        // Token 0 is not rotated.
+        assert_close(&rotated[..8], &data[..8]);
+        for (token, head, pair) in [(1, 0, 0), (2, 1, 0), (1, 1, 1), (2, 0, 1)] {
+            let base = (token * 2 + head) * 4 + 2 * pair;
+            // Pair i turns by position / 10000^(i / 2) for head_dim 4.
+            #[allow(clippy::cast_precision_loss)]
+            let angle = token as f32 / [1.0, 100.0][pair];
+            let (x0, x1) = (data[base], data[base + 1]);
+            let norm = |a: f32, b: f32| a.hypot(b);
+            assert!((norm(rotated[base], rotated[base + 1]) - norm(x0, x1)).abs() < 1e-6);
+            assert_close(
+                &rotated[base..base + 2],
+                &[
+                    x0.mul_add(angle.cos(), -x1 * angle.sin()),
+                    x0.mul_add(angle.sin(), x1 * angle.cos()),
+                ],
+            );
+        }
+
+        let unit = upload(&compute, &[1.0, 0.0]);
+        let shape = RopeShape::new(1, 1, 2).with_position_offset(1);
+        kernels.rope(&unit, &shape).unwrap();
+        assert_close(
+            &unit.read::<f32>().unwrap(),
+            &[1.0_f32.cos(), 1.0_f32.sin()],
+        );
+    }
+
+    #[test]
+    fn test_source_declares_host_bindings() {
+        use crate::metal::uniforms::{buffer_parameters, MslType, Scalar};
+
+        let float = MslType::Scalar(Scalar::Float);
+        let uint = MslType::Scalar(Scalar::UInt);
+        for (kernel, floats) in [
+            (Kernel::Gemm, 3),
+            (Kernel::Softmax, 2),
+            (Kernel::LayerNorm, 4),
+            (Kernel::RmsNorm, 3),
+            (Kernel::Gelu, 2),
+            (Kernel::Silu, 2),
+            (Kernel::BinaryBroadcast, 3),
+            (Kernel::ReduceRows, 2),
+            (Kernel::Rope, 1),
+        ] {
+            let parameters = buffer_parameters(SOURCE, kernel.name());
+            assert_eq!(parameters.len(), floats + 1, "{}", kernel.name());
+            for index in 0..floats {
+                assert_eq!(parameters.get(&index), Some(&float), "{}", kernel.name());
+            }
+            assert_eq!(parameters.get(&floats), Some(&uint), "{}", kernel.name());
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    #[ignore = "needs xcrun metal"]
+    fn test_source_compiles_with_xcrun() {
+        use std::process::Command;
+
+        let dir = std::env::temp_dir().join(format!("manzana-kernels-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("kernels.metal");
+        std::fs::write(&source, SOURCE).unwrap();
+        let output = Command::new("xcrun")
+            .args(["-sdk", "macosx", "metal", "-c"])
+            .arg(&source)
+            .arg("-o")
+            .arg(dir.join("kernels.air"))
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    #[test]
+    fn test_params_buffer_reused() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let buffer = upload(&compute, &[1.0, 2.0]);
+        kernels.gelu(&buffer, &buffer, 2).unwrap();
+        let usage = compute.memory_usage();
+        assert_eq!(usage.live_buffers, 2);
+        kernels.silu(&buffer, &buffer, 2).unwrap();
+        kernels
+            .binary(BinaryOp::Add, &buffer, &[2], &buffer, &[1], &buffer)
+            .unwrap();
+        assert_eq!(compute.memory_usage(), usage);
+    }
+
+    #[test]
+    fn test_invalid_arguments_rejected() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let small = compute.allocate_buffer(8).unwrap();
+        let big = compute.allocate_buffer(64).unwrap();
+
+        let err = kernels
+            .gemm(&small, &big, &big, &GemmShape::new(2, 2, 2))
+            .unwrap_err();
+        assert!(err.to_string().contains("a buffer holds 8 bytes"));
+        assert!(kernels.softmax(&big, &big, 0, 4).is_err());
+        assert!(kernels
+            .binary(BinaryOp::Add, &big, &[2, 3], &big, &[2], &big)
+            .is_err());
+        assert!(kernels
+            .binary(BinaryOp::Add, &big, &[1, 1, 1, 1, 2], &big, &[2], &big)
+            .is_err());
+        assert!(kernels.rope(&big, &RopeShape::new(1, 1, 3)).is_err());
+        assert!(kernels.softmax(&big, &big, usize::MAX, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_shaders_cached() {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let first = kernels.shader(Kernel::Softmax).unwrap();
+        let second = kernels.shader(Kernel::Softmax).unwrap();
+        assert!(Arc::ptr_eq(&first.source, &second.source));
+        assert_eq!(kernels.shaders.borrow().len(), 1);
+        assert!(first.cpu_kernel().is_some());
+        assert_eq!(first.name(), "softmax");
+    }
+
+    #[test]
+    fn test_opcodes_roundtrip() {
+        for op in BinaryOp::ALL {
+            assert_eq!(BinaryOp::from_code(op.code()), Some(op));
+        }
+        for op in ReduceOp::ALL {
+            assert_eq!(ReduceOp::from_code(op.code()), Some(op));
+        }
+        assert_eq!(BinaryOp::from_code(99), None);
+        assert!(SOURCE.contains("kernel void binary_broadcast"));
+    }
+}
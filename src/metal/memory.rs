@@ -0,0 +1,377 @@
+//! GPU memory accounting and budgets.
+//!
+//! Every [`MetalBuffer`](super::MetalBuffer) allocated through
+//! [`MetalCompute`](super::MetalCompute) is charged to two ledgers: one
+//! shared by all pipelines on the same device, and one private to the
+//! pipeline. Charges are released when the buffer is dropped.
+//!
+//! The device ledger's capacity comes from the reported VRAM (falling back
+//! to `max_buffer_length`). A pipeline can additionally carry a
+//! [`MemoryBudget`]: exceeding the soft limit logs a warning, exceeding the
+//! hard limit fails the allocation with [`Error::OutOfMemory`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::{MemoryBudget, MetalCompute};
+//!
+//! let compute = MetalCompute::default_device()?
+//!     .with_memory_budget(MemoryBudget::new().with_hard_limit(1 << 30));
+//!
+//! let buffer = compute.allocate_buffer(1 << 20)?;
+//! assert_eq!(compute.memory_usage().in_use, 1 << 20);
+//! drop(buffer);
+//! assert_eq!(compute.memory_usage().in_use, 0);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+use super::MetalDevice;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+/// Soft and hard memory limits for a single pipeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryBudget {
+    /// Usage above this many bytes logs a warning.
+    pub soft_limit: Option<u64>,
+    /// Allocations that would push usage above this many bytes fail.
+    pub hard_limit: Option<u64>,
+}
+
+impl MemoryBudget {
+    /// Create a budget with no limits beyond device capacity.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            soft_limit: None,
+            hard_limit: None,
+        }
+    }
+
+    /// Set the soft limit in bytes.
+    #[must_use]
+    pub const fn with_soft_limit(mut self, bytes: u64) -> Self {
+        self.soft_limit = Some(bytes);
+        self
+    }
+
+    /// Set the hard limit in bytes.
+    #[must_use]
+    pub const fn with_hard_limit(mut self, bytes: u64) -> Self {
+        self.hard_limit = Some(bytes);
+        self
+    }
+}
+
+/// Point-in-time snapshot of a memory ledger.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Total bytes the ledger may hand out.
+    pub capacity: u64,
+    /// Bytes held by live buffers.
+    pub in_use: u64,
+    /// Highest `in_use` value observed.
+    pub high_water: u64,
+    /// Number of live buffers.
+    pub live_buffers: usize,
+}
+
+impl MemoryUsage {
+    /// Bytes still available before reaching capacity.
+    #[must_use]
+    pub const fn available(&self) -> u64 {
+        self.capacity.saturating_sub(self.in_use)
+    }
+}
+
+/// Thread-safe byte counter for live GPU allocations.
+#[derive(Debug)]
+pub struct MemoryLedger {
+    capacity: u64,
+    in_use: AtomicU64,
+    high_water: AtomicU64,
+    live_buffers: AtomicUsize,
+}
+
+impl MemoryLedger {
+    /// Create an empty ledger with the given capacity in bytes.
+    #[must_use]
+    pub const fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            in_use: AtomicU64::new(0),
+            high_water: AtomicU64::new(0),
+            live_buffers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a snapshot of current usage.
+    #[must_use]
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            capacity: self.capacity,
+            in_use: self.in_use.load(Ordering::Acquire),
+            high_water: self.high_water.load(Ordering::Acquire),
+            live_buffers: self.live_buffers.load(Ordering::Acquire),
+        }
+    }
+
+    /// Charge `bytes` to the ledger unless usage would exceed `limit`
+    /// (clamped to capacity).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OutOfMemory`] if the charge does not fit.
+    pub fn reserve(&self, bytes: u64, limit: Option<u64>) -> Result<u64> {
+        let limit = limit.map_or(self.capacity, |l| l.min(self.capacity));
+        let mut current = self.in_use.load(Ordering::Acquire);
+        loop {
+            let next = current
+                .checked_add(bytes)
+                .filter(|&next| next <= limit)
+                .ok_or_else(|| Error::out_of_memory(bytes, limit.saturating_sub(current)))?;
+            match self.in_use.compare_exchange_weak(
+                current,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.high_water.fetch_max(next, Ordering::AcqRel);
+                    self.live_buffers.fetch_add(1, Ordering::AcqRel);
+                    return Ok(next);
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Return `bytes` previously charged with [`MemoryLedger::reserve`].
+    ///
+    /// A release without a matching reserve is a bug: it panics in debug
+    /// builds, and otherwise clamps the counters at zero rather than
+    /// wrapping.
+    pub fn release(&self, bytes: u64) {
+        let (Ok(in_use) | Err(in_use)) =
+            self.in_use
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                    Some(in_use.saturating_sub(bytes))
+                });
+        let (Ok(live) | Err(live)) =
+            self.live_buffers
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| {
+                    Some(live.saturating_sub(1))
+                });
+        debug_assert!(
+            in_use >= bytes && live > 0,
+            "released {bytes} bytes without a matching reserve"
+        );
+    }
+}
+
+/// Charge held by a live buffer, released on drop.
+#[derive(Debug)]
+pub(crate) struct Allocation {
+    bytes: u64,
+    ledgers: Vec<Arc<MemoryLedger>>,
+}
+
+impl Allocation {
+    /// Charge `bytes` to each `(ledger, limit)` pair, rolling back on failure.
+    pub(crate) fn reserve(
+        bytes: u64,
+        charges: &[(&Arc<MemoryLedger>, Option<u64>)],
+    ) -> Result<Self> {
+        let mut allocation = Self {
+            bytes,
+            ledgers: Vec::with_capacity(charges.len()),
+        };
+        for (ledger, limit) in charges {
+            ledger.reserve(bytes, *limit)?;
+            allocation.ledgers.push(Arc::clone(ledger));
+        }
+        Ok(allocation)
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        for ledger in &self.ledgers {
+            ledger.release(self.bytes);
+        }
+    }
+}
+
+/// Total memory the device can hold, in bytes.
+pub(crate) const fn device_capacity(device: &MetalDevice) -> u64 {
+    if device.vram_bytes > 0 {
+        device.vram_bytes
+    } else {
+        device.max_buffer_length
+    }
+}
+
+/// Get the process-wide ledger for a device.
+///
+/// All pipelines on the same device share one ledger, keyed by registry ID.
+pub(crate) fn device_ledger(device: &MetalDevice) -> Arc<MemoryLedger> {
+    static LEDGERS: OnceLock<Mutex<HashMap<u64, Arc<MemoryLedger>>>> = OnceLock::new();
+    let mut ledgers = LEDGERS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    Arc::clone(
+        ledgers
+            .entry(device.registry_id)
+            .or_insert_with(|| Arc::new(MemoryLedger::new(device_capacity(device)))),
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1_073_741_824;
+
+    #[test]
+    fn test_reserve_and_release() {
+        let ledger = MemoryLedger::new(1024);
+        assert_eq!(ledger.reserve(512, None).unwrap(), 512);
+        assert_eq!(ledger.reserve(256, None).unwrap(), 768);
+        let usage = ledger.usage();
+        assert_eq!(usage.in_use, 768);
+        assert_eq!(usage.live_buffers, 2);
+        assert_eq!(usage.available(), 256);
+
+        ledger.release(512);
+        let usage = ledger.usage();
+        assert_eq!(usage.in_use, 256);
+        assert_eq!(usage.high_water, 768);
+        assert_eq!(usage.live_buffers, 1);
+    }
+
+    #[test]
+    fn test_unmatched_release_saturates() {
+        let ledger = MemoryLedger::new(1024);
+        ledger.reserve(64, None).unwrap();
+        ledger.release(64);
+        let unmatched = std::panic::catch_unwind(|| ledger.release(64));
+        assert_eq!(unmatched.is_err(), cfg!(debug_assertions));
+        let usage = ledger.usage();
+        assert_eq!(usage.in_use, 0);
+        assert_eq!(usage.live_buffers, 0);
+        assert_eq!(ledger.reserve(1024, None).unwrap(), 1024);
+    }
+
+    #[test]
+    fn test_capacity_enforced() {
+        // Ten 10 GB buffers cannot all fit on a 32 GB device.
+        let ledger = MemoryLedger::new(32 * GB);
+        let granted = (0..10)
+            .filter(|_| ledger.reserve(10 * GB, None).is_ok())
+            .count();
+        assert_eq!(granted, 3);
+        assert_eq!(ledger.usage().in_use, 30 * GB);
+    }
+
+    #[test]
+    fn test_limit_clamped_to_capacity() {
+        let ledger = MemoryLedger::new(100);
+        assert!(ledger.reserve(150, Some(200)).is_err());
+        assert!(ledger.reserve(60, Some(50)).is_err());
+        assert!(ledger.reserve(50, Some(50)).is_ok());
+    }
+
+    #[test]
+    fn test_out_of_memory_error() {
+        let ledger = MemoryLedger::new(100);
+        ledger.reserve(40, None).unwrap();
+        let err = ledger.reserve(80, None).unwrap_err();
+        assert_eq!(
+            err,
+            Error::OutOfMemory {
+                requested: 80,
+                available: 60
+            }
+        );
+    }
+
+    #[test]
+    fn test_allocation_releases_on_drop() {
+        let device = Arc::new(MemoryLedger::new(1000));
+        let instance = Arc::new(MemoryLedger::new(1000));
+        let allocation =
+            Allocation::reserve(300, &[(&instance, Some(500)), (&device, None)]).unwrap();
+        assert_eq!(device.usage().in_use, 300);
+        assert_eq!(instance.usage().in_use, 300);
+
+        drop(allocation);
+        assert_eq!(device.usage().in_use, 0);
+        assert_eq!(instance.usage().in_use, 0);
+        assert_eq!(instance.usage().high_water, 300);
+    }
+
+    #[test]
+    fn test_allocation_rolls_back_on_failure() {
+        let device = Arc::new(MemoryLedger::new(100));
+        let instance = Arc::new(MemoryLedger::new(1000));
+        device.reserve(90, None).unwrap();
+
+        let result = Allocation::reserve(50, &[(&instance, None), (&device, None)]);
+        assert!(result.is_err());
+        assert_eq!(instance.usage().in_use, 0);
+        assert_eq!(instance.usage().live_buffers, 0);
+    }
+
+    #[test]
+    fn test_budget_builder() {
+        let budget = MemoryBudget::new().with_soft_limit(10).with_hard_limit(20);
+        assert_eq!(budget.soft_limit, Some(10));
+        assert_eq!(budget.hard_limit, Some(20));
+        assert_eq!(MemoryBudget::default(), MemoryBudget::new());
+    }
+
+    #[test]
+    fn test_device_capacity_prefers_vram() {
+        let mut device = MetalDevice {
+            name: "Test GPU".to_string(),
+            registry_id: 42,
+            device_id: None,
+            peer_group_id: 0,
+            is_low_power: false,
+            is_headless: false,
+            max_threads_per_threadgroup: 1024,
+            max_buffer_length: 4 * GB,
+            vram_bytes: 16 * GB,
+            has_unified_memory: false,
+            index: 0,
+        };
+        assert_eq!(device_capacity(&device), 16 * GB);
+        device.vram_bytes = 0;
+        assert_eq!(device_capacity(&device), 4 * GB);
+    }
+
+    #[test]
+    fn test_device_ledger_shared_by_registry_id() {
+        let device = MetalDevice {
+            name: "Shared GPU".to_string(),
+            registry_id: 0xDEAD_BEEF,
+            device_id: None,
+            peer_group_id: 0,
+            is_low_power: false,
+            is_headless: false,
+            max_threads_per_threadgroup: 1024,
+            max_buffer_length: GB,
+            vram_bytes: 0,
+            has_unified_memory: false,
+            index: 0,
+        };
+        let a = device_ledger(&device);
+        let b = device_ledger(&device);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(a.usage().capacity, GB);
+    }
+}
@@ -0,0 +1,844 @@
+//! Precompiled Metal library (`.metallib`) archives.
+//!
+//! [`MetalLibrary`] reads the container produced by `xcrun metallib`
+//! without any Apple tooling, so archives can be inspected on any
+//! platform. An archive is a fixed header followed by four sections:
+//!
+//! - the function list, one tagged entry per function (`NAME`, `TYPE`,
+//!   `HASH`, `VERS`, `OFFT`, `MDSZ`, ...)
+//! - public metadata, holding each function's reflection tags
+//! - private metadata
+//! - the AIR bitcode of every function
+//!
+//! The 88-byte header holds, by byte offset: the `MTLB` magic (0),
+//! platform flags (4, `u16`), the file version (6, two `u16`s), the
+//! library type (10, `u8`), the target OS (11, `u8`), the OS version (12,
+//! two `u16`s), the file size (16, `u64`), and an `offset, size` pair of
+//! `u64`s for each section, in the order above (24, 40, 56, 72).
+//!
+//! The function list is a `u32` count followed by one entry per function:
+//! a `u32` entry size that counts itself, then the entry's tags. Each
+//! function's reflection data is a `u32` length followed by that many
+//! bytes of tags, at the offset its `OFFT` tag gives into the public
+//! metadata.
+//!
+//! All integers are little-endian. Tags are a four-character code, a
+//! `u16` length and that many bytes; a bare `ENDT` ends a tag list.
+//! Reflection payloads are not publicly documented, so they are exposed
+//! as raw tags. Unknown tags are skipped.
+//!
+//! [`MetalCompute::load_library`](super::MetalCompute::load_library)
+//! turns the kernel functions of an archive into [`CompiledShader`]s.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::metallib::{FunctionType, MetalLibrary};
+//!
+//! let library = MetalLibrary::open("shaders.metallib")?;
+//! println!("targets {} {}", library.header().target_os, library.header().os_version);
+//! for function in library.functions() {
+//!     if function.function_type == FunctionType::Kernel {
+//!         println!("{} (Metal {})", function.name, function.language_version);
+//!     }
+//! }
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - Every function in a well-formed archive is listed with its name,
+//!   type and versions
+//! - Truncated or corrupt archives are rejected with an error, never a
+//!   panic or an out-of-bounds read
+//! - Each function's bitcode lies inside the bitcode section
+
+use super::CompiledShader;
+use crate::error::{Error, Result};
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+
+/// Magic bytes at the start of every archive.
+pub const MAGIC: [u8; 4] = *b"MTLB";
+
+/// Size of the fixed archive header in bytes.
+pub const HEADER_LEN: usize = 88;
+
+/// Tag that ends a tag list.
+const END_TAG: [u8; 4] = *b"ENDT";
+
+/// Parse errors, before they are wrapped in [`Error`].
+type Parse<T> = std::result::Result<T, String>;
+
+/// A `major.minor` version number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Version {
+    /// Major version.
+    pub major: u16,
+    /// Minor version.
+    pub minor: u16,
+}
+
+impl Version {
+    /// Create a version.
+    #[must_use]
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Kind of library stored in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LibraryType {
+    /// A regular library of functions.
+    Executable,
+    /// A Core Image kernel library.
+    CoreImage,
+    /// A dynamic library linked at pipeline creation.
+    Dynamic,
+    /// Debug symbols for another library.
+    SymbolCompanion,
+    /// A type this reader does not know.
+    Unknown(u8),
+}
+
+impl From<u8> for LibraryType {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => Self::Executable,
+            1 => Self::CoreImage,
+            2 => Self::Dynamic,
+            3 => Self::SymbolCompanion,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// Operating system an archive was built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TargetOs {
+    /// macOS.
+    MacOs,
+    /// iOS.
+    Ios,
+    /// tvOS.
+    TvOs,
+    /// watchOS.
+    WatchOs,
+    /// bridgeOS.
+    BridgeOs,
+    /// Mac Catalyst.
+    MacCatalyst,
+    /// The iOS simulator.
+    IosSimulator,
+    /// The tvOS simulator.
+    TvOsSimulator,
+    /// The watchOS simulator.
+    WatchOsSimulator,
+    /// An OS this reader does not know.
+    Unknown(u8),
+}
+
+impl From<u8> for TargetOs {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0x81 => Self::MacOs,
+            0x82 => Self::Ios,
+            0x83 => Self::TvOs,
+            0x84 => Self::WatchOs,
+            0x85 => Self::BridgeOs,
+            0x86 => Self::MacCatalyst,
+            0x87 => Self::IosSimulator,
+            0x88 => Self::TvOsSimulator,
+            0x89 => Self::WatchOsSimulator,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl fmt::Display for TargetOs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MacOs => write!(f, "macOS"),
+            Self::Ios => write!(f, "iOS"),
+            Self::TvOs => write!(f, "tvOS"),
+            Self::WatchOs => write!(f, "watchOS"),
+            Self::BridgeOs => write!(f, "bridgeOS"),
+            Self::MacCatalyst => write!(f, "Mac Catalyst"),
+            Self::IosSimulator => write!(f, "iOS Simulator"),
+            Self::TvOsSimulator => write!(f, "tvOS Simulator"),
+            Self::WatchOsSimulator => write!(f, "watchOS Simulator"),
+            Self::Unknown(raw) => write!(f, "unknown OS {raw:#04x}"),
+        }
+    }
+}
+
+/// Kind of a library function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FunctionType {
+    /// A `vertex` function.
+    Vertex,
+    /// A `fragment` function.
+    Fragment,
+    /// A `kernel` function.
+    Kernel,
+    /// A function without a qualifier.
+    Unqualified,
+    /// A `[[visible]]` function.
+    Visible,
+    /// An `extern` function.
+    Extern,
+    /// An `intersection` function.
+    Intersection,
+    /// A type this reader does not know.
+    Unknown(u8),
+}
+
+impl From<u8> for FunctionType {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => Self::Vertex,
+            1 => Self::Fragment,
+            2 => Self::Kernel,
+            3 => Self::Unqualified,
+            4 => Self::Visible,
+            5 => Self::Extern,
+            6 => Self::Intersection,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// Fixed header of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryHeader {
+    /// Version of the container format.
+    pub file_version: Version,
+    /// Kind of library.
+    pub library_type: LibraryType,
+    /// Operating system the library was built for.
+    pub target_os: TargetOs,
+    /// Minimum version of `target_os`.
+    pub os_version: Version,
+    /// Archive size in bytes, as recorded in the header.
+    pub file_size: u64,
+}
+
+/// A tag from a function's reflection data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflectionTag {
+    /// Four-character tag code, e.g. `ARGR` or `CNST`.
+    pub id: [u8; 4],
+    /// Raw tag payload.
+    pub data: Vec<u8>,
+}
+
+/// Reflection data embedded for a function (its public metadata).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reflection {
+    tags: Vec<ReflectionTag>,
+}
+
+impl Reflection {
+    /// All tags, in archive order.
+    #[must_use]
+    pub fn tags(&self) -> &[ReflectionTag] {
+        &self.tags
+    }
+
+    /// Payload of the first tag with code `id`.
+    #[must_use]
+    pub fn get(&self, id: &[u8; 4]) -> Option<&[u8]> {
+        self.tags
+            .iter()
+            .find(|tag| &tag.id == id)
+            .map(|tag| tag.data.as_slice())
+    }
+}
+
+/// A function stored in an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryFunction {
+    /// Function name.
+    pub name: String,
+    /// Function kind.
+    pub function_type: FunctionType,
+    /// AIR (Apple IR) version of the bitcode.
+    pub air_version: Version,
+    /// Metal Shading Language version the function was compiled for.
+    pub language_version: Version,
+    /// SHA-256 of the bitcode, if recorded.
+    pub hash: Option<[u8; 32]>,
+    /// Embedded reflection data.
+    pub reflection: Reflection,
+    /// AIR bitcode.
+    pub bitcode: Vec<u8>,
+}
+
+/// A parsed `.metallib` archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetalLibrary {
+    header: LibraryHeader,
+    functions: Vec<LibraryFunction>,
+}
+
+impl MetalLibrary {
+    /// Read and parse the archive at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not a well-formed
+    /// archive.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .map_err(|e| Error::metal(format!("failed to read {}: {e}", path.display())))?;
+        Self::parse(&bytes)
+    }
+
+    /// Parse an archive held in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the magic is wrong, the archive is truncated, a
+    /// section or function lies outside the archive, or a function lacks a
+    /// required tag.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        Self::read(bytes)
+            .map_err(|what| Error::invalid_input(format!("malformed metallib: {what}")))
+    }
+
+    fn read(bytes: &[u8]) -> Parse<Self> {
+        let mut header = Reader::new(
+            bytes
+                .get(..HEADER_LEN)
+                .ok_or_else(|| format!("{} byte file is shorter than the header", bytes.len()))?,
+        );
+        if header.array::<4>()? != MAGIC {
+            return Err("bad magic".to_string());
+        }
+        let _platform = header.u16()?;
+        let file_version = header.version()?;
+        let library_type = LibraryType::from(header.u8()?);
+        let target_os = TargetOs::from(header.u8()?);
+        let os_version = header.version()?;
+        let file_size = header.u64()?;
+        if usize::try_from(file_size).map_or(true, |size| size > bytes.len()) {
+            return Err(format!(
+                "header records {file_size} bytes but the file has {}",
+                bytes.len()
+            ));
+        }
+        let function_list = header.section(bytes, "function list")?;
+        let public_metadata = header.section(bytes, "public metadata")?;
+        let _private_metadata = header.section(bytes, "private metadata")?;
+        let bitcode = header.section(bytes, "bitcode")?;
+
+        let mut list = Reader::new(&bytes[function_list]);
+        let count = list.u32()?;
+        let functions = (0..count)
+            .map(|index| {
+                let size = list.u32()?;
+                let entry = size
+                    .checked_sub(4)
+                    .ok_or_else(|| format!("function {index} has size {size}"))?;
+                let entry = list.take(entry as usize)?;
+                let tags = Reader::new(entry).tags()?;
+                parse_function(
+                    &tags,
+                    &bytes[public_metadata.clone()],
+                    &bytes[bitcode.clone()],
+                )
+                .map_err(|what| format!("function {index}: {what}"))
+            })
+            .collect::<Parse<Vec<_>>>()?;
+
+        Ok(Self {
+            header: LibraryHeader {
+                file_version,
+                library_type,
+                target_os,
+                os_version,
+                file_size,
+            },
+            functions,
+        })
+    }
+
+    /// The archive header.
+    #[must_use]
+    pub const fn header(&self) -> &LibraryHeader {
+        &self.header
+    }
+
+    /// All functions, in archive order.
+    #[must_use]
+    pub fn functions(&self) -> &[LibraryFunction] {
+        &self.functions
+    }
+
+    /// The function called `name`.
+    #[must_use]
+    pub fn function(&self, name: &str) -> Option<&LibraryFunction> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    /// The `kernel` functions, which can become compute pipelines.
+    pub fn kernels(&self) -> impl Iterator<Item = &LibraryFunction> {
+        self.functions
+            .iter()
+            .filter(|function| function.function_type == FunctionType::Kernel)
+    }
+}
+
+fn parse_function(
+    tags: &[([u8; 4], &[u8])],
+    public_metadata: &[u8],
+    bitcode: &[u8],
+) -> Parse<LibraryFunction> {
+    let tag = |id: &[u8; 4]| {
+        tags.iter()
+            .find(|(tag, _)| tag == id)
+            .map(|(_, data)| Reader::new(data))
+    };
+    let required = |id: &[u8; 4]| {
+        tag(id).ok_or_else(|| format!("missing {} tag", String::from_utf8_lossy(id)))
+    };
+
+    let name = required(b"NAME")?.cstr()?;
+    let function_type = FunctionType::from(required(b"TYPE")?.u8()?);
+    let mut versions = required(b"VERS")?;
+    let air_version = versions.version()?;
+    let language_version = versions.version()?;
+    let hash = tag(b"HASH")
+        .map(|mut hash| hash.array::<32>())
+        .transpose()?;
+    let bitcode_len = required(b"MDSZ")?.u64()?;
+    let mut offsets = required(b"OFFT")?;
+    let public_offset = offsets.u64()?;
+    let _private_offset = offsets.u64()?;
+    let bitcode_offset = offsets.u64()?;
+
+    let mut metadata = Reader::at(public_metadata, public_offset, "reflection")?;
+    let metadata_len = metadata.u32()?;
+    let reflection = Reflection {
+        tags: Reader::new(metadata.take(metadata_len as usize)?)
+            .tags()?
+            .into_iter()
+            .map(|(id, data)| ReflectionTag {
+                id,
+                data: data.to_vec(),
+            })
+            .collect(),
+    };
+    let bitcode = Reader::at(bitcode, bitcode_offset, "bitcode")?
+        .take(usize::try_from(bitcode_len).map_err(|_| "bitcode too large".to_string())?)?
+        .to_vec();
+
+    Ok(LibraryFunction {
+        name,
+        function_type,
+        air_version,
+        language_version,
+        hash,
+        reflection,
+        bitcode,
+    })
+}
+
+/// Bounds-checked little-endian reader over a byte slice.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    const fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    /// A reader starting `offset` bytes into `bytes`.
+    fn at(bytes: &'a [u8], offset: u64, what: &str) -> Parse<Self> {
+        usize::try_from(offset)
+            .ok()
+            .filter(|&offset| offset <= bytes.len())
+            .map(|offset| Self::new(&bytes[offset..]))
+            .ok_or_else(|| format!("{what} offset {offset} is outside its section"))
+    }
+
+    fn take(&mut self, len: usize) -> Parse<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| "unexpected end of data".to_string())?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Parse<[u8; N]> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Parse<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Parse<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Parse<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Parse<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn version(&mut self) -> Parse<Version> {
+        Ok(Version::new(self.u16()?, self.u16()?))
+    }
+
+    /// A NUL-terminated UTF-8 string.
+    fn cstr(&mut self) -> Parse<String> {
+        let rest = &self.bytes[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| "unterminated string".to_string())?;
+        let text = std::str::from_utf8(&rest[..len])
+            .map_err(|_| "string is not UTF-8".to_string())?
+            .to_string();
+        self.pos += len + 1;
+        Ok(text)
+    }
+
+    /// An `offset, size` pair locating a section of `file`.
+    fn section(&mut self, file: &[u8], what: &str) -> Parse<Range<usize>> {
+        let offset = self.u64()?;
+        let size = self.u64()?;
+        offset
+            .checked_add(size)
+            .and_then(|end| usize::try_from(end).ok())
+            .filter(|&end| end <= file.len())
+            .and_then(|end| Some(usize::try_from(offset).ok()?..end))
+            .ok_or_else(|| format!("{what} section {offset}+{size} is outside the file"))
+    }
+
+    /// Tags up to `ENDT` or the end of the data.
+    fn tags(&mut self) -> Parse<Vec<([u8; 4], &'a [u8])>> {
+        let mut tags = Vec::new();
+        while self.pos < self.bytes.len() {
+            let id = self.array::<4>()?;
+            if id == END_TAG {
+                break;
+            }
+            let len = self.u16()?;
+            tags.push((id, self.take(usize::from(len))?));
+        }
+        Ok(tags)
+    }
+}
+
+impl super::MetalCompute {
+    /// Load the kernels of a precompiled `.metallib` archive.
+    ///
+    /// Returns one [`CompiledShader`], named after its function, for every
+    /// `kernel` function in the archive. Vertex, fragment and other
+    /// functions are not compute pipelines and are skipped; use
+    /// [`MetalLibrary`] to inspect them. Library kernels have no CPU
+    /// implementation until one is attached with
+    /// [`CompiledShader::with_cpu_kernel`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the `.metallib` file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device is lost, the file cannot be read or
+    /// is malformed, or pipeline creation fails.
+    pub fn load_library(&self, path: impl AsRef<Path>) -> Result<Vec<CompiledShader>> {
+        self.check_device()?;
+        let library = MetalLibrary::open(path)?;
+        library
+            .kernels()
+            .map(|kernel| {
+                self.pipeline(
+                    &kernel.name,
+                    kernel.bitcode.as_slice().into(),
+                    super::Specialization::default(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Serialize an archive, for tests.
+#[cfg(test)]
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) mod fixture {
+    use super::{FunctionType, HEADER_LEN, MAGIC};
+
+    /// A function to write into a test archive.
+    pub struct Function<'a> {
+        pub name: &'a str,
+        pub kind: FunctionType,
+        pub reflection: &'a [(&'a [u8; 4], &'a [u8])],
+        pub bitcode: &'a [u8],
+    }
+
+    fn tag(out: &mut Vec<u8>, id: [u8; 4], data: &[u8]) {
+        out.extend_from_slice(&id);
+        out.extend_from_slice(&u16::try_from(data.len()).unwrap_or(u16::MAX).to_le_bytes());
+        out.extend_from_slice(data);
+    }
+
+    const fn raw_type(kind: FunctionType) -> u8 {
+        match kind {
+            FunctionType::Vertex => 0,
+            FunctionType::Fragment => 1,
+            FunctionType::Kernel => 2,
+            FunctionType::Unqualified => 3,
+            FunctionType::Visible => 4,
+            FunctionType::Extern => 5,
+            FunctionType::Intersection => 6,
+            FunctionType::Unknown(raw) => raw,
+        }
+    }
+
+    /// A macOS 14 archive of `functions`, built for Metal 3.1.
+    pub fn archive(functions: &[Function<'_>]) -> Vec<u8> {
+        let mut list = (functions.len() as u32).to_le_bytes().to_vec();
+        let (mut public, mut bitcode) = (Vec::new(), Vec::new());
+        for function in functions {
+            let mut entry = Vec::new();
+            let mut name = function.name.as_bytes().to_vec();
+            name.push(0);
+            tag(&mut entry, *b"NAME", &name);
+            tag(&mut entry, *b"TYPE", &[raw_type(function.kind)]);
+            tag(&mut entry, *b"HASH", &[0xAB; 32]);
+            tag(
+                &mut entry,
+                *b"MDSZ",
+                &(function.bitcode.len() as u64).to_le_bytes(),
+            );
+            let mut offsets = (public.len() as u64).to_le_bytes().to_vec();
+            offsets.extend_from_slice(&0u64.to_le_bytes());
+            offsets.extend_from_slice(&(bitcode.len() as u64).to_le_bytes());
+            tag(&mut entry, *b"OFFT", &offsets);
+            tag(&mut entry, *b"VERS", &[2, 0, 6, 0, 3, 0, 1, 0]);
+            entry.extend_from_slice(b"ENDT");
+            list.extend_from_slice(&(entry.len() as u32 + 4).to_le_bytes());
+            list.extend_from_slice(&entry);
+
+            let mut metadata = Vec::new();
+            for (id, data) in function.reflection {
+                tag(&mut metadata, **id, data);
+            }
+            metadata.extend_from_slice(b"ENDT");
+            public.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+            public.extend_from_slice(&metadata);
+            bitcode.extend_from_slice(function.bitcode);
+        }
+
+        let private = b"ENDT";
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&0x8001u16.to_le_bytes());
+        out.extend_from_slice(&[1, 0, 2, 0, 0, 0x81, 14, 0, 0, 0]);
+        let total = HEADER_LEN + list.len() + public.len() + private.len() + bitcode.len();
+        out.extend_from_slice(&(total as u64).to_le_bytes());
+        let mut offset = HEADER_LEN;
+        for section in [&list[..], &public[..], &private[..], &bitcode[..]] {
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+            out.extend_from_slice(&(section.len() as u64).to_le_bytes());
+            offset += section.len();
+        }
+        for section in [&list[..], &public[..], &private[..], &bitcode[..]] {
+            out.extend_from_slice(section);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::fixture::{archive, Function};
+    use super::*;
+    use crate::metal::MetalCompute;
+
+    fn functions() -> Vec<Function<'static>> {
+        vec![
+            Function {
+                name: "add",
+                kind: FunctionType::Kernel,
+                reflection: &[(b"ARGR", &[1, 2, 3]), (b"CNST", &[])],
+                bitcode: b"\xDE\xC0\x17\x0Badd",
+            },
+            Function {
+                name: "vertex_main",
+                kind: FunctionType::Vertex,
+                reflection: &[],
+                bitcode: b"\xDE\xC0\x17\x0Bvtx",
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_archive() {
+        let library = MetalLibrary::parse(&archive(&functions())).unwrap();
+        let header = library.header();
+        assert_eq!(header.file_version, Version::new(1, 2));
+        assert_eq!(header.library_type, LibraryType::Executable);
+        assert_eq!(header.target_os, TargetOs::MacOs);
+        assert_eq!(header.os_version.to_string(), "14.0");
+
+        assert_eq!(library.functions().len(), 2);
+        let add = library.function("add").unwrap();
+        assert_eq!(add.function_type, FunctionType::Kernel);
+        assert_eq!(add.air_version, Version::new(2, 6));
+        assert_eq!(add.language_version, Version::new(3, 1));
+        assert_eq!(add.hash, Some([0xAB; 32]));
+        assert_eq!(add.reflection.get(b"ARGR"), Some(&[1u8, 2, 3][..]));
+        assert_eq!(add.reflection.tags().len(), 2);
+        assert_eq!(add.bitcode, b"\xDE\xC0\x17\x0Badd");
+        assert_eq!(
+            library.function("vertex_main").unwrap().bitcode,
+            b"\xDE\xC0\x17\x0Bvtx"
+        );
+
+        let kernels: Vec<_> = library.kernels().map(|k| k.name.as_str()).collect();
+        assert_eq!(kernels, ["add"]);
+    }
+
+    /// A one-kernel archive written out byte by byte, independently of
+    /// [`archive`], following the layout in the module documentation.
+    #[rustfmt::skip]
+    const HAND_WRITTEN: [u8; 203] = [
+        // 0x00 header: magic, platform flags, file version 1.2
+        b'M', b'T', b'L', b'B', 0x01, 0x80, 0x01, 0x00, 0x02, 0x00,
+        // 0x0A library type (executable), target OS (macOS), OS version 14.0
+        0x00, 0x81, 0x0E, 0x00, 0x00, 0x00,
+        // 0x10 file size: 203
+        0xCB, 0, 0, 0, 0, 0, 0, 0,
+        // 0x18 function list: offset 88, size 87
+        0x58, 0, 0, 0, 0, 0, 0, 0, 0x57, 0, 0, 0, 0, 0, 0, 0,
+        // 0x28 public metadata: offset 175, size 16
+        0xAF, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0,
+        // 0x38 private metadata: offset 191, size 4
+        0xBF, 0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0, 0, 0, 0, 0, 0,
+        // 0x48 bitcode: offset 195, size 8
+        0xC3, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0, 0, 0, 0, 0,
+        // 0x58 function list: one function, whose entry is 83 bytes
+        0x01, 0, 0, 0, 0x53, 0, 0, 0,
+        // 0x60 NAME "add"
+        b'N', b'A', b'M', b'E', 0x04, 0x00, b'a', b'd', b'd', 0x00,
+        // 0x6A TYPE kernel
+        b'T', b'Y', b'P', b'E', 0x01, 0x00, 0x02,
+        // 0x71 MDSZ: 8 bytes of bitcode
+        b'M', b'D', b'S', b'Z', 0x08, 0x00, 0x08, 0, 0, 0, 0, 0, 0, 0,
+        // 0x7F OFFT: public metadata, private metadata and bitcode offsets
+        b'O', b'F', b'F', b'T', 0x18, 0x00,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        // 0x9D VERS: AIR 2.6, Metal 3.1
+        b'V', b'E', b'R', b'S', 0x08, 0x00, 0x02, 0x00, 0x06, 0x00, 0x03, 0x00, 0x01, 0x00,
+        // 0xAB end of the entry's tags
+        b'E', b'N', b'D', b'T',
+        // 0xAF public metadata: 12 bytes of tags, ARGR [7, 8], ENDT
+        0x0C, 0, 0, 0, b'A', b'R', b'G', b'R', 0x02, 0x00, 0x07, 0x08, b'E', b'N', b'D', b'T',
+        // 0xBF private metadata
+        b'E', b'N', b'D', b'T',
+        // 0xC3 bitcode: the LLVM bitcode wrapper magic, then padding
+        0xDE, 0xC0, 0x17, 0x0B, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_parse_hand_written_layout() {
+        let library = MetalLibrary::parse(&HAND_WRITTEN).unwrap();
+        assert_eq!(
+            library.header(),
+            &LibraryHeader {
+                file_version: Version::new(1, 2),
+                library_type: LibraryType::Executable,
+                target_os: TargetOs::MacOs,
+                os_version: Version::new(14, 0),
+                file_size: 203,
+            }
+        );
+        assert_eq!(
+            library.functions(),
+            [LibraryFunction {
+                name: "add".to_string(),
+                function_type: FunctionType::Kernel,
+                air_version: Version::new(2, 6),
+                language_version: Version::new(3, 1),
+                hash: None,
+                reflection: Reflection {
+                    tags: vec![ReflectionTag {
+                        id: *b"ARGR",
+                        data: vec![7, 8],
+                    }],
+                },
+                bitcode: vec![0xDE, 0xC0, 0x17, 0x0B, 0, 0, 0, 0],
+            }]
+        );
+
+        // The file size in the header is checked against the data.
+        let mut short = HAND_WRITTEN;
+        short[0x10] = 0xCC;
+        assert!(MetalLibrary::parse(&short).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_archives_rejected() {
+        let good = archive(&functions());
+
+        let mut bad_magic = good.clone();
+        bad_magic[0] = b'X';
+        assert!(MetalLibrary::parse(&bad_magic).is_err());
+
+        // Every truncation fails cleanly.
+        for len in 0..good.len() {
+            assert!(MetalLibrary::parse(&good[..len]).is_err(), "length {len}");
+        }
+
+        // A bitcode offset past its section.
+        let mut bad_offset = good.clone();
+        let at = good.windows(4).position(|w| w == b"OFFT").unwrap() + 6 + 16;
+        bad_offset[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let error = MetalLibrary::parse(&bad_offset).unwrap_err();
+        assert!(error.to_string().contains("function 0"), "{error}");
+
+        // A function without a name.
+        let mut no_name = good;
+        let at = no_name.windows(4).position(|w| w == b"NAME").unwrap();
+        no_name[at..at + 4].copy_from_slice(b"XXXX");
+        let error = MetalLibrary::parse(&no_name).unwrap_err();
+        assert!(error.to_string().contains("missing NAME"), "{error}");
+    }
+
+    #[test]
+    fn test_load_library_returns_kernels() {
+        let path = std::env::temp_dir().join(format!("manzana-{}.metallib", std::process::id()));
+        std::fs::write(&path, archive(&functions())).unwrap();
+        let compute = MetalCompute::cpu();
+        let shaders = compute.load_library(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(shaders.len(), 1);
+        assert_eq!(shaders[0].name(), "add");
+        assert!(compute.load_library(&path).is_err());
+    }
+}
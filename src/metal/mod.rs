@@ -0,0 +1,2836 @@
+//! Metal GPU compute for macOS.
+//!
+//! Provides access to Apple's Metal framework for GPU compute operations.
+//! Supports both discrete and integrated GPUs, including Apple Silicon.
+//!
+//! A host CPU backend ([`MetalCompute::cpu`]) runs the same pipelines on
+//! any platform, using CPU implementations attached to each shader. The
+//! built-in [`kernels`] ship with one.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::MetalCompute;
+//!
+//! // Enumerate all Metal devices
+//! let devices = MetalCompute::devices();
+//! for (i, device) in devices.iter().enumerate() {
+//!     println!("GPU {}: {} ({} MB)", i, device.name, device.max_buffer_length / 1_000_000);
+//! }
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - F046: All Metal devices enumerated
+//! - F047: Device properties accurate
+//! - F053: Multi-GPU dispatch works
+//! - F057: Device lost handled gracefully
+//! - F058: Headless GPU works
+
+pub mod blit;
+#[cfg(feature = "async")]
+pub mod completion;
+pub mod cpu;
+pub mod dispatcher;
+#[cfg(feature = "async")]
+pub mod executor;
+pub mod expr;
+pub mod family;
+pub mod fault;
+pub mod graph;
+pub mod grid;
+pub mod heap;
+pub mod kernels;
+pub mod memory;
+pub mod metallib;
+pub mod preprocessor;
+pub mod profiler;
+pub mod scheduler;
+pub mod specialization;
+mod storage;
+pub mod texture;
+pub mod threadgroup;
+pub mod uma;
+pub mod uniforms;
+pub mod validation;
+pub mod watcher;
+
+pub use blit::{BlitEncoder, CopyPath, BLIT_ALIGNMENT};
+#[cfg(feature = "async")]
+pub use completion::GpuFuture;
+pub use cpu::{CpuKernel, ThreadContext};
+pub use dispatcher::{Capabilities, DispatchTask, Dispatcher, Selection, SelectionReason};
+#[cfg(feature = "async")]
+pub use executor::{ExecutorHandle, MetalExecutor, RemoteBuffer, RemoteShader};
+pub use expr::{DType, Expr, ExprInput, FusedKernel, HostData};
+pub use family::{Feature, GpuFamily, Support};
+pub use fault::{Fault, FaultInjector, FaultOp};
+pub use graph::{ComputeGraph, ExecutionPlan};
+pub use grid::IndirectArguments;
+pub use heap::{HeapStats, MetalHeap, HEAP_ALIGNMENT};
+pub use memory::{MemoryBudget, MemoryLedger, MemoryUsage};
+pub use metallib::MetalLibrary;
+pub use preprocessor::{ShaderLoader, ShaderSource, SourceLocation};
+pub use profiler::{DispatchProfile, HistogramBucket, KernelHistogram, Profiler};
+pub use scheduler::{
+    Clock, GpuJob, GpuScheduler, JobId, JobLease, JobQueue, JobReport, Priority, SchedulerMetrics,
+    SimulatedClock, SystemClock, TenantConfig, TenantMetrics,
+};
+pub use specialization::{
+    declared_constants, CompileOptions, ConstantId, ConstantValue, FunctionConstant, Specialization,
+};
+pub use storage::BufferElement;
+pub use texture::{
+    AddressMode, MetalTexture, PixelFormat, Region, Sampler, SamplerFilter, TextureDescriptor,
+    TextureType, TextureUsage,
+};
+pub use threadgroup::{
+    select_threadgroup_size, Size3, ThreadgroupLimits, ThreadgroupTuner, TuningCache, TuningKey,
+};
+pub use uma::UmaMetalBuffer;
+pub use uniforms::{MslType, ShaderUniforms, UniformValue};
+pub use validation::{Validation, ValidationChecks, Violation, ViolationKind};
+pub use watcher::{ChangeSource, ManualSource, PollingSource, ShaderHandle, ShaderWatcher};
+
+use heap::HeapSlice;
+use memory::Allocation;
+use storage::{Storage, StorageView};
+
+use crate::error::{Error, Result, Subsystem};
+use crate::unified_memory::{UmaBuffer, PAGE_SIZE};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Information about a Metal GPU device.
+#[derive(Debug, Clone)]
+pub struct MetalDevice {
+    /// Human-readable device name.
+    pub name: String,
+    /// Unique registry ID for the device.
+    pub registry_id: u64,
+    /// PCI device ID, if the system reports one (discrete and Intel GPUs).
+    pub device_id: Option<u32>,
+    /// Link group of GPUs that can copy peer-to-peer, such as the two GPUs
+    /// of a Duo card; 0 if the device is not linked.
+    pub peer_group_id: u64,
+    /// True if this is a low-power (integrated) GPU.
+    pub is_low_power: bool,
+    /// True if this is a headless (no display) GPU.
+    pub is_headless: bool,
+    /// Maximum threads per threadgroup.
+    pub max_threads_per_threadgroup: u32,
+    /// Maximum buffer length in bytes.
+    pub max_buffer_length: u64,
+    /// Total VRAM in bytes as reported by the system (0 if unknown).
+    pub vram_bytes: u64,
+    /// Unified memory architecture (Apple Silicon).
+    pub has_unified_memory: bool,
+    /// Device index for selection.
+    pub index: usize,
+}
+
+impl MetalDevice {
+    /// Pseudo-device describing the host CPU backend.
+    #[must_use]
+    pub fn cpu() -> Self {
+        Self {
+            name: "CPU".to_string(),
+            registry_id: 0,
+            device_id: None,
+            peer_group_id: 0,
+            is_low_power: false,
+            is_headless: true,
+            max_threads_per_threadgroup: 1024,
+            max_buffer_length: crate::unified_memory::MAX_ALLOCATION as u64,
+            vram_bytes: 0,
+            has_unified_memory: true,
+            index: CPU_DEVICE_INDEX,
+        }
+    }
+
+    /// Check if this device supports unified memory.
+    #[must_use]
+    pub const fn is_apple_silicon(&self) -> bool {
+        self.has_unified_memory
+    }
+
+    /// Get approximate VRAM in gigabytes.
+    ///
+    /// Falls back to `max_buffer_length` when VRAM was not reported.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub const fn vram_gb(&self) -> f64 {
+        memory::device_capacity(self) as f64 / 1_073_741_824.0
+    }
+}
+
+/// Liveness token shared by a pipeline and every resource it created.
+///
+/// Invalidated when the device is lost or the pipeline recovers, so stale
+/// buffers and shaders are rejected instead of silently reused.
+#[derive(Debug, Clone)]
+pub(crate) struct DeviceEpoch(Arc<AtomicBool>);
+
+impl DeviceEpoch {
+    fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+
+    fn is_alive(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn invalidate(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Device index reported by the CPU backend's pseudo-device.
+pub const CPU_DEVICE_INDEX: usize = usize::MAX;
+
+/// Where a [`MetalCompute`] pipeline executes kernels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// A Metal GPU device.
+    Metal,
+    /// The host CPU, through each shader's [`CpuKernel`].
+    Cpu,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Metal => write!(f, "Metal"),
+            Self::Cpu => write!(f, "CPU"),
+        }
+    }
+}
+
+/// Cache key of a specialized shader: source hash (after defines),
+/// function name, and canonical constant values.
+type SpecializationKey = (String, String, String);
+
+/// How a dispatch's grid is sized.
+#[derive(Clone, Copy)]
+enum Grid<'a> {
+    /// Thread count, trimmed at the edges.
+    Threads(Size3),
+    /// Whole threadgroup count.
+    Threadgroups(Size3),
+    /// Threadgroup count read from a buffer at an offset.
+    Indirect(&'a MetalBuffer, usize),
+}
+
+/// Thread grid of an encoded dispatch.
+enum Extent {
+    /// Known when encoded.
+    Threads(Size3),
+    /// Read from indirect arguments when the dispatch runs.
+    Indirect(StorageView),
+}
+
+/// A validated dispatch, detached from the borrows of its bindings so it
+/// can run after encoding.
+struct Execution {
+    kernel: String,
+    device_index: usize,
+    cpu_kernel: Option<CpuKernel>,
+    extent: Extent,
+    threadgroup: Size3,
+    buffers: Vec<StorageView>,
+    textures: Vec<(TextureDescriptor, StorageView)>,
+    bound_bytes: u64,
+    validation: Option<Validation>,
+}
+
+impl Execution {
+    /// Run the dispatch and return the thread grid it covered.
+    fn run(&self) -> Result<Size3> {
+        let threads = match &self.extent {
+            Extent::Threads(threads) => *threads,
+            Extent::Indirect(arguments) => grid::threads_for_threadgroups(
+                IndirectArguments::from_bytes(&arguments.read())?.threadgroups,
+                self.threadgroup,
+            )?,
+        };
+        if let Some(kernel) = &self.cpu_kernel {
+            let validator = self.validation.as_ref().map(|validation| {
+                validation::Validator::new(&self.kernel, validation.checks(), &self.buffers)
+            });
+            cpu::execute(
+                kernel,
+                threads,
+                self.threadgroup,
+                &self.buffers,
+                &self.textures,
+                validator.as_ref(),
+            );
+            if let (Some(validation), Some(validator)) = (&self.validation, validator) {
+                validation.report(validator.finish())?;
+            }
+        }
+        // Stub: actual dispatch would use Metal command buffer
+        Ok(threads)
+    }
+
+    /// Record the finished dispatch in `profiler`.
+    fn record(
+        &self,
+        profiler: &Profiler,
+        threads: Size3,
+        started: Instant,
+        encoded: Instant,
+        scheduled: Instant,
+    ) {
+        profiler.record(DispatchProfile {
+            kernel: self.kernel.clone(),
+            device_index: self.device_index,
+            grid: threads,
+            threadgroup: self.threadgroup,
+            buffer_bytes: self.bound_bytes,
+            start: profiler.offset(started),
+            encode: encoded - started,
+            schedule: scheduled - encoded,
+            execute: scheduled.elapsed(),
+        });
+    }
+}
+
+/// SIMD width reported for compute pipelines on Apple and AMD GPUs.
+const DEFAULT_THREAD_EXECUTION_WIDTH: u32 = 32;
+
+/// A compiled Metal shader (compute kernel).
+///
+/// Cloning is cheap and yields another handle to the same pipeline.
+#[derive(Debug, Clone)]
+pub struct CompiledShader {
+    name: String,
+    /// Preprocessed source or bitcode the pipeline was built from.
+    source: Arc<[u8]>,
+    max_total_threads_per_threadgroup: u32,
+    thread_execution_width: u32,
+    specialization: Specialization,
+    cpu_kernel: Option<CpuKernel>,
+    parameters: Arc<HashMap<usize, MslType>>,
+    epoch: DeviceEpoch,
+}
+
+impl CompiledShader {
+    /// Get the shader function name.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Maximum total threads per threadgroup this pipeline supports.
+    ///
+    /// May be lower than the device limit for register-heavy kernels.
+    #[must_use]
+    pub const fn max_total_threads_per_threadgroup(&self) -> u32 {
+        self.max_total_threads_per_threadgroup
+    }
+
+    /// SIMD width of the pipeline; threadgroup widths should be multiples.
+    #[must_use]
+    pub const fn thread_execution_width(&self) -> u32 {
+        self.thread_execution_width
+    }
+
+    /// Function constant values the shader was specialized with.
+    #[must_use]
+    pub const fn specialization(&self) -> &Specialization {
+        &self.specialization
+    }
+
+    /// Value of the function constant called `name`, if it was set.
+    #[must_use]
+    pub fn function_constant(&self, name: &str) -> Option<ConstantValue> {
+        self.specialization.get(name)
+    }
+
+    /// Type of the `[[buffer(index)]]` parameter, if it was parsed from the
+    /// shader source.
+    ///
+    /// Buffers created with [`MetalCompute::uniforms_buffer`] are checked
+    /// against it at dispatch.
+    #[must_use]
+    pub fn buffer_parameter(&self, index: usize) -> Option<&MslType> {
+        self.parameters.get(&index)
+    }
+
+    /// Attach the host implementation used by the CPU backend.
+    #[must_use]
+    pub fn with_cpu_kernel(mut self, kernel: CpuKernel) -> Self {
+        self.cpu_kernel = Some(kernel);
+        self
+    }
+
+    /// Get the host implementation, if one is attached.
+    #[must_use]
+    pub const fn cpu_kernel(&self) -> Option<&CpuKernel> {
+        self.cpu_kernel.as_ref()
+    }
+
+    /// Check if the shader is still usable.
+    ///
+    /// Returns `false` once the device it was compiled for is lost or
+    /// its pipeline has recovered.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.epoch.is_alive()
+    }
+}
+
+/// A Metal buffer for GPU data.
+///
+/// The buffer's bytes are charged to its pipeline's memory ledgers until
+/// it is dropped. Buffers sub-allocated from a [`MetalHeap`] are slices of
+/// the heap's region instead.
+///
+/// Contents are host-visible (shared storage mode): [`MetalBuffer::write`]
+/// and [`MetalBuffer::read`] copy data in and out without a dispatch.
+#[derive(Debug)]
+pub struct MetalBuffer {
+    length: usize,
+    device_index: usize,
+    offset: usize,
+    storage: Storage,
+    epoch: DeviceEpoch,
+    _allocation: Option<Allocation>,
+    heap: Option<HeapSlice>,
+    uniforms: Option<MslType>,
+}
+
+impl MetalBuffer {
+    /// Get the buffer length in bytes.
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Check if the buffer is empty.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Get the device this buffer is allocated on.
+    #[must_use]
+    pub const fn device_index(&self) -> usize {
+        self.device_index
+    }
+
+    /// Get the byte offset within the backing heap (0 for standalone buffers).
+    #[must_use]
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Check if the buffer was sub-allocated from a [`MetalHeap`].
+    #[must_use]
+    pub const fn is_heap_allocated(&self) -> bool {
+        self.heap.is_some()
+    }
+
+    /// Check if the buffer is still usable.
+    ///
+    /// Returns `false` once the device it was allocated on is lost, its
+    /// pipeline has recovered, or its heap has been reset.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.epoch.is_alive() && !self.is_released()
+    }
+
+    /// The buffer's bytes, for work that outlives this borrow.
+    fn view(&self) -> StorageView {
+        StorageView::new(self.storage.clone(), self.offset, self.length)
+    }
+
+    /// A buffer over `len` bytes at `offset` of this one, sharing its
+    /// storage without a separate allocation.
+    fn alias(&self, offset: usize, len: usize) -> Self {
+        Self {
+            length: len,
+            device_index: self.device_index,
+            offset: self.offset + offset,
+            storage: self.storage.clone(),
+            epoch: self.epoch.clone(),
+            _allocation: None,
+            heap: None,
+            uniforms: None,
+        }
+    }
+
+    /// True if the buffer's heap was reset after it was allocated.
+    fn is_released(&self) -> bool {
+        self.heap.as_ref().is_some_and(HeapSlice::is_released)
+    }
+
+    /// Check that `len` bytes at `offset` lie inside a usable buffer.
+    fn check_range(&self, offset: usize, len: usize) -> Result<()> {
+        if !self.epoch.is_alive() {
+            return Err(Error::device_lost(self.device_index));
+        }
+        if self.is_released() {
+            return Err(Error::invalid_input("buffer was released by a heap reset"));
+        }
+        match offset.checked_add(len) {
+            Some(end) if end <= self.length => Ok(()),
+            _ => Err(Error::invalid_input(format!(
+                "range {offset}+{len} exceeds buffer length {}",
+                self.length
+            ))),
+        }
+    }
+
+    /// Copy raw bytes into the buffer at a byte offset.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range exceeds the buffer, the device is lost,
+    /// or the buffer's heap was reset.
+    pub fn write_bytes(&self, offset: usize, data: &[u8]) -> Result<()> {
+        self.check_range(offset, data.len())?;
+        self.storage.write(self.offset + offset, data);
+        Ok(())
+    }
+
+    /// Copy raw bytes out of the buffer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range exceeds the buffer, the device is lost,
+    /// or the buffer's heap was reset.
+    pub fn read_bytes(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        self.check_range(offset, len)?;
+        Ok(self.storage.read(self.offset + offset, len))
+    }
+
+    /// Copy elements into the buffer, starting at element `index`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the elements do not fit, the device is lost, or
+    /// the buffer's heap was reset.
+    pub fn write<T: BufferElement>(&self, index: usize, data: &[T]) -> Result<()> {
+        let offset = index
+            .checked_mul(T::SIZE)
+            .ok_or_else(|| Error::invalid_input(format!("element index {index} overflows")))?;
+        self.write_bytes(offset, &storage::to_bytes(data))
+    }
+
+    /// Copy the whole buffer out as elements.
+    ///
+    /// A trailing partial element is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device is lost or the buffer's heap was
+    /// reset.
+    pub fn read<T: BufferElement>(&self) -> Result<Vec<T>> {
+        Ok(storage::from_bytes(&self.read_bytes(0, self.length)?))
+    }
+}
+
+/// Metal compute pipeline.
+///
+/// Provides GPU compute capabilities via Apple's Metal framework.
+///
+/// # Thread Safety
+///
+/// This type is `!Send` and `!Sync` because Metal command queues
+/// are not thread-safe. Create pipelines on each thread that needs them.
+///
+/// # Device Loss
+///
+/// Once the device is lost every operation returns
+/// [`Error::DeviceLost`], and buffers and shaders created before the loss
+/// become invalid. [`MetalCompute::recover`] rebuilds the pipeline state.
+///
+/// # Memory Accounting
+///
+/// Live buffers are tracked against the device's capacity and an optional
+/// per-pipeline [`MemoryBudget`]; see the [`memory`] module.
+pub struct MetalCompute {
+    device: MetalDevice,
+    backend: Backend,
+    epoch: DeviceEpoch,
+    faults: Option<FaultInjector>,
+    profiler: Option<Profiler>,
+    validation: Option<Validation>,
+    specializations: RefCell<HashMap<SpecializationKey, CompiledShader>>,
+    budget: MemoryBudget,
+    ledger: Arc<MemoryLedger>,
+    device_ledger: Arc<MemoryLedger>,
+    #[cfg(feature = "async")]
+    queue: RefCell<Option<completion::CommandQueue>>,
+    _not_send_sync: std::marker::PhantomData<*const ()>,
+}
+
+impl MetalCompute {
+    /// Enumerate all available Metal devices.
+    ///
+    /// Uses `system_profiler` to detect real GPU hardware on macOS.
+    /// Returns an empty vector on non-macOS platforms.
+    #[must_use]
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn devices() -> Vec<MetalDevice> {
+        #[cfg(target_os = "macos")]
+        {
+            Self::detect_gpus_via_system_profiler()
+        }
+
+        #[cfg(not(target_os = "macos"))]
+        {
+            Vec::new()
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn detect_gpus_via_system_profiler() -> Vec<MetalDevice> {
+        use std::process::Command;
+
+        let output = match Command::new("system_profiler")
+            .args(["SPDisplaysDataType"])
+            .output()
+        {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).to_string(),
+            _ => return Self::fallback_device(),
+        };
+
+        let mut devices = Vec::new();
+        let mut current_name = String::new();
+        let mut current_vram: u64 = 0;
+        let mut current_device_id = None;
+        let mut index = 0;
+
+        for line in output.lines() {
+            let line = line.trim();
+
+            // GPU name line (e.g., "AMD Radeon Pro W5700X:")
+            if line.ends_with(':')
+                && !line.starts_with("Graphics")
+                && !line.contains("Displays")
+                && !line.contains("VRAM")
+                && !line.contains("Vendor")
+                && !line.contains("Device")
+                && !line.contains("Bus")
+                && !line.contains("Slot")
+                && !line.contains("Metal")
+            {
+                // Save previous GPU if we have one
+                if !current_name.is_empty() {
+                    devices.push(Self::create_device(
+                        &current_name,
+                        current_vram,
+                        current_device_id,
+                        index,
+                    ));
+                    index += 1;
+                }
+                current_name = line.trim_end_matches(':').to_string();
+                current_vram = 0;
+                current_device_id = None;
+            }
+
+            // Device ID line (e.g., "Device ID: 0x7310")
+            if let Some(id) = line.strip_prefix("Device ID:") {
+                current_device_id =
+                    u32::from_str_radix(id.trim().trim_start_matches("0x"), 16).ok();
+            }
+
+            // VRAM line (e.g., "VRAM (Total): 16 GB")
+            if line.starts_with("VRAM") {
+                if let Some(vram_str) = line.split(':').nth(1) {
+                    let vram_str = vram_str.trim();
+                    if let Some(gb_pos) = vram_str.find(" GB") {
+                        if let Ok(gb) = vram_str[..gb_pos].trim().parse::<u64>() {
+                            current_vram = gb * 1_073_741_824; // Convert GB to bytes
+                        }
+                    } else if let Some(mb_pos) = vram_str.find(" MB") {
+                        if let Ok(mb) = vram_str[..mb_pos].trim().parse::<u64>() {
+                            current_vram = mb * 1_048_576; // Convert MB to bytes
+                        }
+                    }
+                }
+            }
+        }
+
+        // Don't forget the last GPU
+        if !current_name.is_empty() {
+            devices.push(Self::create_device(
+                &current_name,
+                current_vram,
+                current_device_id,
+                index,
+            ));
+        }
+
+        if devices.is_empty() {
+            return Self::fallback_device();
+        }
+
+        // The two GPUs of a Duo card are joined by Infinity Fabric Link.
+        let mut i = 0;
+        while i + 1 < devices.len() {
+            if devices[i].name.ends_with(" Duo") && devices[i].name == devices[i + 1].name {
+                let group = devices[i].registry_id;
+                devices[i].peer_group_id = group;
+                devices[i + 1].peer_group_id = group;
+                i += 2;
+            } else {
+                i += 1;
+            }
+        }
+        devices
+    }
+
+    #[cfg(target_os = "macos")]
+    fn create_device(
+        name: &str,
+        vram_bytes: u64,
+        device_id: Option<u32>,
+        index: usize,
+    ) -> MetalDevice {
+        let is_apple_silicon = name.contains("Apple") || cfg!(target_arch = "aarch64");
+        let is_integrated = name.contains("Intel") || name.contains("Integrated");
+
+        MetalDevice {
+            name: name.to_string(),
+            registry_id: (index + 1) as u64,
+            device_id,
+            peer_group_id: 0,
+            is_low_power: is_integrated,
+            is_headless: false,
+            max_threads_per_threadgroup: 1024,
+            max_buffer_length: if vram_bytes > 0 {
+                vram_bytes
+            } else if is_apple_silicon {
+                17_179_869_184 // 16 GB default for Apple Silicon
+            } else {
+                4_294_967_296 // 4 GB default
+            },
+            vram_bytes,
+            has_unified_memory: is_apple_silicon,
+            index,
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn fallback_device() -> Vec<MetalDevice> {
+        let is_apple_silicon = cfg!(target_arch = "aarch64");
+        vec![MetalDevice {
+            name: if is_apple_silicon {
+                "Apple GPU".to_string()
+            } else {
+                "Unknown GPU".to_string()
+            },
+            registry_id: 1,
+            device_id: None,
+            peer_group_id: 0,
+            is_low_power: false,
+            is_headless: false,
+            max_threads_per_threadgroup: 1024,
+            max_buffer_length: 4_294_967_296,
+            vram_bytes: 0,
+            has_unified_memory: is_apple_silicon,
+            index: 0,
+        }]
+    }
+
+    /// Check if any Metal device is available.
+    #[must_use]
+    pub fn is_available() -> bool {
+        !Self::devices().is_empty()
+    }
+
+    /// Create a compute pipeline on the specified device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_index` - Index into the devices list from `devices()`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device index is out of bounds.
+    pub fn new(device_index: usize) -> Result<Self> {
+        Ok(Self::with_device(
+            Self::find_device(device_index)?,
+            Backend::Metal,
+        ))
+    }
+
+    /// Create a compute pipeline on the host CPU backend.
+    ///
+    /// Always available. Dispatches run each shader's [`CpuKernel`]; see
+    /// the [`cpu`] module.
+    #[must_use]
+    pub fn cpu() -> Self {
+        Self::with_device(MetalDevice::cpu(), Backend::Cpu)
+    }
+
+    fn with_device(device: MetalDevice, backend: Backend) -> Self {
+        Self {
+            ledger: Arc::new(MemoryLedger::new(memory::device_capacity(&device))),
+            device_ledger: memory::device_ledger(&device),
+            device,
+            backend,
+            epoch: DeviceEpoch::new(),
+            faults: None,
+            profiler: None,
+            validation: None,
+            specializations: RefCell::new(HashMap::new()),
+            budget: MemoryBudget::new(),
+            #[cfg(feature = "async")]
+            queue: RefCell::new(None),
+            _not_send_sync: std::marker::PhantomData,
+        }
+    }
+
+    fn find_device(device_index: usize) -> Result<MetalDevice> {
+        let mut devices = Self::devices();
+        if device_index >= devices.len() {
+            return Err(Error::not_found(format!(
+                "Metal device index {device_index} (only {} devices available)",
+                devices.len()
+            )));
+        }
+        Ok(devices.swap_remove(device_index))
+    }
+
+    /// Create a compute pipeline on the default (first) device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no Metal devices are available.
+    pub fn default_device() -> Result<Self> {
+        if Self::devices().is_empty() {
+            return Err(Error::not_available(Subsystem::Metal));
+        }
+        Self::new(0)
+    }
+
+    /// Get the device name.
+    #[must_use]
+    pub fn device_name(&self) -> &str {
+        &self.device.name
+    }
+
+    /// Get the device index.
+    #[must_use]
+    pub const fn device_index(&self) -> usize {
+        self.device.index
+    }
+
+    /// Get the device this pipeline runs on.
+    #[must_use]
+    pub const fn device(&self) -> &MetalDevice {
+        &self.device
+    }
+
+    /// Get the backend this pipeline executes on.
+    #[must_use]
+    pub const fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Attach a fault injector to simulate failures and device loss.
+    #[must_use]
+    pub const fn with_fault_injector(mut self, injector: FaultInjector) -> Self {
+        self.faults = Some(injector);
+        self
+    }
+
+    /// Record the timing of every dispatch in `profiler`.
+    #[must_use]
+    pub fn with_profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = Some(profiler);
+        self
+    }
+
+    /// Get the attached profiler, if any.
+    #[must_use]
+    pub const fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Validate buffer accesses of every CPU-backend dispatch; see the
+    /// [`validation`] module.
+    #[must_use]
+    pub fn with_validation(mut self, validation: Validation) -> Self {
+        self.validation = Some(validation);
+        self
+    }
+
+    /// Get the attached validation, if any.
+    #[must_use]
+    pub const fn validation(&self) -> Option<&Validation> {
+        self.validation.as_ref()
+    }
+
+    /// Apply soft and hard memory limits to this pipeline.
+    #[must_use]
+    pub const fn with_memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Get the memory budget applied to this pipeline.
+    #[must_use]
+    pub const fn memory_budget(&self) -> MemoryBudget {
+        self.budget
+    }
+
+    /// Memory held by live buffers allocated through this pipeline.
+    #[must_use]
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.ledger.usage()
+    }
+
+    /// Memory held by live buffers from all pipelines on this device.
+    #[must_use]
+    pub fn device_memory_usage(&self) -> MemoryUsage {
+        self.device_ledger.usage()
+    }
+
+    /// Check if the device has been lost.
+    #[must_use]
+    pub fn is_device_lost(&self) -> bool {
+        !self.epoch.is_alive()
+    }
+
+    /// Rebuild pipeline state on the current device.
+    ///
+    /// Buffers and shaders created before the call are invalidated and
+    /// must be re-created. An attached fault injector stays attached, and
+    /// the memory budget carries over.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device is no longer present.
+    pub fn recover(&mut self) -> Result<()> {
+        match self.backend {
+            Backend::Metal => self.recover_on(self.device.index),
+            Backend::Cpu => {
+                self.rebuild(MetalDevice::cpu(), Backend::Cpu);
+                Ok(())
+            }
+        }
+    }
+
+    /// Rebuild pipeline state on another device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_index` - Index into the devices list from `devices()`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device index is out of bounds.
+    pub fn recover_on(&mut self, device_index: usize) -> Result<()> {
+        let device = Self::find_device(device_index)?;
+        self.rebuild(device, Backend::Metal);
+        Ok(())
+    }
+
+    fn rebuild(&mut self, device: MetalDevice, backend: Backend) {
+        #[cfg(feature = "async")]
+        self.wait_idle();
+        self.epoch.invalidate();
+        self.epoch = DeviceEpoch::new();
+        if device.registry_id != self.device.registry_id {
+            self.ledger = Arc::new(MemoryLedger::new(memory::device_capacity(&device)));
+            self.device_ledger = memory::device_ledger(&device);
+        }
+        self.device = device;
+        self.backend = backend;
+        self.specializations.get_mut().clear();
+        info!(device = self.device.index, name = %self.device.name, "Metal pipeline recovered");
+    }
+
+    /// Fail with `DeviceLost` if the device is gone.
+    fn check_device(&self) -> Result<()> {
+        if self.epoch.is_alive() {
+            Ok(())
+        } else {
+            Err(Error::device_lost(self.device.index))
+        }
+    }
+
+    /// Consult the fault injector before performing `op`.
+    fn inject(&self, op: FaultOp) -> Result<()> {
+        match self.faults.as_ref().and_then(|faults| faults.check(op)) {
+            None => Ok(()),
+            Some(Fault::Fail) => Err(Error::metal(format!("injected {op} failure"))),
+            Some(Fault::DeviceLost) => {
+                warn!(device = self.device.index, %op, "Metal device lost");
+                self.epoch.invalidate();
+                Err(Error::device_lost(self.device.index))
+            }
+        }
+    }
+
+    /// Compile a Metal shader from source.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Metal Shading Language (MSL) source code
+    /// * `function_name` - Name of the kernel function to compile
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if compilation fails or the kernel uses function
+    /// constants that are not optional; use
+    /// [`MetalCompute::compile_shader_with`] to specialize those.
+    pub fn compile_shader(&self, source: &str, function_name: &str) -> Result<CompiledShader> {
+        self.check_device()?;
+        let specialization = CompileOptions::new()
+            .resolve(&specialization::kernel_constants(source, function_name)?)?;
+        self.build_shader(source, function_name, specialization)
+    }
+
+    /// Compile a specialized Metal shader.
+    ///
+    /// Defines are prepended to the source and function constants are
+    /// bound to the values in `options`. Each distinct specialization is
+    /// compiled once and cached; later calls return a handle to the cached
+    /// pipeline.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Metal Shading Language (MSL) source code
+    /// * `function_name` - Name of the kernel function to compile
+    /// * `options` - Function constant values and preprocessor defines
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if compilation fails, a function constant the kernel
+    /// uses is unset, or a value does not match its constant.
+    pub fn compile_shader_with(
+        &self,
+        source: &str,
+        function_name: &str,
+        options: &CompileOptions,
+    ) -> Result<CompiledShader> {
+        self.check_device()?;
+        let source = options.preprocess(source);
+        let specialization =
+            options.resolve(&specialization::kernel_constants(&source, function_name)?)?;
+        let key = (
+            source.clone(),
+            function_name.to_string(),
+            CompileOptions::constants_key(&specialization),
+        );
+        if let Some(shader) = self.specializations.borrow().get(&key) {
+            if shader.is_valid() {
+                return Ok(shader.clone());
+            }
+        }
+
+        let shader = self.build_shader(&source, function_name, specialization)?;
+        debug!(function = function_name, constants = %key.2, "compiled shader specialization");
+        self.specializations
+            .borrow_mut()
+            .insert(key, shader.clone());
+        Ok(shader)
+    }
+
+    /// Number of specialized shaders in the cache.
+    #[must_use]
+    pub fn cached_specializations(&self) -> usize {
+        self.specializations.borrow().len()
+    }
+
+    fn build_shader(
+        &self,
+        source: &str,
+        function_name: &str,
+        specialization: Specialization,
+    ) -> Result<CompiledShader> {
+        // Validate source isn't empty
+        if source.trim().is_empty() {
+            return Err(Error::invalid_input("shader source is empty"));
+        }
+
+        // Validate function name isn't empty
+        if function_name.trim().is_empty() {
+            return Err(Error::invalid_input("function name is empty"));
+        }
+
+        let mut shader = self.pipeline(function_name, source.as_bytes().into(), specialization)?;
+        shader.parameters = Arc::new(uniforms::buffer_parameters(source, function_name));
+        Ok(shader)
+    }
+
+    /// Create the pipeline for a compiled function.
+    fn pipeline(
+        &self,
+        function_name: &str,
+        source: Arc<[u8]>,
+        specialization: Specialization,
+    ) -> Result<CompiledShader> {
+        self.inject(FaultOp::Compile)?;
+
+        Ok(CompiledShader {
+            name: function_name.to_string(),
+            source,
+            max_total_threads_per_threadgroup: self.device.max_threads_per_threadgroup,
+            thread_execution_width: DEFAULT_THREAD_EXECUTION_WIDTH,
+            specialization,
+            cpu_kernel: None,
+            parameters: Arc::default(),
+            epoch: self.epoch.clone(),
+        })
+    }
+
+    /// Allocate a buffer on the GPU.
+    ///
+    /// The buffer is charged to this pipeline's budget and to the device's
+    /// shared ledger until it is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `length` - Size in bytes
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - `length` is zero or exceeds the device's `max_buffer_length`
+    /// - The pipeline's hard budget or the device's capacity would be exceeded
+    /// - The device is lost
+    pub fn allocate_buffer(&self, length: usize) -> Result<MetalBuffer> {
+        self.check_device()?;
+
+        if length == 0 {
+            return Err(Error::invalid_input("buffer length cannot be zero"));
+        }
+
+        let bytes = length as u64;
+        let max_length = self.device.max_buffer_length;
+        if bytes > max_length {
+            return Err(Error::invalid_input(format!(
+                "buffer length {length} exceeds device limit {max_length}"
+            )));
+        }
+
+        let allocation = Allocation::reserve(
+            bytes,
+            &[
+                (&self.ledger, self.budget.hard_limit),
+                (&self.device_ledger, None),
+            ],
+        )?;
+
+        self.inject(FaultOp::Allocate)?;
+
+        let in_use = self.ledger.usage().in_use;
+        if let Some(soft_limit) = self.budget.soft_limit.filter(|&limit| in_use > limit) {
+            warn!(
+                device = self.device.index,
+                in_use, soft_limit, "Metal memory soft budget exceeded"
+            );
+        }
+
+        Ok(MetalBuffer {
+            length,
+            device_index: self.device.index,
+            offset: 0,
+            storage: Storage::new(),
+            epoch: self.epoch.clone(),
+            _allocation: Some(allocation),
+            heap: None,
+            uniforms: None,
+        })
+    }
+
+    /// Expose `memory` to kernels without copying it
+    /// (`newBufferWithBytesNoCopy`); see the [`uma`] module.
+    ///
+    /// The view covers the page-rounded allocation, with the padding past
+    /// `memory.len()` zeroed, and is not charged to the memory ledgers.
+    ///
+    /// The view borrows `memory`, so it cannot be dropped or mutably
+    /// borrowed while the view exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `memory` is not page-aligned, its rounded length
+    /// exceeds the device's maximum buffer length, or the device is lost.
+    pub fn wrap_uma_buffer<'a>(&self, memory: &'a UmaBuffer) -> Result<UmaMetalBuffer<'a>> {
+        self.check_device()?;
+
+        if !memory.is_aligned() {
+            return Err(Error::invalid_input(format!(
+                "UMA buffer must be aligned to {PAGE_SIZE} bytes for no-copy access"
+            )));
+        }
+        let length = memory.allocated_size();
+        if length % PAGE_SIZE != 0 {
+            return Err(Error::invalid_input(format!(
+                "UMA buffer length {length} is not a multiple of {PAGE_SIZE} bytes"
+            )));
+        }
+        let max_length = self.device.max_buffer_length;
+        if length as u64 > max_length {
+            return Err(Error::invalid_input(format!(
+                "buffer length {length} exceeds device limit {max_length}"
+            )));
+        }
+
+        self.inject(FaultOp::Allocate)?;
+
+        let (storage, detach) = Storage::uma(memory);
+        let buffer = MetalBuffer {
+            length,
+            device_index: self.device.index,
+            offset: 0,
+            storage,
+            epoch: self.epoch.clone(),
+            _allocation: None,
+            heap: None,
+            uniforms: None,
+        };
+        Ok(UmaMetalBuffer::new(buffer, memory, detach))
+    }
+
+    /// Reserve a heap for sub-allocating many small buffers.
+    ///
+    /// The whole region is allocated (and charged to the memory budget)
+    /// up front.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Heap size in bytes
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backing allocation fails.
+    pub fn create_heap(&self, size: usize) -> Result<MetalHeap> {
+        Ok(MetalHeap::new(self.allocate_buffer(size)?))
+    }
+
+    /// Create a texture.
+    ///
+    /// Texel memory is charged to the memory budget like a buffer's and
+    /// starts zeroed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the descriptor is invalid (see
+    /// [`TextureDescriptor::byte_len`]), allocation fails, or the device
+    /// is lost.
+    pub fn create_texture(&self, descriptor: &TextureDescriptor) -> Result<MetalTexture> {
+        self.check_device()?;
+        let bytes = descriptor.byte_len()? as u64;
+        let allocation = Allocation::reserve(
+            bytes,
+            &[
+                (&self.ledger, self.budget.hard_limit),
+                (&self.device_ledger, None),
+            ],
+        )?;
+        self.inject(FaultOp::Allocate)?;
+        Ok(MetalTexture::new(
+            *descriptor,
+            self.device.index,
+            self.epoch.clone(),
+            allocation,
+        ))
+    }
+
+    /// Dispatch a compute shader over `grid_size` threads.
+    ///
+    /// Equivalent to [`dispatch_threads`](Self::dispatch_threads).
+    ///
+    /// # Arguments
+    ///
+    /// * `shader` - Compiled shader to execute
+    /// * `buffers` - Buffers to bind to the shader
+    /// * `grid_size` - Total number of threads (width, height, depth)
+    /// * `threadgroup_size` - Threads per threadgroup (width, height, depth)
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if dispatch fails, the device is lost, the
+    /// shader or a buffer predates the last recovery, or the CPU backend
+    /// is used with a shader that has no CPU implementation.
+    pub fn dispatch(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        grid_size: (u32, u32, u32),
+        threadgroup_size: (u32, u32, u32),
+    ) -> Result<()> {
+        self.dispatch_threads(shader, buffers, grid_size, threadgroup_size)
+    }
+
+    /// Dispatch exactly `threads` threads (`dispatchThreads`).
+    ///
+    /// The grid need not be a multiple of the threadgroup size: edge
+    /// threadgroups are trimmed, so every thread position is inside
+    /// `threads`. On the CPU backend the shader's [`CpuKernel`] runs once
+    /// per grid position before this returns. If a [`Profiler`] is
+    /// attached, the dispatch's timing is recorded once it succeeds.
+    ///
+    /// # Arguments
+    ///
+    /// * `shader` - Compiled shader to execute
+    /// * `buffers` - Buffers to bind to the shader
+    /// * `threads` - Total number of threads (width, height, depth)
+    /// * `threadgroup_size` - Threads per threadgroup (width, height, depth)
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a grid dimension is zero, dispatch fails, the
+    /// device is lost, the shader or a buffer predates the last recovery,
+    /// or the CPU backend is used with a shader that has no CPU
+    /// implementation.
+    pub fn dispatch_threads(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        threads: Size3,
+        threadgroup_size: Size3,
+    ) -> Result<()> {
+        self.run_dispatch(
+            shader,
+            buffers,
+            &[],
+            Grid::Threads(threads),
+            threadgroup_size,
+        )
+    }
+
+    /// Dispatch whole threadgroups (`dispatchThreadgroups`).
+    ///
+    /// The thread grid is `threadgroups * threadgroup_size` in each
+    /// dimension, so kernels must bounds-check positions beyond their
+    /// logical size.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero or the thread grid
+    /// overflows, or for the reasons listed on
+    /// [`dispatch_threads`](Self::dispatch_threads).
+    pub fn dispatch_threadgroups(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        threadgroups: Size3,
+        threadgroup_size: Size3,
+    ) -> Result<()> {
+        self.run_dispatch(
+            shader,
+            buffers,
+            &[],
+            Grid::Threadgroups(threadgroups),
+            threadgroup_size,
+        )
+    }
+
+    /// Dispatch threadgroups counted by the [`IndirectArguments`] at
+    /// `offset` in `arguments`.
+    ///
+    /// The counts are read when the dispatch executes, so they may be
+    /// written by an earlier kernel. A zero count dispatches nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `arguments` is on another device, predates the
+    /// last recovery, `offset` is misaligned or out of bounds, the counts
+    /// overflow the thread grid, or for the reasons listed on
+    /// [`dispatch_threads`](Self::dispatch_threads).
+    pub fn dispatch_indirect(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        arguments: &MetalBuffer,
+        offset: usize,
+        threadgroup_size: Size3,
+    ) -> Result<()> {
+        self.run_dispatch(
+            shader,
+            buffers,
+            &[],
+            Grid::Indirect(arguments, offset),
+            threadgroup_size,
+        )
+    }
+
+    /// Dispatch `threads` threads with `textures` bound after `buffers`.
+    ///
+    /// Textures are bound like `[[texture(n)]]`, in order. They must have
+    /// [`TextureUsage::SHADER_READ`] or [`TextureUsage::SHADER_WRITE`]
+    /// usage; on the CPU backend, writes to textures without
+    /// `SHADER_WRITE` are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a texture is on another device, predates the
+    /// last recovery, or is not usable from shaders, or for the reasons
+    /// listed on [`dispatch_threads`](Self::dispatch_threads).
+    pub fn dispatch_with_textures(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        textures: &[&MetalTexture],
+        threads: Size3,
+        threadgroup_size: Size3,
+    ) -> Result<()> {
+        self.run_dispatch(
+            shader,
+            buffers,
+            textures,
+            Grid::Threads(threads),
+            threadgroup_size,
+        )
+    }
+
+    fn run_dispatch(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        textures: &[&MetalTexture],
+        grid: Grid<'_>,
+        threadgroup_size: Size3,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let execution = self.encode_dispatch(shader, buffers, textures, grid, threadgroup_size)?;
+        let encoded = Instant::now();
+
+        self.inject(FaultOp::Dispatch)?;
+        // Synchronous work runs after everything already committed.
+        #[cfg(feature = "async")]
+        self.wait_idle();
+        let scheduled = Instant::now();
+
+        let threads = execution.run()?;
+        if let Some(profiler) = &self.profiler {
+            execution.record(profiler, threads, started, encoded, scheduled);
+        }
+        Ok(())
+    }
+
+    /// Commit a dispatch of `threads` threads and return without waiting.
+    ///
+    /// Validation happens immediately; the dispatch then runs after
+    /// previously committed work, off the calling thread. The returned
+    /// future resolves when it completes, and its profile (if a
+    /// [`Profiler`] is attached) is recorded then.
+    ///
+    /// Synchronous dispatches wait for committed work first, but
+    /// [`MetalBuffer::read`] does not: await the future, or use
+    /// [`read_buffer_async`](Self::read_buffer_async), before reading
+    /// results.
+    ///
+    /// The future resolves to an error for the reasons listed on
+    /// [`dispatch_threads`](Self::dispatch_threads).
+    #[cfg(feature = "async")]
+    pub fn dispatch_async(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        threads: Size3,
+        threadgroup_size: Size3,
+    ) -> GpuFuture<()> {
+        let started = Instant::now();
+        let execution = match self.encode_dispatch(
+            shader,
+            buffers,
+            &[],
+            Grid::Threads(threads),
+            threadgroup_size,
+        ) {
+            Ok(execution) => execution,
+            Err(e) => return GpuFuture::ready(Err(e)),
+        };
+        let encoded = Instant::now();
+        if let Err(e) = self.inject(FaultOp::Dispatch) {
+            return GpuFuture::ready(Err(e));
+        }
+
+        let profiler = self.profiler.clone();
+        let (completion, future) = completion::Completion::new();
+        self.submit(Box::new(move || {
+            let scheduled = Instant::now();
+            let result = execution.run().map(|threads| {
+                if let Some(profiler) = &profiler {
+                    execution.record(profiler, threads, started, encoded, scheduled);
+                }
+            });
+            completion.complete(result);
+        }));
+        future
+    }
+
+    /// Read `buffer` as elements once previously committed work completes.
+    ///
+    /// The future resolves to an error if the buffer's device is lost or
+    /// its heap was reset.
+    #[cfg(feature = "async")]
+    pub fn read_buffer_async<T>(&self, buffer: &MetalBuffer) -> GpuFuture<Vec<T>>
+    where
+        T: BufferElement + Send + 'static,
+    {
+        if !buffer.epoch.is_alive() {
+            return GpuFuture::ready(Err(Error::device_lost(buffer.device_index)));
+        }
+        if buffer.is_released() {
+            return GpuFuture::ready(Err(Error::invalid_input(
+                "buffer was released by a heap reset",
+            )));
+        }
+        let view = buffer.view();
+        let (completion, future) = completion::Completion::new();
+        self.submit(Box::new(move || {
+            completion.complete(Ok(storage::from_bytes(&view.read())));
+        }));
+        future
+    }
+
+    /// Commit `job` to the command queue, starting it on first use.
+    ///
+    /// If the queue cannot start, `job` is dropped, which fails its
+    /// future.
+    #[cfg(feature = "async")]
+    fn submit(&self, job: Box<dyn FnOnce() + Send>) {
+        let mut queue = self.queue.borrow_mut();
+        if queue.is_none() {
+            match completion::CommandQueue::new() {
+                Ok(started) => *queue = Some(started),
+                Err(e) => {
+                    warn!(device = self.device.index, error = %e, "Metal command queue unavailable");
+                    return;
+                }
+            }
+        }
+        if let Some(queue) = queue.as_ref() {
+            queue.submit(job);
+        }
+    }
+
+    /// Block until all committed asynchronous work has completed.
+    #[cfg(feature = "async")]
+    fn wait_idle(&self) {
+        if let Some(queue) = self.queue.borrow().as_ref() {
+            queue.wait_idle();
+        }
+    }
+
+    /// Validate a dispatch and capture what it needs to run.
+    ///
+    /// The CPU kernel is captured on the CPU backend.
+    fn encode_dispatch(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        textures: &[&MetalTexture],
+        grid: Grid<'_>,
+        threadgroup_size: Size3,
+    ) -> Result<Execution> {
+        self.check_device()?;
+        let arguments = match grid {
+            Grid::Indirect(arguments, _) => Some(arguments),
+            Grid::Threads(_) | Grid::Threadgroups(_) => None,
+        };
+        let bound = || buffers.iter().copied().chain(arguments);
+        if !shader.is_valid()
+            || bound().any(|buffer| !buffer.epoch.is_alive())
+            || textures.iter().any(|texture| !texture.is_valid())
+        {
+            return Err(Error::device_lost(self.device.index));
+        }
+        if bound().any(MetalBuffer::is_released) {
+            return Err(Error::invalid_input("buffer was released by a heap reset"));
+        }
+
+        // Validate grid size
+        match grid {
+            Grid::Threads((x, y, z)) | Grid::Threadgroups((x, y, z)) => {
+                if x == 0 || y == 0 || z == 0 {
+                    return Err(Error::invalid_input("grid size dimensions cannot be zero"));
+                }
+            }
+            Grid::Indirect(arguments, offset) => {
+                IndirectArguments::check_offset(arguments, offset)?;
+            }
+        }
+
+        // Validate threadgroup size against the pipeline and device
+        self.threadgroup_limits(shader).validate(threadgroup_size)?;
+
+        // Validate buffers belong to this device
+        for buffer in bound() {
+            if buffer.device_index != self.device.index {
+                return Err(Error::invalid_input("buffer allocated on different device"));
+            }
+        }
+        for (index, buffer) in buffers.iter().enumerate() {
+            if let (Some(ours), Some(kernel)) = (&buffer.uniforms, shader.buffer_parameter(index)) {
+                ours.check_compatible(kernel).map_err(|e| {
+                    Error::invalid_input(format!(
+                        "buffer({index}) of kernel '{}': {e}",
+                        shader.name
+                    ))
+                })?;
+            }
+        }
+        for texture in textures {
+            if texture.device_index() != self.device.index {
+                return Err(Error::invalid_input(
+                    "texture allocated on different device",
+                ));
+            }
+            if !texture
+                .usage()
+                .intersects(TextureUsage::SHADER_READ | TextureUsage::SHADER_WRITE)
+            {
+                return Err(Error::invalid_input(
+                    "texture usage must include shader read or write",
+                ));
+            }
+        }
+
+        let cpu_kernel = match self.backend {
+            Backend::Metal => None,
+            Backend::Cpu => Some(shader.cpu_kernel.clone().ok_or_else(|| {
+                Error::invalid_input(format!(
+                    "kernel '{}' has no CPU implementation",
+                    shader.name
+                ))
+            })?),
+        };
+        let extent = match grid {
+            Grid::Threads(threads) => Extent::Threads(threads),
+            Grid::Threadgroups(threadgroups) => Extent::Threads(grid::threads_for_threadgroups(
+                threadgroups,
+                threadgroup_size,
+            )?),
+            Grid::Indirect(arguments, offset) => Extent::Indirect(StorageView::new(
+                arguments.storage.clone(),
+                arguments.offset + offset,
+                IndirectArguments::SIZE,
+            )),
+        };
+        Ok(Execution {
+            kernel: shader.name.clone(),
+            device_index: self.device.index,
+            cpu_kernel,
+            extent,
+            threadgroup: threadgroup_size,
+            buffers: buffers.iter().map(|buffer| buffer.view()).collect(),
+            textures: textures
+                .iter()
+                .map(|texture| (*texture.descriptor(), texture.view()))
+                .collect(),
+            bound_bytes: buffers
+                .iter()
+                .map(|buffer| buffer.len())
+                .chain(textures.iter().map(|texture| texture.len()))
+                .map(|len| len as u64)
+                .sum(),
+            validation: self.validation.clone(),
+        })
+    }
+
+    /// Threadgroup limits for a pipeline on this device.
+    #[must_use]
+    pub const fn threadgroup_limits(&self, shader: &CompiledShader) -> ThreadgroupLimits {
+        let per_dimension = self.device.max_threads_per_threadgroup;
+        ThreadgroupLimits {
+            max_total_threads: shader.max_total_threads_per_threadgroup,
+            thread_execution_width: shader.thread_execution_width,
+            max_per_dimension: (per_dimension, per_dimension, per_dimension),
+        }
+    }
+
+    /// Pick a valid, efficient threadgroup size for dispatching `shader`
+    /// over `grid_size`.
+    ///
+    /// See [`select_threadgroup_size`] for the heuristic.
+    #[must_use]
+    pub fn threadgroup_size_for(&self, shader: &CompiledShader, grid_size: Size3) -> Size3 {
+        select_threadgroup_size(grid_size, &self.threadgroup_limits(shader))
+    }
+
+    /// Benchmark candidate threadgroup sizes and remember the fastest.
+    ///
+    /// Returns the cached choice for this device, kernel, specialization
+    /// and grid if there is one; otherwise times real dispatches of each
+    /// candidate and records the winner in `cache`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a benchmark dispatch fails.
+    pub fn tune_threadgroup(
+        &self,
+        shader: &CompiledShader,
+        buffers: &[&MetalBuffer],
+        grid_size: Size3,
+        tuner: &ThreadgroupTuner,
+        cache: &mut TuningCache,
+    ) -> Result<Size3> {
+        let key = TuningKey::new(&self.device.name, shader.name(), grid_size)
+            .with_specialization(CompileOptions::constants_key(shader.specialization()));
+        if let Some(size) = cache.get(&key) {
+            return Ok(size);
+        }
+
+        let candidates =
+            threadgroup::candidate_threadgroup_sizes(grid_size, &self.threadgroup_limits(shader));
+        let best = tuner.tune(&candidates, |threadgroup| {
+            let start = Instant::now();
+            self.dispatch(shader, buffers, grid_size, threadgroup)?;
+            Ok(start.elapsed())
+        })?;
+
+        debug!(kernel = shader.name(), ?best, "Tuned threadgroup size");
+        cache.insert(key, best);
+        Ok(best)
+    }
+}
+
+/// Check if Metal is available.
+///
+/// Convenience function equivalent to `MetalCompute::is_available()`.
+#[must_use]
+pub fn is_available() -> bool {
+    MetalCompute::is_available()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    #[cfg(feature = "async")]
+    use std::sync::{Condvar, Mutex};
+
+    #[test]
+    fn test_devices_no_panic() {
+        let devices = MetalCompute::devices();
+        // On macOS: at least one device
+        // On other platforms: empty
+        #[cfg(target_os = "macos")]
+        assert!(
+            !devices.is_empty(),
+            "Should have at least one Metal device on macOS"
+        );
+        #[cfg(not(target_os = "macos"))]
+        assert!(
+            devices.is_empty(),
+            "Should have no Metal devices on non-macOS"
+        );
+    }
+
+    #[test]
+    fn test_is_available_consistent() {
+        let available = MetalCompute::is_available();
+        let devices = MetalCompute::devices();
+        assert_eq!(available, !devices.is_empty());
+    }
+
+    #[test]
+    fn test_device_properties() {
+        let devices = MetalCompute::devices();
+        for device in &devices {
+            assert!(!device.name.is_empty());
+            assert!(device.max_threads_per_threadgroup > 0);
+            assert!(device.max_buffer_length > 0);
+            assert!(device.vram_gb() > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_new_invalid_index() {
+        let result = MetalCompute::new(999);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_new_valid_index() {
+        let result = MetalCompute::new(0);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_default_device() {
+        let result = MetalCompute::default_device();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_compile_shader() {
+        let compute = MetalCompute::default_device().unwrap();
+        let shader = compute.compile_shader(
+            r"
+            kernel void add(device float* a [[buffer(0)]],
+                           device float* b [[buffer(1)]],
+                           uint id [[thread_position_in_grid]]) {
+                a[id] = a[id] + b[id];
+            }
+            ",
+            "add",
+        );
+        assert!(shader.is_ok());
+        assert_eq!(shader.unwrap().name(), "add");
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_compile_shader_empty_source() {
+        let compute = MetalCompute::default_device().unwrap();
+        let result = compute.compile_shader("", "test");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_compile_shader_empty_name() {
+        let compute = MetalCompute::default_device().unwrap();
+        let result = compute.compile_shader("kernel void test() {}", "");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_allocate_buffer() {
+        let compute = MetalCompute::default_device().unwrap();
+        let buffer = compute.allocate_buffer(1024);
+        assert!(buffer.is_ok());
+        let buffer = buffer.unwrap();
+        assert_eq!(buffer.len(), 1024);
+        assert!(!buffer.is_empty());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_allocate_buffer_zero() {
+        let compute = MetalCompute::default_device().unwrap();
+        let result = compute.allocate_buffer(0);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_dispatch_invalid_grid() {
+        let compute = MetalCompute::default_device().unwrap();
+        let shader = compute
+            .compile_shader("kernel void test() {}", "test")
+            .unwrap();
+        let result = compute.dispatch(&shader, &[], (0, 1, 1), (1, 1, 1));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_dispatch_invalid_threadgroup() {
+        let compute = MetalCompute::default_device().unwrap();
+        let shader = compute
+            .compile_shader("kernel void test() {}", "test")
+            .unwrap();
+        let result = compute.dispatch(&shader, &[], (64, 64, 1), (32, 32, 2)); // 2048 > 1024
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_convenience_function() {
+        assert_eq!(is_available(), MetalCompute::is_available());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_detect_real_gpus() {
+        // Should detect actual GPUs on Mac via system_profiler
+        let devices = MetalCompute::devices();
+        assert!(!devices.is_empty(), "Should detect at least one GPU");
+
+        // Device name should be real, not stub
+        let first = &devices[0];
+        assert!(
+            !first.name.contains("Intel UHD"),
+            "Should detect real GPU, not stub. Got: {}",
+            first.name
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_detect_gpu_vram() {
+        let devices = MetalCompute::devices();
+        if !devices.is_empty() {
+            // Real GPUs should report VRAM
+            let first = &devices[0];
+            // Mac Pro AMD GPUs have 16GB, Apple Silicon has unified memory
+            assert!(
+                first.vram_gb() >= 1.0,
+                "GPU should report at least 1GB VRAM, got: {} GB",
+                first.vram_gb()
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_cross_device_copy() {
+        let devices = MetalCompute::devices();
+        if devices.len() < 2 {
+            return;
+        }
+        let first = MetalCompute::new(0).unwrap();
+        let second = MetalCompute::new(1).unwrap();
+        let source = first.allocate_buffer(16).unwrap();
+        let destination = second.allocate_buffer(16).unwrap();
+        source.write(0, &[1u32, 2, 3, 4]).unwrap();
+
+        let mut blit = second.blit_encoder();
+        let path = blit
+            .copy_from_device(&first, &source, 0..16, &destination, 0)
+            .unwrap();
+        blit.commit().unwrap();
+        let linked =
+            devices[0].peer_group_id != 0 && devices[0].peer_group_id == devices[1].peer_group_id;
+        let expected = if linked {
+            CopyPath::PeerToPeer
+        } else {
+            CopyPath::Staged
+        };
+        assert_eq!(path, expected);
+        assert_eq!(destination.read::<u32>().unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_metal_buffer_methods() {
+        let buffer = MetalBuffer {
+            length: 1024,
+            device_index: 0,
+            offset: 0,
+            storage: Storage::new(),
+            epoch: DeviceEpoch::new(),
+            _allocation: None,
+            heap: None,
+            uniforms: None,
+        };
+        assert_eq!(buffer.len(), 1024);
+        assert!(!buffer.is_empty());
+        assert_eq!(buffer.device_index(), 0);
+
+        let empty_buffer = MetalBuffer {
+            length: 0,
+            device_index: 0,
+            offset: 0,
+            storage: Storage::new(),
+            epoch: DeviceEpoch::new(),
+            _allocation: None,
+            heap: None,
+            uniforms: None,
+        };
+        assert!(empty_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_epoch_invalidation() {
+        let epoch = DeviceEpoch::new();
+        let buffer = MetalBuffer {
+            length: 16,
+            device_index: 0,
+            offset: 0,
+            storage: Storage::new(),
+            epoch: epoch.clone(),
+            _allocation: None,
+            heap: None,
+            uniforms: None,
+        };
+        assert!(buffer.is_valid());
+        epoch.invalidate();
+        assert!(!buffer.is_valid());
+    }
+
+    /// Shader whose CPU kernel does nothing.
+    fn noop(compute: &MetalCompute) -> CompiledShader {
+        compute
+            .compile_shader("kernel void test() {}", "test")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|_| {}))
+    }
+
+    // F057: Device lost handled gracefully
+    #[test]
+    fn test_device_lost_invalidates_resources() {
+        let compute =
+            MetalCompute::cpu().with_fault_injector(FaultInjector::new().lose_device_after(2));
+        let buffer = compute.allocate_buffer(64).unwrap();
+        let shader = noop(&compute);
+
+        let err = compute
+            .dispatch(&shader, &[&buffer], (1, 1, 1), (1, 1, 1))
+            .unwrap_err();
+        assert!(err.is_device_lost());
+        assert!(compute.is_device_lost());
+        assert!(!buffer.is_valid());
+        assert!(!shader.is_valid());
+        assert!(compute.allocate_buffer(64).unwrap_err().is_device_lost());
+    }
+
+    #[test]
+    fn test_recover_after_device_lost() {
+        let mut compute =
+            MetalCompute::cpu().with_fault_injector(FaultInjector::new().lose_device_after(1));
+        let stale = compute.allocate_buffer(64).unwrap();
+        assert!(compute.allocate_buffer(64).unwrap_err().is_device_lost());
+
+        compute.recover().unwrap();
+        assert!(!compute.is_device_lost());
+
+        let shader = noop(&compute);
+        let fresh = compute.allocate_buffer(64).unwrap();
+        assert!(compute
+            .dispatch(&shader, &[&fresh], (1, 1, 1), (1, 1, 1))
+            .is_ok());
+        assert!(compute
+            .dispatch(&shader, &[&stale], (1, 1, 1), (1, 1, 1))
+            .unwrap_err()
+            .is_device_lost());
+    }
+
+    #[test]
+    fn test_injected_failures_keep_device() {
+        let compute = MetalCompute::cpu().with_fault_injector(
+            FaultInjector::new()
+                .fail_allocation_at(0)
+                .fail_compile_at(0)
+                .fail_dispatch_at(0),
+        );
+        assert!(matches!(
+            compute.allocate_buffer(64),
+            Err(Error::Metal { .. })
+        ));
+        assert!(compute.compile_shader("kernel void t() {}", "t").is_err());
+        let shader = noop(&compute);
+        assert!(compute
+            .dispatch(&shader, &[], (1, 1, 1), (1, 1, 1))
+            .is_err());
+        assert!(compute.dispatch(&shader, &[], (1, 1, 1), (1, 1, 1)).is_ok());
+        assert!(!compute.is_device_lost());
+    }
+
+    /// CPU pipeline on a device of its own, so that its device ledger is
+    /// not shared with other tests.
+    fn isolated_cpu(capacity: u64) -> MetalCompute {
+        static NEXT_REGISTRY_ID: AtomicU64 = AtomicU64::new(1);
+        let device = MetalDevice {
+            registry_id: u64::MAX - NEXT_REGISTRY_ID.fetch_add(1, Ordering::Relaxed),
+            vram_bytes: capacity,
+            ..MetalDevice::cpu()
+        };
+        MetalCompute::with_device(device, Backend::Cpu)
+    }
+
+    #[test]
+    fn test_buffer_memory_tracked_until_drop() {
+        let compute = isolated_cpu(1 << 20);
+        let a = compute.allocate_buffer(4096).unwrap();
+        let b = compute.allocate_buffer(1024).unwrap();
+        assert_eq!(compute.memory_usage().in_use, 5120);
+        assert_eq!(compute.memory_usage().live_buffers, 2);
+        assert_eq!(compute.device_memory_usage().in_use, 5120);
+
+        drop(a);
+        drop(b);
+        let usage = compute.memory_usage();
+        assert_eq!(usage.in_use, 0);
+        assert_eq!(usage.live_buffers, 0);
+        assert_eq!(usage.high_water, 5120);
+        assert_eq!(compute.device_memory_usage().in_use, 0);
+    }
+
+    #[test]
+    fn test_hard_budget_enforced() {
+        let compute =
+            MetalCompute::cpu().with_memory_budget(MemoryBudget::new().with_hard_limit(10_000));
+        let _a = compute.allocate_buffer(6_000).unwrap();
+        let err = compute.allocate_buffer(6_000).unwrap_err();
+        assert_eq!(err, Error::out_of_memory(6_000, 4_000));
+        assert_eq!(compute.memory_usage().in_use, 6_000);
+    }
+
+    #[test]
+    fn test_soft_budget_does_not_fail() {
+        let compute =
+            MetalCompute::cpu().with_memory_budget(MemoryBudget::new().with_soft_limit(1_000));
+        assert!(compute.allocate_buffer(2_000).is_ok());
+    }
+
+    #[test]
+    fn test_device_capacity_enforced() {
+        let compute = isolated_cpu(1 << 20);
+        let capacity = compute.memory_usage().capacity;
+        let chunk = usize::try_from(capacity / 3 + 1).unwrap();
+        let buffers: Vec<_> = (0..10)
+            .filter_map(|_| compute.allocate_buffer(chunk).ok())
+            .collect();
+        assert_eq!(buffers.len(), 2);
+        assert!(compute.device_memory_usage().in_use <= capacity);
+    }
+
+    #[test]
+    fn test_failed_allocation_releases_charge() {
+        let compute =
+            MetalCompute::cpu().with_fault_injector(FaultInjector::new().fail_allocation_at(0));
+        assert!(compute.allocate_buffer(1024).is_err());
+        assert_eq!(compute.memory_usage().in_use, 0);
+    }
+
+    #[test]
+    fn test_heap_charged_once() {
+        let compute = MetalCompute::cpu();
+        let heap = compute.create_heap(1 << 20).unwrap();
+        let slices: Vec<_> = (0..100).map(|_| heap.allocate(1024).unwrap()).collect();
+        assert_eq!(compute.memory_usage().in_use, 1 << 20);
+        assert_eq!(compute.memory_usage().live_buffers, 1);
+        assert!(slices.iter().all(MetalBuffer::is_heap_allocated));
+
+        drop(slices);
+        drop(heap);
+        assert_eq!(compute.memory_usage().in_use, 0);
+    }
+
+    #[test]
+    fn test_dispatch_rejects_reset_heap_slice() {
+        let compute = MetalCompute::cpu();
+        let shader = noop(&compute);
+        let heap = compute.create_heap(4096).unwrap();
+        let slice = heap.allocate(64).unwrap();
+        assert!(compute
+            .dispatch(&shader, &[&slice], (1, 1, 1), (1, 1, 1))
+            .is_ok());
+
+        heap.reset();
+        let err = compute
+            .dispatch(&shader, &[&slice], (1, 1, 1), (1, 1, 1))
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidInput { .. }));
+    }
+
+    #[test]
+    fn test_reset_heap_slice_cannot_touch_new_slices() {
+        let compute = MetalCompute::cpu();
+        let heap = compute.create_heap(4096).unwrap();
+        let stale = heap.allocate(16).unwrap();
+        stale.write(0, &[1u32, 2, 3, 4]).unwrap();
+
+        heap.reset();
+        let fresh = heap.allocate(16).unwrap();
+        assert_eq!(fresh.offset(), stale.offset());
+        fresh.write(0, &[5u32, 6, 7, 8]).unwrap();
+
+        assert!(matches!(
+            stale.write(0, &[9u32; 4]),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(stale.write_bytes(0, &[9; 16]).is_err());
+        assert!(stale.read::<u32>().is_err());
+        assert!(stale.read_bytes(0, 4).is_err());
+        assert!(!stale.is_valid());
+        assert_eq!(fresh.read::<u32>().unwrap(), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_threadgroup_size_for_is_dispatchable() {
+        let compute = MetalCompute::cpu();
+        let shader = noop(&compute);
+        for grid in [(1, 1, 1), (1000, 1, 1), (1920, 1080, 1), (64, 64, 64)] {
+            let threadgroup = compute.threadgroup_size_for(&shader, grid);
+            assert!(compute.dispatch(&shader, &[], grid, threadgroup).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_dispatch_rejects_zero_threadgroup() {
+        let compute = MetalCompute::cpu();
+        let shader = noop(&compute);
+        assert!(compute
+            .dispatch(&shader, &[], (1, 1, 1), (0, 1, 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_tune_threadgroup_caches_winner() {
+        let compute = MetalCompute::cpu();
+        let runs = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&runs);
+        let shader = compute
+            .compile_shader("kernel void test() {}", "test")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }));
+        let tuner = ThreadgroupTuner::new().with_iterations(1);
+        let mut cache = TuningCache::new();
+
+        let best = compute
+            .tune_threadgroup(&shader, &[], (256, 1, 1), &tuner, &mut cache)
+            .unwrap();
+        assert!(compute.threadgroup_limits(&shader).validate(best).is_ok());
+        let key = TuningKey::new(compute.device_name(), "test", (256, 1, 1));
+        assert_eq!(cache.get(&key), Some(best));
+
+        let tuned_runs = runs.load(Ordering::Relaxed);
+        assert!(tuned_runs > 0);
+        let cached = compute
+            .tune_threadgroup(&shader, &[], (256, 1, 1), &tuner, &mut cache)
+            .unwrap();
+        assert_eq!(cached, best);
+        assert_eq!(runs.load(Ordering::Relaxed), tuned_runs);
+
+        // Another grid is tuned separately.
+        compute
+            .tune_threadgroup(&shader, &[], (16, 16, 1), &tuner, &mut cache)
+            .unwrap();
+        assert!(runs.load(Ordering::Relaxed) > tuned_runs);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_cpu_backend_always_available() {
+        let compute = MetalCompute::cpu();
+        assert_eq!(compute.backend(), Backend::Cpu);
+        assert_eq!(compute.device_index(), CPU_DEVICE_INDEX);
+        assert_eq!(compute.device_name(), "CPU");
+        assert_eq!(Backend::Cpu.to_string(), "CPU");
+        assert_eq!(Backend::Metal.to_string(), "Metal");
+    }
+
+    #[test]
+    fn test_buffer_read_write() {
+        let compute = MetalCompute::cpu();
+        let buffer = compute.allocate_buffer(16).unwrap();
+        assert_eq!(buffer.read::<u32>().unwrap(), vec![0; 4]);
+
+        buffer.write(1, &[7u32, 8]).unwrap();
+        assert_eq!(buffer.read::<u32>().unwrap(), vec![0, 7, 8, 0]);
+        assert_eq!(buffer.read_bytes(4, 1).unwrap(), vec![7]);
+
+        assert!(buffer.write(3, &[1u32, 2]).is_err());
+        assert!(buffer.read_bytes(12, 8).is_err());
+        assert!(buffer.write(usize::MAX, &[1u32]).is_err());
+    }
+
+    #[test]
+    fn test_heap_slices_share_storage() {
+        let compute = MetalCompute::cpu();
+        let heap = compute.create_heap(4096).unwrap();
+        let a = heap.allocate(8).unwrap();
+        let b = heap.allocate(8).unwrap();
+        a.write(0, &[1.0f32, 2.0]).unwrap();
+        b.write(0, &[3.0f32, 4.0]).unwrap();
+        assert_eq!(a.read::<f32>().unwrap(), vec![1.0, 2.0]);
+        assert_eq!(b.read::<f32>().unwrap(), vec![3.0, 4.0]);
+
+        heap.make_aliasable(&a).unwrap();
+        let c = heap.allocate(8).unwrap();
+        assert_eq!(c.read::<f32>().unwrap(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_cpu_dispatch_runs_kernel() {
+        let compute = MetalCompute::cpu();
+        let shader = compute
+            .compile_shader("kernel void iota() {}", "iota")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let (x, y, _) = ctx.thread_position();
+                let width = ctx.grid_size().0;
+                ctx.write_u32(0, (y * width + x) as usize, y * width + x);
+            }));
+        let buffer = compute.allocate_buffer(24).unwrap();
+        compute
+            .dispatch(&shader, &[&buffer], (3, 2, 1), (3, 2, 1))
+            .unwrap();
+        assert_eq!(buffer.read::<u32>().unwrap(), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    /// Shader that marks every thread position it runs at in binding 0.
+    fn marker(compute: &MetalCompute) -> CompiledShader {
+        compute
+            .compile_shader("kernel void mark() {}", "mark")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let i = ctx.thread_position().0 as usize;
+                ctx.write_u32(0, i, ctx.threadgroup_position().0 + 1);
+            }))
+    }
+
+    #[test]
+    fn test_dispatch_threads_is_non_uniform() {
+        let compute = MetalCompute::cpu();
+        let shader = marker(&compute);
+        let buffer = compute.allocate_buffer(48).unwrap();
+        compute
+            .dispatch_threads(&shader, &[&buffer], (10, 1, 1), (4, 1, 1))
+            .unwrap();
+        assert_eq!(
+            buffer.read::<u32>().unwrap(),
+            vec![1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_dispatch_threadgroups_covers_whole_groups() {
+        let compute = MetalCompute::cpu();
+        let shader = marker(&compute);
+        let buffer = compute.allocate_buffer(48).unwrap();
+        compute
+            .dispatch_threadgroups(&shader, &[&buffer], (3, 1, 1), (4, 1, 1))
+            .unwrap();
+        assert_eq!(
+            buffer.read::<u32>().unwrap(),
+            vec![1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]
+        );
+
+        let err = compute
+            .dispatch_threadgroups(&shader, &[&buffer], (u32::MAX, 1, 1), (4, 1, 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("overflow"));
+        assert!(compute
+            .dispatch_threadgroups(&shader, &[&buffer], (0, 1, 1), (4, 1, 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_dispatch_indirect_reads_counts_from_earlier_kernel() {
+        let compute = MetalCompute::cpu();
+        // Writes ceil(n / 4) threadgroups for the n stored in binding 0.
+        let count = compute
+            .compile_shader("kernel void count() {}", "count")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let n = ctx.read_u32(0, 0);
+                ctx.write_u32(1, 1, n.div_ceil(4));
+                ctx.write_u32(1, 2, 1);
+                ctx.write_u32(1, 3, 1);
+            }));
+        let shader = marker(&compute);
+        let input = compute.allocate_buffer(4).unwrap();
+        input.write(0, &[6u32]).unwrap();
+        let arguments = compute.allocate_buffer(16).unwrap();
+        let output = compute.allocate_buffer(48).unwrap();
+
+        compute
+            .dispatch(&count, &[&input, &arguments], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+        assert_eq!(
+            IndirectArguments::read(&arguments, 4).unwrap(),
+            IndirectArguments::new((2, 1, 1))
+        );
+        compute
+            .dispatch_indirect(&shader, &[&output], &arguments, 4, (4, 1, 1))
+            .unwrap();
+        assert_eq!(
+            output.read::<u32>().unwrap(),
+            vec![1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0]
+        );
+
+        // A zero count dispatches nothing.
+        IndirectArguments::new((0, 1, 1))
+            .write(&arguments, 0)
+            .unwrap();
+        let empty = compute.allocate_buffer(4).unwrap();
+        compute
+            .dispatch_indirect(&shader, &[&empty], &arguments, 0, (4, 1, 1))
+            .unwrap();
+        assert_eq!(empty.read::<u32>().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_dispatch_indirect_validates_arguments() {
+        let compute = MetalCompute::cpu();
+        let shader = marker(&compute);
+        let arguments = compute.allocate_buffer(16).unwrap();
+        let err = compute
+            .dispatch_indirect(&shader, &[], &arguments, 2, (1, 1, 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("aligned"));
+        let err = compute
+            .dispatch_indirect(&shader, &[], &arguments, 8, (1, 1, 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("exceed buffer"));
+
+        IndirectArguments::new((u32::MAX, 1, 1))
+            .write(&arguments, 0)
+            .unwrap();
+        let err = compute
+            .dispatch_indirect(&shader, &[], &arguments, 0, (2, 1, 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("overflow"));
+    }
+
+    #[test]
+    fn test_texture_upload_and_readback() {
+        let compute = MetalCompute::cpu();
+        let texture = compute
+            .create_texture(&TextureDescriptor::texture_2d_array(
+                PixelFormat::R8Unorm,
+                4,
+                3,
+                2,
+            ))
+            .unwrap();
+        assert_eq!(compute.memory_usage().in_use, 24);
+        texture
+            .replace_region(Region::new_2d(1, 1, 2, 2), 1, &[1, 2, 3, 4])
+            .unwrap();
+        assert_eq!(
+            texture.read_region(Region::new_2d(0, 1, 4, 1), 1).unwrap(),
+            vec![0, 1, 2, 0]
+        );
+        assert_eq!(
+            texture.read_region(texture.region(), 0).unwrap(),
+            vec![0; 12]
+        );
+        assert!(texture
+            .replace_region(Region::new_2d(3, 0, 2, 1), 0, &[0, 0])
+            .is_err());
+        assert!(texture
+            .replace_region(Region::new_2d(0, 0, 1, 1), 2, &[0])
+            .is_err());
+        assert!(texture
+            .replace_region(Region::new_2d(0, 0, 2, 1), 0, &[0])
+            .is_err());
+        drop(texture);
+        assert_eq!(compute.memory_usage().in_use, 0);
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn test_cpu_dispatch_samples_textures() {
+        let compute = MetalCompute::cpu();
+        // Downscale RGBA to half-size grayscale with bilinear sampling.
+        let shader = compute
+            .compile_shader("kernel void downscale() {}", "downscale")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let (col, row, _) = ctx.thread_position();
+                let (width, height, _) = ctx.texture_size(1);
+                let sampler = Sampler::new().with_filter(SamplerFilter::Linear);
+                let coord = [
+                    (col as f32 + 0.5) / width as f32,
+                    (row as f32 + 0.5) / height as f32,
+                    0.0,
+                ];
+                let [red, green, blue, _] = ctx.sample(0, &sampler, coord, 0);
+                let gray = (red + green + blue) / 3.0;
+                ctx.write_texture(1, (col, row, 0), 0, [gray, 0.0, 0.0, 1.0]);
+            }));
+        let input = compute
+            .create_texture(&TextureDescriptor::texture_2d(
+                PixelFormat::Rgba8Unorm,
+                4,
+                2,
+            ))
+            .unwrap();
+        let mut pixels = Vec::new();
+        for x in 0..8u8 {
+            let v = if x % 4 < 2 { 0 } else { 255 };
+            pixels.extend_from_slice(&[v, v, v, 255]);
+        }
+        input.replace_region(input.region(), 0, &pixels).unwrap();
+        let output = compute
+            .create_texture(
+                &TextureDescriptor::texture_2d(PixelFormat::R32Float, 2, 1)
+                    .with_usage(TextureUsage::SHADER_WRITE),
+            )
+            .unwrap();
+
+        compute
+            .dispatch_with_textures(&shader, &[], &[&input, &output], (2, 1, 1), (2, 1, 1))
+            .unwrap();
+        let left = output.read_pixel((0, 0, 0), 0).unwrap()[0];
+        let right = output.read_pixel((1, 0, 0), 0).unwrap()[0];
+        assert!(left.abs() < 1e-6, "{left}");
+        assert!((right - 1.0).abs() < 1e-6, "{right}");
+    }
+
+    #[test]
+    fn test_dispatch_texture_validation() {
+        let compute = MetalCompute::cpu();
+        let shader = compute
+            .compile_shader("kernel void write() {}", "write")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                ctx.write_texture(0, (0, 0, 0), 0, [1.0; 4]);
+            }));
+        let read_only = compute
+            .create_texture(&TextureDescriptor::texture_1d(PixelFormat::R8Unorm, 1))
+            .unwrap();
+        compute
+            .dispatch_with_textures(&shader, &[], &[&read_only], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+        assert_eq!(
+            read_only.read_region(read_only.region(), 0).unwrap(),
+            vec![0]
+        );
+
+        let attachment = compute
+            .create_texture(
+                &TextureDescriptor::texture_1d(PixelFormat::R8Unorm, 1)
+                    .with_usage(TextureUsage::RENDER_TARGET),
+            )
+            .unwrap();
+        let err = compute
+            .dispatch_with_textures(&shader, &[], &[&attachment], (1, 1, 1), (1, 1, 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("shader read or write"));
+
+        assert!(compute
+            .create_texture(&TextureDescriptor::texture_2d(PixelFormat::R8Unorm, 0, 4))
+            .is_err());
+    }
+
+    /// Shader whose CPU kernel waits for `gate` to open, then increments
+    /// each word of binding 0.
+    #[cfg(feature = "async")]
+    fn gated(compute: &MetalCompute, gate: &Arc<(Mutex<bool>, Condvar)>) -> CompiledShader {
+        let gate = Arc::clone(gate);
+        compute
+            .compile_shader("kernel void gated() {}", "gated")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(move |ctx| {
+                let (open, opened) = &*gate;
+                drop(
+                    opened
+                        .wait_while(open.lock().unwrap(), |open| !*open)
+                        .unwrap(),
+                );
+                let i = ctx.thread_position().0 as usize;
+                ctx.write_u32(0, i, ctx.read_u32(0, i) + 1);
+            }))
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_dispatch_async_returns_before_completion() {
+        let profiler = Profiler::new();
+        let compute = MetalCompute::cpu().with_profiler(profiler.clone());
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+        let shader = gated(&compute, &gate);
+        let buffer = compute.allocate_buffer(8).unwrap();
+
+        let first = compute.dispatch_async(&shader, &[&buffer], (2, 1, 1), (2, 1, 1));
+        let second = compute.dispatch_async(&shader, &[&buffer], (2, 1, 1), (2, 1, 1));
+        let readback = compute.read_buffer_async::<u32>(&buffer);
+        assert!(!first.is_complete());
+        assert!(!readback.is_complete());
+        assert_eq!(buffer.read::<u32>().unwrap(), vec![0, 0]);
+
+        *gate.0.lock().unwrap() = true;
+        gate.1.notify_all();
+        completion::block_on(first).unwrap();
+        completion::block_on(second).unwrap();
+        assert_eq!(completion::block_on(readback).unwrap(), vec![2, 2]);
+        assert_eq!(profiler.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_sync_dispatch_waits_for_async_work() {
+        let compute = MetalCompute::cpu();
+        let gate = Arc::new((Mutex::new(true), Condvar::new()));
+        let shader = gated(&compute, &gate);
+        let buffer = compute.allocate_buffer(4).unwrap();
+        let pending = compute.dispatch_async(&shader, &[&buffer], (1, 1, 1), (1, 1, 1));
+        compute
+            .dispatch(&shader, &[&buffer], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+        assert!(pending.is_complete());
+        assert_eq!(buffer.read::<u32>().unwrap(), vec![2]);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_dispatch_async_errors_resolve_immediately() {
+        let compute =
+            MetalCompute::cpu().with_fault_injector(FaultInjector::new().fail_dispatch_at(0));
+        let gate = Arc::new((Mutex::new(true), Condvar::new()));
+        let shader = gated(&compute, &gate);
+        let buffer = compute.allocate_buffer(4).unwrap();
+
+        let invalid = compute.dispatch_async(&shader, &[&buffer], (0, 1, 1), (1, 1, 1));
+        assert!(invalid.is_complete());
+        assert!(completion::block_on(invalid).is_err());
+        let injected = compute.dispatch_async(&shader, &[&buffer], (1, 1, 1), (1, 1, 1));
+        assert!(completion::block_on(injected)
+            .unwrap_err()
+            .to_string()
+            .contains("injected"));
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    #[allow(clippy::panic)]
+    fn test_panicking_kernel_fails_only_its_future() {
+        let compute = MetalCompute::cpu();
+        let shader = compute
+            .compile_shader("kernel void boom() {}", "boom")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|_| panic!("kernel failure")));
+        let buffer = compute.allocate_buffer(4).unwrap();
+        buffer.write(0, &[5u32]).unwrap();
+        let failed = compute.dispatch_async(&shader, &[&buffer], (1, 1, 1), (1, 1, 1));
+        assert!(completion::block_on(failed).is_err());
+        assert_eq!(
+            completion::block_on(compute.read_buffer_async::<u32>(&buffer)).unwrap(),
+            vec![5]
+        );
+    }
+
+    #[test]
+    fn test_cpu_dispatch_requires_cpu_kernel() {
+        let compute = MetalCompute::cpu();
+        let shader = compute
+            .compile_shader("kernel void test() {}", "test")
+            .unwrap();
+        let err = compute
+            .dispatch(&shader, &[], (1, 1, 1), (1, 1, 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("no CPU implementation"));
+    }
+
+    #[test]
+    fn test_cpu_recover() {
+        let mut compute =
+            MetalCompute::cpu().with_fault_injector(FaultInjector::new().lose_device_after(0));
+        let err = compute.allocate_buffer(16).unwrap_err();
+        assert!(err.is_device_lost());
+        compute.recover().unwrap();
+        assert_eq!(compute.backend(), Backend::Cpu);
+        assert!(compute.allocate_buffer(16).is_ok());
+    }
+
+    #[test]
+    fn test_validation_flags_races_and_uninitialized_reads() {
+        let validation = Validation::all();
+        let compute = MetalCompute::cpu().with_validation(validation.clone());
+        assert!(compute.validation().is_some());
+        let buffer = compute.allocate_buffer(16).unwrap();
+        buffer.write(0, &[5u32]).unwrap();
+
+        // Every thread reads word 0 and writes word 1: word 1 races.
+        let racy = compute
+            .compile_shader("kernel void racy() {}", "racy")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                ctx.write_u32(0, 1, ctx.read_u32(0, 0));
+            }));
+        let err = compute
+            .dispatch(&racy, &[&buffer], (2, 1, 1), (2, 1, 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("data race on buffer(0)[1]"));
+        assert_eq!(
+            validation.violations()[0].kind,
+            ViolationKind::Race { other: (0, 0, 0) }
+        );
+        // The dispatch still ran.
+        assert_eq!(buffer.read::<u32>().unwrap()[1], 5);
+
+        // Words 0 and 1 are initialized now; 2 and 3 are not.
+        validation.clear();
+        let sum = compute
+            .compile_shader("kernel void sum() {}", "sum")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                let i = ctx.thread_position().0 as usize;
+                ctx.atomic_fetch_add_u32(0, 0, ctx.read_u32(0, i));
+            }));
+        assert!(compute
+            .dispatch(&sum, &[&buffer], (2, 1, 1), (2, 1, 1))
+            .is_ok());
+        assert_eq!(buffer.read::<u32>().unwrap()[0], 15);
+        compute
+            .dispatch(&sum, &[&buffer], (4, 1, 1), (4, 1, 1))
+            .unwrap_err();
+        let uninitialized: Vec<_> = validation
+            .violations()
+            .iter()
+            .map(|v| (v.kind, v.thread, v.index))
+            .collect();
+        assert_eq!(
+            uninitialized,
+            vec![
+                (ViolationKind::UninitializedRead, (2, 0, 0), 2),
+                (ViolationKind::UninitializedRead, (3, 0, 0), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profiler_records_dispatches() {
+        let profiler = Profiler::new();
+        let compute = MetalCompute::cpu().with_profiler(profiler.clone());
+        assert!(compute.profiler().is_some());
+        let shader = compute
+            .compile_shader("kernel void noop() {}", "noop")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|_| {}));
+        let a = compute.allocate_buffer(64).unwrap();
+        let b = compute.allocate_buffer(32).unwrap();
+
+        compute
+            .dispatch(&shader, &[&a, &b], (8, 2, 1), (4, 2, 1))
+            .unwrap();
+        compute
+            .dispatch(&shader, &[], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+        assert!(compute
+            .dispatch(&shader, &[], (0, 1, 1), (1, 1, 1))
+            .is_err());
+
+        let records = profiler.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].kernel, "noop");
+        assert_eq!(records[0].grid, (8, 2, 1));
+        assert_eq!(records[0].threadgroup, (4, 2, 1));
+        assert_eq!(records[0].buffer_bytes, 96);
+        assert_eq!(records[0].device_index, CPU_DEVICE_INDEX);
+        assert!(records[1].start >= records[0].start + records[0].total());
+        assert_eq!(profiler.histogram("noop").unwrap().count(), 2);
+    }
+
+    const SPECIALIZED: &str = "
+        constant uint FACTOR [[function_constant(0)]];
+        constant bool NEGATE [[function_constant(1)]];
+        kernel void scale(device uint* x [[buffer(0)]], uint i [[thread_position_in_grid]]) {
+            x[i] *= NEGATE ? -FACTOR : FACTOR;
+        }";
+
+    #[test]
+    fn test_specializations_cached_separately() {
+        let compute = MetalCompute::cpu();
+        let double = CompileOptions::new()
+            .with_constant("FACTOR", 2u32)
+            .with_constant("NEGATE", false);
+        let a = compute
+            .compile_shader_with(SPECIALIZED, "scale", &double)
+            .unwrap();
+        let reordered = CompileOptions::new()
+            .with_constant_index(1, false)
+            .with_constant_index(0, 2u32);
+        let b = compute
+            .compile_shader_with(SPECIALIZED, "scale", &reordered)
+            .unwrap();
+        assert_eq!(compute.cached_specializations(), 1);
+        assert_eq!(a.source, b.source);
+
+        let triple = CompileOptions::new()
+            .with_constant("FACTOR", 3u32)
+            .with_constant("NEGATE", false);
+        let c = compute
+            .compile_shader_with(SPECIALIZED, "scale", &triple)
+            .unwrap();
+        assert_eq!(compute.cached_specializations(), 2);
+        assert_eq!(c.function_constant("FACTOR"), Some(ConstantValue::UInt(3)));
+
+        let defined = double.clone().with_define("WIDE", "1");
+        let d = compute
+            .compile_shader_with(SPECIALIZED, "scale", &defined)
+            .unwrap();
+        assert_eq!(compute.cached_specializations(), 3);
+        assert_ne!(a.source, d.source);
+
+        // "Aa" and "BB" collide under a polynomial-31 hash; the cache must
+        // still tell the sources apart.
+        let aa = compute
+            .compile_shader_with(&format!("{SPECIALIZED}// Aa"), "scale", &double)
+            .unwrap();
+        let bb = compute
+            .compile_shader_with(&format!("{SPECIALIZED}// BB"), "scale", &double)
+            .unwrap();
+        assert_eq!(compute.cached_specializations(), 5);
+        assert_ne!(aa.source, bb.source);
+    }
+
+    #[test]
+    fn test_unset_function_constant_fails() {
+        let compute = MetalCompute::cpu();
+        let err = compute.compile_shader(SPECIALIZED, "scale").unwrap_err();
+        assert!(err.to_string().contains("'FACTOR' (index 0) is not set"));
+
+        let partial = CompileOptions::new().with_constant("FACTOR", 2u32);
+        assert!(compute
+            .compile_shader_with(SPECIALIZED, "scale", &partial)
+            .is_err());
+        assert_eq!(compute.cached_specializations(), 0);
+    }
+
+    #[test]
+    fn test_unused_function_constant_may_be_unset() {
+        let source = r"
+            constant uint TILE [[function_constant(0)]];
+            kernel void tiled(device uint* out [[buffer(0)]]) { out[0] = TILE; }
+            kernel void plain(device uint* out [[buffer(0)]]) { out[0] = 1; }
+        ";
+        let compute = MetalCompute::cpu();
+        assert!(compute.compile_shader(source, "plain").is_ok());
+        assert!(compute
+            .compile_shader_with(source, "plain", &CompileOptions::new())
+            .is_ok());
+        let err = compute.compile_shader(source, "tiled").unwrap_err();
+        assert!(err.to_string().contains("'TILE' (index 0) is not set"));
+    }
+
+    #[test]
+    fn test_specialized_cpu_kernel_reads_constants() {
+        let compute = MetalCompute::cpu();
+        let options = CompileOptions::new()
+            .with_constant("FACTOR", 5u32)
+            .with_constant("NEGATE", false);
+        let shader = compute
+            .compile_shader_with(SPECIALIZED, "scale", &options)
+            .unwrap();
+        let Some(ConstantValue::UInt(factor)) = shader.function_constant("FACTOR") else {
+            unreachable!("FACTOR is set");
+        };
+        let shader = shader.with_cpu_kernel(CpuKernel::new(move |ctx| {
+            let i = ctx.thread_position().0 as usize;
+            ctx.write_u32(0, i, ctx.read_u32(0, i) * factor);
+        }));
+        let buffer = compute.allocate_buffer(8).unwrap();
+        buffer.write(0, &[1u32, 2]).unwrap();
+        compute
+            .dispatch(&shader, &[&buffer], (2, 1, 1), (2, 1, 1))
+            .unwrap();
+        assert_eq!(buffer.read::<u32>().unwrap(), vec![5, 10]);
+    }
+
+    #[test]
+    fn test_specialization_cache_cleared_on_recover() {
+        let mut compute = MetalCompute::cpu();
+        let options = CompileOptions::new()
+            .with_constant("FACTOR", 1u32)
+            .with_constant("NEGATE", true);
+        let stale = compute
+            .compile_shader_with(SPECIALIZED, "scale", &options)
+            .unwrap();
+        compute.recover().unwrap();
+        assert_eq!(compute.cached_specializations(), 0);
+        assert!(!stale.is_valid());
+        let fresh = compute
+            .compile_shader_with(SPECIALIZED, "scale", &options)
+            .unwrap();
+        assert!(fresh.is_valid());
+    }
+
+    #[test]
+    fn test_recover_on_invalid_index() {
+        if let Ok(mut compute) = MetalCompute::default_device() {
+            assert!(compute.recover_on(999).is_err());
+        }
+    }
+}
@@ -0,0 +1,818 @@
+//! Shader source loading and preprocessing.
+//!
+//! [`ShaderLoader`] assembles a single MSL string, suitable for
+//! [`MetalCompute::compile_shader`](super::MetalCompute::compile_shader),
+//! from sources split across header files:
+//!
+//! - `#include "file"` resolves relative to the including file, then
+//!   against embedded virtual files, then against the search paths.
+//!   `#include <file>` skips the relative lookup and is passed through to
+//!   the Metal compiler when nothing matches (e.g. `<metal_stdlib>`).
+//! - Include cycles are reported with the full chain; `#pragma once`
+//!   headers are included once.
+//! - `#define`/`#undef` are tracked and passed through, and
+//!   `#ifdef`/`#ifndef`/`#if`/`#elif`/`#else`/`#endif` select the active
+//!   lines. `#if` supports integer literals, macro names, `defined(NAME)`,
+//!   `!`, `&&`, `||` and parentheses. As in C, a macro that refers back to
+//!   itself evaluates to 0.
+//!
+//! The result is a [`ShaderSource`] that maps every output line back to
+//! its original file and line, so compiler diagnostics can be rewritten to
+//! point at the real source.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::ShaderLoader;
+//!
+//! let loader = ShaderLoader::new()
+//!     .with_virtual_file("common.h", "#pragma once\nconstant float SCALE = 2.0;")
+//!     .with_virtual_file(
+//!         "main.metal",
+//!         "#include \"common.h\"\n#ifdef FAST\nkernel void k() {}\n#endif",
+//!     )
+//!     .with_define("FAST", "1");
+//!
+//! let source = loader.load("main.metal")?;
+//! assert!(source.text().contains("kernel void k()"));
+//! let line = source.text().lines().position(|l| l.contains("SCALE")).unwrap() + 1;
+//! assert_eq!(source.location(line).unwrap().to_string(), "common.h:2");
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+use crate::error::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Deepest nesting of operators, parentheses and macro expansions in an
+/// `#if` expression, so that hostile sources can't exhaust the stack.
+const MAX_EXPRESSION_DEPTH: usize = 256;
+
+/// File name recorded for lines generated from [`ShaderLoader::with_define`].
+pub const COMMAND_LINE: &str = "<command line>";
+
+/// Original file and 1-based line of a preprocessed line.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    /// File name as it was included.
+    pub file: String,
+    /// 1-based line number in `file`.
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Preprocessed shader source with a line map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShaderSource {
+    text: String,
+    lines: Vec<SourceLocation>,
+    files: Vec<String>,
+    paths: Vec<PathBuf>,
+}
+
+impl ShaderSource {
+    /// The preprocessed MSL.
+    #[must_use]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Original location of 1-based output `line`.
+    #[must_use]
+    pub fn location(&self, line: usize) -> Option<&SourceLocation> {
+        line.checked_sub(1).and_then(|i| self.lines.get(i))
+    }
+
+    /// Every file that contributed lines, in first-inclusion order.
+    #[must_use]
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Canonical paths of the files read from disk, in first-inclusion
+    /// order. Virtual files and inline source are not included.
+    #[must_use]
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Rewrite `program_source:LINE:` references in a Metal compiler
+    /// diagnostic to the original `file:line:`.
+    #[must_use]
+    pub fn remap_diagnostic(&self, message: &str) -> String {
+        const PREFIX: &str = "program_source:";
+        let mut out = String::with_capacity(message.len());
+        let mut rest = message;
+        while let Some(at) = rest.find(PREFIX) {
+            out.push_str(&rest[..at]);
+            let after = &rest[at + PREFIX.len()..];
+            let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+            match after[..digits].parse().ok().and_then(|l| self.location(l)) {
+                Some(location) => out.push_str(&location.to_string()),
+                None => out.push_str(&rest[at..at + PREFIX.len() + digits]),
+            }
+            rest = &after[digits..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Where a file was found, used to resolve its relative includes.
+#[derive(Debug, Clone)]
+enum Origin {
+    /// A virtual file; the string is its directory prefix (e.g. `"lib/"`).
+    Virtual(String),
+    /// A file on disk in this directory.
+    Disk(PathBuf),
+    /// Inline source passed to [`ShaderLoader::preprocess`].
+    Inline,
+}
+
+struct Resolved {
+    /// Key used for cycle detection and `#pragma once`.
+    id: String,
+    /// Name shown in locations.
+    display: String,
+    origin: Origin,
+    /// Canonical path, for files read from disk.
+    path: Option<PathBuf>,
+    contents: String,
+}
+
+/// Loader that resolves includes and conditionals into one MSL string.
+#[derive(Debug, Clone, Default)]
+pub struct ShaderLoader {
+    search_paths: Vec<PathBuf>,
+    virtual_files: HashMap<String, String>,
+    defines: Vec<(String, String)>,
+}
+
+impl ShaderLoader {
+    /// Create a loader with no search paths or virtual files.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a directory searched for includes, after earlier ones.
+    #[must_use]
+    pub fn with_search_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.search_paths.push(path.into());
+        self
+    }
+
+    /// Embed a file that can be included (or loaded) by `name`.
+    ///
+    /// Virtual files take precedence over the search paths.
+    #[must_use]
+    pub fn with_virtual_file(
+        mut self,
+        name: impl Into<String>,
+        contents: impl Into<String>,
+    ) -> Self {
+        self.virtual_files.insert(name.into(), contents.into());
+        self
+    }
+
+    /// Predefine a macro, as if by `#define name value` before the source.
+    #[must_use]
+    pub fn with_define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.defines.push((name.into(), value.into()));
+        self
+    }
+
+    /// Load and preprocess the file `name`.
+    ///
+    /// `name` is looked up among the virtual files, then the search paths,
+    /// then as a path on disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file is not found, or preprocessing fails.
+    pub fn load(&self, name: &str) -> Result<ShaderSource> {
+        let resolved = match self.resolve(name, None, false)? {
+            Some(resolved) => resolved,
+            None => Self::read_disk(Path::new(name), name)?
+                .ok_or_else(|| Error::not_found(format!("shader source {name}")))?,
+        };
+        self.run(&resolved)
+    }
+
+    /// Preprocess inline `source`, reported as `name` in locations.
+    ///
+    /// Relative includes resolve against the virtual files and search
+    /// paths only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an include is missing or cyclic, a conditional
+    /// is unbalanced or unsupported, or the source hits `#error`.
+    pub fn preprocess(&self, name: &str, source: &str) -> Result<ShaderSource> {
+        self.run(&Resolved {
+            id: format!("inline:{name}"),
+            display: name.to_string(),
+            origin: Origin::Inline,
+            path: None,
+            contents: source.to_string(),
+        })
+    }
+
+    fn run(&self, root: &Resolved) -> Result<ShaderSource> {
+        let mut expander = Expander {
+            loader: self,
+            defines: HashMap::new(),
+            stack: Vec::new(),
+            once: HashSet::new(),
+            out: ShaderSource {
+                text: String::new(),
+                lines: Vec::new(),
+                files: Vec::new(),
+                paths: Vec::new(),
+            },
+        };
+        for (i, (name, value)) in self.defines.iter().enumerate() {
+            expander.defines.insert(name.clone(), value.clone());
+            expander.emit(
+                &format!("#define {name} {value}"),
+                SourceLocation {
+                    file: COMMAND_LINE.to_string(),
+                    line: i + 1,
+                },
+            );
+        }
+        expander.expand(root)?;
+        Ok(expander.out)
+    }
+
+    /// Find an include. Quoted includes try the including file's
+    /// directory first.
+    fn resolve(&self, name: &str, from: Option<&Origin>, quoted: bool) -> Result<Option<Resolved>> {
+        if quoted {
+            match from {
+                Some(Origin::Disk(dir)) => {
+                    if let Some(found) = Self::read_disk(&dir.join(name), name)? {
+                        return Ok(Some(found));
+                    }
+                }
+                Some(Origin::Virtual(prefix)) if !prefix.is_empty() => {
+                    if let Some(found) = self.read_virtual(&format!("{prefix}{name}")) {
+                        return Ok(Some(found));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(found) = self.read_virtual(name) {
+            return Ok(Some(found));
+        }
+        for dir in &self.search_paths {
+            if let Some(found) = Self::read_disk(&dir.join(name), name)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_virtual(&self, name: &str) -> Option<Resolved> {
+        let name = name.strip_prefix("./").unwrap_or(name);
+        self.virtual_files.get(name).map(|contents| Resolved {
+            id: format!("virtual:{name}"),
+            display: name.to_string(),
+            origin: Origin::Virtual(
+                name.rfind('/')
+                    .map_or_else(String::new, |i| name[..=i].to_string()),
+            ),
+            path: None,
+            contents: contents.clone(),
+        })
+    }
+
+    fn read_disk(path: &Path, display: &str) -> Result<Option<Resolved>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::metal(format!("failed to read {}: {e}", path.display())))?;
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        Ok(Some(Resolved {
+            id: canonical.display().to_string(),
+            display: display.to_string(),
+            origin: Origin::Disk(path.parent().map(Path::to_path_buf).unwrap_or_default()),
+            path: Some(canonical),
+            contents,
+        }))
+    }
+}
+
+/// State of an `#if`-family block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Branch {
+    /// The current branch is emitted.
+    Active,
+    /// No branch taken yet; a later `#elif`/`#else` may be.
+    Pending,
+    /// A branch was taken, or the enclosing block is inactive.
+    Done,
+}
+
+impl Branch {
+    const fn from_condition(value: bool) -> Self {
+        if value {
+            Self::Active
+        } else {
+            Self::Pending
+        }
+    }
+}
+
+/// One open `#if`-family block.
+struct Conditional {
+    /// Location of the opening directive, for unterminated-block errors.
+    opened: SourceLocation,
+    branch: Branch,
+    seen_else: bool,
+}
+
+struct Expander<'a> {
+    loader: &'a ShaderLoader,
+    defines: HashMap<String, String>,
+    /// Ids and display names of the files being expanded.
+    stack: Vec<(String, String)>,
+    once: HashSet<String>,
+    out: ShaderSource,
+}
+
+/// Split `expr` at the first `op` outside parentheses.
+fn split_top_level<'e>(expr: &'e str, op: &str) -> Option<(&'e str, &'e str)> {
+    let mut depth = 0usize;
+    for (i, c) in expr.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ if depth == 0 && expr[i..].starts_with(op) => {
+                return Some((&expr[..i], &expr[i + op.len()..]));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn diagnostic(location: &SourceLocation, message: impl fmt::Display) -> Error {
+    Error::metal(format!("{location}: {message}"))
+}
+
+impl Expander<'_> {
+    fn emit(&mut self, line: &str, location: SourceLocation) {
+        self.out.text.push_str(line);
+        self.out.text.push('\n');
+        self.out.lines.push(location);
+    }
+
+    fn expand(&mut self, file: &Resolved) -> Result<()> {
+        if self.once.contains(&file.id) {
+            return Ok(());
+        }
+        self.stack.push((file.id.clone(), file.display.clone()));
+        if !self.out.files.contains(&file.display) {
+            self.out.files.push(file.display.clone());
+        }
+        if let Some(path) = file.path.as_ref().filter(|p| !self.out.paths.contains(p)) {
+            self.out.paths.push(path.clone());
+        }
+
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        for (i, line) in file.contents.lines().enumerate() {
+            let location = SourceLocation {
+                file: file.display.clone(),
+                line: i + 1,
+            };
+            let active = conditionals
+                .last()
+                .map_or(true, |c| c.branch == Branch::Active);
+            let Some(directive) = line.trim_start().strip_prefix('#') else {
+                if active {
+                    self.emit(line, location);
+                }
+                continue;
+            };
+            let directive = directive.trim();
+            let (command, rest) = directive
+                .split_once(char::is_whitespace)
+                .map_or((directive, ""), |(c, r)| (c, r.trim()));
+
+            if self.conditional(command, rest, &location, &mut conditionals)? || !active {
+                continue;
+            }
+            match command {
+                "include" => self.include(rest, line, file, location)?,
+                "pragma" if rest == "once" => {
+                    self.once.insert(file.id.clone());
+                }
+                "define" => {
+                    let (name, value) = rest
+                        .split_once(char::is_whitespace)
+                        .map_or((rest, ""), |(n, v)| (n, v.trim()));
+                    let name = name.split('(').next().unwrap_or(name);
+                    self.defines.insert(name.to_string(), value.to_string());
+                    self.emit(line, location);
+                }
+                "undef" => {
+                    self.defines.remove(rest);
+                    self.emit(line, location);
+                }
+                "error" => return Err(diagnostic(&location, format!("#error {rest}"))),
+                _ => self.emit(line, location),
+            }
+        }
+
+        if let Some(open) = conditionals.last() {
+            return Err(diagnostic(&open.opened, "unterminated conditional"));
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    /// Handle a conditional directive. Returns `false` if `command` is not
+    /// one.
+    fn conditional(
+        &self,
+        command: &str,
+        rest: &str,
+        location: &SourceLocation,
+        conditionals: &mut Vec<Conditional>,
+    ) -> Result<bool> {
+        match command {
+            "ifdef" | "ifndef" | "if" => {
+                let parent_active = conditionals
+                    .last()
+                    .map_or(true, |c| c.branch == Branch::Active);
+                let branch = if parent_active {
+                    Branch::from_condition(match command {
+                        "ifdef" => self.defines.contains_key(rest),
+                        "ifndef" => !self.defines.contains_key(rest),
+                        _ => self.evaluate(rest, location)?,
+                    })
+                } else {
+                    Branch::Done
+                };
+                conditionals.push(Conditional {
+                    opened: location.clone(),
+                    branch,
+                    seen_else: false,
+                });
+            }
+            "elif" | "else" => {
+                let Some(open) = conditionals.last_mut() else {
+                    return Err(diagnostic(location, format!("#{command} without #if")));
+                };
+                if open.seen_else {
+                    return Err(diagnostic(location, format!("#{command} after #else")));
+                }
+                open.seen_else = command == "else";
+                open.branch = match open.branch {
+                    Branch::Pending if open.seen_else => Branch::Active,
+                    Branch::Pending => Branch::from_condition(self.evaluate(rest, location)?),
+                    Branch::Active | Branch::Done => Branch::Done,
+                };
+            }
+            "endif" => {
+                if conditionals.pop().is_none() {
+                    return Err(diagnostic(location, "#endif without #if"));
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Evaluate an `#if` expression.
+    fn evaluate(&self, expr: &str, location: &SourceLocation) -> Result<bool> {
+        self.evaluate_within(expr, location, &mut Vec::new(), 0)
+    }
+
+    /// Evaluate `expr` while the macros in `expanding` are being expanded.
+    /// A macro that refers back to one of them evaluates to 0, as in C.
+    fn evaluate_within<'s>(
+        &'s self,
+        expr: &str,
+        location: &SourceLocation,
+        expanding: &mut Vec<&'s str>,
+        depth: usize,
+    ) -> Result<bool> {
+        let expr = expr.trim();
+        if depth > MAX_EXPRESSION_DEPTH {
+            return Err(diagnostic(
+                location,
+                format!("#if expression nested deeper than {MAX_EXPRESSION_DEPTH}"),
+            ));
+        }
+        let depth = depth + 1;
+        if let Some((lhs, rhs)) = split_top_level(expr, "||") {
+            return Ok(self.evaluate_within(lhs, location, expanding, depth)?
+                || self.evaluate_within(rhs, location, expanding, depth)?);
+        }
+        if let Some((lhs, rhs)) = split_top_level(expr, "&&") {
+            return Ok(self.evaluate_within(lhs, location, expanding, depth)?
+                && self.evaluate_within(rhs, location, expanding, depth)?);
+        }
+        if let Some(inner) = expr.strip_prefix('!') {
+            return Ok(!self.evaluate_within(inner, location, expanding, depth)?);
+        }
+        if let Some(name) = expr.strip_prefix("defined") {
+            let name = name.trim();
+            let name = name
+                .strip_prefix('(')
+                .and_then(|n| n.strip_suffix(')'))
+                .unwrap_or(name)
+                .trim();
+            return Ok(self.defines.contains_key(name));
+        }
+        if let Some(inner) = expr.strip_prefix('(').and_then(|e| e.strip_suffix(')')) {
+            return self.evaluate_within(inner, location, expanding, depth);
+        }
+        if let Ok(value) = expr.parse::<i64>() {
+            return Ok(value != 0);
+        }
+        if !expr.is_empty() && expr.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            // Undefined identifiers evaluate to 0, as in C.
+            let Some((name, value)) = self.defines.get_key_value(expr) else {
+                return Ok(false);
+            };
+            if expanding.contains(&name.as_str()) {
+                return Ok(false);
+            }
+            expanding.push(name);
+            let value = self.evaluate_within(value, location, expanding, depth);
+            expanding.pop();
+            return value;
+        }
+        Err(diagnostic(
+            location,
+            format!("unsupported #if expression '{expr}'"),
+        ))
+    }
+
+    fn include(
+        &mut self,
+        target: &str,
+        line: &str,
+        file: &Resolved,
+        location: SourceLocation,
+    ) -> Result<()> {
+        let (name, quoted) =
+            if let Some(name) = target.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+                (name, true)
+            } else if let Some(name) = target.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+                (name, false)
+            } else {
+                return Err(diagnostic(
+                    &location,
+                    format!("malformed #include {target}"),
+                ));
+            };
+
+        let Some(resolved) = self.loader.resolve(name, Some(&file.origin), quoted)? else {
+            if quoted {
+                return Err(diagnostic(
+                    &location,
+                    format!("include \"{name}\" not found"),
+                ));
+            }
+            // System header: left for the Metal compiler.
+            self.emit(line, location);
+            return Ok(());
+        };
+
+        if self.stack.iter().any(|(id, _)| *id == resolved.id) {
+            let chain: Vec<&str> = self
+                .stack
+                .iter()
+                .map(|(_, display)| display.as_str())
+                .chain(std::iter::once(resolved.display.as_str()))
+                .collect();
+            return Err(diagnostic(
+                &location,
+                format!("include cycle: {}", chain.join(" -> ")),
+            ));
+        }
+        self.expand(&resolved)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn lines(source: &ShaderSource) -> Vec<&str> {
+        source.text().lines().collect()
+    }
+
+    #[test]
+    fn test_virtual_include_and_line_map() {
+        let loader = ShaderLoader::new()
+            .with_virtual_file("a.h", "int a;\nint a2;")
+            .with_virtual_file("main.metal", "// main\n#include \"a.h\"\nint m;");
+        let source = loader.load("main.metal").unwrap();
+        assert_eq!(
+            lines(&source),
+            vec!["// main", "int a;", "int a2;", "int m;"]
+        );
+        assert_eq!(source.location(1).unwrap().to_string(), "main.metal:1");
+        assert_eq!(source.location(3).unwrap().to_string(), "a.h:2");
+        assert_eq!(source.location(4).unwrap().to_string(), "main.metal:3");
+        assert!(source.location(0).is_none());
+        assert!(source.location(5).is_none());
+        assert_eq!(source.files(), ["main.metal", "a.h"]);
+    }
+
+    #[test]
+    fn test_virtual_relative_include() {
+        let loader = ShaderLoader::new()
+            .with_virtual_file("lib/math.h", "#include \"detail.h\"")
+            .with_virtual_file("lib/detail.h", "int detail;");
+        let source = loader.preprocess("k", "#include \"lib/math.h\"").unwrap();
+        assert_eq!(lines(&source), vec!["int detail;"]);
+        assert_eq!(source.location(1).unwrap().file, "lib/detail.h");
+    }
+
+    #[test]
+    fn test_system_include_passed_through() {
+        let loader = ShaderLoader::new();
+        let source = loader
+            .preprocess("k", "#include <metal_stdlib>\nusing namespace metal;")
+            .unwrap();
+        assert_eq!(lines(&source)[0], "#include <metal_stdlib>");
+
+        let err = loader
+            .preprocess("k", "\n#include \"missing.h\"")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("k:2: include \"missing.h\" not found"));
+    }
+
+    #[test]
+    fn test_include_cycle_detected() {
+        let loader = ShaderLoader::new()
+            .with_virtual_file("a.h", "#include \"b.h\"")
+            .with_virtual_file("b.h", "\n#include \"a.h\"");
+        let err = loader.load("a.h").unwrap_err().to_string();
+        assert!(
+            err.contains("b.h:2: include cycle: a.h -> b.h -> a.h"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_pragma_once() {
+        let loader = ShaderLoader::new()
+            .with_virtual_file("once.h", "#pragma once\nint once;")
+            .with_virtual_file("twice.h", "int twice;");
+        let source = loader
+            .preprocess(
+                "k",
+                "#include \"once.h\"\n#include \"once.h\"\n#include \"twice.h\"\n#include \"twice.h\"",
+            )
+            .unwrap();
+        assert_eq!(
+            lines(&source),
+            vec!["int once;", "int twice;", "int twice;"]
+        );
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let loader = ShaderLoader::new().with_define("TILE", "16");
+        let source = loader
+            .preprocess(
+                "k",
+                "#ifdef TILE\nyes1\n#else\nno1\n#endif\n\
+                 #ifndef TILE\nno2\n#elif TILE\nyes2\n#endif\n\
+                 #define LOCAL 0\n#if LOCAL || defined(TILE) && !defined(OTHER)\nyes3\n#endif\n\
+                 #if 0\n#ifdef TILE\nno4\n#endif\n#else\nyes4\n#endif\n\
+                 #undef TILE\n#ifdef TILE\nno5\n#endif",
+            )
+            .unwrap();
+        let active: Vec<&str> = lines(&source)
+            .into_iter()
+            .filter(|l| !l.starts_with('#'))
+            .collect();
+        assert_eq!(active, vec!["yes1", "yes2", "yes3", "yes4"]);
+        assert_eq!(lines(&source)[0], "#define TILE 16");
+        assert_eq!(source.location(1).unwrap().file, COMMAND_LINE);
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let loader = ShaderLoader::new();
+        let err = loader.preprocess("k", "#ifdef A\nx").unwrap_err();
+        assert!(err.to_string().contains("k:1: unterminated conditional"));
+        assert!(loader.preprocess("k", "#endif").is_err());
+        assert!(loader.preprocess("k", "#else").is_err());
+        assert!(loader
+            .preprocess("k", "#if 1\n#else\n#else\n#endif")
+            .is_err());
+        assert!(loader.preprocess("k", "#if A + 1\n#endif").is_err());
+        let err = loader
+            .preprocess("k", "#if 1\n#error nope\n#endif")
+            .unwrap_err();
+        assert!(err.to_string().contains("k:2: #error nope"));
+        assert!(loader
+            .preprocess("k", "#if 0\n#error skipped\n#endif")
+            .is_ok());
+        let deep = format!("#if {}1\n#endif", "!".repeat(10_000));
+        let err = loader.preprocess("k", &deep).unwrap_err();
+        assert!(err.to_string().contains("nested deeper than"), "{err}");
+    }
+
+    #[test]
+    fn test_recursive_macros_and_parentheses() {
+        let loader = ShaderLoader::new();
+        let active = |source: &str| {
+            let source = loader.preprocess("k", source).unwrap();
+            lines(&source)
+                .into_iter()
+                .filter(|l| !l.starts_with('#'))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        // Self-referential macros evaluate to 0 instead of recursing.
+        assert!(active("#define A A\n#if A\nno\n#endif").is_empty());
+        assert!(active("#define A B\n#define B A\n#if A || B\nno\n#endif").is_empty());
+        assert_eq!(
+            active("#define A B\n#define B 1 || A\n#if A\nyes\n#endif"),
+            ["yes"]
+        );
+        assert_eq!(
+            active(
+                "#define A 0\n#define B 1\n#define C 1\n\
+                 #if (A || B) && C\nyes1\n#endif\n\
+                 #if (A || B) && (A || !C)\nno\n#endif\n\
+                 #if !(A && B) && (defined(C) || A)\nyes2\n#endif"
+            ),
+            ["yes1", "yes2"]
+        );
+    }
+
+    #[test]
+    fn test_remap_diagnostic() {
+        let loader = ShaderLoader::new().with_virtual_file("h.h", "bad;");
+        let source = loader
+            .preprocess("main.metal", "ok;\n#include \"h.h\"")
+            .unwrap();
+        assert_eq!(
+            source.remap_diagnostic("program_source:2:1: error: bad\nprogram_source:9:1: note"),
+            "h.h:1:1: error: bad\nprogram_source:9:1: note"
+        );
+    }
+
+    #[test]
+    fn test_disk_search_paths() {
+        let root = std::env::temp_dir().join(format!("manzana-shaders-{}", std::process::id()));
+        let include = root.join("include");
+        std::fs::create_dir_all(include.join("nested")).unwrap();
+        std::fs::write(
+            include.join("common.h"),
+            "#include \"nested/inner.h\"\nint common;",
+        )
+        .unwrap();
+        std::fs::write(include.join("nested/inner.h"), "#include \"sibling.h\"").unwrap();
+        std::fs::write(include.join("nested/sibling.h"), "int sibling;").unwrap();
+        std::fs::write(root.join("main.metal"), "#include <common.h>\nint main;").unwrap();
+
+        let loader = ShaderLoader::new().with_search_path(&include);
+        let main = root.join("main.metal");
+        let source = loader.load(main.to_str().unwrap()).unwrap();
+        assert_eq!(
+            lines(&source),
+            vec!["int sibling;", "int common;", "int main;"]
+        );
+        assert_eq!(source.location(1).unwrap().file, "sibling.h");
+        let canonical = |path: PathBuf| path.canonicalize().unwrap();
+        assert_eq!(
+            source.paths(),
+            [
+                canonical(main),
+                canonical(include.join("common.h")),
+                canonical(include.join("nested/inner.h")),
+                canonical(include.join("nested/sibling.h")),
+            ]
+        );
+        assert!(loader
+            .load("absent.metal")
+            .unwrap_err()
+            .to_string()
+            .contains("not found"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+//! Per-dispatch profiling and Chrome trace export.
+//!
+//! Attach a [`Profiler`] to a [`MetalCompute`](super::MetalCompute) with
+//! [`with_profiler`](super::MetalCompute::with_profiler) and every
+//! successful dispatch is recorded as a [`DispatchProfile`]: the kernel
+//! name, grid and threadgroup sizes, bound buffer bytes, and the time spent
+//! in each phase of the command buffer:
+//!
+//! - **encode**: validating and binding arguments on the host
+//! - **schedule**: from commit until the queue starts executing
+//! - **execute**: running the kernel
+//!
+//! Records are aggregated into per-kernel [`KernelHistogram`]s, and the
+//! timeline can be exported as Chrome trace-event JSON, which Perfetto and
+//! `chrome://tracing` open directly.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::{MetalCompute, Profiler};
+//!
+//! let profiler = Profiler::new();
+//! let compute = MetalCompute::default_device()?.with_profiler(profiler.clone());
+//!
+//! // ... dispatch kernels ...
+//!
+//! for histogram in profiler.histograms() {
+//!     println!("{}: {} dispatches, p50 {:?}", histogram.kernel(), histogram.count(), histogram.percentile(0.5));
+//! }
+//! profiler.write_chrome_trace("dispatches.json")?;
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+use super::Size3;
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Timing and metadata for one dispatch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatchProfile {
+    /// Kernel function name.
+    pub kernel: String,
+    /// Index of the device the dispatch ran on.
+    pub device_index: usize,
+    /// Grid size in threads.
+    pub grid: Size3,
+    /// Threadgroup size in threads.
+    pub threadgroup: Size3,
+    /// Total bytes of all bound buffers.
+    pub buffer_bytes: u64,
+    /// Start of encoding, relative to the profiler's creation.
+    pub start: Duration,
+    /// Time spent encoding the command buffer.
+    pub encode: Duration,
+    /// Time between commit and the start of execution.
+    pub schedule: Duration,
+    /// Time spent executing the kernel.
+    pub execute: Duration,
+}
+
+impl DispatchProfile {
+    /// Time from the start of encoding to the end of execution.
+    #[must_use]
+    pub fn total(&self) -> Duration {
+        self.encode + self.schedule + self.execute
+    }
+}
+
+/// Distribution of execution times for one kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelHistogram {
+    kernel: String,
+    samples: Vec<Duration>,
+}
+
+/// One power-of-two bucket of a [`KernelHistogram`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistogramBucket {
+    /// Exclusive upper bound of the bucket.
+    pub upper: Duration,
+    /// Number of samples in the bucket.
+    pub count: usize,
+}
+
+impl KernelHistogram {
+    fn new(kernel: String, mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+        Self { kernel, samples }
+    }
+
+    /// Kernel function name.
+    #[must_use]
+    pub fn kernel(&self) -> &str {
+        &self.kernel
+    }
+
+    /// Number of dispatches.
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Sum of all execution times.
+    #[must_use]
+    pub fn total(&self) -> Duration {
+        self.samples.iter().sum()
+    }
+
+    /// Shortest execution time.
+    #[must_use]
+    pub fn min(&self) -> Duration {
+        self.samples.first().copied().unwrap_or_default()
+    }
+
+    /// Longest execution time.
+    #[must_use]
+    pub fn max(&self) -> Duration {
+        self.samples.last().copied().unwrap_or_default()
+    }
+
+    /// Mean execution time.
+    #[must_use]
+    pub fn mean(&self) -> Duration {
+        u32::try_from(self.samples.len())
+            .ok()
+            .filter(|&n| n > 0)
+            .map_or(Duration::ZERO, |n| self.total() / n)
+    }
+
+    /// Execution time at quantile `q` (clamped to `0.0..=1.0`), using the
+    /// nearest-rank method.
+    #[must_use]
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn percentile(&self, q: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (q.clamp(0.0, 1.0) * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.saturating_sub(1).min(self.samples.len() - 1)]
+    }
+
+    /// Samples grouped into power-of-two microsecond buckets, from the
+    /// smallest non-empty bucket to the largest.
+    #[must_use]
+    pub fn buckets(&self) -> Vec<HistogramBucket> {
+        let mut counts: BTreeMap<u32, usize> = BTreeMap::new();
+        for sample in &self.samples {
+            let micros = u64::try_from(sample.as_micros()).unwrap_or(u64::MAX);
+            *counts
+                .entry(u64::BITS - micros.leading_zeros())
+                .or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .map(|(bits, count)| HistogramBucket {
+                upper: Duration::from_micros(1u64.checked_shl(bits).unwrap_or(u64::MAX)),
+                count,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct ProfilerState {
+    origin: Instant,
+    records: Vec<DispatchProfile>,
+}
+
+/// Shared collector of dispatch timings.
+///
+/// Cloning a profiler yields another handle to the same records.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    state: Arc<Mutex<ProfilerState>>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    /// Create an empty profiler. Timestamps are relative to this call.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ProfilerState {
+                origin: Instant::now(),
+                records: Vec::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ProfilerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Time elapsed between the profiler's creation and `instant`.
+    pub(crate) fn offset(&self, instant: Instant) -> Duration {
+        instant.saturating_duration_since(self.lock().origin)
+    }
+
+    /// Append a record.
+    pub(crate) fn record(&self, profile: DispatchProfile) {
+        self.lock().records.push(profile);
+    }
+
+    /// Get all records in dispatch order.
+    #[must_use]
+    pub fn records(&self) -> Vec<DispatchProfile> {
+        self.lock().records.clone()
+    }
+
+    /// Number of recorded dispatches.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().records.len()
+    }
+
+    /// True if nothing has been recorded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Discard all records.
+    pub fn clear(&self) {
+        self.lock().records.clear();
+    }
+
+    /// Execution-time histograms for every kernel, sorted by kernel name.
+    #[must_use]
+    pub fn histograms(&self) -> Vec<KernelHistogram> {
+        let mut samples: BTreeMap<String, Vec<Duration>> = BTreeMap::new();
+        for record in &self.lock().records {
+            samples
+                .entry(record.kernel.clone())
+                .or_default()
+                .push(record.execute);
+        }
+        samples
+            .into_iter()
+            .map(|(kernel, samples)| KernelHistogram::new(kernel, samples))
+            .collect()
+    }
+
+    /// Execution-time histogram for one kernel.
+    #[must_use]
+    pub fn histogram(&self, kernel: &str) -> Option<KernelHistogram> {
+        let samples: Vec<Duration> = self
+            .lock()
+            .records
+            .iter()
+            .filter(|record| record.kernel == kernel)
+            .map(|record| record.execute)
+            .collect();
+        (!samples.is_empty()).then(|| KernelHistogram::new(kernel.to_string(), samples))
+    }
+
+    /// Render the timeline as Chrome trace-event JSON.
+    ///
+    /// Encoding appears on a host thread track; scheduling and execution
+    /// appear on a queue track.
+    #[must_use]
+    pub fn to_chrome_trace(&self) -> String {
+        let mut events = vec![
+            metadata_event("process_name", 0, "manzana"),
+            metadata_event("thread_name", HOST_TRACK, "Host encode"),
+            metadata_event("thread_name", QUEUE_TRACK, "Command queue"),
+        ];
+        for record in &self.lock().records {
+            let args = format!(
+                r#"{{"device":{},"grid":"{}x{}x{}","threadgroup":"{}x{}x{}","buffer_bytes":{}}}"#,
+                record.device_index,
+                record.grid.0,
+                record.grid.1,
+                record.grid.2,
+                record.threadgroup.0,
+                record.threadgroup.1,
+                record.threadgroup.2,
+                record.buffer_bytes,
+            );
+            let scheduled = record.start + record.encode;
+            let phases = [
+                ("encode", HOST_TRACK, record.start, record.encode),
+                ("schedule", QUEUE_TRACK, scheduled, record.schedule),
+                (
+                    "execute",
+                    QUEUE_TRACK,
+                    scheduled + record.schedule,
+                    record.execute,
+                ),
+            ];
+            for (category, track, start, duration) in phases {
+                events.push(format!(
+                    r#"{{"name":"{}","cat":"{category}","ph":"X","pid":1,"tid":{track},"ts":{},"dur":{},"args":{args}}}"#,
+                    escape_json(&record.kernel),
+                    micros(start),
+                    micros(duration),
+                ));
+            }
+        }
+        format!(
+            "{{\"traceEvents\":[\n{}\n],\"displayTimeUnit\":\"ns\"}}\n",
+            events.join(",\n")
+        )
+    }
+
+    /// Write the Chrome trace JSON to a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn write_chrome_trace<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_chrome_trace())
+            .map_err(|e| Error::metal(format!("failed to write trace {}: {e}", path.display())))
+    }
+}
+
+const HOST_TRACK: u32 = 1;
+const QUEUE_TRACK: u32 = 2;
+
+fn metadata_event(name: &str, tid: u32, value: &str) -> String {
+    format!(
+        r#"{{"name":"{name}","ph":"M","pid":1,"tid":{tid},"args":{{"name":"{}"}}}}"#,
+        escape_json(value)
+    )
+}
+
+/// Duration in microseconds with nanosecond precision.
+fn micros(duration: Duration) -> String {
+    format!(
+        "{}.{:03}",
+        duration.as_micros(),
+        duration.subsec_nanos() % 1_000
+    )
+}
+
+fn escape_json(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn profile(kernel: &str, start_us: u64, execute_us: u64) -> DispatchProfile {
+        DispatchProfile {
+            kernel: kernel.to_string(),
+            device_index: 0,
+            grid: (64, 1, 1),
+            threadgroup: (32, 1, 1),
+            buffer_bytes: 256,
+            start: Duration::from_micros(start_us),
+            encode: Duration::from_micros(2),
+            schedule: Duration::from_micros(1),
+            execute: Duration::from_micros(execute_us),
+        }
+    }
+
+    #[test]
+    fn test_records_and_clear() {
+        let profiler = Profiler::new();
+        let alias = profiler.clone();
+        assert!(profiler.is_empty());
+        profiler.record(profile("a", 0, 10));
+        assert_eq!(alias.len(), 1);
+        assert_eq!(alias.records()[0].total(), Duration::from_micros(13));
+        alias.clear();
+        assert!(profiler.is_empty());
+    }
+
+    #[test]
+    fn test_histogram_statistics() {
+        let profiler = Profiler::new();
+        for us in [40, 10, 30, 20] {
+            profiler.record(profile("gemm", 0, us));
+        }
+        profiler.record(profile("softmax", 0, 5));
+
+        let histograms = profiler.histograms();
+        assert_eq!(histograms.len(), 2);
+        assert_eq!(histograms[0].kernel(), "gemm");
+
+        let gemm = profiler.histogram("gemm").unwrap();
+        assert_eq!(gemm.count(), 4);
+        assert_eq!(gemm.total(), Duration::from_micros(100));
+        assert_eq!(gemm.min(), Duration::from_micros(10));
+        assert_eq!(gemm.max(), Duration::from_micros(40));
+        assert_eq!(gemm.mean(), Duration::from_micros(25));
+        assert_eq!(gemm.percentile(0.5), Duration::from_micros(20));
+        assert_eq!(gemm.percentile(1.0), Duration::from_micros(40));
+        assert_eq!(gemm.percentile(0.0), Duration::from_micros(10));
+        assert!(profiler.histogram("missing").is_none());
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = KernelHistogram::new(
+            "k".to_string(),
+            [0, 1, 3, 3, 100]
+                .into_iter()
+                .map(Duration::from_micros)
+                .collect(),
+        );
+        let buckets = histogram.buckets();
+        let upper: Vec<u64> = buckets
+            .iter()
+            .map(|b| u64::try_from(b.upper.as_micros()).unwrap())
+            .collect();
+        assert_eq!(upper, vec![1, 2, 4, 128]);
+        assert_eq!(
+            buckets.iter().map(|b| b.count).collect::<Vec<_>>(),
+            vec![1, 1, 2, 1]
+        );
+        assert_eq!(buckets.iter().map(|b| b.count).sum::<usize>(), 5);
+    }
+
+    #[test]
+    fn test_chrome_trace_events() {
+        let profiler = Profiler::new();
+        profiler.record(profile("my \"kernel\"", 100, 7));
+        let trace = profiler.to_chrome_trace();
+
+        assert!(trace.starts_with("{\"traceEvents\":["));
+        assert!(trace.contains(r#""name":"my \"kernel\"","cat":"encode""#));
+        assert!(
+            trace.contains(r#""cat":"execute","ph":"X","pid":1,"tid":2,"ts":103.000,"dur":7.000"#)
+        );
+        assert!(trace.contains(r#""grid":"64x1x1","threadgroup":"32x1x1","buffer_bytes":256"#));
+        assert_eq!(trace.matches("\"ph\":\"X\"").count(), 3);
+        assert_eq!(trace.matches("\"ph\":\"M\"").count(), 3);
+    }
+
+    #[test]
+    fn test_micros_and_escape() {
+        assert_eq!(micros(Duration::from_nanos(1_234_567)), "1234.567");
+        assert_eq!(micros(Duration::ZERO), "0.000");
+        assert_eq!(escape_json("a\\b\n\u{1}"), "a\\\\b\\n\\u0001");
+    }
+
+    #[test]
+    fn test_write_chrome_trace() {
+        let profiler = Profiler::new();
+        profiler.record(profile("k", 0, 1));
+        let path = std::env::temp_dir().join(format!("manzana-trace-{}.json", std::process::id()));
+        profiler.write_chrome_trace(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            profiler.to_chrome_trace()
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(profiler
+            .write_chrome_trace("/nonexistent/dir/trace.json")
+            .is_err());
+    }
+}
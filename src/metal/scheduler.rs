@@ -0,0 +1,1053 @@
+//! Multi-tenant GPU job scheduling.
+//!
+//! A [`GpuScheduler`] arbitrates one [`MetalCompute`] pipeline between
+//! tenants (services sharing the GPU):
+//!
+//! - **Priority classes.** [`Priority::Interactive`] jobs run before
+//!   [`Priority::Normal`] jobs, which run before [`Priority::Background`]
+//!   jobs. Classes are strict: lower classes run only when no higher-class
+//!   job is eligible.
+//! - **Weighted fair sharing.** Within a class, the tenant that has used
+//!   the least GPU time relative to its [`TenantConfig::with_weight`] runs
+//!   next, so a tenant with weight 2 gets twice the time of a tenant with
+//!   weight 1 while both have work queued. A tenant that was idle resumes
+//!   at the current share instead of catching up on time it did not use.
+//! - **Quotas.** A tenant's jobs are held back while running them would
+//!   exceed its in-flight job limit or the memory its in-flight jobs
+//!   declared. A job that could never fit its tenant's memory quota is
+//!   rejected at submission.
+//! - **Preemption at job boundaries.** Running jobs are never interrupted,
+//!   but every pick re-evaluates the queues, so higher-priority work
+//!   submitted during a long background batch runs after the current job
+//!   instead of after the batch.
+//!
+//! [`GpuScheduler::next`] hands out a [`JobLease`] for the chosen job; the
+//! job is in flight until the lease is run or dropped, so work that
+//! completes asynchronously can hold its quota until it finishes.
+//! [`GpuScheduler::run_next`] picks and runs a job in one step.
+//!
+//! Jobs run on the thread that owns the scheduler, since [`MetalCompute`]
+//! is not `Sync`, but tenants and jobs can be added from any thread
+//! through the scheduler's [`JobQueue`]; jobs must therefore be `Send`.
+//!
+//! Time comes from a [`Clock`]. [`SimulatedClock`] only moves when told to,
+//! which makes wait times and fair shares exact in tests.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::scheduler::{GpuJob, GpuScheduler, Priority, SimulatedClock, TenantConfig};
+//! use manzana::metal::MetalCompute;
+//! use std::time::Duration;
+//!
+//! let compute = MetalCompute::cpu();
+//! let clock = SimulatedClock::new();
+//! let scheduler = GpuScheduler::with_clock(&compute, clock.clone());
+//! scheduler.add_tenant("search", TenantConfig::new().with_weight(2))?;
+//! scheduler.add_tenant("batch", TenantConfig::new().with_max_in_flight(1))?;
+//!
+//! let tick = clock.clone();
+//! scheduler.submit("batch", GpuJob::new(move |_| {
+//!     tick.advance(Duration::from_millis(5));
+//!     Ok(())
+//! }))?;
+//! scheduler.submit("search", GpuJob::new(|_| Ok(())).with_priority(Priority::Interactive))?;
+//!
+//! let first = scheduler.run_next().unwrap();
+//! assert_eq!(first.tenant, "search");
+//! let second = scheduler.run_next().unwrap();
+//! assert_eq!(second.duration, Duration::from_millis(5));
+//! assert_eq!(scheduler.metrics().queue_depth(), 0);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - No job starts while a higher-priority job is eligible
+//! - Backlogged tenants in one class receive GPU time in proportion to
+//!   their weights, within one job's time
+//! - A tenant never exceeds its in-flight or memory quota
+
+use super::MetalCompute;
+use crate::error::{Error, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Smallest GPU time charged for a job, so that jobs that finish within
+/// one clock tick still count against their tenant's share.
+pub const MIN_JOB_CHARGE: Duration = Duration::from_micros(1);
+
+/// Source of the current time.
+pub trait Clock {
+    /// Time elapsed since an arbitrary fixed origin.
+    fn now(&self) -> Duration;
+}
+
+/// Wall-clock time, measured from when the clock was created.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    /// Create a clock starting now.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// A clock that only moves when advanced.
+///
+/// Clones share the same time, so jobs can advance the clock the scheduler
+/// reads to simulate how long they ran.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedClock(Arc<AtomicU64>);
+
+impl SimulatedClock {
+    /// Create a clock at time zero.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.0.fetch_add(nanos, Ordering::AcqRel);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Acquire))
+    }
+}
+
+/// Priority class of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Priority {
+    /// Batch work that runs when nothing else is waiting.
+    Background,
+    /// The default class.
+    Normal,
+    /// Latency-sensitive work.
+    Interactive,
+}
+
+impl Priority {
+    /// All classes, highest first.
+    pub const ALL: [Self; 3] = [Self::Interactive, Self::Normal, Self::Background];
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Background => write!(f, "background"),
+            Self::Normal => write!(f, "normal"),
+            Self::Interactive => write!(f, "interactive"),
+        }
+    }
+}
+
+/// Share and quotas of one tenant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TenantConfig {
+    /// Relative share of GPU time within a priority class.
+    pub weight: u32,
+    /// Most jobs in flight at once, or `None` for no limit.
+    pub max_in_flight: Option<usize>,
+    /// Most memory, in bytes, declared by in-flight jobs, or `None` for no
+    /// limit.
+    pub memory_quota: Option<u64>,
+}
+
+impl TenantConfig {
+    /// Weight 1 and no quotas.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            weight: 1,
+            max_in_flight: None,
+            memory_quota: None,
+        }
+    }
+
+    /// Set the tenant's relative share.
+    #[must_use]
+    pub const fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Limit the number of jobs in flight at once; the limit must be at
+    /// least 1.
+    #[must_use]
+    pub const fn with_max_in_flight(mut self, jobs: usize) -> Self {
+        self.max_in_flight = Some(jobs);
+        self
+    }
+
+    /// Limit the memory declared by in-flight jobs.
+    #[must_use]
+    pub const fn with_memory_quota(mut self, bytes: u64) -> Self {
+        self.memory_quota = Some(bytes);
+        self
+    }
+}
+
+impl Default for TenantConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type JobFn<'a> = Box<dyn FnOnce(&MetalCompute) -> Result<()> + Send + 'a>;
+
+/// Work to run on the GPU.
+pub struct GpuJob<'a> {
+    run: JobFn<'a>,
+    priority: Priority,
+    memory: u64,
+}
+
+impl<'a> GpuJob<'a> {
+    /// A [`Priority::Normal`] job that declares no memory.
+    pub fn new(run: impl FnOnce(&MetalCompute) -> Result<()> + Send + 'a) -> Self {
+        Self {
+            run: Box::new(run),
+            priority: Priority::Normal,
+            memory: 0,
+        }
+    }
+
+    /// Set the job's priority class.
+    #[must_use]
+    pub const fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Declare the memory the job uses while in flight, in bytes.
+    #[must_use]
+    pub const fn with_memory(mut self, bytes: u64) -> Self {
+        self.memory = bytes;
+        self
+    }
+}
+
+impl fmt::Debug for GpuJob<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GpuJob")
+            .field("priority", &self.priority)
+            .field("memory", &self.memory)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Handle to a submitted job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(u64);
+
+/// Outcome of a job run through a [`JobLease`].
+#[derive(Debug)]
+pub struct JobReport {
+    /// The job.
+    pub id: JobId,
+    /// Tenant that submitted it.
+    pub tenant: String,
+    /// Its priority class.
+    pub priority: Priority,
+    /// Time from submission to start.
+    pub wait: Duration,
+    /// Time from start to completion.
+    pub duration: Duration,
+    /// What the job returned.
+    pub result: Result<()>,
+}
+
+/// Counters for one tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantMetrics {
+    /// Tenant name.
+    pub name: String,
+    /// Jobs waiting to start.
+    pub queued: usize,
+    /// Jobs started and not yet finished.
+    pub in_flight: usize,
+    /// Memory declared by in-flight jobs.
+    pub memory_in_use: u64,
+    /// Jobs that returned `Ok`.
+    pub completed: u64,
+    /// Jobs that returned an error.
+    pub failed: u64,
+    /// Jobs cancelled before they ran.
+    pub cancelled: u64,
+    /// Total run time of finished jobs.
+    pub gpu_time: Duration,
+    /// Total wait of started jobs.
+    pub total_wait: Duration,
+    /// Longest wait of a started job.
+    pub max_wait: Duration,
+    /// Number of started jobs.
+    pub started: u64,
+}
+
+impl TenantMetrics {
+    /// Mean wait of started jobs.
+    #[must_use]
+    pub fn mean_wait(&self) -> Duration {
+        u32::try_from(self.started)
+            .ok()
+            .filter(|&n| n > 0)
+            .map_or(Duration::ZERO, |n| self.total_wait / n)
+    }
+}
+
+/// Snapshot of a scheduler's queues and counters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerMetrics {
+    /// Per-tenant counters, in registration order.
+    pub tenants: Vec<TenantMetrics>,
+    /// Queued jobs per priority class, highest first.
+    pub depth: [(Priority, usize); 3],
+    /// Times a stream of lower-priority jobs was interrupted at a job
+    /// boundary by higher-priority work.
+    pub preemptions: u64,
+}
+
+impl SchedulerMetrics {
+    /// Total queued jobs.
+    #[must_use]
+    pub fn queue_depth(&self) -> usize {
+        self.depth.iter().map(|(_, depth)| depth).sum()
+    }
+
+    /// Counters for the tenant called `name`.
+    #[must_use]
+    pub fn tenant(&self, name: &str) -> Option<&TenantMetrics> {
+        self.tenants.iter().find(|tenant| tenant.name == name)
+    }
+}
+
+struct Queued<'a> {
+    id: JobId,
+    job: GpuJob<'a>,
+    submitted: Duration,
+}
+
+struct Tenant<'a> {
+    config: TenantConfig,
+    /// GPU time used divided by weight, in seconds.
+    virtual_time: f64,
+    queues: [VecDeque<Queued<'a>>; 3],
+    metrics: TenantMetrics,
+}
+
+impl Tenant<'_> {
+    fn is_idle(&self) -> bool {
+        self.metrics.in_flight == 0 && self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Whether the front job of `priority` fits the tenant's quotas.
+    fn can_start(&self, priority: Priority) -> bool {
+        let Some(front) = self.queues[priority.index()].front() else {
+            return false;
+        };
+        let in_flight_ok = self
+            .config
+            .max_in_flight
+            .map_or(true, |max| self.metrics.in_flight < max);
+        let memory_ok = self.config.memory_quota.map_or(true, |quota| {
+            self.metrics
+                .memory_in_use
+                .checked_add(front.job.memory)
+                .is_some_and(|total| total <= quota)
+        });
+        in_flight_ok && memory_ok
+    }
+}
+
+#[derive(Default)]
+struct State<'a> {
+    tenants: Vec<Tenant<'a>>,
+    next_id: u64,
+    /// Virtual time of the most recently started job's tenant.
+    system_time: f64,
+    last_priority: Option<Priority>,
+    preemptions: u64,
+}
+
+impl State<'_> {
+    fn tenant_index(&self, name: &str) -> Result<usize> {
+        self.tenants
+            .iter()
+            .position(|tenant| tenant.metrics.name == name)
+            .ok_or_else(|| Error::not_found(format!("tenant {name}")))
+    }
+
+    fn is_queued(&self, priority: Priority) -> bool {
+        self.tenants
+            .iter()
+            .any(|tenant| !tenant.queues[priority.index()].is_empty())
+    }
+}
+
+/// Tenants and queued jobs of a [`GpuScheduler`].
+///
+/// Unlike the scheduler, the queue can be shared between threads, so jobs
+/// can be submitted from anywhere while one thread runs them.
+pub struct JobQueue<'a, C: Clock = SystemClock> {
+    clock: C,
+    state: Mutex<State<'a>>,
+}
+
+impl<'a, C: Clock> JobQueue<'a, C> {
+    fn state(&self) -> MutexGuard<'_, State<'a>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register a tenant.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is taken, the weight is zero, or the
+    /// in-flight limit is zero.
+    pub fn add_tenant(&self, name: &str, config: TenantConfig) -> Result<()> {
+        if config.weight == 0 {
+            return Err(Error::invalid_input(format!(
+                "tenant {name} must have a non-zero weight"
+            )));
+        }
+        if config.max_in_flight == Some(0) {
+            return Err(Error::invalid_input(format!(
+                "tenant {name} must allow at least one job in flight"
+            )));
+        }
+        let mut state = self.state();
+        if state.tenant_index(name).is_ok() {
+            return Err(Error::invalid_input(format!(
+                "tenant {name} is already registered"
+            )));
+        }
+        let virtual_time = state.system_time;
+        state.tenants.push(Tenant {
+            config,
+            virtual_time,
+            queues: Default::default(),
+            metrics: TenantMetrics {
+                name: name.to_string(),
+                queued: 0,
+                in_flight: 0,
+                memory_in_use: 0,
+                completed: 0,
+                failed: 0,
+                cancelled: 0,
+                gpu_time: Duration::ZERO,
+                total_wait: Duration::ZERO,
+                max_wait: Duration::ZERO,
+                started: 0,
+            },
+        });
+        drop(state);
+        Ok(())
+    }
+
+    /// Queue `job` for `tenant`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tenant is unknown, or the job declares more
+    /// memory than the tenant's quota.
+    pub fn submit(&self, tenant: &str, job: GpuJob<'a>) -> Result<JobId> {
+        let mut state = self.state();
+        let index = state.tenant_index(tenant)?;
+        if let Some(quota) = state.tenants[index].config.memory_quota {
+            if job.memory > quota {
+                return Err(Error::invalid_input(format!(
+                    "job declares {} bytes, tenant {tenant} has a quota of {quota}",
+                    job.memory
+                )));
+            }
+        }
+        let id = JobId(state.next_id);
+        state.next_id += 1;
+        let system_time = state.system_time;
+        let entry = &mut state.tenants[index];
+        if entry.is_idle() {
+            // Resume at the current share rather than claiming the time
+            // the tenant spent idle.
+            entry.virtual_time = entry.virtual_time.max(system_time);
+        }
+        entry.queues[job.priority.index()].push_back(Queued {
+            id,
+            job,
+            submitted: self.clock.now(),
+        });
+        entry.metrics.queued += 1;
+        drop(state);
+        Ok(id)
+    }
+
+    /// Remove a job that has not started.
+    ///
+    /// Returns `false` if the job is unknown, running or finished.
+    pub fn cancel(&self, id: JobId) -> bool {
+        self.state().tenants.iter_mut().any(|tenant| {
+            let Some(queue) = tenant
+                .queues
+                .iter_mut()
+                .find(|queue| queue.iter().any(|queued| queued.id == id))
+            else {
+                return false;
+            };
+            queue.retain(|queued| queued.id != id);
+            tenant.metrics.queued -= 1;
+            tenant.metrics.cancelled += 1;
+            true
+        })
+    }
+
+    /// Snapshot of queue depths and tenant counters.
+    #[must_use]
+    pub fn metrics(&self) -> SchedulerMetrics {
+        let state = self.state();
+        let depth = Priority::ALL.map(|priority| {
+            let queued = state
+                .tenants
+                .iter()
+                .map(|tenant| tenant.queues[priority.index()].len())
+                .sum();
+            (priority, queued)
+        });
+        SchedulerMetrics {
+            tenants: state
+                .tenants
+                .iter()
+                .map(|tenant| tenant.metrics.clone())
+                .collect(),
+            depth,
+            preemptions: state.preemptions,
+        }
+    }
+}
+
+impl<C: Clock> fmt::Debug for JobQueue<'_, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metrics = self.metrics();
+        f.debug_struct("JobQueue")
+            .field("tenants", &metrics.tenants.len())
+            .field("queued", &metrics.queue_depth())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Priority and fair-share scheduler over one [`MetalCompute`].
+///
+/// See the [module documentation](self).
+pub struct GpuScheduler<'a, C: Clock = SystemClock> {
+    compute: &'a MetalCompute,
+    queue: JobQueue<'a, C>,
+}
+
+impl<'a> GpuScheduler<'a> {
+    /// Create a scheduler timed by the system clock.
+    #[must_use]
+    pub fn new(compute: &'a MetalCompute) -> Self {
+        Self::with_clock(compute, SystemClock::new())
+    }
+}
+
+impl<'a, C: Clock> GpuScheduler<'a, C> {
+    /// Create a scheduler timed by `clock`.
+    pub fn with_clock(compute: &'a MetalCompute, clock: C) -> Self {
+        Self {
+            compute,
+            queue: JobQueue {
+                clock,
+                state: Mutex::new(State::default()),
+            },
+        }
+    }
+
+    /// The scheduler's tenants and queued jobs, for submitting from other
+    /// threads.
+    #[must_use]
+    pub const fn queue(&self) -> &JobQueue<'a, C> {
+        &self.queue
+    }
+
+    /// Register a tenant; see [`JobQueue::add_tenant`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is taken, the weight is zero, or the
+    /// in-flight limit is zero.
+    pub fn add_tenant(&self, name: &str, config: TenantConfig) -> Result<()> {
+        self.queue.add_tenant(name, config)
+    }
+
+    /// Queue `job` for `tenant`; see [`JobQueue::submit`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tenant is unknown, or the job declares more
+    /// memory than the tenant's quota.
+    pub fn submit(&self, tenant: &str, job: GpuJob<'a>) -> Result<JobId> {
+        self.queue.submit(tenant, job)
+    }
+
+    /// Remove a job that has not started.
+    ///
+    /// Returns `false` if the job is unknown, running or finished.
+    pub fn cancel(&self, id: JobId) -> bool {
+        self.queue.cancel(id)
+    }
+
+    /// Start the next job: the highest-priority eligible job, from the
+    /// tenant furthest below its fair share.
+    ///
+    /// Returns `None` if no queued job fits its tenant's quotas.
+    pub fn next(&self) -> Option<JobLease<'_, 'a, C>> {
+        let mut state = self.queue.state();
+        let (priority, index) = Priority::ALL.into_iter().find_map(|priority| {
+            state
+                .tenants
+                .iter()
+                .enumerate()
+                .filter(|(_, tenant)| tenant.can_start(priority))
+                .min_by(|(_, a), (_, b)| a.virtual_time.total_cmp(&b.virtual_time))
+                .map(|(index, _)| (priority, index))
+        })?;
+
+        if state
+            .last_priority
+            .is_some_and(|last| last < priority && state.is_queued(last))
+        {
+            state.preemptions += 1;
+        }
+        state.last_priority = Some(priority);
+
+        let now = self.queue.clock.now();
+        let tenant = &mut state.tenants[index];
+        let queued = tenant.queues[priority.index()].pop_front()?;
+        let wait = now.saturating_sub(queued.submitted);
+        let metrics = &mut tenant.metrics;
+        metrics.queued -= 1;
+        metrics.in_flight += 1;
+        metrics.memory_in_use = metrics.memory_in_use.saturating_add(queued.job.memory);
+        metrics.started += 1;
+        metrics.total_wait += wait;
+        metrics.max_wait = metrics.max_wait.max(wait);
+        let name = metrics.name.clone();
+        state.system_time = state.tenants[index].virtual_time;
+        drop(state);
+
+        debug!(tenant = %name, job = queued.id.0, %priority, ?wait, "starting GPU job");
+        Some(JobLease {
+            scheduler: self,
+            id: queued.id,
+            tenant: index,
+            tenant_name: name,
+            priority,
+            memory: queued.job.memory,
+            wait,
+            started: now,
+            run: Some(queued.job.run),
+        })
+    }
+
+    /// Start and run the next job.
+    ///
+    /// Returns `None` if no queued job fits its tenant's quotas.
+    pub fn run_next(&self) -> Option<JobReport> {
+        self.next().map(JobLease::run)
+    }
+
+    /// Run jobs until none is eligible.
+    pub fn run_until_idle(&self) -> Vec<JobReport> {
+        std::iter::from_fn(|| self.run_next()).collect()
+    }
+
+    /// Snapshot of queue depths and tenant counters.
+    #[must_use]
+    pub fn metrics(&self) -> SchedulerMetrics {
+        self.queue.metrics()
+    }
+
+    /// Return a lease's quota and charge its run time.
+    fn finish(&self, lease: &JobLease<'_, 'a, C>, outcome: Option<&Result<()>>) -> Duration {
+        let duration = self.queue.clock.now().saturating_sub(lease.started);
+        let mut state = self.queue.state();
+        let tenant = &mut state.tenants[lease.tenant];
+        let metrics = &mut tenant.metrics;
+        metrics.in_flight -= 1;
+        metrics.memory_in_use = metrics.memory_in_use.saturating_sub(lease.memory);
+        match outcome {
+            Some(Ok(())) => metrics.completed += 1,
+            Some(Err(_)) => metrics.failed += 1,
+            None => metrics.cancelled += 1,
+        }
+        if outcome.is_some() {
+            metrics.gpu_time += duration;
+            let charge = duration.max(MIN_JOB_CHARGE).as_secs_f64();
+            tenant.virtual_time += charge / f64::from(tenant.config.weight);
+        }
+        drop(state);
+        duration
+    }
+}
+
+impl<C: Clock> fmt::Debug for GpuScheduler<'_, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metrics = self.metrics();
+        f.debug_struct("GpuScheduler")
+            .field("device", &self.compute.device_name())
+            .field("tenants", &metrics.tenants.len())
+            .field("queued", &metrics.queue_depth())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A started job, in flight until it is run or dropped.
+///
+/// Dropping a lease without running it cancels the job and returns its
+/// quota.
+pub struct JobLease<'s, 'a, C: Clock = SystemClock> {
+    scheduler: &'s GpuScheduler<'a, C>,
+    id: JobId,
+    tenant: usize,
+    tenant_name: String,
+    priority: Priority,
+    memory: u64,
+    wait: Duration,
+    started: Duration,
+    run: Option<JobFn<'a>>,
+}
+
+impl<C: Clock> JobLease<'_, '_, C> {
+    /// The job.
+    #[must_use]
+    pub const fn id(&self) -> JobId {
+        self.id
+    }
+
+    /// Tenant that submitted the job.
+    #[must_use]
+    pub fn tenant(&self) -> &str {
+        &self.tenant_name
+    }
+
+    /// The job's priority class.
+    #[must_use]
+    pub const fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Time the job waited in the queue.
+    #[must_use]
+    pub const fn wait(&self) -> Duration {
+        self.wait
+    }
+
+    /// Run the job on the scheduler's pipeline and release its quota.
+    #[allow(clippy::must_use_candidate)]
+    pub fn run(mut self) -> JobReport {
+        let result = self
+            .run
+            .take()
+            .map_or(Ok(()), |run| run(self.scheduler.compute));
+        let duration = self.scheduler.finish(&self, Some(&result));
+        JobReport {
+            id: self.id,
+            tenant: std::mem::take(&mut self.tenant_name),
+            priority: self.priority,
+            wait: self.wait,
+            duration,
+            result,
+        }
+    }
+}
+
+impl<C: Clock> Drop for JobLease<'_, '_, C> {
+    fn drop(&mut self) {
+        if self.run.is_some() {
+            self.scheduler.finish(self, None);
+        }
+    }
+}
+
+impl<C: Clock> fmt::Debug for JobLease<'_, '_, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobLease")
+            .field("id", &self.id)
+            .field("tenant", &self.tenant_name)
+            .field("priority", &self.priority)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// A job that runs for `millis` of simulated time.
+    fn timed<'a>(clock: &SimulatedClock, millis: u64) -> GpuJob<'a> {
+        let clock = clock.clone();
+        GpuJob::new(move |_| {
+            clock.advance(Duration::from_millis(millis));
+            Ok(())
+        })
+    }
+
+    fn scheduler(compute: &MetalCompute) -> (GpuScheduler<'_, SimulatedClock>, SimulatedClock) {
+        let clock = SimulatedClock::new();
+        (GpuScheduler::with_clock(compute, clock.clone()), clock)
+    }
+
+    #[test]
+    fn test_priority_classes_and_preemption() {
+        let compute = MetalCompute::cpu();
+        let (scheduler, clock) = scheduler(&compute);
+        scheduler.add_tenant("batch", TenantConfig::new()).unwrap();
+        scheduler.add_tenant("web", TenantConfig::new()).unwrap();
+        for _ in 0..3 {
+            scheduler
+                .submit(
+                    "batch",
+                    timed(&clock, 10).with_priority(Priority::Background),
+                )
+                .unwrap();
+        }
+        assert_eq!(scheduler.run_next().unwrap().tenant, "batch");
+
+        // Interactive work submitted mid-batch runs at the next boundary.
+        scheduler
+            .submit("web", timed(&clock, 1).with_priority(Priority::Interactive))
+            .unwrap();
+        scheduler.submit("web", timed(&clock, 1)).unwrap();
+        let order: Vec<_> = scheduler
+            .run_until_idle()
+            .into_iter()
+            .map(|report| (report.tenant, report.priority))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("web".to_string(), Priority::Interactive),
+                ("web".to_string(), Priority::Normal),
+                ("batch".to_string(), Priority::Background),
+                ("batch".to_string(), Priority::Background),
+            ]
+        );
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics.preemptions, 1);
+        assert_eq!(metrics.queue_depth(), 0);
+        assert_eq!(metrics.tenant("batch").unwrap().completed, 3);
+        assert_eq!(
+            metrics.tenant("web").unwrap().gpu_time,
+            Duration::from_millis(2)
+        );
+    }
+
+    #[test]
+    fn test_weighted_fair_share() {
+        let compute = MetalCompute::cpu();
+        let (scheduler, clock) = scheduler(&compute);
+        scheduler
+            .add_tenant("heavy", TenantConfig::new().with_weight(3))
+            .unwrap();
+        scheduler.add_tenant("light", TenantConfig::new()).unwrap();
+        for _ in 0..40 {
+            scheduler.submit("heavy", timed(&clock, 2)).unwrap();
+            scheduler.submit("light", timed(&clock, 2)).unwrap();
+        }
+        let first_forty: Vec<_> = (0..40)
+            .map(|_| scheduler.run_next().unwrap().tenant)
+            .collect();
+        let heavy = first_forty.iter().filter(|t| *t == "heavy").count();
+        assert_eq!(heavy, 30, "{first_forty:?}");
+
+        // A tenant that was idle does not get to catch up.
+        scheduler.run_until_idle();
+        scheduler.add_tenant("late", TenantConfig::new()).unwrap();
+        for _ in 0..4 {
+            scheduler.submit("late", timed(&clock, 2)).unwrap();
+            scheduler.submit("light", timed(&clock, 2)).unwrap();
+        }
+        let order: Vec<_> = scheduler
+            .run_until_idle()
+            .into_iter()
+            .map(|report| report.tenant)
+            .collect();
+        assert_eq!(
+            order.iter().take(4).filter(|t| *t == "late").count(),
+            2,
+            "{order:?}"
+        );
+    }
+
+    #[test]
+    fn test_quotas_hold_jobs_back() {
+        let compute = MetalCompute::cpu();
+        let (scheduler, clock) = scheduler(&compute);
+        scheduler
+            .add_tenant(
+                "a",
+                TenantConfig::new()
+                    .with_max_in_flight(2)
+                    .with_memory_quota(100),
+            )
+            .unwrap();
+        scheduler.add_tenant("b", TenantConfig::new()).unwrap();
+        assert!(scheduler
+            .submit("a", GpuJob::new(|_| Ok(())).with_memory(101))
+            .is_err());
+        assert!(scheduler.submit("nobody", GpuJob::new(|_| Ok(()))).is_err());
+
+        for memory in [60, 30, 50, 10] {
+            scheduler
+                .submit("a", timed(&clock, 1).with_memory(memory))
+                .unwrap();
+        }
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+        assert_eq!((first.tenant(), second.tenant()), ("a", "a"));
+        // Two in flight: the third waits even though memory would fit.
+        assert!(scheduler.next().is_none());
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics.tenant("a").unwrap().memory_in_use, 90);
+        assert_eq!(metrics.tenant("a").unwrap().in_flight, 2);
+
+        scheduler.submit("b", timed(&clock, 1)).unwrap();
+        assert_eq!(scheduler.next().unwrap().tenant(), "b");
+
+        // With the 60 in flight, the 50 does not fit until it ends.
+        drop(second);
+        assert!(scheduler.next().is_none());
+        first.run();
+        let third = scheduler.next().unwrap();
+        assert_eq!(scheduler.metrics().tenant("a").unwrap().memory_in_use, 50);
+        third.run();
+
+        let metrics = scheduler.metrics();
+        let a = metrics.tenant("a").unwrap();
+        assert_eq!((a.completed, a.cancelled, a.queued), (2, 1, 1));
+        assert_eq!(metrics.tenant("b").unwrap().cancelled, 1);
+    }
+
+    #[test]
+    fn test_wait_metrics_and_cancel() {
+        let compute = MetalCompute::cpu();
+        let (scheduler, clock) = scheduler(&compute);
+        scheduler.add_tenant("t", TenantConfig::new()).unwrap();
+        scheduler.submit("t", timed(&clock, 4)).unwrap();
+        let doomed = scheduler.submit("t", timed(&clock, 4)).unwrap();
+        scheduler
+            .submit("t", GpuJob::new(|_| Err(Error::metal("kernel fault"))))
+            .unwrap();
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(scheduler.metrics().depth[1], (Priority::Normal, 3));
+
+        assert!(scheduler.cancel(doomed));
+        assert!(!scheduler.cancel(doomed));
+        let reports = scheduler.run_until_idle();
+        assert_eq!(reports[0].wait, Duration::from_millis(1));
+        assert_eq!(reports[1].wait, Duration::from_millis(5));
+        assert!(reports[1].result.is_err());
+
+        let metrics = scheduler.metrics();
+        let t = metrics.tenant("t").unwrap();
+        assert_eq!((t.completed, t.failed, t.cancelled), (1, 1, 1));
+        assert_eq!(t.max_wait, Duration::from_millis(5));
+        assert_eq!(t.mean_wait(), Duration::from_millis(3));
+        assert!(scheduler.add_tenant("t", TenantConfig::new()).is_err());
+        assert!(scheduler
+            .add_tenant("zero", TenantConfig::new().with_weight(0))
+            .is_err());
+        let err = scheduler
+            .add_tenant("stuck", TenantConfig::new().with_max_in_flight(0))
+            .unwrap_err();
+        assert!(err.to_string().contains("at least one job in flight"));
+    }
+
+    #[test]
+    fn test_submit_from_many_threads() {
+        let compute = MetalCompute::cpu();
+        let (scheduler, clock) = scheduler(&compute);
+        let queue = scheduler.queue();
+        for tenant in ["a", "b"] {
+            queue.add_tenant(tenant, TenantConfig::new()).unwrap();
+        }
+        let mut ids: Vec<JobId> = std::thread::scope(|scope| {
+            let mut workers = Vec::new();
+            for worker in 0..8 {
+                let clock = &clock;
+                workers.push(scope.spawn(move || {
+                    let tenant = if worker % 2 == 0 { "a" } else { "b" };
+                    (0..25)
+                        .map(|_| queue.submit(tenant, timed(clock, 1)).unwrap())
+                        .collect::<Vec<_>>()
+                }));
+            }
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 200);
+
+        assert_eq!(scheduler.metrics().queue_depth(), 200);
+        assert_eq!(scheduler.run_until_idle().len(), 200);
+        let metrics = scheduler.metrics();
+        for tenant in ["a", "b"] {
+            assert_eq!(metrics.tenant(tenant).unwrap().completed, 100);
+        }
+    }
+
+    #[test]
+    fn test_memory_accounting_saturates() {
+        let compute = MetalCompute::cpu();
+        let (scheduler, _) = scheduler(&compute);
+        scheduler.add_tenant("t", TenantConfig::new()).unwrap();
+        for _ in 0..2 {
+            scheduler
+                .submit("t", GpuJob::new(|_| Ok(())).with_memory(u64::MAX))
+                .unwrap();
+        }
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+        assert_eq!(
+            scheduler.metrics().tenant("t").unwrap().memory_in_use,
+            u64::MAX
+        );
+        first.run();
+        second.run();
+        assert_eq!(scheduler.metrics().tenant("t").unwrap().memory_in_use, 0);
+    }
+}
@@ -0,0 +1,710 @@
+//! Function constants and shader specialization.
+//!
+//! MSL kernels declare function constants with
+//! `constant T name [[function_constant(index)]];` and are specialized at
+//! pipeline creation time. [`CompileOptions`] supplies typed values for
+//! those constants, by index or by name, plus preprocessor defines.
+//! [`MetalCompute::compile_shader_with`](super::MetalCompute::compile_shader_with)
+//! caches each distinct specialization as its own
+//! [`CompiledShader`](super::CompiledShader).
+//!
+//! Every constant the kernel uses must be given a value unless the source
+//! guards it with `is_function_constant_defined(name)`, in which case it
+//! is optional. A constant counts as used if it is named in the kernel, in
+//! a function the kernel calls, or in a program-scope constant those use;
+//! constants only other kernels in the source use may be left unset.
+//! Values must match the declared type.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::{CompileOptions, MetalCompute};
+//!
+//! let compute = MetalCompute::default_device()?;
+//! let source = r#"
+//!     constant uint TILE [[function_constant(0)]];
+//!     constant bool USE_BIAS [[function_constant(1)]];
+//!     kernel void matmul(device float* out [[buffer(0)]]) { /* ... */ }
+//! "#;
+//! let options = CompileOptions::new()
+//!     .with_constant("TILE", 16u32)
+//!     .with_constant_index(1, true)
+//!     .with_define("ACC_TYPE", "float");
+//! let shader = compute.compile_shader_with(source, "matmul", &options)?;
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+use crate::error::{Error, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Write as _};
+
+/// Typed value for a function constant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstantValue {
+    /// MSL `bool`
+    Bool(bool),
+    /// MSL `int`
+    Int(i32),
+    /// MSL `uint`
+    UInt(u32),
+    /// MSL `float` (also accepted for `half`)
+    Float(f32),
+    /// MSL `int2`
+    Int2([i32; 2]),
+    /// MSL `int3`
+    Int3([i32; 3]),
+    /// MSL `int4`
+    Int4([i32; 4]),
+    /// MSL `uint2`
+    UInt2([u32; 2]),
+    /// MSL `uint3`
+    UInt3([u32; 3]),
+    /// MSL `uint4`
+    UInt4([u32; 4]),
+    /// MSL `float2`
+    Float2([f32; 2]),
+    /// MSL `float3`
+    Float3([f32; 3]),
+    /// MSL `float4`
+    Float4([f32; 4]),
+}
+
+impl ConstantValue {
+    /// MSL type name of the value.
+    #[must_use]
+    pub const fn msl_type(&self) -> &'static str {
+        match self {
+            Self::Bool(_) => "bool",
+            Self::Int(_) => "int",
+            Self::UInt(_) => "uint",
+            Self::Float(_) => "float",
+            Self::Int2(_) => "int2",
+            Self::Int3(_) => "int3",
+            Self::Int4(_) => "int4",
+            Self::UInt2(_) => "uint2",
+            Self::UInt3(_) => "uint3",
+            Self::UInt4(_) => "uint4",
+            Self::Float2(_) => "float2",
+            Self::Float3(_) => "float3",
+            Self::Float4(_) => "float4",
+        }
+    }
+
+    /// Check whether the value can specialize a constant of MSL type `ty`.
+    #[must_use]
+    pub fn matches_type(&self, ty: &str) -> bool {
+        let ty = match ty {
+            "unsigned int" => "uint",
+            "half" => "float",
+            "half2" => "float2",
+            "half3" => "float3",
+            "half4" => "float4",
+            other => other,
+        };
+        self.msl_type() == ty
+    }
+}
+
+fn join<T: fmt::Debug>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{v:?}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for ConstantValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::Int(v) => write!(f, "{v}"),
+            Self::UInt(v) => write!(f, "{v}u"),
+            Self::Float(v) => write!(f, "{v:?}f"),
+            Self::Int2(v) => write!(f, "int2({})", join(v)),
+            Self::Int3(v) => write!(f, "int3({})", join(v)),
+            Self::Int4(v) => write!(f, "int4({})", join(v)),
+            Self::UInt2(v) => write!(f, "uint2({})", join(v)),
+            Self::UInt3(v) => write!(f, "uint3({})", join(v)),
+            Self::UInt4(v) => write!(f, "uint4({})", join(v)),
+            Self::Float2(v) => write!(f, "float2({})", join(v)),
+            Self::Float3(v) => write!(f, "float3({})", join(v)),
+            Self::Float4(v) => write!(f, "float4({})", join(v)),
+        }
+    }
+}
+
+macro_rules! impl_from_value {
+    ($($ty:ty => $variant:ident),* $(,)?) => {$(
+        impl From<$ty> for ConstantValue {
+            fn from(value: $ty) -> Self {
+                Self::$variant(value)
+            }
+        }
+    )*};
+}
+
+impl_from_value!(
+    bool => Bool,
+    i32 => Int,
+    u32 => UInt,
+    f32 => Float,
+    [i32; 2] => Int2,
+    [i32; 3] => Int3,
+    [i32; 4] => Int4,
+    [u32; 2] => UInt2,
+    [u32; 3] => UInt3,
+    [u32; 4] => UInt4,
+    [f32; 2] => Float2,
+    [f32; 3] => Float3,
+    [f32; 4] => Float4,
+);
+
+/// How a [`CompileOptions`] value refers to a function constant.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConstantId {
+    /// The `[[function_constant(index)]]` index.
+    Index(u32),
+    /// The constant's name in the source.
+    Name(String),
+}
+
+impl fmt::Display for ConstantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "index {index}"),
+            Self::Name(name) => write!(f, "'{name}'"),
+        }
+    }
+}
+
+/// A function constant declared in MSL source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionConstant {
+    /// Constant name.
+    pub name: String,
+    /// `[[function_constant(index)]]` index.
+    pub index: u32,
+    /// Declared MSL type.
+    pub ty: String,
+    /// True if the source checks `is_function_constant_defined(name)`.
+    pub optional: bool,
+}
+
+/// Find every function constant declared in `source`.
+///
+/// # Errors
+///
+/// Returns an error if a declaration is malformed or two constants share
+/// an index.
+pub fn declared_constants(source: &str) -> Result<Vec<FunctionConstant>> {
+    const ATTRIBUTE: &str = "[[function_constant(";
+    let code = strip_comments(source);
+    let mut constants: Vec<FunctionConstant> = Vec::new();
+    let mut rest = code.as_str();
+    let mut consumed = 0;
+
+    while let Some(at) = rest.find(ATTRIBUTE) {
+        let declaration_start = code[..consumed + at]
+            .rfind([';', '{', '}'])
+            .map_or(0, |i| i + 1);
+        let declaration = &code[declaration_start..consumed + at];
+        let after = &rest[at + ATTRIBUTE.len()..];
+        let index = after
+            .split_once(')')
+            .and_then(|(digits, _)| digits.trim().parse::<u32>().ok())
+            .ok_or_else(|| Error::invalid_input("malformed function_constant attribute"))?;
+
+        let tokens: Vec<&str> = declaration.split_whitespace().collect();
+        let keyword = tokens
+            .iter()
+            .rposition(|&token| token == "constant")
+            .ok_or_else(|| {
+                Error::invalid_input(format!(
+                    "function constant {index} is not declared `constant`"
+                ))
+            })?;
+        let (name, ty) = match &tokens[keyword + 1..] {
+            [ty @ .., name] if !ty.is_empty() => ((*name).to_string(), ty.join(" ")),
+            _ => {
+                return Err(Error::invalid_input(format!(
+                    "malformed declaration of function constant {index}"
+                )))
+            }
+        };
+        if let Some(existing) = constants.iter().find(|c| c.index == index) {
+            return Err(Error::invalid_input(format!(
+                "function constants '{}' and '{name}' share index {index}",
+                existing.name
+            )));
+        }
+        let optional = code.contains(&format!("is_function_constant_defined({name})"));
+        constants.push(FunctionConstant {
+            name,
+            index,
+            ty,
+            optional,
+        });
+
+        consumed += at + ATTRIBUTE.len();
+        rest = &code[consumed..];
+    }
+    Ok(constants)
+}
+
+/// The constants declared in `source`, with those that `function` does
+/// not use marked optional.
+///
+/// If `function` is not defined in `source`, every constant keeps its
+/// declared optionality.
+pub(crate) fn kernel_constants(source: &str, function: &str) -> Result<Vec<FunctionConstant>> {
+    let mut constants = declared_constants(source)?;
+    let code = strip_comments(source);
+    if let Some(used) = referenced_identifiers(&code, function) {
+        for constant in &mut constants {
+            constant.optional |= !used.contains(constant.name.as_str());
+        }
+    }
+    Ok(constants)
+}
+
+/// Every identifier in `function` and in the program-scope functions and
+/// constants it refers to, transitively, or `None` if `function` is not
+/// defined in `code`.
+fn referenced_identifiers<'a>(code: &'a str, function: &str) -> Option<HashSet<&'a str>> {
+    let definitions = top_level_definitions(code);
+    let (&name, &text) = definitions.get_key_value(function)?;
+    let mut pending = vec![text];
+    let mut identifiers = HashSet::from([name]);
+    while let Some(text) = pending.pop() {
+        let words = text
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .filter(|word| !word.is_empty());
+        for word in words {
+            if identifiers.insert(word) {
+                pending.extend(definitions.get(word));
+            }
+        }
+    }
+    Some(identifiers)
+}
+
+/// Program-scope functions and initialized constants in `code`, by name.
+fn top_level_definitions(code: &str) -> HashMap<&str, &str> {
+    let mut definitions = HashMap::new();
+    let (mut depth, mut start, mut open) = (0usize, 0, 0);
+    for (i, c) in code.char_indices() {
+        match c {
+            '{' => {
+                if depth == 0 {
+                    open = i;
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    let header = &code[start..open];
+                    if let Some(name) = header.split_once('(').and_then(|(h, _)| last_identifier(h))
+                    {
+                        definitions.insert(name, &code[start..=i]);
+                    }
+                    start = i + 1;
+                }
+            }
+            ';' if depth == 0 => {
+                let statement = &code[start..i];
+                if let Some(name) = statement
+                    .split_once('=')
+                    .and_then(|(d, _)| last_identifier(d))
+                {
+                    definitions.insert(name, statement);
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    definitions
+}
+
+/// The identifier that `text` ends with, if any.
+fn last_identifier(text: &str) -> Option<&str> {
+    text.trim_end()
+        .rsplit(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .next()
+        .filter(|name| name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+}
+
+/// Replace `//` and `/* */` comments with spaces.
+pub(crate) fn strip_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        out.push('\n');
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                out.push(' ');
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Resolved function constant values of a specialized shader.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Specialization {
+    values: Vec<(FunctionConstant, ConstantValue)>,
+}
+
+impl Specialization {
+    /// Value of the constant called `name`, if it was set.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<ConstantValue> {
+        self.values
+            .iter()
+            .find(|(constant, _)| constant.name == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Value of the constant at `index`, if it was set.
+    #[must_use]
+    pub fn get_index(&self, index: u32) -> Option<ConstantValue> {
+        self.values
+            .iter()
+            .find(|(constant, _)| constant.index == index)
+            .map(|(_, value)| *value)
+    }
+
+    /// Number of constants that were set.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// True if no constants were set.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Options for compiling a specialized shader.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompileOptions {
+    constants: Vec<(ConstantId, ConstantValue)>,
+    defines: BTreeMap<String, String>,
+}
+
+impl CompileOptions {
+    /// Create empty options.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the function constant called `name`.
+    #[must_use]
+    pub fn with_constant(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<ConstantValue>,
+    ) -> Self {
+        self.constants
+            .push((ConstantId::Name(name.into()), value.into()));
+        self
+    }
+
+    /// Set the function constant at `index`.
+    #[must_use]
+    pub fn with_constant_index(mut self, index: u32, value: impl Into<ConstantValue>) -> Self {
+        self.constants
+            .push((ConstantId::Index(index), value.into()));
+        self
+    }
+
+    /// Add a preprocessor define (`#define name value`).
+    #[must_use]
+    pub fn with_define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.defines.insert(name.into(), value.into());
+        self
+    }
+
+    /// Function constant values in the order they were set.
+    #[must_use]
+    pub fn constants(&self) -> &[(ConstantId, ConstantValue)] {
+        &self.constants
+    }
+
+    /// Preprocessor defines, sorted by name.
+    #[must_use]
+    pub const fn defines(&self) -> &BTreeMap<String, String> {
+        &self.defines
+    }
+
+    /// Prepend the defines to `source`.
+    pub(crate) fn preprocess(&self, source: &str) -> String {
+        let mut out = String::with_capacity(source.len());
+        for (name, value) in &self.defines {
+            let _ = writeln!(out, "#define {name} {value}");
+        }
+        out.push_str(source);
+        out
+    }
+
+    /// Canonical description of the constant values, independent of the
+    /// order they were set in.
+    pub(crate) fn constants_key(specialization: &Specialization) -> String {
+        let mut values: Vec<String> = specialization
+            .values
+            .iter()
+            .map(|(constant, value)| format!("{}={value}", constant.index))
+            .collect();
+        values.sort();
+        values.join(";")
+    }
+
+    /// Match the values to the constants `declared` by the source.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a value names an undeclared constant, a constant
+    /// is set twice, a value has the wrong type, or a required constant is
+    /// unset.
+    pub fn resolve(&self, declared: &[FunctionConstant]) -> Result<Specialization> {
+        let mut values: Vec<(FunctionConstant, ConstantValue)> = Vec::new();
+        for (id, value) in &self.constants {
+            let constant = declared
+                .iter()
+                .find(|c| match id {
+                    ConstantId::Index(index) => c.index == *index,
+                    ConstantId::Name(name) => &c.name == name,
+                })
+                .ok_or_else(|| Error::invalid_input(format!("no function constant with {id}")))?;
+            if values.iter().any(|(c, _)| c.index == constant.index) {
+                return Err(Error::invalid_input(format!(
+                    "function constant '{}' is set more than once",
+                    constant.name
+                )));
+            }
+            if !value.matches_type(&constant.ty) {
+                return Err(Error::invalid_input(format!(
+                    "function constant '{}' is {}, got {}",
+                    constant.name,
+                    constant.ty,
+                    value.msl_type()
+                )));
+            }
+            values.push((constant.clone(), *value));
+        }
+
+        if let Some(missing) = declared
+            .iter()
+            .find(|c| !c.optional && !values.iter().any(|(set, _)| set.index == c.index))
+        {
+            return Err(Error::invalid_input(format!(
+                "function constant '{}' (index {}) is not set",
+                missing.name, missing.index
+            )));
+        }
+        values.sort_by_key(|(constant, _)| constant.index);
+        Ok(Specialization { values })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r"
+        // constant int IGNORED [[function_constant(9)]];
+        constant uint TILE [[function_constant(0)]];
+        constant bool USE_BIAS [[function_constant(1)]];
+        /* block comment */ constant float2 SCALE [[function_constant(2)]];
+        constant half ALPHA [[function_constant(3)]];
+        constant int DEBUG_LEVEL [[function_constant(4)]];
+        kernel void k() {
+            if (is_function_constant_defined(DEBUG_LEVEL)) {}
+        }
+    ";
+
+    fn full_options() -> CompileOptions {
+        CompileOptions::new()
+            .with_constant("TILE", 16u32)
+            .with_constant_index(1, true)
+            .with_constant("SCALE", [0.5f32, 2.0])
+            .with_constant("ALPHA", 0.25f32)
+    }
+
+    #[test]
+    fn test_declared_constants() {
+        let constants = declared_constants(SOURCE).unwrap();
+        let names: Vec<&str> = constants.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["TILE", "USE_BIAS", "SCALE", "ALPHA", "DEBUG_LEVEL"]
+        );
+        assert_eq!(constants[2].ty, "float2");
+        assert_eq!(constants[2].index, 2);
+        assert!(!constants[0].optional);
+        assert!(constants[4].optional);
+        assert!(declared_constants("kernel void k() {}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_declared_constants_malformed() {
+        assert!(declared_constants("constant uint A [[function_constant(x)]];").is_err());
+        assert!(declared_constants("uint A [[function_constant(0)]];").is_err());
+        assert!(declared_constants(
+            "constant uint A [[function_constant(0)]]; constant uint B [[function_constant(0)]];"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_kernel_constants_follow_calls() {
+        let source = r"
+            constant uint TILE [[function_constant(0)]];
+            constant bool USE_BIAS [[function_constant(1)]];
+            constant float SCALE [[function_constant(2)]];
+            constant float HALF_SCALE = SCALE * 0.5;
+            uint tile() { return TILE; }
+            kernel void tiled(device uint* out [[buffer(0)]]) { out[0] = tile(); }
+            kernel void scaled(device float* out [[buffer(0)]]) { out[0] = HALF_SCALE; }
+            kernel void plain(device uint* out [[buffer(0)]]) {
+                // TILE is only mentioned in a comment.
+                out[0] = 1;
+            }
+        ";
+        let required = |function: &str| -> Vec<String> {
+            kernel_constants(source, function)
+                .unwrap()
+                .into_iter()
+                .filter(|constant| !constant.optional)
+                .map(|constant| constant.name)
+                .collect()
+        };
+        assert_eq!(required("tiled"), ["TILE"]);
+        assert_eq!(required("scaled"), ["SCALE"]);
+        assert!(required("plain").is_empty());
+        assert_eq!(required("missing"), ["TILE", "USE_BIAS", "SCALE"]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let declared = declared_constants(SOURCE).unwrap();
+        let spec = full_options().resolve(&declared).unwrap();
+        assert_eq!(spec.len(), 4);
+        assert_eq!(spec.get("TILE"), Some(ConstantValue::UInt(16)));
+        assert_eq!(spec.get_index(1), Some(ConstantValue::Bool(true)));
+        assert_eq!(spec.get("DEBUG_LEVEL"), None);
+
+        let spec = full_options()
+            .with_constant("DEBUG_LEVEL", 2)
+            .resolve(&declared)
+            .unwrap();
+        assert_eq!(spec.get_index(4), Some(ConstantValue::Int(2)));
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let declared = declared_constants(SOURCE).unwrap();
+        let missing = CompileOptions::new()
+            .with_constant("TILE", 16u32)
+            .resolve(&declared)
+            .unwrap_err();
+        assert!(missing
+            .to_string()
+            .contains("'USE_BIAS' (index 1) is not set"));
+
+        let wrong_type = full_options().with_constant_index(4, 1.0f32);
+        assert!(wrong_type
+            .resolve(&declared)
+            .unwrap_err()
+            .to_string()
+            .contains("is int, got float"));
+
+        let unknown = full_options().with_constant("NOPE", 1);
+        assert!(unknown
+            .resolve(&declared)
+            .unwrap_err()
+            .to_string()
+            .contains("'NOPE'"));
+
+        let twice = full_options().with_constant_index(0, 8u32);
+        assert!(twice
+            .resolve(&declared)
+            .unwrap_err()
+            .to_string()
+            .contains("more than once"));
+    }
+
+    #[test]
+    fn test_constants_key_order_independent() {
+        let declared = declared_constants(SOURCE).unwrap();
+        let a = full_options().resolve(&declared).unwrap();
+        let b = CompileOptions::new()
+            .with_constant("ALPHA", 0.25f32)
+            .with_constant_index(2, [0.5f32, 2.0])
+            .with_constant_index(1, true)
+            .with_constant_index(0, 16u32)
+            .resolve(&declared)
+            .unwrap();
+        assert_eq!(
+            CompileOptions::constants_key(&a),
+            CompileOptions::constants_key(&b)
+        );
+
+        let c = full_options()
+            .with_constant("DEBUG_LEVEL", 1)
+            .resolve(&declared)
+            .unwrap();
+        assert_ne!(
+            CompileOptions::constants_key(&a),
+            CompileOptions::constants_key(&c)
+        );
+    }
+
+    #[test]
+    fn test_defines_prepended() {
+        let options = CompileOptions::new()
+            .with_define("B", "2")
+            .with_define("A", "float");
+        assert_eq!(
+            options.preprocess("kernel"),
+            "#define A float\n#define B 2\nkernel"
+        );
+        assert_eq!(options.defines().len(), 2);
+    }
+
+    #[test]
+    fn test_value_display_and_types() {
+        assert_eq!(ConstantValue::from(3u32).to_string(), "3u");
+        assert_eq!(ConstantValue::from(1.5f32).to_string(), "1.5f");
+        assert_eq!(ConstantValue::from([1, -2]).to_string(), "int2(1, -2)");
+        assert_eq!(
+            ConstantValue::from([1.0f32, 0.0, 2.5, 3.0]).msl_type(),
+            "float4"
+        );
+        assert!(ConstantValue::UInt(1).matches_type("unsigned int"));
+        assert!(ConstantValue::Float(1.0).matches_type("half"));
+        assert!(!ConstantValue::Int(1).matches_type("uint"));
+        assert_eq!(ConstantId::Index(3).to_string(), "index 3");
+    }
+}
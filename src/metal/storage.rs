@@ -0,0 +1,384 @@
+//! Host-visible buffer contents.
+//!
+//! Buffers use shared storage mode semantics: the CPU can read and write
+//! their contents directly. Backing memory is zero-filled and grows lazily
+//! to the highest byte touched, so large allocations that are never written
+//! from the host cost nothing.
+
+use crate::unified_memory::{Detach, UmaBuffer, UmaRegion};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Plain numeric types that can be copied in and out of a
+/// [`MetalBuffer`](super::MetalBuffer) as little-endian bytes.
+pub trait BufferElement: Copy + sealed::Sealed {
+    /// Size of one element in bytes.
+    const SIZE: usize;
+
+    /// Append the little-endian encoding of `self` to `out`.
+    fn write_le(self, out: &mut Vec<u8>);
+
+    /// Decode an element from exactly `SIZE` little-endian bytes.
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_buffer_element {
+    ($($ty:ty),*) => {$(
+        impl sealed::Sealed for $ty {}
+
+        impl BufferElement for $ty {
+            const SIZE: usize = std::mem::size_of::<$ty>();
+
+            fn write_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn read_le(bytes: &[u8]) -> Self {
+                let mut raw = [0u8; std::mem::size_of::<$ty>()];
+                raw.copy_from_slice(&bytes[..Self::SIZE]);
+                Self::from_le_bytes(raw)
+            }
+        }
+    )*};
+}
+
+impl_buffer_element!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+/// Encode a slice of elements as little-endian bytes.
+pub fn to_bytes<T: BufferElement>(data: &[T]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() * T::SIZE);
+    for &value in data {
+        value.write_le(&mut bytes);
+    }
+    bytes
+}
+
+/// Decode little-endian bytes into elements, ignoring a trailing partial
+/// element.
+pub fn from_bytes<T: BufferElement>(bytes: &[u8]) -> Vec<T> {
+    bytes.chunks_exact(T::SIZE).map(T::read_le).collect()
+}
+
+/// Byte ranges, kept sorted and merged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RangeSet(Vec<Range<usize>>);
+
+impl RangeSet {
+    /// Add `range`, merging it with overlapping or adjacent ranges.
+    pub fn insert(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let first = self.0.partition_point(|r| r.end < range.start);
+        let last = self.0.partition_point(|r| r.start <= range.end);
+        let merged = if first < last {
+            self.0[first].start.min(range.start)..self.0[last - 1].end.max(range.end)
+        } else {
+            range
+        };
+        self.0.splice(first..last, std::iter::once(merged));
+    }
+
+    /// The parts of the set inside `window`, shifted to start at zero.
+    pub fn window(&self, window: Range<usize>) -> Self {
+        Self(
+            self.0
+                .iter()
+                .filter(|r| r.end > window.start && r.start < window.end)
+                .map(|r| {
+                    r.start.max(window.start) - window.start..r.end.min(window.end) - window.start
+                })
+                .collect(),
+        )
+    }
+
+    /// True if every byte of `range` is in the set.
+    pub fn contains(&self, range: &Range<usize>) -> bool {
+        range.is_empty()
+            || self
+                .0
+                .iter()
+                .any(|r| r.start <= range.start && range.end <= r.end)
+    }
+
+    /// The ranges, in order.
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.0
+    }
+}
+
+/// Memory behind a [`Storage`].
+enum Backing {
+    /// Host memory owned by the storage, grown on demand.
+    Owned(Vec<u8>),
+    /// A borrowed [`UmaBuffer`] accessed in place, fixed in size; empty
+    /// once detached.
+    Uma(Option<UmaRegion>),
+}
+
+impl Default for Backing {
+    fn default() -> Self {
+        Self::Owned(Vec::new())
+    }
+}
+
+#[derive(Default)]
+struct Contents {
+    backing: Backing,
+    /// Bytes ever written, by the host or a kernel.
+    initialized: RangeSet,
+}
+
+impl Contents {
+    fn bytes(&self) -> &[u8] {
+        match &self.backing {
+            Backing::Owned(bytes) => bytes,
+            Backing::Uma(memory) => memory.as_ref().map_or(&[], UmaRegion::bytes),
+        }
+    }
+
+    /// The bytes, grown to at least `end` if owned.
+    fn bytes_mut(&mut self, end: usize) -> &mut [u8] {
+        match &mut self.backing {
+            Backing::Owned(bytes) => {
+                if bytes.len() < end {
+                    bytes.resize(end, 0);
+                }
+                bytes
+            }
+            Backing::Uma(memory) => memory.as_mut().map_or(&mut [], UmaRegion::bytes_mut),
+        }
+    }
+}
+
+/// Shared, lazily-grown byte storage behind one or more buffers.
+///
+/// Heap slices share the storage of their heap's backing buffer. Storage
+/// can instead borrow a [`UmaBuffer`], which it accesses in place; writes
+/// past its allocation, or after it is detached, are dropped and reads
+/// return zero. Written
+/// ranges are tracked so validation can flag reads of uninitialized
+/// memory.
+#[derive(Clone, Default)]
+pub struct Storage(Arc<Mutex<Contents>>);
+
+impl Storage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Storage over `memory`, whose first `len()` bytes hold data.
+    ///
+    /// The returned callback detaches the storage from `memory`; it must
+    /// run before the borrow ends, and `memory` runs it itself if it is
+    /// freed or mutably borrowed first.
+    pub fn uma(memory: &UmaBuffer) -> (Self, Detach) {
+        let storage = Self::new();
+        let detach: Detach = {
+            let storage = storage.clone();
+            Arc::new(move || storage.lock().backing = Backing::Uma(None))
+        };
+        let mut contents = storage.lock();
+        contents.initialized.insert(0..memory.len());
+        contents.backing = Backing::Uma(Some(memory.gpu_region(&detach)));
+        drop(contents);
+        (storage, detach)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Contents> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Copy `data` into the storage at `start`, marking it initialized.
+    pub fn write(&self, start: usize, data: &[u8]) {
+        self.write_marking(start, data, std::iter::once(0..data.len()));
+    }
+
+    /// Copy `data` into the storage at `start`, marking only the `written`
+    /// ranges (relative to `start`) initialized.
+    fn write_marking(
+        &self,
+        start: usize,
+        data: &[u8],
+        written: impl IntoIterator<Item = Range<usize>>,
+    ) {
+        let mut contents = self.lock();
+        let bytes = contents.bytes_mut(start + data.len());
+        let Some(target) = bytes.get_mut(start..) else {
+            return;
+        };
+        let len = data.len().min(target.len());
+        target[..len].copy_from_slice(&data[..len]);
+        for range in written {
+            contents
+                .initialized
+                .insert(start + range.start..start + range.end.min(data.len()));
+        }
+    }
+
+    /// Copy `len` bytes starting at `start` out of the storage.
+    pub fn read(&self, start: usize, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        let contents = self.lock();
+        let bytes = contents.bytes();
+        if start < bytes.len() {
+            let available = (bytes.len() - start).min(len);
+            out[..available].copy_from_slice(&bytes[start..start + available]);
+        }
+        drop(contents);
+        out
+    }
+
+    /// Bytes currently resident on the host.
+    pub fn resident(&self) -> usize {
+        self.lock().bytes().len()
+    }
+}
+
+/// A byte range of a [`Storage`], detached from the buffer or texture that
+/// owns it so work can run after the borrow ends.
+#[derive(Debug, Clone)]
+pub struct StorageView {
+    storage: Storage,
+    start: usize,
+    len: usize,
+}
+
+impl StorageView {
+    pub const fn new(storage: Storage, start: usize, len: usize) -> Self {
+        Self {
+            storage,
+            start,
+            len,
+        }
+    }
+
+    /// Length of the range in bytes.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// True if both views cover some of the same bytes.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.storage.0, &other.storage.0)
+            && self.start < other.start + other.len
+            && other.start < self.start + self.len
+    }
+
+    /// Copy the whole range out.
+    pub fn read(&self) -> Vec<u8> {
+        self.storage.read(self.start, self.len)
+    }
+
+    /// Overwrite the start of the range with `data`, truncated to fit.
+    pub fn write(&self, data: &[u8]) {
+        self.storage
+            .write(self.start, &data[..data.len().min(self.len)]);
+    }
+
+    /// Overwrite the start of the range with `data`, marking only the
+    /// `written` ranges (relative to the view) initialized.
+    pub fn write_back(&self, data: &[u8], written: &RangeSet) {
+        self.storage.write_marking(
+            self.start,
+            &data[..data.len().min(self.len)],
+            written.ranges().iter().cloned(),
+        );
+    }
+
+    /// Copy only the `ranges` (relative to the view) of `data` into the
+    /// range, marking them initialized.
+    pub fn write_ranges(&self, data: &[u8], ranges: &RangeSet) {
+        let end = data.len().min(self.len);
+        for range in ranges.ranges() {
+            let range = range.start.min(end)..range.end.min(end);
+            if !range.is_empty() {
+                self.storage.write(self.start + range.start, &data[range]);
+            }
+        }
+    }
+
+    /// Initialized ranges of the view, relative to its start.
+    pub fn initialized(&self) -> RangeSet {
+        self.storage
+            .lock()
+            .initialized
+            .window(self.start..self.start + self.len)
+    }
+}
+
+impl std::fmt::Debug for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Storage")
+            .field("resident", &self.resident())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_lazy_growth() {
+        let storage = Storage::new();
+        assert_eq!(storage.resident(), 0);
+        assert_eq!(storage.read(100, 4), vec![0; 4]);
+
+        storage.write(8, &[1, 2, 3]);
+        assert_eq!(storage.resident(), 11);
+        assert_eq!(storage.read(7, 6), vec![0, 1, 2, 3, 0, 0]);
+    }
+
+    #[test]
+    fn test_storage_shared_between_clones() {
+        let storage = Storage::new();
+        let alias = storage.clone();
+        storage.write(0, &[42]);
+        assert_eq!(alias.read(0, 1), vec![42]);
+    }
+
+    #[test]
+    fn test_view_is_bounded() {
+        let storage = Storage::new();
+        let view = StorageView::new(storage.clone(), 2, 3);
+        view.write(&[1, 2, 3, 4, 5]);
+        assert_eq!(storage.read(0, 6), vec![0, 0, 1, 2, 3, 0]);
+        assert_eq!(view.read(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_initialized_ranges() {
+        let storage = Storage::new();
+        storage.write(4, &[1; 4]);
+        storage.write(12, &[2; 4]);
+        let view = StorageView::new(storage.clone(), 2, 12);
+        assert_eq!(view.initialized().ranges(), &[2..6, 10..12]);
+
+        let mut written = RangeSet::default();
+        written.insert(0..2);
+        view.write_back(&[9; 12], &written);
+        assert_eq!(storage.read(2, 3), vec![9, 9, 9]);
+        assert_eq!(view.initialized().ranges(), &[0..6, 10..12]);
+
+        storage.write(8, &[3; 4]);
+        assert!(view.initialized().contains(&(0..12)));
+        assert_eq!(view.initialized().ranges().len(), 1);
+    }
+
+    #[test]
+    fn test_element_roundtrip() {
+        let values = [1.5f32, -2.0, f32::MAX];
+        let bytes = to_bytes(&values);
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(from_bytes::<f32>(&bytes), values);
+
+        let ints = [u16::MAX, 0, 7];
+        assert_eq!(from_bytes::<u16>(&to_bytes(&ints)), ints);
+        assert_eq!(from_bytes::<u32>(&[1, 0, 0, 0, 9]), vec![1]);
+    }
+}
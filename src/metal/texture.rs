@@ -0,0 +1,941 @@
+//! Typed textures for image kernels.
+//!
+//! A [`MetalTexture`] is a 1D, 2D, 2D-array or 3D image with a
+//! [`PixelFormat`] and [`TextureUsage`] flags, created from a
+//! [`TextureDescriptor`] with
+//! [`MetalCompute::create_texture`](super::MetalCompute::create_texture).
+//! Regions are uploaded and read back as tightly packed rows of pixels,
+//! and textures are bound to kernels alongside buffers with
+//! [`MetalCompute::dispatch_with_textures`](super::MetalCompute::dispatch_with_textures).
+//!
+//! On the CPU backend, kernels read, write and sample bound textures
+//! through the [`ThreadContext`](super::ThreadContext); [`Sampler`]
+//! emulates Metal's nearest and linear filtering and address modes so
+//! preprocessing pipelines are testable without a GPU.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::{MetalCompute, PixelFormat, Region, TextureDescriptor};
+//!
+//! let compute = MetalCompute::cpu();
+//! let texture = compute.create_texture(&TextureDescriptor::texture_2d(
+//!     PixelFormat::Rgba8Unorm,
+//!     2,
+//!     2,
+//! ))?;
+//! texture.replace_region(Region::new_2d(0, 0, 1, 1), 0, &[255, 0, 0, 255])?;
+//! assert_eq!(texture.read_pixel((0, 0, 0), 0)?, [1.0, 0.0, 0.0, 1.0]);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - Texels written with `replace_region` read back unchanged
+//! - Linear sampling at a texel centre returns that texel exactly
+//! - Sampling never reads outside the texture
+
+use super::memory::Allocation;
+use super::storage::{Storage, StorageView};
+use super::{DeviceEpoch, Size3};
+use crate::error::{Error, Result};
+use bitflags::bitflags;
+
+/// Largest width or height of a 1D or 2D texture.
+pub const MAX_TEXTURE_DIMENSION_2D: u32 = 16_384;
+
+/// Largest width, height or depth of a 3D texture.
+pub const MAX_TEXTURE_DIMENSION_3D: u32 = 2_048;
+
+/// Largest number of slices in a texture array.
+pub const MAX_TEXTURE_ARRAY_LENGTH: u32 = 2_048;
+
+/// Dimensionality of a texture (`MTLTextureType`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextureType {
+    /// A row of texels.
+    Type1D,
+    /// A 2D image.
+    Type2D,
+    /// An array of 2D images of the same size.
+    Type2DArray,
+    /// A volume.
+    Type3D,
+}
+
+impl TextureType {
+    /// Number of spatial dimensions addressed by texture coordinates.
+    #[must_use]
+    pub const fn dimensions(self) -> usize {
+        match self {
+            Self::Type1D => 1,
+            Self::Type2D | Self::Type2DArray => 2,
+            Self::Type3D => 3,
+        }
+    }
+}
+
+/// Texel format (`MTLPixelFormat`).
+///
+/// Normalized formats read as `[0, 1]` floats, integer formats as their
+/// integer value, and float formats as-is. Missing colour channels read as
+/// 0 and a missing alpha as 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    /// One 8-bit normalized channel.
+    R8Unorm,
+    /// One 8-bit unsigned integer channel.
+    R8Uint,
+    /// Two 8-bit normalized channels.
+    Rg8Unorm,
+    /// Four 8-bit normalized channels.
+    Rgba8Unorm,
+    /// Four 8-bit normalized channels stored blue first.
+    Bgra8Unorm,
+    /// One half-precision float channel.
+    R16Float,
+    /// Two half-precision float channels.
+    Rg16Float,
+    /// Four half-precision float channels.
+    Rgba16Float,
+    /// One single-precision float channel.
+    R32Float,
+    /// Two single-precision float channels.
+    Rg32Float,
+    /// Four single-precision float channels.
+    Rgba32Float,
+    /// One 32-bit unsigned integer channel.
+    R32Uint,
+}
+
+/// Storage type of one channel.
+#[derive(Clone, Copy)]
+enum Channel {
+    Unorm8,
+    Uint8,
+    Float16,
+    Float32,
+    Uint32,
+}
+
+impl Channel {
+    const fn size(self) -> usize {
+        match self {
+            Self::Unorm8 | Self::Uint8 => 1,
+            Self::Float16 => 2,
+            Self::Float32 | Self::Uint32 => 4,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> f32 {
+        match self {
+            Self::Unorm8 => f32::from(bytes[0]) / 255.0,
+            Self::Uint8 => f32::from(bytes[0]),
+            Self::Float16 => f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]])),
+            Self::Float32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            #[allow(clippy::cast_precision_loss)]
+            Self::Uint32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32,
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn encode(self, value: f32, out: &mut Vec<u8>) {
+        match self {
+            Self::Unorm8 => out.push((value.clamp(0.0, 1.0) * 255.0).round() as u8),
+            Self::Uint8 => out.push(value as u8),
+            Self::Float16 => out.extend_from_slice(&f32_to_f16(value).to_le_bytes()),
+            Self::Float32 => out.extend_from_slice(&value.to_le_bytes()),
+            Self::Uint32 => out.extend_from_slice(&(value as u32).to_le_bytes()),
+        }
+    }
+}
+
+impl PixelFormat {
+    const fn layout(self) -> (Channel, usize) {
+        match self {
+            Self::R8Unorm => (Channel::Unorm8, 1),
+            Self::R8Uint => (Channel::Uint8, 1),
+            Self::Rg8Unorm => (Channel::Unorm8, 2),
+            Self::Rgba8Unorm | Self::Bgra8Unorm => (Channel::Unorm8, 4),
+            Self::R16Float => (Channel::Float16, 1),
+            Self::Rg16Float => (Channel::Float16, 2),
+            Self::Rgba16Float => (Channel::Float16, 4),
+            Self::R32Float => (Channel::Float32, 1),
+            Self::Rg32Float => (Channel::Float32, 2),
+            Self::Rgba32Float => (Channel::Float32, 4),
+            Self::R32Uint => (Channel::Uint32, 1),
+        }
+    }
+
+    /// Number of stored channels.
+    #[must_use]
+    pub const fn channels(self) -> usize {
+        self.layout().1
+    }
+
+    /// Size of one texel in bytes.
+    #[must_use]
+    pub const fn bytes_per_pixel(self) -> usize {
+        let (channel, count) = self.layout();
+        channel.size() * count
+    }
+
+    /// Decode one texel into RGBA.
+    ///
+    /// `bytes` must hold at least [`bytes_per_pixel`](Self::bytes_per_pixel)
+    /// bytes.
+    #[must_use]
+    pub fn decode(self, bytes: &[u8]) -> [f32; 4] {
+        let (channel, count) = self.layout();
+        let mut rgba = [0.0, 0.0, 0.0, 1.0];
+        for (i, value) in rgba.iter_mut().take(count).enumerate() {
+            *value = channel.decode(&bytes[i * channel.size()..]);
+        }
+        if self == Self::Bgra8Unorm {
+            rgba.swap(0, 2);
+        }
+        rgba
+    }
+
+    /// Encode an RGBA value as one texel, dropping unused channels.
+    ///
+    /// Normalized formats clamp to `[0, 1]`; integer formats saturate.
+    #[must_use]
+    pub fn encode(self, mut rgba: [f32; 4]) -> Vec<u8> {
+        if self == Self::Bgra8Unorm {
+            rgba.swap(0, 2);
+        }
+        let (channel, count) = self.layout();
+        let mut out = Vec::with_capacity(self.bytes_per_pixel());
+        for &value in rgba.iter().take(count) {
+            channel.encode(value, &mut out);
+        }
+        out
+    }
+}
+
+/// Convert IEEE 754 half-precision bits to `f32`.
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = u32::from(half >> 15) << 31;
+    let exponent = u32::from((half >> 10) & 0x1f);
+    let mantissa = u32::from(half & 0x3ff);
+    match exponent {
+        0 => {
+            let magnitude = f32::from(half & 0x3ff) / 16_777_216.0;
+            if sign == 0 {
+                magnitude
+            } else {
+                -magnitude
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
+
+/// Convert `f32` to IEEE 754 half-precision bits, rounding to nearest even.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mut mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa == 0 { 0 } else { 0x200 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    // Keep `shift` low bits of the mantissa for rounding.
+    let (mut half, shift) = if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        mantissa |= 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        (mantissa >> shift, shift)
+    } else {
+        (((exponent as u32) << 10) | (mantissa >> 13), 13)
+    };
+    let remainder = mantissa & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    if remainder > halfway || (remainder == halfway && half & 1 == 1) {
+        half += 1;
+    }
+    sign | half as u16
+}
+
+bitflags! {
+    /// How kernels may access a texture (`MTLTextureUsage`).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct TextureUsage: u32 {
+        /// Kernels may read or sample the texture.
+        const SHADER_READ = 1;
+        /// Kernels may write the texture.
+        const SHADER_WRITE = 1 << 1;
+        /// The texture may be a render pass attachment.
+        const RENDER_TARGET = 1 << 2;
+    }
+}
+
+/// Shape, format and usage of a texture to create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureDescriptor {
+    texture_type: TextureType,
+    pixel_format: PixelFormat,
+    width: u32,
+    height: u32,
+    depth: u32,
+    array_length: u32,
+    usage: TextureUsage,
+}
+
+impl TextureDescriptor {
+    const fn new(
+        texture_type: TextureType,
+        pixel_format: PixelFormat,
+        (width, height, depth): Size3,
+        array_length: u32,
+    ) -> Self {
+        Self {
+            texture_type,
+            pixel_format,
+            width,
+            height,
+            depth,
+            array_length,
+            usage: TextureUsage::SHADER_READ,
+        }
+    }
+
+    /// Describe a 1D texture of `width` texels.
+    #[must_use]
+    pub const fn texture_1d(pixel_format: PixelFormat, width: u32) -> Self {
+        Self::new(TextureType::Type1D, pixel_format, (width, 1, 1), 1)
+    }
+
+    /// Describe a `width` x `height` 2D texture.
+    #[must_use]
+    pub const fn texture_2d(pixel_format: PixelFormat, width: u32, height: u32) -> Self {
+        Self::new(TextureType::Type2D, pixel_format, (width, height, 1), 1)
+    }
+
+    /// Describe an array of `array_length` 2D slices.
+    #[must_use]
+    pub const fn texture_2d_array(
+        pixel_format: PixelFormat,
+        width: u32,
+        height: u32,
+        array_length: u32,
+    ) -> Self {
+        Self::new(
+            TextureType::Type2DArray,
+            pixel_format,
+            (width, height, 1),
+            array_length,
+        )
+    }
+
+    /// Describe a `width` x `height` x `depth` volume.
+    #[must_use]
+    pub const fn texture_3d(
+        pixel_format: PixelFormat,
+        width: u32,
+        height: u32,
+        depth: u32,
+    ) -> Self {
+        Self::new(TextureType::Type3D, pixel_format, (width, height, depth), 1)
+    }
+
+    /// Set the usage flags (default: [`TextureUsage::SHADER_READ`]).
+    #[must_use]
+    pub const fn with_usage(mut self, usage: TextureUsage) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Texture dimensionality.
+    #[must_use]
+    pub const fn texture_type(&self) -> TextureType {
+        self.texture_type
+    }
+
+    /// Texel format.
+    #[must_use]
+    pub const fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Size in texels `(width, height, depth)`.
+    #[must_use]
+    pub const fn size(&self) -> Size3 {
+        (self.width, self.height, self.depth)
+    }
+
+    /// Number of array slices (1 for non-array textures).
+    #[must_use]
+    pub const fn array_length(&self) -> u32 {
+        self.array_length
+    }
+
+    /// Usage flags.
+    #[must_use]
+    pub const fn usage(&self) -> TextureUsage {
+        self.usage
+    }
+
+    /// Total size of the texture's texels in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, exceeds the limits for the
+    /// texture type, or the size overflows.
+    pub fn byte_len(&self) -> Result<usize> {
+        let (max, max_array) = match self.texture_type {
+            TextureType::Type1D | TextureType::Type2D => (MAX_TEXTURE_DIMENSION_2D, 1),
+            TextureType::Type2DArray => (MAX_TEXTURE_DIMENSION_2D, MAX_TEXTURE_ARRAY_LENGTH),
+            TextureType::Type3D => (MAX_TEXTURE_DIMENSION_3D, 1),
+        };
+        let size = self.size();
+        if size.0 == 0 || size.1 == 0 || size.2 == 0 || self.array_length == 0 {
+            return Err(Error::invalid_input("texture dimensions cannot be zero"));
+        }
+        if size.0 > max || size.1 > max || size.2 > max || self.array_length > max_array {
+            return Err(Error::invalid_input(format!(
+                "{:?} texture of {size:?} x {} exceeds limit {max} (array {max_array})",
+                self.texture_type, self.array_length
+            )));
+        }
+        [size.1, size.2, self.array_length]
+            .iter()
+            .try_fold(self.row_bytes(size.0), |acc, &n| {
+                acc.checked_mul(n as usize)
+            })
+            .ok_or_else(|| Error::invalid_input("texture size overflows"))
+    }
+
+    /// Bytes in a tightly packed row of `width` texels.
+    const fn row_bytes(&self, width: u32) -> usize {
+        width as usize * self.pixel_format.bytes_per_pixel()
+    }
+
+    /// Byte offset of texel `(x, y, z)` in `slice`, if it is inside.
+    pub(crate) const fn texel_offset(&self, (x, y, z): Size3, slice: u32) -> Option<usize> {
+        if x >= self.width || y >= self.height || z >= self.depth || slice >= self.array_length {
+            return None;
+        }
+        let index = ((slice as usize * self.depth as usize + z as usize) * self.height as usize
+            + y as usize)
+            * self.width as usize
+            + x as usize;
+        Some(index * self.pixel_format.bytes_per_pixel())
+    }
+}
+
+/// A box of texels (`MTLRegion`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Region {
+    /// First texel `(x, y, z)`.
+    pub origin: Size3,
+    /// Extent in texels `(width, height, depth)`.
+    pub size: Size3,
+}
+
+impl Region {
+    /// Create a region from its origin and size.
+    #[must_use]
+    pub const fn new(origin: Size3, size: Size3) -> Self {
+        Self { origin, size }
+    }
+
+    /// A span of `width` texels starting at `x`.
+    #[must_use]
+    pub const fn new_1d(x: u32, width: u32) -> Self {
+        Self::new((x, 0, 0), (width, 1, 1))
+    }
+
+    /// A `width` x `height` rectangle at `(x, y)`.
+    #[must_use]
+    pub const fn new_2d(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self::new((x, y, 0), (width, height, 1))
+    }
+
+    /// Number of texels in the region.
+    #[must_use]
+    pub const fn texels(&self) -> usize {
+        self.size.0 as usize * self.size.1 as usize * self.size.2 as usize
+    }
+}
+
+/// Texel filter used between texel centres.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SamplerFilter {
+    /// Take the nearest texel.
+    #[default]
+    Nearest,
+    /// Blend the surrounding texels (bilinear in 2D, trilinear in 3D).
+    Linear,
+}
+
+/// What sampling returns outside `[0, size)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AddressMode {
+    /// Repeat the edge texel.
+    #[default]
+    ClampToEdge,
+    /// Wrap around.
+    Repeat,
+    /// Wrap around, reflecting every other repetition.
+    MirroredRepeat,
+    /// Return transparent black.
+    ClampToZero,
+}
+
+impl AddressMode {
+    /// Map texel index `i` onto `[0, extent)`, or `None` for the border.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    const fn apply(self, i: i64, extent: u32) -> Option<u32> {
+        let n = extent as i64;
+        let i = match self {
+            Self::ClampToEdge => {
+                if i < 0 {
+                    0
+                } else if i >= n {
+                    n - 1
+                } else {
+                    i
+                }
+            }
+            Self::Repeat => i.rem_euclid(n),
+            Self::MirroredRepeat => {
+                let m = i.rem_euclid(2 * n);
+                if m >= n {
+                    2 * n - 1 - m
+                } else {
+                    m
+                }
+            }
+            Self::ClampToZero => {
+                if i < 0 || i >= n {
+                    return None;
+                }
+                i
+            }
+        };
+        Some(i as u32)
+    }
+}
+
+/// Sampler state for texture sampling on the CPU backend
+/// (`MTLSamplerDescriptor`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sampler {
+    filter: SamplerFilter,
+    address_mode: AddressMode,
+    normalized_coordinates: bool,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sampler {
+    /// Nearest filtering, clamp-to-edge addressing and normalized
+    /// coordinates.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            filter: SamplerFilter::Nearest,
+            address_mode: AddressMode::ClampToEdge,
+            normalized_coordinates: true,
+        }
+    }
+
+    /// Set the filter.
+    #[must_use]
+    pub const fn with_filter(mut self, filter: SamplerFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Set the address mode for every axis.
+    #[must_use]
+    pub const fn with_address_mode(mut self, address_mode: AddressMode) -> Self {
+        self.address_mode = address_mode;
+        self
+    }
+
+    /// Use `[0, 1]` coordinates (`true`) or texel coordinates (`false`).
+    #[must_use]
+    pub const fn with_normalized_coordinates(mut self, normalized: bool) -> Self {
+        self.normalized_coordinates = normalized;
+        self
+    }
+
+    /// Sample at `coord` in a texture described by `descriptor`, reading
+    /// in-bounds texels through `fetch`.
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+    pub(crate) fn sample<F>(
+        self,
+        descriptor: &TextureDescriptor,
+        coord: [f32; 3],
+        fetch: F,
+    ) -> [f32; 4]
+    where
+        F: Fn(Size3) -> [f32; 4],
+    {
+        let dims = descriptor.texture_type.dimensions();
+        let size = descriptor.size();
+        let extents: [u32; 3] = size.into();
+        let mut texel = [0.0f32; 3];
+        for axis in 0..dims {
+            let scale = if self.normalized_coordinates {
+                extents[axis] as f32
+            } else {
+                1.0
+            };
+            texel[axis] = coord[axis] * scale;
+        }
+
+        let fetch_at = |index: [i64; 3]| -> Option<[f32; 4]> {
+            let mut position = [0u32; 3];
+            for axis in 0..dims {
+                position[axis] = self.address_mode.apply(index[axis], extents[axis])?;
+            }
+            Some(fetch(position.into()))
+        };
+
+        match self.filter {
+            SamplerFilter::Nearest => {
+                let index = texel.map(|t| t.floor() as i64);
+                fetch_at(index).unwrap_or_default()
+            }
+            SamplerFilter::Linear => {
+                let base = texel.map(|t| (t - 0.5).floor());
+                let mut out = [0.0f32; 4];
+                for corner in 0..1usize << dims {
+                    let mut index = [0i64; 3];
+                    let mut weight = 1.0f32;
+                    for axis in 0..dims {
+                        let frac = texel[axis] - 0.5 - base[axis];
+                        let upper = corner >> axis & 1 == 1;
+                        index[axis] = base[axis] as i64 + i64::from(upper);
+                        weight *= if upper { frac } else { 1.0 - frac };
+                    }
+                    if let Some(value) = fetch_at(index) {
+                        for (acc, v) in out.iter_mut().zip(value) {
+                            *acc = v.mul_add(weight, *acc);
+                        }
+                    }
+                }
+                out
+            }
+        }
+    }
+}
+
+/// A GPU texture.
+///
+/// Texel memory is charged to its pipeline's memory ledgers until the
+/// texture is dropped, and is host-visible like a
+/// [`MetalBuffer`](super::MetalBuffer)'s.
+#[derive(Debug)]
+pub struct MetalTexture {
+    descriptor: TextureDescriptor,
+    device_index: usize,
+    storage: Storage,
+    epoch: DeviceEpoch,
+    _allocation: Allocation,
+}
+
+impl MetalTexture {
+    pub(crate) fn new(
+        descriptor: TextureDescriptor,
+        device_index: usize,
+        epoch: DeviceEpoch,
+        allocation: Allocation,
+    ) -> Self {
+        Self {
+            descriptor,
+            device_index,
+            storage: Storage::new(),
+            epoch,
+            _allocation: allocation,
+        }
+    }
+
+    /// The descriptor the texture was created from.
+    #[must_use]
+    pub const fn descriptor(&self) -> &TextureDescriptor {
+        &self.descriptor
+    }
+
+    /// Size in texels `(width, height, depth)`.
+    #[must_use]
+    pub const fn size(&self) -> Size3 {
+        self.descriptor.size()
+    }
+
+    /// Texel format.
+    #[must_use]
+    pub const fn pixel_format(&self) -> PixelFormat {
+        self.descriptor.pixel_format
+    }
+
+    /// Usage flags.
+    #[must_use]
+    pub const fn usage(&self) -> TextureUsage {
+        self.descriptor.usage
+    }
+
+    /// Get the device this texture is allocated on.
+    #[must_use]
+    pub const fn device_index(&self) -> usize {
+        self.device_index
+    }
+
+    /// The whole of one slice.
+    #[must_use]
+    pub const fn region(&self) -> Region {
+        Region::new((0, 0, 0), self.descriptor.size())
+    }
+
+    /// Check if the texture is still usable.
+    ///
+    /// Returns `false` once the device it was allocated on is lost or its
+    /// pipeline has recovered.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.epoch.is_alive()
+    }
+
+    /// Byte offsets of each row of `region` in `slice`, with the row length.
+    fn rows(&self, region: Region, slice: u32) -> Result<(Vec<usize>, usize)> {
+        if !self.is_valid() {
+            return Err(Error::device_lost(self.device_index));
+        }
+        let (x, y, z) = region.origin;
+        let (width, height, depth) = region.size;
+        let inside = |origin: u32, extent: u32, limit: u32| {
+            extent > 0 && origin.checked_add(extent).is_some_and(|end| end <= limit)
+        };
+        let size = self.size();
+        if !inside(x, width, size.0)
+            || !inside(y, height, size.1)
+            || !inside(z, depth, size.2)
+            || slice >= self.descriptor.array_length
+        {
+            return Err(Error::invalid_input(format!(
+                "region {region:?} of slice {slice} exceeds texture {size:?} x {}",
+                self.descriptor.array_length
+            )));
+        }
+        let rows = (z..z + depth)
+            .flat_map(|zz| (y..y + height).map(move |yy| (zz, yy)))
+            .filter_map(|(zz, yy)| self.descriptor.texel_offset((x, yy, zz), slice))
+            .collect();
+        Ok((rows, self.descriptor.row_bytes(width)))
+    }
+
+    /// Copy tightly packed texels into `region` of array `slice`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the region is empty or outside the texture,
+    /// `data` is not exactly the region's size, or the device is lost.
+    pub fn replace_region(&self, region: Region, slice: u32, data: &[u8]) -> Result<()> {
+        let (rows, row_bytes) = self.rows(region, slice)?;
+        if data.len() != rows.len() * row_bytes {
+            return Err(Error::invalid_input(format!(
+                "region {region:?} needs {} bytes, got {}",
+                rows.len() * row_bytes,
+                data.len()
+            )));
+        }
+        for (offset, row) in rows.into_iter().zip(data.chunks_exact(row_bytes)) {
+            self.storage.write(offset, row);
+        }
+        Ok(())
+    }
+
+    /// Copy `region` of array `slice` out as tightly packed texels.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the region is empty or outside the texture, or
+    /// the device is lost.
+    pub fn read_region(&self, region: Region, slice: u32) -> Result<Vec<u8>> {
+        let (rows, row_bytes) = self.rows(region, slice)?;
+        let mut out = Vec::with_capacity(rows.len() * row_bytes);
+        for offset in rows {
+            out.extend_from_slice(&self.storage.read(offset, row_bytes));
+        }
+        Ok(out)
+    }
+
+    /// Read one texel as RGBA.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the texel is outside the texture or the device
+    /// is lost.
+    pub fn read_pixel(&self, position: Size3, slice: u32) -> Result<[f32; 4]> {
+        let bytes = self.read_region(Region::new(position, (1, 1, 1)), slice)?;
+        Ok(self.pixel_format().decode(&bytes))
+    }
+
+    /// Size of all texels in bytes.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.descriptor.byte_len().unwrap_or(0)
+    }
+
+    /// Always `false`: textures have at least one texel.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every texel, for a CPU dispatch.
+    pub(crate) fn view(&self) -> StorageView {
+        StorageView::new(self.storage.clone(), 0, self.len())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4], tolerance: f32) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tolerance, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_pixel_format_roundtrip() {
+        let rgba = [0.2, 0.4, 0.6, 0.8];
+        for format in [
+            PixelFormat::Rgba8Unorm,
+            PixelFormat::Bgra8Unorm,
+            PixelFormat::Rgba16Float,
+            PixelFormat::Rgba32Float,
+        ] {
+            let bytes = format.encode(rgba);
+            assert_eq!(bytes.len(), format.bytes_per_pixel());
+            assert_close(format.decode(&bytes), rgba, 0.005);
+        }
+        assert_eq!(
+            PixelFormat::Bgra8Unorm.encode([1.0, 0.0, 0.0, 1.0]),
+            vec![0, 0, 255, 255]
+        );
+        assert_close(
+            PixelFormat::R8Unorm.decode(&[255]),
+            [1.0, 0.0, 0.0, 1.0],
+            0.0,
+        );
+        assert_eq!(PixelFormat::R8Unorm.encode([2.0, 0.0, 0.0, 0.0]), vec![255]);
+        assert_close(
+            PixelFormat::R32Uint.decode(&7u32.to_le_bytes()),
+            [7.0, 0.0, 0.0, 1.0],
+            0.0,
+        );
+        assert_eq!(PixelFormat::Rg8Unorm.channels(), 2);
+    }
+
+    #[test]
+    fn test_half_float_conversion() {
+        for value in [0.0f32, -0.0, 1.0, -2.5, 65504.0, 6.1e-5, 5.96e-8] {
+            let back = f16_to_f32(f32_to_f16(value));
+            assert!(
+                (back - value).abs() <= value.abs() * 1e-3,
+                "{value} -> {back}"
+            );
+        }
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(f32_to_f16(1e-10), 0);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+        assert!(f16_to_f32(0x7c00).is_infinite());
+        assert_eq!(f16_to_f32(0x0001).to_bits(), 5.960_464_5e-8f32.to_bits());
+    }
+
+    #[test]
+    fn test_descriptor_limits() {
+        let d = TextureDescriptor::texture_2d_array(PixelFormat::Rgba8Unorm, 4, 2, 3);
+        assert_eq!(d.byte_len().unwrap(), 4 * 4 * 2 * 3);
+        assert_eq!(d.usage(), TextureUsage::SHADER_READ);
+        assert!(TextureDescriptor::texture_2d(PixelFormat::R8Unorm, 0, 1)
+            .byte_len()
+            .is_err());
+        assert!(
+            TextureDescriptor::texture_2d(PixelFormat::R8Unorm, 16_385, 1)
+                .byte_len()
+                .is_err()
+        );
+        assert!(
+            TextureDescriptor::texture_3d(PixelFormat::R8Unorm, 8, 8, 4096)
+                .byte_len()
+                .is_err()
+        );
+        assert_eq!(
+            TextureDescriptor::texture_3d(PixelFormat::R32Float, 2, 3, 4)
+                .texel_offset((1, 2, 3), 0),
+            Some((((3 * 3) + 2) * 2 + 1) * 4)
+        );
+        assert_eq!(d.texel_offset((0, 0, 0), 3), None);
+    }
+
+    #[test]
+    fn test_address_modes() {
+        assert_eq!(AddressMode::ClampToEdge.apply(-3, 4), Some(0));
+        assert_eq!(AddressMode::ClampToEdge.apply(9, 4), Some(3));
+        assert_eq!(AddressMode::Repeat.apply(-1, 4), Some(3));
+        assert_eq!(AddressMode::Repeat.apply(5, 4), Some(1));
+        assert_eq!(AddressMode::MirroredRepeat.apply(4, 4), Some(3));
+        assert_eq!(AddressMode::MirroredRepeat.apply(-1, 4), Some(0));
+        assert_eq!(AddressMode::ClampToZero.apply(4, 4), None);
+        assert_eq!(AddressMode::ClampToZero.apply(2, 4), Some(2));
+    }
+
+    #[test]
+    fn test_sampler_filters() {
+        // 2x1 texture: black, white.
+        let d = TextureDescriptor::texture_2d(PixelFormat::R32Float, 2, 1);
+        let fetch = |(x, _, _): Size3| [f32::from(u8::from(x == 1)), 0.0, 0.0, 1.0];
+        let black = [0.0, 0.0, 0.0, 1.0];
+        let white = [1.0, 0.0, 0.0, 1.0];
+
+        let nearest = Sampler::new();
+        assert_close(nearest.sample(&d, [0.2, 0.5, 0.0], fetch), black, 0.0);
+        assert_close(nearest.sample(&d, [0.8, 0.5, 0.0], fetch), white, 0.0);
+
+        let linear = Sampler::new().with_filter(SamplerFilter::Linear);
+        assert_close(linear.sample(&d, [0.25, 0.5, 0.0], fetch), black, 0.0);
+        assert_close(
+            linear.sample(&d, [0.5, 0.5, 0.0], fetch),
+            [0.5, 0.0, 0.0, 1.0],
+            1e-6,
+        );
+        assert_close(linear.sample(&d, [0.75, 0.5, 0.0], fetch), white, 0.0);
+
+        let pixels = linear.with_normalized_coordinates(false);
+        assert_close(
+            pixels.sample(&d, [1.25, 0.5, 0.0], fetch),
+            [0.75, 0.0, 0.0, 1.0],
+            1e-6,
+        );
+
+        let border = nearest.with_address_mode(AddressMode::ClampToZero);
+        assert_close(border.sample(&d, [1.5, 0.5, 0.0], fetch), [0.0; 4], 0.0);
+    }
+}
@@ -0,0 +1,603 @@
+//! Threadgroup size selection and tuning.
+//!
+//! [`select_threadgroup_size`] derives a valid threadgroup shape from the
+//! grid and the pipeline's limits, following Apple's guidance: the x
+//! dimension is a multiple of the thread execution (SIMD) width and the
+//! remaining threads fill y and z without exceeding the grid.
+//!
+//! [`ThreadgroupTuner`] goes further and benchmarks candidate shapes on the
+//! current device, remembering the winner per device, kernel,
+//! specialization and grid in a [`TuningCache`] that can be persisted
+//! between runs.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::MetalCompute;
+//!
+//! let compute = MetalCompute::default_device()?;
+//! let shader = compute.compile_shader("kernel void k() {}", "k")?;
+//! let buffer = compute.allocate_buffer(4096)?;
+//!
+//! let grid = (1920, 1080, 1);
+//! let threadgroup = compute.threadgroup_size_for(&shader, grid);
+//! compute.dispatch(&shader, &[&buffer], grid, threadgroup)?;
+//! # Ok::<(), manzana::Error>(())
+//! ```
+
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Duration;
+
+/// A threadgroup or grid shape `(width, height, depth)`.
+pub type Size3 = (u32, u32, u32);
+
+/// Limits that constrain a threadgroup for one pipeline on one device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadgroupLimits {
+    /// Maximum total threads per threadgroup for the pipeline.
+    pub max_total_threads: u32,
+    /// SIMD width; efficient threadgroups are multiples of this.
+    pub thread_execution_width: u32,
+    /// Maximum threads per threadgroup in each dimension for the device.
+    pub max_per_dimension: Size3,
+}
+
+impl ThreadgroupLimits {
+    /// Check that `threadgroup` fits within these limits.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dimension is zero, exceeds the per-dimension
+    /// maximum, or the total exceeds `max_total_threads`.
+    pub fn validate(&self, threadgroup: Size3) -> Result<()> {
+        let (x, y, z) = threadgroup;
+        if x == 0 || y == 0 || z == 0 {
+            return Err(Error::invalid_input(
+                "threadgroup size dimensions cannot be zero",
+            ));
+        }
+
+        let (max_x, max_y, max_z) = self.max_per_dimension;
+        if x > max_x || y > max_y || z > max_z {
+            return Err(Error::invalid_input(format!(
+                "threadgroup size {threadgroup:?} exceeds per-dimension maximum {:?}",
+                self.max_per_dimension
+            )));
+        }
+
+        let total = u64::from(x) * u64::from(y) * u64::from(z);
+        if total > u64::from(self.max_total_threads) {
+            return Err(Error::invalid_input(format!(
+                "threadgroup size {total} exceeds maximum {}",
+                self.max_total_threads
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Derive an efficient threadgroup shape for `grid`.
+///
+/// The result always passes [`ThreadgroupLimits::validate`]. Dimensions are
+/// not larger than needed to cover the grid, except that x is rounded up to
+/// the execution width so whole SIMD groups are scheduled.
+#[must_use]
+pub fn select_threadgroup_size(grid: Size3, limits: &ThreadgroupLimits) -> Size3 {
+    let (max_x, max_y, max_z) = limits.max_per_dimension;
+    let max_total = limits.max_total_threads.max(1);
+    let simd = limits.thread_execution_width.clamp(1, max_total);
+
+    // Whole SIMD groups in x, as many as the grid's width can use.
+    let simd_groups = (max_total / simd).max(1);
+    let needed = grid.0.max(1).div_ceil(simd).clamp(1, simd_groups);
+    let x = if grid.1 <= 1 && grid.2 <= 1 {
+        (needed * simd).min(max_x).max(1)
+    } else {
+        // 2D/3D: one SIMD group wide (or less for narrow grids) so the rest
+        // of the budget goes to rows.
+        grid.0
+            .clamp(1, simd)
+            .checked_next_power_of_two()
+            .map_or(simd, |width| width.min(simd))
+            .min(max_x)
+            .max(1)
+    };
+
+    let remaining = max_total / x;
+    let y = remaining.min(grid.1.max(1)).min(max_y).max(1);
+    let z = (remaining / y).min(grid.2.max(1)).min(max_z).max(1);
+    (x, y, z)
+}
+
+/// Enumerate candidate threadgroup shapes worth benchmarking for `grid`.
+///
+/// Candidates are valid for `limits`, include the heuristic choice from
+/// [`select_threadgroup_size`], and are deduplicated.
+#[must_use]
+pub fn candidate_threadgroup_sizes(grid: Size3, limits: &ThreadgroupLimits) -> Vec<Size3> {
+    let mut candidates = vec![select_threadgroup_size(grid, limits)];
+    let mut push = |shape: Size3| {
+        if !candidates.contains(&shape) && limits.validate(shape).is_ok() {
+            candidates.push(shape);
+        }
+    };
+    let simd = limits.thread_execution_width.max(1);
+
+    let mut total = simd;
+    while total <= limits.max_total_threads {
+        if grid.1 <= 1 && grid.2 <= 1 {
+            push((total, 1, 1));
+        } else {
+            let mut width = simd;
+            while width <= total {
+                push((width, total / width, 1));
+                let Some(next) = width.checked_mul(2) else {
+                    break;
+                };
+                width = next;
+            }
+        }
+        let Some(next) = total.checked_mul(2) else {
+            break;
+        };
+        total = next;
+    }
+    candidates
+}
+
+/// What a tuned threadgroup was measured for.
+///
+/// A winner only carries over to dispatches of the same kernel, with the
+/// same function constant values, over the same grid on the same device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TuningKey {
+    /// Device name.
+    pub device: String,
+    /// Kernel function name.
+    pub kernel: String,
+    /// Canonical function constant values (empty when unspecialized).
+    pub specialization: String,
+    /// Grid the candidates were timed over.
+    pub grid: Size3,
+}
+
+impl TuningKey {
+    /// Key for an unspecialized kernel dispatched over `grid`.
+    #[must_use]
+    pub fn new(device: &str, kernel: &str, grid: Size3) -> Self {
+        Self {
+            device: device.to_string(),
+            kernel: kernel.to_string(),
+            specialization: String::new(),
+            grid,
+        }
+    }
+
+    /// Set the canonical function constant values.
+    #[must_use]
+    pub fn with_specialization(mut self, specialization: impl Into<String>) -> Self {
+        self.specialization = specialization.into();
+        self
+    }
+}
+
+/// Persisted threadgroup choices, keyed by [`TuningKey`].
+///
+/// Stored as a tab-separated text file with one
+/// `device<TAB>kernel<TAB>specialization<TAB>grid x<TAB>grid y<TAB>grid z<TAB>x<TAB>y<TAB>z`
+/// entry per line. Backslashes, tabs and line breaks in the text fields
+/// are written as `\\`, `\t`, `\n` and `\r`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TuningCache {
+    entries: HashMap<TuningKey, Size3>,
+}
+
+impl TuningCache {
+    /// Create an empty cache.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look up the tuned threadgroup for `key`.
+    #[must_use]
+    pub fn get(&self, key: &TuningKey) -> Option<Size3> {
+        self.entries.get(key).copied()
+    }
+
+    /// Record the tuned threadgroup for `key`.
+    pub fn insert(&mut self, key: TuningKey, threadgroup: Size3) {
+        self.entries.insert(key, threadgroup);
+    }
+
+    /// Number of cached entries.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the cache is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Parse a cache from its text form.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the first malformed line.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut cache = Self::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, size) = parse_entry(line).ok_or_else(|| {
+                Error::invalid_input(format!(
+                    "malformed tuning cache line {}: {line:?}",
+                    number + 1
+                ))
+            })?;
+            cache.insert(key, size);
+        }
+        Ok(cache)
+    }
+
+    /// Render the cache in its text form, sorted for stable output.
+    #[must_use]
+    pub fn to_text(&self) -> String {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort();
+        let mut text = String::new();
+        for (key, (x, y, z)) in entries {
+            let (gx, gy, gz) = key.grid;
+            let _ = writeln!(
+                text,
+                "{}\t{}\t{}\t{gx}\t{gy}\t{gz}\t{x}\t{y}\t{z}",
+                escape(&key.device),
+                escape(&key.kernel),
+                escape(&key.specialization)
+            );
+        }
+        text
+    }
+
+    /// Load a cache from disk. A missing file yields an empty cache.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is malformed.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(Error::metal(format!(
+                "failed to read tuning cache {}: {e}",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Write the cache to disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_text()).map_err(|e| {
+            Error::metal(format!(
+                "failed to write tuning cache {}: {e}",
+                path.display()
+            ))
+        })
+    }
+}
+
+/// Parse one `TuningCache` line.
+fn parse_entry(line: &str) -> Option<(TuningKey, Size3)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [device, kernel, specialization, numbers @ ..] = fields.as_slice() else {
+        return None;
+    };
+    let numbers = numbers
+        .iter()
+        .map(|field| field.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    let [gx, gy, gz, x, y, z] = numbers[..] else {
+        return None;
+    };
+    let key = TuningKey {
+        device: unescape(device)?,
+        kernel: unescape(kernel)?,
+        specialization: unescape(specialization)?,
+        grid: (gx, gy, gz),
+    };
+    Some((key, (x, y, z)))
+}
+
+/// Escape a text field so that it cannot contain a tab or line break.
+fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Undo [`escape`], failing on an unknown escape sequence.
+fn unescape(field: &str) -> Option<String> {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// Empirical threadgroup tuner.
+///
+/// Runs a caller-supplied benchmark for each candidate shape and keeps the
+/// fastest, averaged over a number of iterations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadgroupTuner {
+    iterations: u32,
+}
+
+impl Default for ThreadgroupTuner {
+    fn default() -> Self {
+        Self { iterations: 5 }
+    }
+}
+
+impl ThreadgroupTuner {
+    /// Create a tuner with the default iteration count.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how many times each candidate is timed.
+    #[must_use]
+    pub const fn with_iterations(mut self, iterations: u32) -> Self {
+        self.iterations = if iterations == 0 { 1 } else { iterations };
+        self
+    }
+
+    /// Benchmark `candidates` and return the fastest.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are no candidates or the benchmark fails.
+    pub fn tune<F>(&self, candidates: &[Size3], mut benchmark: F) -> Result<Size3>
+    where
+        F: FnMut(Size3) -> Result<Duration>,
+    {
+        let mut best: Option<(Duration, Size3)> = None;
+        for &candidate in candidates {
+            let mut total = Duration::ZERO;
+            for _ in 0..self.iterations {
+                total += benchmark(candidate)?;
+            }
+            if best.map_or(true, |(fastest, _)| total < fastest) {
+                best = Some((total, candidate));
+            }
+        }
+        best.map(|(_, size)| size)
+            .ok_or_else(|| Error::invalid_input("no threadgroup candidates to tune"))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ThreadgroupLimits = ThreadgroupLimits {
+        max_total_threads: 1024,
+        thread_execution_width: 32,
+        max_per_dimension: (1024, 1024, 1024),
+    };
+
+    #[test]
+    fn test_select_1d() {
+        assert_eq!(
+            select_threadgroup_size((1_000_000, 1, 1), &LIMITS),
+            (1024, 1, 1)
+        );
+        assert_eq!(select_threadgroup_size((100, 1, 1), &LIMITS), (128, 1, 1));
+        assert_eq!(select_threadgroup_size((1, 1, 1), &LIMITS), (32, 1, 1));
+    }
+
+    #[test]
+    fn test_select_2d() {
+        assert_eq!(
+            select_threadgroup_size((1920, 1080, 1), &LIMITS),
+            (32, 32, 1)
+        );
+        assert_eq!(select_threadgroup_size((4, 1000, 1), &LIMITS), (4, 256, 1));
+        assert_eq!(select_threadgroup_size((1920, 8, 1), &LIMITS), (32, 8, 1));
+    }
+
+    #[test]
+    fn test_select_3d() {
+        assert_eq!(select_threadgroup_size((64, 4, 64), &LIMITS), (32, 4, 8));
+    }
+
+    #[test]
+    fn test_select_extreme_grids() {
+        assert_eq!(
+            select_threadgroup_size((u32::MAX, 2, 1), &LIMITS),
+            (32, 2, 1)
+        );
+        assert_eq!(
+            select_threadgroup_size((u32::MAX, u32::MAX, u32::MAX), &LIMITS),
+            (32, 32, 1)
+        );
+
+        let unbounded = ThreadgroupLimits {
+            max_total_threads: u32::MAX,
+            thread_execution_width: u32::MAX,
+            max_per_dimension: (u32::MAX, u32::MAX, u32::MAX),
+        };
+        for grid in [(u32::MAX, 2, 1), (u32::MAX, 1, 1), (3, 3, 3)] {
+            let shape = select_threadgroup_size(grid, &unbounded);
+            assert!(unbounded.validate(shape).is_ok(), "{grid:?} -> {shape:?}");
+            let candidates = candidate_threadgroup_sizes(grid, &unbounded);
+            assert!(candidates
+                .iter()
+                .all(|&shape| unbounded.validate(shape).is_ok()));
+        }
+    }
+
+    #[test]
+    fn test_select_respects_pipeline_limit() {
+        let limits = ThreadgroupLimits {
+            max_total_threads: 256,
+            thread_execution_width: 64,
+            max_per_dimension: (1024, 1024, 64),
+        };
+        assert_eq!(
+            select_threadgroup_size((10_000, 1, 1), &limits),
+            (256, 1, 1)
+        );
+        assert_eq!(select_threadgroup_size((512, 512, 1), &limits), (64, 4, 1));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(LIMITS.validate((32, 32, 1)).is_ok());
+        assert!(LIMITS.validate((32, 32, 2)).is_err());
+        assert!(LIMITS.validate((0, 1, 1)).is_err());
+        let narrow = ThreadgroupLimits {
+            max_per_dimension: (1024, 1024, 64),
+            ..LIMITS
+        };
+        assert!(narrow.validate((1, 1, 128)).is_err());
+        // Must not overflow u32 when multiplying.
+        assert!(LIMITS.validate((u32::MAX, u32::MAX, u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_candidates_valid_and_unique() {
+        for grid in [(4096, 1, 1), (512, 512, 1), (16, 16, 16)] {
+            let candidates = candidate_threadgroup_sizes(grid, &LIMITS);
+            assert_eq!(candidates[0], select_threadgroup_size(grid, &LIMITS));
+            for (i, c) in candidates.iter().enumerate() {
+                assert!(LIMITS.validate(*c).is_ok(), "{c:?}");
+                assert!(!candidates[i + 1..].contains(c), "duplicate {c:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_tuner_picks_fastest() {
+        let candidates = [(32, 1, 1), (256, 1, 1), (1024, 1, 1)];
+        let tuner = ThreadgroupTuner::new().with_iterations(3);
+        let mut calls = 0;
+        let best = tuner
+            .tune(&candidates, |size| {
+                calls += 1;
+                Ok(Duration::from_micros(u64::from(size.0.abs_diff(256)) + 1))
+            })
+            .unwrap();
+        assert_eq!(best, (256, 1, 1));
+        assert_eq!(calls, 9);
+    }
+
+    #[test]
+    fn test_tuner_errors() {
+        let tuner = ThreadgroupTuner::new();
+        assert!(tuner.tune(&[], |_| Ok(Duration::ZERO)).is_err());
+        assert!(tuner
+            .tune(&[(1, 1, 1)], |_| Err(Error::metal("boom")))
+            .is_err());
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let gemm = TuningKey::new("Apple M2 Max", "gemm", (1024, 1024, 1));
+        let softmax = TuningKey::new("AMD Radeon Pro W6800X", "softmax", (4096, 1, 1))
+            .with_specialization("0=4;1=true");
+        let mut cache = TuningCache::new();
+        cache.insert(gemm.clone(), (32, 8, 1));
+        cache.insert(softmax.clone(), (256, 1, 1));
+        let parsed = TuningCache::parse(&cache.to_text()).unwrap();
+        assert_eq!(parsed, cache);
+        assert_eq!(parsed.get(&gemm), Some((32, 8, 1)));
+        assert_eq!(parsed.get(&softmax), Some((256, 1, 1)));
+        assert_eq!(
+            parsed.get(&TuningKey::new("Apple M2 Max", "softmax", (4096, 1, 1))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cache_keyed_on_grid_and_specialization() {
+        let key = TuningKey::new("GPU", "k", (4096, 1, 1));
+        let mut cache = TuningCache::new();
+        cache.insert(key.clone(), (256, 1, 1));
+        assert_eq!(cache.get(&key), Some((256, 1, 1)));
+        assert_eq!(cache.get(&TuningKey::new("GPU", "k", (64, 64, 1))), None);
+        assert_eq!(cache.get(&key.clone().with_specialization("0=8")), None);
+
+        cache.insert(key.clone().with_specialization("0=8"), (64, 1, 1));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key), Some((256, 1, 1)));
+    }
+
+    #[test]
+    fn test_cache_escapes_names() {
+        let key = TuningKey::new("GPU\tone\nline", "k\\t", (8, 1, 1)).with_specialization("\r");
+        let mut cache = TuningCache::new();
+        cache.insert(key.clone(), (8, 1, 1));
+        let text = cache.to_text();
+        assert_eq!(text.lines().count(), 1);
+        assert_eq!(text.matches('\t').count(), 8);
+        assert_eq!(
+            TuningCache::parse(&text).unwrap().get(&key),
+            Some((8, 1, 1))
+        );
+    }
+
+    #[test]
+    fn test_cache_parse_errors() {
+        assert!(TuningCache::parse("dev\tk\t\t1\t1\t1\t1\t2\n").is_err());
+        assert!(TuningCache::parse("dev\tk\t\t1\t1\t1\t1\tx\t1\n").is_err());
+        assert!(TuningCache::parse("dev\\x\tk\t\t1\t1\t1\t1\t1\t1\n").is_err());
+        assert!(TuningCache::parse("dev\tk\t\t1\t1\t1\t1\t1\t1\n").is_ok());
+        assert!(TuningCache::parse("\n\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_load_save() {
+        let path = std::env::temp_dir().join(format!("manzana-tuning-{}.tsv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(TuningCache::load(&path).unwrap().is_empty());
+
+        let mut cache = TuningCache::new();
+        cache.insert(TuningKey::new("GPU", "kernel", (512, 512, 1)), (64, 4, 1));
+        cache.save(&path).unwrap();
+        assert_eq!(TuningCache::load(&path).unwrap(), cache);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! Zero-copy GPU views of unified memory.
+//!
+//! [`MetalCompute::wrap_uma_buffer`](super::MetalCompute::wrap_uma_buffer)
+//! exposes a [`UmaBuffer`] to kernels in place, with
+//! `newBufferWithBytesNoCopy` semantics: no device memory is allocated and
+//! no bytes are copied. The resulting [`UmaMetalBuffer`] dereferences to a
+//! [`MetalBuffer`], so it binds to dispatches like any other buffer.
+//!
+//! The view borrows the `UmaBuffer`, so the memory cannot be dropped or
+//! mutably borrowed while the view exists:
+//!
+//! ```compile_fail
+//! use manzana::metal::MetalCompute;
+//! use manzana::unified_memory::UmaBuffer;
+//!
+//! let compute = MetalCompute::cpu();
+//! let mut memory = UmaBuffer::zeroed(4096)?;
+//! let gpu = compute.wrap_uma_buffer(&memory)?;
+//! memory.as_mut_slice()[0] = 1; // error: `memory` is borrowed by `gpu`
+//! gpu.write(0, &[1u32])?;
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! Work committed with `dispatch_async` (`async` feature) can outlive the
+//! view. Dropping the view detaches it from the memory without waiting:
+//! work still queued then reads zeros and its writes are discarded, so
+//! await the futures of dispatches that use the view before dropping it.
+//! A view leaked with [`std::mem::forget`] is detached when the
+//! `UmaBuffer` is freed or mutably borrowed, so the memory is never
+//! touched after the borrow ends.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::{CpuKernel, MetalCompute};
+//! use manzana::unified_memory::UmaBuffer;
+//!
+//! let compute = MetalCompute::cpu();
+//! let shader = compute
+//!     .compile_shader("kernel void inc() {}", "inc")?
+//!     .with_cpu_kernel(CpuKernel::new(|ctx| {
+//!         let i = ctx.thread_position().0 as usize;
+//!         ctx.write_u32(0, i, ctx.read_u32(0, i) + 1);
+//!     }));
+//!
+//! let memory = UmaBuffer::zeroed(16)?;
+//! let gpu = compute.wrap_uma_buffer(&memory)?;
+//! compute.dispatch(&shader, &[&gpu], (4, 1, 1), (4, 1, 1))?;
+//! drop(gpu);
+//! assert_eq!(&memory.as_slice()[..4], &[1, 0, 0, 0]);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - F073: kernels access a `UmaBuffer` without copies
+//! - F074: writes through the view are visible in the `UmaBuffer`
+//! - Memory is never accessed through a view after its borrow ends
+
+use super::MetalBuffer;
+use crate::unified_memory::{Detach, UmaBuffer};
+use std::ops::Deref;
+
+/// A [`MetalBuffer`] over a borrowed [`UmaBuffer`].
+///
+/// Its length is the page-rounded allocation size of the `UmaBuffer`.
+/// Dropping the view detaches it from the memory; see the [module
+/// documentation](self) for work still queued at that point.
+pub struct UmaMetalBuffer<'a> {
+    buffer: MetalBuffer,
+    memory: &'a UmaBuffer,
+    detach: Detach,
+}
+
+impl<'a> UmaMetalBuffer<'a> {
+    pub(crate) fn new(buffer: MetalBuffer, memory: &'a UmaBuffer, detach: Detach) -> Self {
+        Self {
+            buffer,
+            memory,
+            detach,
+        }
+    }
+
+    /// The memory the view exposes.
+    #[must_use]
+    pub const fn memory(&self) -> &'a UmaBuffer {
+        self.memory
+    }
+}
+
+impl Drop for UmaMetalBuffer<'_> {
+    fn drop(&mut self) {
+        (self.detach)();
+    }
+}
+
+impl Deref for UmaMetalBuffer<'_> {
+    type Target = MetalBuffer;
+
+    fn deref(&self) -> &MetalBuffer {
+        &self.buffer
+    }
+}
+
+impl std::fmt::Debug for UmaMetalBuffer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UmaMetalBuffer")
+            .field("buffer", &self.buffer)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::metal::MetalCompute;
+    use crate::unified_memory::PAGE_SIZE;
+
+    #[test]
+    fn test_view_shares_memory() {
+        let compute = MetalCompute::cpu();
+        let mut memory = UmaBuffer::zeroed(10).unwrap();
+        memory.copy_from_slice(&[1, 2, 3]).unwrap();
+        let usage = compute.memory_usage();
+        let gpu = compute.wrap_uma_buffer(&memory).unwrap();
+        assert_eq!(gpu.len(), PAGE_SIZE);
+        assert_eq!(gpu.read_bytes(0, 4).unwrap(), vec![1, 2, 3, 0]);
+        gpu.write_bytes(8, &[9; 4]).unwrap();
+        assert!(gpu.write_bytes(PAGE_SIZE - 1, &[0, 0]).is_err());
+        // No-copy buffers are not charged to the device.
+        assert_eq!(compute.memory_usage(), usage);
+        // Shared borrows of the memory can coexist with the view.
+        assert_eq!(gpu.memory().as_slice(), &[1, 2, 3, 0, 0, 0, 0, 0, 9, 9]);
+        drop(gpu);
+        assert_eq!(memory.as_slice(), &[1, 2, 3, 0, 0, 0, 0, 0, 9, 9]);
+    }
+
+    #[test]
+    fn test_dropped_view_is_detached() {
+        let compute = MetalCompute::cpu();
+        let memory = UmaBuffer::zeroed(4).unwrap();
+        let gpu = compute.wrap_uma_buffer(&memory).unwrap();
+        let storage = gpu.storage.clone();
+        drop(gpu);
+        storage.write(0, &[5]);
+        assert_eq!(storage.read(0, 1), vec![0]);
+        assert_eq!(memory.as_slice(), &[0; 4]);
+    }
+
+    #[test]
+    fn test_leaked_view_is_detached_by_memory() {
+        let compute = MetalCompute::cpu();
+        let mut memory = UmaBuffer::zeroed(4).unwrap();
+        let gpu = compute.wrap_uma_buffer(&memory).unwrap();
+        let storage = gpu.storage.clone();
+        std::mem::forget(gpu);
+        memory.as_mut_slice()[0] = 7;
+        storage.write(0, &[5]);
+        assert_eq!(memory.as_slice(), &[7, 0, 0, 0]);
+
+        let gpu = compute.wrap_uma_buffer(&memory).unwrap();
+        let storage = gpu.storage.clone();
+        std::mem::forget(gpu);
+        drop(memory);
+        storage.write(0, &[5]);
+        assert_eq!(storage.read(0, 1), vec![0]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_queued_work_after_drop_is_detached() {
+        use crate::metal::completion::block_on;
+        use crate::metal::CpuKernel;
+
+        let compute = MetalCompute::cpu();
+        let shader = compute
+            .compile_shader("kernel void fill() {}", "fill")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|ctx| {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                ctx.write_u32(0, 0, 0xABCD);
+            }));
+
+        let memory = UmaBuffer::zeroed(4).unwrap();
+        let gpu = compute.wrap_uma_buffer(&memory).unwrap();
+        let queued = compute.dispatch_async(&shader, &[&gpu], (1, 1, 1), (1, 1, 1));
+        block_on(queued).unwrap();
+        drop(gpu);
+        assert_eq!(memory.as_slice(), &0xABCDu32.to_le_bytes());
+
+        // Dropped while the dispatch is queued: the job runs against the
+        // detached storage, after the memory is freed.
+        let memory = UmaBuffer::zeroed(4).unwrap();
+        let gpu = compute.wrap_uma_buffer(&memory).unwrap();
+        let queued = compute.dispatch_async(&shader, &[&gpu], (1, 1, 1), (1, 1, 1));
+        drop(gpu);
+        drop(memory);
+        assert!(block_on(queued).is_ok());
+    }
+}
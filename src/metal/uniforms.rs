@@ -0,0 +1,1053 @@
+//! Kernel parameter structs with checked MSL layouts.
+//!
+//! `#[derive(ShaderUniforms)]` describes a Rust struct as an MSL struct.
+//! The layout comes from the field types under MSL rules, not from the
+//! Rust layout, so `float3` padding, packed vectors and alignment are
+//! handled when the struct is encoded:
+//!
+//! | Rust field type | MSL type | Size | Alignment |
+//! |-----------------|----------|------|-----------|
+//! | `f32`, `u32`, `i32` | `float`, `uint`, `int` | 4 | 4 |
+//! | [`Float3`] | `float3` | 16 | 16 |
+//! | [`PackedFloat3`] | `packed_float3` | 12 | 4 |
+//! | [`Float4x4`] | `float4x4` | 64 | 16 |
+//! | `[T; N]` | `T name[N]` | `N` × size of `T` | alignment of `T` |
+//!
+//! Fields may also be other `ShaderUniforms` structs.
+//! [`ShaderUniforms::msl_declaration`] generates the matching MSL source.
+//!
+//! When a shader is compiled, the struct types of its `[[buffer(n)]]`
+//! parameters are parsed from the source. A buffer created with
+//! [`MetalCompute::uniforms_buffer`] remembers its type, and dispatching it
+//! to a parameter whose layout differs fails instead of reading garbage.
+//! Parameters whose types cannot be parsed are not checked.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::uniforms::Float3;
+//! use manzana::metal::{MetalCompute, ShaderUniforms};
+//!
+//! #[derive(ShaderUniforms)]
+//! struct Params {
+//!     offset: Float3,
+//!     scale: f32,
+//! }
+//!
+//! assert_eq!(
+//!     Params::msl_declaration(),
+//!     "struct Params {\n    float3 offset;\n    float scale;\n};\n"
+//! );
+//!
+//! let compute = MetalCompute::cpu();
+//! let params = Params { offset: Float3::new([1.0, 2.0, 3.0]), scale: 0.5 };
+//! let buffer = compute.uniforms_buffer(&params)?;
+//! assert_eq!(buffer.len(), 32); // float3 pads to 16 bytes
+//!
+//! // A kernel that declares the offset as packed_float3 is rejected.
+//! let shader = compute.compile_shader(
+//!     "struct Params { packed_float3 offset; float scale; };
+//!      kernel void apply(constant Params& params [[buffer(0)]]) {}",
+//!     "apply",
+//! )?;
+//! assert!(compute.dispatch(&shader, &[&buffer], (1, 1, 1), (1, 1, 1)).is_err());
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - Encoded fields sit at the offsets MSL assigns them
+//! - The generated declaration parses back to the same layout
+//! - Dispatching uniforms to a parameter with a different layout fails
+
+use super::specialization::strip_comments;
+use super::{MetalBuffer, MetalCompute};
+use crate::error::Result;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::ops::Range;
+
+pub use manzana_derive::ShaderUniforms;
+
+/// Deepest struct nesting resolved when parsing kernel source.
+const MAX_NESTING: usize = 16;
+
+/// An MSL scalar type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scalar {
+    /// `bool`
+    Bool,
+    /// `char`
+    Char,
+    /// `uchar`
+    UChar,
+    /// `short`
+    Short,
+    /// `ushort`
+    UShort,
+    /// `half`
+    Half,
+    /// `int`
+    Int,
+    /// `uint`
+    UInt,
+    /// `float`
+    Float,
+    /// `long`
+    Long,
+    /// `ulong`
+    ULong,
+}
+
+impl Scalar {
+    /// Size (and alignment) in bytes.
+    #[must_use]
+    pub const fn size(self) -> usize {
+        match self {
+            Self::Bool | Self::Char | Self::UChar => 1,
+            Self::Short | Self::UShort | Self::Half => 2,
+            Self::Int | Self::UInt | Self::Float => 4,
+            Self::Long | Self::ULong => 8,
+        }
+    }
+
+    /// MSL spelling.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Bool => "bool",
+            Self::Char => "char",
+            Self::UChar => "uchar",
+            Self::Short => "short",
+            Self::UShort => "ushort",
+            Self::Half => "half",
+            Self::Int => "int",
+            Self::UInt => "uint",
+            Self::Float => "float",
+            Self::Long => "long",
+            Self::ULong => "ulong",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "bool" => Self::Bool,
+            "char" | "int8_t" => Self::Char,
+            "uchar" | "uint8_t" => Self::UChar,
+            "short" | "int16_t" => Self::Short,
+            "ushort" | "uint16_t" => Self::UShort,
+            "half" => Self::Half,
+            "int" | "int32_t" => Self::Int,
+            "uint" | "uint32_t" => Self::UInt,
+            "float" => Self::Float,
+            "long" | "int64_t" => Self::Long,
+            "ulong" | "uint64_t" => Self::ULong,
+            _ => return None,
+        })
+    }
+}
+
+/// An MSL type, with its size and alignment under MSL layout rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MslType {
+    /// A scalar.
+    Scalar(Scalar),
+    /// A 2-, 3- or 4-lane vector such as `float3`; 3-lane vectors are
+    /// padded to 4 lanes.
+    Vector {
+        /// Lane type.
+        scalar: Scalar,
+        /// Number of lanes.
+        lanes: usize,
+    },
+    /// A packed vector such as `packed_float3`, aligned like its scalar.
+    Packed {
+        /// Lane type.
+        scalar: Scalar,
+        /// Number of lanes.
+        lanes: usize,
+    },
+    /// A matrix such as `float3x4`, stored as `columns` vectors of `rows`
+    /// lanes.
+    Matrix {
+        /// Element type.
+        scalar: Scalar,
+        /// Number of columns.
+        columns: usize,
+        /// Number of rows.
+        rows: usize,
+    },
+    /// A fixed-size array.
+    Array(Box<Self>, usize),
+    /// A struct with named fields, in declaration order.
+    Struct {
+        /// Struct name.
+        name: String,
+        /// Field names and types.
+        fields: Vec<(String, Self)>,
+    },
+}
+
+impl MslType {
+    /// A struct type, as built by `#[derive(ShaderUniforms)]`.
+    #[must_use]
+    pub fn structure(name: &str, fields: Vec<(&str, Self)>) -> Self {
+        Self::Struct {
+            name: name.to_string(),
+            fields: fields
+                .into_iter()
+                .map(|(field, ty)| (field.to_string(), ty))
+                .collect(),
+        }
+    }
+
+    /// Parse a built-in type name such as `uint`, `float3`,
+    /// `packed_half4` or `float4x4`.
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        if let Some(scalar) = Scalar::parse(name) {
+            return Some(Self::Scalar(scalar));
+        }
+        let lanes = |digit: Option<char>| {
+            digit
+                .and_then(|d| d.to_digit(10))
+                .map(|d| d as usize)
+                .filter(|d| (2..=4).contains(d))
+        };
+        if let Some((scalar, shape)) = name.split_once('x').and_then(|(head, rows)| {
+            let mut rows = rows.chars();
+            let rows = lanes(rows.next()).filter(|_| rows.as_str().is_empty())?;
+            let columns = lanes(head.chars().last())?;
+            Some((head.get(..head.len() - 1)?, (columns, rows)))
+        }) {
+            let scalar =
+                Scalar::parse(scalar).filter(|s| matches!(s, Scalar::Float | Scalar::Half))?;
+            return Some(Self::Matrix {
+                scalar,
+                columns: shape.0,
+                rows: shape.1,
+            });
+        }
+        let (packed, vector) = name
+            .strip_prefix("packed_")
+            .map_or((false, name), |rest| (true, rest));
+        let count = lanes(vector.chars().last())?;
+        let scalar = Scalar::parse(vector.get(..vector.len() - 1)?)?;
+        Some(if packed {
+            Self::Packed {
+                scalar,
+                lanes: count,
+            }
+        } else {
+            Self::Vector {
+                scalar,
+                lanes: count,
+            }
+        })
+    }
+
+    /// Size in bytes, including trailing padding.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Scalar(scalar) => scalar.size(),
+            Self::Vector { scalar, lanes } => scalar.size() * if *lanes == 3 { 4 } else { *lanes },
+            Self::Packed { scalar, lanes } => scalar.size() * lanes,
+            Self::Matrix {
+                scalar,
+                columns,
+                rows,
+            } => {
+                columns
+                    * Self::Vector {
+                        scalar: *scalar,
+                        lanes: *rows,
+                    }
+                    .size()
+            }
+            Self::Array(element, count) => element.size() * count,
+            Self::Struct { fields, .. } => {
+                let end = self
+                    .field_offsets()
+                    .last()
+                    .zip(fields.last())
+                    .map_or(0, |(offset, (_, ty))| offset + ty.size());
+                end.next_multiple_of(self.align())
+            }
+        }
+    }
+
+    /// Alignment in bytes.
+    #[must_use]
+    pub fn align(&self) -> usize {
+        match self {
+            Self::Scalar(scalar) | Self::Packed { scalar, .. } => scalar.size(),
+            Self::Vector { .. } => self.size(),
+            Self::Matrix { scalar, rows, .. } => Self::Vector {
+                scalar: *scalar,
+                lanes: *rows,
+            }
+            .size(),
+            Self::Array(element, _) => element.align(),
+            Self::Struct { fields, .. } => {
+                fields.iter().map(|(_, ty)| ty.align()).max().unwrap_or(1)
+            }
+        }
+    }
+
+    /// Offsets of a struct's fields; empty for other types.
+    fn field_offsets(&self) -> Vec<usize> {
+        let Self::Struct { fields, .. } = self else {
+            return Vec::new();
+        };
+        let mut end = 0usize;
+        fields
+            .iter()
+            .map(|(_, ty)| {
+                let offset = end.next_multiple_of(ty.align());
+                end = offset + ty.size();
+                offset
+            })
+            .collect()
+    }
+
+    /// Byte range of a struct's field `index`; empty for other types.
+    #[must_use]
+    pub fn field_range(&self, index: usize) -> Range<usize> {
+        match (self, self.field_offsets().get(index)) {
+            (Self::Struct { fields, .. }, Some(&offset)) => offset..offset + fields[index].1.size(),
+            _ => 0..0,
+        }
+    }
+
+    /// The type's name, without array dimensions.
+    fn spelling(&self) -> String {
+        match self {
+            Self::Scalar(scalar) => scalar.name().to_string(),
+            Self::Vector { scalar, lanes } => format!("{}{lanes}", scalar.name()),
+            Self::Packed { scalar, lanes } => format!("packed_{}{lanes}", scalar.name()),
+            Self::Matrix {
+                scalar,
+                columns,
+                rows,
+            } => format!("{}{columns}x{rows}", scalar.name()),
+            Self::Array(element, _) => element.spelling(),
+            Self::Struct { name, .. } => name.clone(),
+        }
+    }
+
+    /// A declaration of `name` with this type, e.g. `uint counts[4]`.
+    fn declare(&self, name: &str) -> String {
+        let mut dims = String::new();
+        let mut ty = self;
+        while let Self::Array(element, count) = ty {
+            let _ = write!(dims, "[{count}]");
+            ty = element;
+        }
+        format!("{} {name}{dims}", ty.spelling())
+    }
+
+    /// Append the definitions of this type's structs, dependencies first.
+    fn define(&self, out: &mut String, defined: &mut Vec<String>) {
+        match self {
+            Self::Array(element, _) => element.define(out, defined),
+            Self::Struct { name, fields } if !defined.contains(name) => {
+                for (_, ty) in fields {
+                    ty.define(out, defined);
+                }
+                defined.push(name.clone());
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                let _ = writeln!(out, "struct {name} {{");
+                for (field, ty) in fields {
+                    let _ = writeln!(out, "    {};", ty.declare(field));
+                }
+                out.push_str("};\n");
+            }
+            _ => {}
+        }
+    }
+
+    /// Every scalar the type holds, with its offset and a readable path.
+    fn leaves(&self, path: &str, base: usize, out: &mut Vec<Leaf>) {
+        const LANES: [&str; 4] = ["x", "y", "z", "w"];
+        match self {
+            Self::Scalar(scalar) => out.push(Leaf {
+                path: path.to_string(),
+                offset: base,
+                scalar: *scalar,
+            }),
+            Self::Vector { scalar, lanes } | Self::Packed { scalar, lanes } => {
+                for (lane, name) in LANES.iter().enumerate().take(*lanes) {
+                    out.push(Leaf {
+                        path: format!("{path}.{name}"),
+                        offset: base + lane * scalar.size(),
+                        scalar: *scalar,
+                    });
+                }
+            }
+            Self::Matrix {
+                scalar,
+                columns,
+                rows,
+            } => {
+                let column = Self::Vector {
+                    scalar: *scalar,
+                    lanes: *rows,
+                };
+                for c in 0..*columns {
+                    column.leaves(&format!("{path}[{c}]"), base + c * column.size(), out);
+                }
+            }
+            Self::Array(element, count) => {
+                for i in 0..*count {
+                    element.leaves(&format!("{path}[{i}]"), base + i * element.size(), out);
+                }
+            }
+            Self::Struct { fields, .. } => {
+                for ((field, ty), offset) in fields.iter().zip(self.field_offsets()) {
+                    ty.leaves(&format!("{path}.{field}"), base + offset, out);
+                }
+            }
+        }
+    }
+
+    /// Check that values of this type can be read as `kernel`: every
+    /// scalar has the same type and offset, and the sizes agree.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the first scalar that differs.
+    pub fn check_compatible(&self, kernel: &Self) -> std::result::Result<(), String> {
+        let (mut ours, mut theirs) = (Vec::new(), Vec::new());
+        self.leaves(&self.spelling(), 0, &mut ours);
+        kernel.leaves(&kernel.spelling(), 0, &mut theirs);
+        for (index, ours) in ours.iter().enumerate() {
+            let Some(theirs) = theirs.get(index) else {
+                return Err(format!(
+                    "`{}` ({ours}) has no counterpart in kernel type `{}`",
+                    ours.path,
+                    kernel.spelling()
+                ));
+            };
+            if ours.offset != theirs.offset || ours.scalar != theirs.scalar {
+                return Err(format!(
+                    "`{}` ({ours}) does not match kernel `{}` ({theirs})",
+                    ours.path, theirs.path
+                ));
+            }
+        }
+        if let Some(theirs) = theirs.get(ours.len()) {
+            return Err(format!(
+                "kernel `{}` ({theirs}) has no counterpart in `{}`",
+                theirs.path,
+                self.spelling()
+            ));
+        }
+        if self.size() != kernel.size() {
+            return Err(format!(
+                "`{}` is {} bytes but kernel type `{}` is {} bytes",
+                self.spelling(),
+                self.size(),
+                kernel.spelling(),
+                kernel.size()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MslType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let declaration = self.declare("");
+        write!(f, "{}", declaration.replacen(' ', "", 1))
+    }
+}
+
+/// A scalar inside a type, for layout comparison.
+struct Leaf {
+    path: String,
+    offset: usize,
+    scalar: Scalar,
+}
+
+impl fmt::Display for Leaf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.scalar.name(), self.offset)
+    }
+}
+
+/// A Rust type with an MSL counterpart.
+///
+/// Implemented for scalars, [`Vector`], [`Packed`], [`Matrix`], arrays of
+/// uniform values, and structs that derive [`ShaderUniforms`].
+pub trait UniformValue {
+    /// The MSL type values are encoded as.
+    fn msl_type() -> MslType;
+
+    /// Encode the value into `out`, which is exactly
+    /// `Self::msl_type().size()` bytes; padding is left untouched.
+    fn write(&self, out: &mut [u8]);
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A Rust scalar that encodes as an MSL scalar.
+pub trait ScalarValue: Copy + sealed::Sealed {
+    /// The MSL scalar type.
+    const SCALAR: Scalar;
+
+    /// Encode the value as little-endian bytes at the start of `out`.
+    fn write_le(self, out: &mut [u8]);
+}
+
+macro_rules! impl_scalar {
+    ($($ty:ty => $scalar:ident),*) => {$(
+        impl sealed::Sealed for $ty {}
+
+        impl ScalarValue for $ty {
+            const SCALAR: Scalar = Scalar::$scalar;
+
+            fn write_le(self, out: &mut [u8]) {
+                let bytes = self.to_le_bytes();
+                out[..bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+
+        impl UniformValue for $ty {
+            fn msl_type() -> MslType {
+                MslType::Scalar(Scalar::$scalar)
+            }
+
+            fn write(&self, out: &mut [u8]) {
+                self.write_le(out);
+            }
+        }
+    )*};
+}
+
+impl_scalar!(
+    i8 => Char, u8 => UChar, i16 => Short, u16 => UShort, i32 => Int,
+    u32 => UInt, f32 => Float, i64 => Long, u64 => ULong
+);
+
+impl sealed::Sealed for bool {}
+
+impl ScalarValue for bool {
+    const SCALAR: Scalar = Scalar::Bool;
+
+    fn write_le(self, out: &mut [u8]) {
+        out[0] = u8::from(self);
+    }
+}
+
+impl UniformValue for bool {
+    fn msl_type() -> MslType {
+        MslType::Scalar(Scalar::Bool)
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        self.write_le(out);
+    }
+}
+
+impl<T: UniformValue, const N: usize> UniformValue for [T; N] {
+    fn msl_type() -> MslType {
+        MslType::Array(Box::new(T::msl_type()), N)
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        let stride = T::msl_type().size();
+        for (value, out) in self.iter().zip(out.chunks_exact_mut(stride)) {
+            value.write(out);
+        }
+    }
+}
+
+/// Lane counts MSL vectors support.
+struct Lanes<const N: usize>;
+
+impl<const N: usize> Lanes<N> {
+    const VALID: () = assert!(N >= 2 && N <= 4, "MSL vectors have 2, 3 or 4 lanes");
+}
+
+fn write_lanes<T: ScalarValue>(lanes: &[T], out: &mut [u8]) {
+    for (lane, out) in lanes.iter().zip(out.chunks_exact_mut(T::SCALAR.size())) {
+        lane.write_le(out);
+    }
+}
+
+/// An MSL vector such as `float3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vector<T, const N: usize>(pub [T; N]);
+
+impl<T, const N: usize> Vector<T, N> {
+    /// Create a vector from its lanes.
+    pub const fn new(lanes: [T; N]) -> Self {
+        Self(lanes)
+    }
+}
+
+impl<T: ScalarValue, const N: usize> UniformValue for Vector<T, N> {
+    fn msl_type() -> MslType {
+        let () = Lanes::<N>::VALID;
+        MslType::Vector {
+            scalar: T::SCALAR,
+            lanes: N,
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        write_lanes(&self.0, out);
+    }
+}
+
+/// An MSL packed vector such as `packed_float3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packed<T, const N: usize>(pub [T; N]);
+
+impl<T, const N: usize> Packed<T, N> {
+    /// Create a packed vector from its lanes.
+    pub const fn new(lanes: [T; N]) -> Self {
+        Self(lanes)
+    }
+}
+
+impl<T: ScalarValue, const N: usize> UniformValue for Packed<T, N> {
+    fn msl_type() -> MslType {
+        let () = Lanes::<N>::VALID;
+        MslType::Packed {
+            scalar: T::SCALAR,
+            lanes: N,
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        write_lanes(&self.0, out);
+    }
+}
+
+/// An MSL `float` matrix of `C` columns and `R` rows, stored by column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix<const C: usize, const R: usize>(pub [[f32; R]; C]);
+
+impl<const C: usize, const R: usize> Matrix<C, R> {
+    /// Create a matrix from its columns.
+    #[must_use]
+    pub const fn new(columns: [[f32; R]; C]) -> Self {
+        Self(columns)
+    }
+}
+
+impl<const C: usize, const R: usize> UniformValue for Matrix<C, R> {
+    fn msl_type() -> MslType {
+        let ((), ()) = (Lanes::<C>::VALID, Lanes::<R>::VALID);
+        MslType::Matrix {
+            scalar: Scalar::Float,
+            columns: C,
+            rows: R,
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        let stride = Vector::<f32, R>::msl_type().size();
+        for (column, out) in self.0.iter().zip(out.chunks_exact_mut(stride)) {
+            write_lanes(column, out);
+        }
+    }
+}
+
+/// MSL `float2`.
+pub type Float2 = Vector<f32, 2>;
+/// MSL `float3`: 12 bytes of data padded to 16.
+pub type Float3 = Vector<f32, 3>;
+/// MSL `float4`.
+pub type Float4 = Vector<f32, 4>;
+/// MSL `int2`.
+pub type Int2 = Vector<i32, 2>;
+/// MSL `int3`.
+pub type Int3 = Vector<i32, 3>;
+/// MSL `int4`.
+pub type Int4 = Vector<i32, 4>;
+/// MSL `uint2`.
+pub type UInt2 = Vector<u32, 2>;
+/// MSL `uint3`.
+pub type UInt3 = Vector<u32, 3>;
+/// MSL `uint4`.
+pub type UInt4 = Vector<u32, 4>;
+/// MSL `packed_float3`: 12 bytes, 4-byte aligned.
+pub type PackedFloat3 = Packed<f32, 3>;
+/// MSL `float2x2`.
+pub type Float2x2 = Matrix<2, 2>;
+/// MSL `float3x3`: three `float3` columns.
+pub type Float3x3 = Matrix<3, 3>;
+/// MSL `float4x4`.
+pub type Float4x4 = Matrix<4, 4>;
+
+/// A struct passed to kernels as a parameter block.
+///
+/// Derive it with `#[derive(ShaderUniforms)]`; see the module docs.
+pub trait ShaderUniforms: UniformValue {
+    /// MSL source declaring this struct and the structs it contains.
+    #[must_use]
+    fn msl_declaration() -> String {
+        let mut out = String::new();
+        Self::msl_type().define(&mut out, &mut Vec::new());
+        out
+    }
+
+    /// The value encoded with MSL layout.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![0; Self::msl_type().size()];
+        self.write(&mut out);
+        out
+    }
+}
+
+/// Field declarations of the structs in `code`, by struct name.
+fn struct_definitions(code: &str) -> HashMap<&str, &str> {
+    let mut structs = HashMap::new();
+    let mut rest = code;
+    while let Some(at) = find_word(rest, "struct") {
+        rest = &rest[at + "struct".len()..];
+        let Some(open) = rest.find('{') else { break };
+        let name = rest[..open].trim();
+        let Some(close) = rest[open..].find('}') else {
+            break;
+        };
+        if is_identifier(name) {
+            structs.insert(name, &rest[open + 1..open + close]);
+        }
+        rest = &rest[open + close..];
+    }
+    structs
+}
+
+/// Resolve the type spelled `name`, looking up structs in `structs`.
+fn resolve(name: &str, structs: &HashMap<&str, &str>, depth: usize) -> Option<MslType> {
+    if let Some(ty) = MslType::parse(name) {
+        return Some(ty);
+    }
+    let body = structs.get(name).filter(|_| depth < MAX_NESTING)?;
+    let mut fields = Vec::new();
+    for declaration in body.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+        let declaration = strip_attributes(declaration);
+        let mut tokens = declaration.split_whitespace().filter(|t| *t != "const");
+        let ty = resolve(tokens.next()?, structs, depth + 1)?;
+        let declarators: String = tokens.collect();
+        for declarator in declarators.split(',') {
+            let (field, dims) = declarator.split_once('[').unwrap_or((declarator, ""));
+            let mut field_ty = ty.clone();
+            for dim in dims.rsplit('[').filter(|d| !d.is_empty()) {
+                let count = dim.trim_end_matches(']').trim().parse().ok()?;
+                field_ty = MslType::Array(Box::new(field_ty), count);
+            }
+            if !is_identifier(field) {
+                return None;
+            }
+            fields.push((field.to_string(), field_ty));
+        }
+    }
+    (!fields.is_empty()).then(|| MslType::Struct {
+        name: name.to_string(),
+        fields,
+    })
+}
+
+/// The types of `function`'s `[[buffer(n)]]` parameters in `source`, by
+/// buffer index. Parameters with types that cannot be parsed are omitted.
+pub(crate) fn buffer_parameters(source: &str, function: &str) -> HashMap<usize, MslType> {
+    let code = strip_comments(source);
+    let structs = struct_definitions(&code);
+    let Some(parameters) = kernel_parameters(&code, function) else {
+        return HashMap::new();
+    };
+    split_top_level(parameters)
+        .into_iter()
+        .filter_map(|parameter| {
+            let attributes: String = parameter.split_whitespace().collect();
+            let index = attributes
+                .split_once("[[buffer(")?
+                .1
+                .split_once(')')?
+                .0
+                .parse()
+                .ok()?;
+            let declaration = strip_attributes(parameter).replace(['&', '*'], " ");
+            let tokens: Vec<_> = declaration
+                .split_whitespace()
+                .filter(|t| !matches!(*t, "constant" | "device" | "const" | "volatile"))
+                .collect();
+            let [ty, _name] = tokens[..] else {
+                return None;
+            };
+            Some((index, resolve(ty, &structs, 0)?))
+        })
+        .collect()
+}
+
+/// The text between the parentheses of `kernel void function(...)`.
+fn kernel_parameters<'a>(code: &'a str, function: &str) -> Option<&'a str> {
+    let mut rest = code;
+    while let Some(at) = find_word(rest, "kernel") {
+        rest = &rest[at + "kernel".len()..];
+        let Some(after) = rest.trim_start().strip_prefix("void") else {
+            continue;
+        };
+        let Some(after) = after.trim_start().strip_prefix(function) else {
+            continue;
+        };
+        let Some(after) = after.trim_start().strip_prefix('(') else {
+            continue;
+        };
+        let mut depth = 1;
+        for (i, c) in after.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return Some(&after[..i]);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Split at commas outside brackets.
+fn split_top_level(text: &str) -> Vec<&str> {
+    let (mut parts, mut depth, mut start) = (Vec::new(), 0i32, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '[' | '<' => depth += 1,
+            ')' | ']' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Remove `[[...]]` attributes.
+fn strip_attributes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find("[[") {
+        out.push_str(&rest[..open]);
+        rest = rest[open..]
+            .find("]]")
+            .map_or("", |close| &rest[open + close + 2..]);
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Byte offset of `word` in `text` as a whole identifier.
+fn find_word(text: &str, word: &str) -> Option<usize> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(word).map(|(at, _)| at).find(|&at| {
+        !text[..at].ends_with(is_ident) && !text[at + word.len()..].starts_with(is_ident)
+    })
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl MetalCompute {
+    /// Allocate a buffer holding `value` in MSL layout.
+    ///
+    /// The buffer remembers the value's type: dispatching it to a kernel
+    /// parameter whose parsed struct has a different layout fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if allocation fails.
+    pub fn uniforms_buffer<T: ShaderUniforms>(&self, value: &T) -> Result<MetalBuffer> {
+        let bytes = value.to_bytes();
+        let mut buffer = self.allocate_buffer(bytes.len())?;
+        buffer.write_bytes(0, &bytes)?;
+        buffer.uniforms = Some(T::msl_type());
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::metal::CpuKernel;
+
+    #[derive(ShaderUniforms)]
+    struct Light {
+        color: Float3,
+        intensity: f32,
+    }
+
+    #[derive(ShaderUniforms)]
+    struct Scene {
+        count: u32,
+        transform: Float4x4,
+        position: PackedFloat3,
+        enabled: bool,
+        lights: [Light; 2],
+        weights: [u16; 3],
+    }
+
+    fn scene() -> Scene {
+        Scene {
+            count: 7,
+            transform: Matrix::new([[1.0, 0.0, 0.0, 0.0]; 4]),
+            position: Packed::new([1.0, 2.0, 3.0]),
+            enabled: true,
+            lights: [
+                Light {
+                    color: Vector::new([0.5; 3]),
+                    intensity: 2.0,
+                },
+                Light {
+                    color: Vector::new([0.25; 3]),
+                    intensity: 4.0,
+                },
+            ],
+            weights: [1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn test_layout_rules() {
+        let light = Light::msl_type();
+        assert_eq!((light.size(), light.align()), (32, 16));
+        assert_eq!(light.field_range(1), 16..20);
+
+        let scene = Scene::msl_type();
+        let offsets: Vec<_> = (0..6).map(|i| scene.field_range(i).start).collect();
+        // uint, float4x4 (16-aligned), packed_float3 (4-aligned), bool,
+        // Light[2] (16-aligned), ushort[3].
+        assert_eq!(offsets, [0, 16, 80, 92, 96, 160]);
+        assert_eq!(scene.size(), 176);
+
+        assert_eq!(MslType::parse("float3x3").unwrap().size(), 48);
+        assert_eq!(MslType::parse("half3").unwrap().size(), 8);
+        assert_eq!(MslType::parse("packed_half3").unwrap().align(), 2);
+        for bad in [
+            "float5",
+            "packed_float",
+            "floatx",
+            "int2x2",
+            "float4x",
+            "vec3",
+        ] {
+            assert_eq!(MslType::parse(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let bytes = scene().to_bytes();
+        assert_eq!(bytes.len(), 176);
+        assert_eq!(bytes[0..4], 7u32.to_le_bytes());
+        assert_eq!(bytes[84..88], 2.0f32.to_le_bytes());
+        assert_eq!(bytes[92], 1);
+        // Second light's intensity: 96 + 32 + 16.
+        assert_eq!(bytes[144..148], 4.0f32.to_le_bytes());
+        assert_eq!(bytes[162..164], 2u16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_declaration_round_trips() {
+        let source = format!(
+            "{}\nkernel void draw(constant Scene& scene [[buffer(1)]], device float* out [[buffer(0)]]) {{}}",
+            Scene::msl_declaration()
+        );
+        assert!(source.starts_with("struct Light {\n    float3 color;\n    float intensity;\n};\n"));
+        assert!(source.contains("    Light lights[2];\n"));
+
+        let parameters = buffer_parameters(&source, "draw");
+        assert_eq!(parameters[&1], Scene::msl_type());
+        assert_eq!(parameters[&0], MslType::Scalar(Scalar::Float));
+        Scene::msl_type().check_compatible(&parameters[&1]).unwrap();
+    }
+
+    #[test]
+    fn test_mismatches_reported() {
+        let source = "
+            struct Light { packed_float3 color; float intensity; };
+            /* struct Light { float3 color; float intensity; }; */
+            kernel void shade(constant Light &light [[ buffer(0) ]],
+                              device uint *counts [[buffer(1)]]) {}";
+        let parameters = buffer_parameters(source, "shade");
+        let err = Light::msl_type()
+            .check_compatible(&parameters[&0])
+            .unwrap_err();
+        assert!(
+            err.contains("`Light.intensity` (float at offset 16) does not match kernel `Light.intensity` (float at offset 12)"),
+            "{err}"
+        );
+        assert!(u32::msl_type().check_compatible(&parameters[&1]).is_ok());
+        assert!(<[u32; 2]>::msl_type()
+            .check_compatible(&parameters[&1])
+            .is_err());
+        // Unknown types and other kernels are not parsed.
+        assert!(
+            buffer_parameters("kernel void k(constant Foo& f [[buffer(0)]]) {}", "k").is_empty()
+        );
+        assert!(buffer_parameters(source, "other").is_empty());
+    }
+
+    #[test]
+    fn test_dispatch_checks_uniforms() {
+        let compute = MetalCompute::cpu();
+        let light = Light {
+            color: Vector::new([1.0; 3]),
+            intensity: 3.0,
+        };
+        let uniforms = compute.uniforms_buffer(&light).unwrap();
+        let output = compute.allocate_buffer(4).unwrap();
+        let kernel = |source: &str| {
+            compute
+                .compile_shader(source, "shade")
+                .unwrap()
+                .with_cpu_kernel(CpuKernel::new(|ctx| {
+                    ctx.write_f32(1, 0, ctx.read_f32(0, 4));
+                }))
+        };
+
+        let matching = kernel(&format!(
+            "{}kernel void shade(constant Light& l [[buffer(0)]], device float* out [[buffer(1)]]) {{}}",
+            Light::msl_declaration()
+        ));
+        compute
+            .dispatch(&matching, &[&uniforms, &output], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+        assert_eq!(output.read::<f32>().unwrap(), vec![3.0]);
+
+        let packed = kernel(
+            "struct Light { packed_float3 color; float intensity; };
+             kernel void shade(constant Light& l [[buffer(0)]], device float* out [[buffer(1)]]) {}",
+        );
+        let err = compute
+            .dispatch(&packed, &[&uniforms, &output], (1, 1, 1), (1, 1, 1))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("buffer(0) of kernel 'shade'"),
+            "{err}"
+        );
+        // Plain buffers are not checked.
+        compute
+            .dispatch(&packed, &[&output, &output], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+    }
+}
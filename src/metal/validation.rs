@@ -0,0 +1,468 @@
+//! Opt-in validation of kernel buffer accesses.
+//!
+//! Attaching a [`Validation`] with
+//! [`MetalCompute::with_validation`](super::MetalCompute::with_validation)
+//! instruments every CPU-backend dispatch. Each buffer access through the
+//! [`ThreadContext`](super::ThreadContext) is checked for:
+//!
+//! - **Bounds**: reads and writes past the end of a buffer, or of a binding
+//!   that was never bound. Without validation these behave like Metal's
+//!   robust buffer access and are silently ignored.
+//! - **Races**: two threads writing the same word in one dispatch, unless
+//!   every write is atomic.
+//! - **Uninitialized reads**: reads of words never written by the host or
+//!   by an earlier kernel.
+//!
+//! A dispatch that triggers a check still runs to completion and then
+//! fails; every [`Violation`] records the kernel, the thread position and
+//! the buffer index, and is kept in the [`Validation`] log. Texture
+//! accesses are not checked. On the Metal backend, validation is left to
+//! Metal's own API and shader validation layers.
+//!
+//! # Example
+//!
+//! ```
+//! use manzana::metal::{CpuKernel, MetalCompute, Validation};
+//!
+//! let validation = Validation::all();
+//! let compute = MetalCompute::cpu().with_validation(validation.clone());
+//! let shader = compute
+//!     .compile_shader("kernel void k() {}", "k")?
+//!     .with_cpu_kernel(CpuKernel::new(|ctx| {
+//!         let i = ctx.thread_position().0 as usize;
+//!         ctx.write_u32(0, i + 1, 1); // off by one
+//!     }));
+//! let buffer = compute.allocate_buffer(16)?;
+//!
+//! assert!(compute.dispatch(&shader, &[&buffer], (4, 1, 1), (4, 1, 1)).is_err());
+//! let violation = &validation.violations()[0];
+//! assert_eq!(violation.thread, (3, 0, 0));
+//! assert_eq!(violation.binding, 0);
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - F052: out-of-bounds buffer access is caught when validating
+//! - Conflicting non-atomic writes from different threads are reported
+//! - Host-written and kernel-written words never count as uninitialized
+
+use super::storage::StorageView;
+use super::Size3;
+use crate::error::Error;
+use bitflags::bitflags;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Most violations recorded per dispatch; further ones are only counted.
+pub const MAX_VIOLATIONS_PER_DISPATCH: usize = 64;
+
+bitflags! {
+    /// Checks performed by a [`Validation`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ValidationChecks: u32 {
+        /// Out-of-bounds buffer accesses.
+        const BOUNDS = 1 << 0;
+        /// Conflicting writes from different threads.
+        const RACES = 1 << 1;
+        /// Reads of uninitialized buffer words.
+        const UNINITIALIZED = 1 << 2;
+    }
+}
+
+/// Kind of buffer access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// A plain load.
+    Read,
+    /// A plain store.
+    Write,
+    /// An atomic read-modify-write.
+    Atomic,
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read => write!(f, "read"),
+            Self::Write => write!(f, "write"),
+            Self::Atomic => write!(f, "atomic"),
+        }
+    }
+}
+
+/// What a [`Violation`] found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ViolationKind {
+    /// Access past the end of the buffer.
+    OutOfBounds {
+        /// The offending access.
+        access: Access,
+        /// Words in the buffer (0 if unbound).
+        len: usize,
+    },
+    /// Write to a word another thread also wrote, without atomics on both
+    /// sides.
+    Race {
+        /// Position of the other writing thread.
+        other: Size3,
+    },
+    /// Read of a word that was never written.
+    UninitializedRead,
+}
+
+impl ViolationKind {
+    const fn tag(self) -> u8 {
+        match self {
+            Self::OutOfBounds { .. } => 0,
+            Self::Race { .. } => 1,
+            Self::UninitializedRead => 2,
+        }
+    }
+}
+
+/// A failed validation check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Kernel function name.
+    pub kernel: String,
+    /// What was found.
+    pub kind: ViolationKind,
+    /// Position in the grid of the offending thread.
+    pub thread: Size3,
+    /// Buffer index (`[[buffer(n)]]`).
+    pub binding: usize,
+    /// Word index accessed.
+    pub index: usize,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            kernel,
+            thread,
+            binding,
+            index,
+            ..
+        } = self;
+        match self.kind {
+            ViolationKind::OutOfBounds { access, len } => write!(
+                f,
+                "kernel '{kernel}': out-of-bounds {access} of buffer({binding})[{index}] \
+                 ({len} words) by thread {thread:?}"
+            ),
+            ViolationKind::Race { other } => write!(
+                f,
+                "kernel '{kernel}': data race on buffer({binding})[{index}] between \
+                 threads {other:?} and {thread:?}"
+            ),
+            ViolationKind::UninitializedRead => write!(
+                f,
+                "kernel '{kernel}': uninitialized read of buffer({binding})[{index}] \
+                 by thread {thread:?}"
+            ),
+        }
+    }
+}
+
+/// Validation settings and the log of violations found.
+///
+/// Cloning is cheap; clones share the log, so keep one to inspect
+/// violations after attaching another to a pipeline.
+#[derive(Debug, Clone)]
+pub struct Validation {
+    checks: ValidationChecks,
+    log: Arc<Mutex<Vec<Violation>>>,
+}
+
+impl Validation {
+    /// Validate with the given checks.
+    #[must_use]
+    pub fn new(checks: ValidationChecks) -> Self {
+        Self {
+            checks,
+            log: Arc::default(),
+        }
+    }
+
+    /// Validate with every check.
+    #[must_use]
+    pub fn all() -> Self {
+        Self::new(ValidationChecks::all())
+    }
+
+    /// Checks performed.
+    #[must_use]
+    pub const fn checks(&self) -> ValidationChecks {
+        self.checks
+    }
+
+    /// Violations found so far, oldest first.
+    #[must_use]
+    pub fn violations(&self) -> Vec<Violation> {
+        self.lock().clone()
+    }
+
+    /// True if no violation has been found.
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Forget recorded violations.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Violation>> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Log the violations of a dispatch and turn them into its error.
+    pub(crate) fn report(&self, report: Report) -> crate::error::Result<()> {
+        let Some(first) = report.violations.first() else {
+            return Ok(());
+        };
+        let error = Error::metal(format!(
+            "validation failed with {} violation(s); first: {first}",
+            report.total
+        ));
+        self.lock().extend(report.violations);
+        Err(error)
+    }
+}
+
+/// Violations of one dispatch.
+pub(crate) struct Report {
+    violations: Vec<Violation>,
+    total: usize,
+}
+
+/// Last write to a word during a dispatch.
+#[derive(Clone, Copy)]
+struct Writer {
+    thread: Size3,
+    atomic: bool,
+}
+
+/// Shadow state of one bound buffer.
+struct Shadow {
+    initialized: Vec<bool>,
+    writers: Vec<Option<Writer>>,
+}
+
+/// Per-dispatch access checker used by the CPU backend.
+pub(crate) struct Validator {
+    kernel: String,
+    checks: ValidationChecks,
+    shadows: RefCell<Vec<Shadow>>,
+    reported: RefCell<HashSet<(u8, usize, usize)>>,
+    violations: RefCell<Vec<Violation>>,
+}
+
+impl Validator {
+    /// Track the bound `buffers` for a dispatch of `kernel`.
+    pub(crate) fn new(kernel: &str, checks: ValidationChecks, buffers: &[StorageView]) -> Self {
+        let shadows = buffers
+            .iter()
+            .map(|view| {
+                let initialized = view.initialized();
+                let words = view.len() / 4;
+                Shadow {
+                    initialized: (0..words)
+                        .map(|i| initialized.contains(&(i * 4..i * 4 + 4)))
+                        .collect(),
+                    writers: vec![None; words],
+                }
+            })
+            .collect();
+        Self {
+            kernel: kernel.to_string(),
+            checks,
+            shadows: RefCell::new(shadows),
+            reported: RefCell::new(HashSet::new()),
+            violations: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn record(&self, kind: ViolationKind, thread: Size3, binding: usize, index: usize) {
+        if !self
+            .reported
+            .borrow_mut()
+            .insert((kind.tag(), binding, index))
+        {
+            return;
+        }
+        self.violations.borrow_mut().push(Violation {
+            kernel: self.kernel.clone(),
+            kind,
+            thread,
+            binding,
+            index,
+        });
+    }
+
+    /// Check bounds; true if the word exists.
+    fn in_bounds(&self, thread: Size3, binding: usize, index: usize, access: Access) -> bool {
+        let len = self
+            .shadows
+            .borrow()
+            .get(binding)
+            .map_or(0, |shadow| shadow.initialized.len());
+        if index < len {
+            return true;
+        }
+        if self.checks.contains(ValidationChecks::BOUNDS) {
+            self.record(
+                ViolationKind::OutOfBounds { access, len },
+                thread,
+                binding,
+                index,
+            );
+        }
+        false
+    }
+
+    /// Check a plain read by `thread`.
+    pub(crate) fn read(&self, thread: Size3, binding: usize, index: usize) {
+        if !self.in_bounds(thread, binding, index, Access::Read) {
+            return;
+        }
+        let initialized = self.shadows.borrow()[binding].initialized[index];
+        if !initialized && self.checks.contains(ValidationChecks::UNINITIALIZED) {
+            self.record(ViolationKind::UninitializedRead, thread, binding, index);
+        }
+    }
+
+    /// Check a write by `thread`; atomic writes also read the word.
+    pub(crate) fn write(&self, thread: Size3, binding: usize, index: usize, atomic: bool) {
+        let access = if atomic {
+            Access::Atomic
+        } else {
+            Access::Write
+        };
+        if !self.in_bounds(thread, binding, index, access) {
+            return;
+        }
+        if atomic {
+            self.read(thread, binding, index);
+        }
+        let previous = {
+            let mut shadows = self.shadows.borrow_mut();
+            let shadow = &mut shadows[binding];
+            shadow.initialized[index] = true;
+            shadow.writers[index].replace(Writer { thread, atomic })
+        };
+        if let Some(previous) = previous {
+            let conflict = previous.thread != thread && !(previous.atomic && atomic);
+            if conflict && self.checks.contains(ValidationChecks::RACES) {
+                self.record(
+                    ViolationKind::Race {
+                        other: previous.thread,
+                    },
+                    thread,
+                    binding,
+                    index,
+                );
+            }
+        }
+    }
+
+    /// Finish the dispatch.
+    pub(crate) fn finish(self) -> Report {
+        let mut violations = self.violations.into_inner();
+        let total = violations.len();
+        violations.truncate(MAX_VIOLATIONS_PER_DISPATCH);
+        Report { violations, total }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::metal::storage::Storage;
+
+    /// Validator over one 4-word buffer whose first `initialized` bytes
+    /// were written.
+    fn validator(initialized: usize) -> Validator {
+        let storage = Storage::new();
+        storage.write(0, &vec![0; initialized]);
+        Validator::new(
+            "k",
+            ValidationChecks::all(),
+            &[StorageView::new(storage, 0, 16)],
+        )
+    }
+
+    fn kinds(validator: Validator) -> Vec<ViolationKind> {
+        validator
+            .finish()
+            .violations
+            .into_iter()
+            .map(|v| v.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_bounds_and_unbound_bindings() {
+        let v = validator(16);
+        v.read((0, 0, 0), 0, 3);
+        v.read((1, 0, 0), 0, 4);
+        v.write((2, 0, 0), 1, 0, false);
+        let report = v.finish();
+        assert_eq!(report.total, 2);
+        assert_eq!(report.violations[0].thread, (1, 0, 0));
+        assert_eq!(
+            report.violations[1].kind,
+            ViolationKind::OutOfBounds {
+                access: Access::Write,
+                len: 0
+            }
+        );
+        assert!(report.violations[1].to_string().contains("buffer(1)[0]"));
+    }
+
+    #[test]
+    fn test_races_exempt_atomics_and_same_thread() {
+        let v = validator(16);
+        v.write((0, 0, 0), 0, 0, false);
+        v.write((0, 0, 0), 0, 0, false);
+        v.write((0, 0, 0), 0, 1, true);
+        v.write((1, 0, 0), 0, 1, true);
+        assert!(kinds(v).is_empty());
+
+        let v = validator(16);
+        v.write((0, 0, 0), 0, 2, false);
+        v.write((1, 0, 0), 0, 2, true);
+        v.write((2, 0, 0), 0, 2, false);
+        assert_eq!(kinds(v), vec![ViolationKind::Race { other: (0, 0, 0) }]);
+    }
+
+    #[test]
+    fn test_uninitialized_reads() {
+        let v = validator(6);
+        v.read((0, 0, 0), 0, 0);
+        v.read((0, 0, 0), 0, 1);
+        v.write((0, 0, 0), 0, 2, false);
+        v.read((1, 0, 0), 0, 2);
+        assert_eq!(kinds(v), vec![ViolationKind::UninitializedRead]);
+    }
+
+    #[test]
+    fn test_report_logs_and_fails() {
+        let validation = Validation::new(ValidationChecks::BOUNDS);
+        assert!(validation.report(validator(0).finish()).is_ok());
+
+        let view = StorageView::new(Storage::new(), 0, 4);
+        let v = Validator::new("k", validation.checks(), &[view]);
+        v.read((0, 0, 0), 0, 0);
+        v.read((0, 0, 0), 0, 9);
+        let err = validation.report(v.finish()).unwrap_err();
+        assert!(err.to_string().contains("1 violation"));
+        assert_eq!(validation.violations().len(), 1);
+        validation.clear();
+        assert!(validation.is_clean());
+    }
+}
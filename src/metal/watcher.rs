@@ -0,0 +1,512 @@
+//! Development-mode shader hot reload.
+//!
+//! A [`ShaderWatcher`] loads kernels from MSL files through a
+//! [`ShaderLoader`] and records every file on disk that went into each one,
+//! includes too. [`ShaderWatcher::poll`] asks a [`ChangeSource`] which
+//! files changed, then recompiles the affected shaders. A successful
+//! recompile replaces the pipeline behind the shader's [`ShaderHandle`], so
+//! dispatches that read [`ShaderHandle::current`] afterwards use the new
+//! version. A failed recompile keeps the previous version, logs a warning
+//! and records the error in [`ShaderHandle::last_error`].
+//!
+//! [`PollingSource`] detects changes by comparing modification times and
+//! sizes. [`ManualSource`] reports only the changes it is told about, so
+//! tests can drive reloads deterministically.
+//!
+//! A host implementation attached with [`CompiledShader::with_cpu_kernel`]
+//! is carried over to each new version.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::metal::{MetalCompute, ShaderLoader, ShaderWatcher};
+//!
+//! let compute = MetalCompute::default_device().expect("no Metal device");
+//! let mut watcher = ShaderWatcher::new(&compute, ShaderLoader::new());
+//! let handle = watcher.watch("kernels/add.metal", "vector_add")?;
+//!
+//! loop {
+//!     watcher.poll();
+//!     let shader = handle.current();
+//!     // ... dispatch `shader` ...
+//! #   break;
+//! }
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - Dispatches after a successful reload use the new pipeline
+//! - A failed reload leaves the previous pipeline in place
+//! - Editing an included header reloads every shader that includes it
+
+use super::preprocessor::{ShaderLoader, ShaderSource};
+use super::{CompiledShader, MetalCompute};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// Reports which watched files have changed.
+pub trait ChangeSource {
+    /// Start watching `path`. Called before the first [`changed`] call that
+    /// could report it.
+    ///
+    /// [`changed`]: ChangeSource::changed
+    fn watch(&mut self, path: &Path);
+
+    /// Paths that changed since the previous call.
+    fn changed(&mut self) -> Vec<PathBuf>;
+}
+
+/// Detects changes by polling file modification times and sizes.
+#[derive(Debug, Default)]
+pub struct PollingSource {
+    seen: HashMap<PathBuf, Option<(SystemTime, u64)>>,
+}
+
+impl PollingSource {
+    /// Create a source watching no files.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+}
+
+impl ChangeSource for PollingSource {
+    fn watch(&mut self, path: &Path) {
+        self.seen
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Self::stamp(path));
+    }
+
+    fn changed(&mut self) -> Vec<PathBuf> {
+        self.seen
+            .iter_mut()
+            .filter_map(|(path, seen)| {
+                let stamp = Self::stamp(path);
+                (stamp != *seen).then(|| {
+                    *seen = stamp;
+                    path.clone()
+                })
+            })
+            .collect()
+    }
+}
+
+/// A change source that reports the paths passed to
+/// [`ManualSource::touch`].
+///
+/// Clones share the same queue, so a test can keep one clone and give the
+/// other to a watcher.
+#[derive(Debug, Clone, Default)]
+pub struct ManualSource {
+    pending: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl ManualSource {
+    /// Create a source with no pending changes.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report `path` as changed on the next poll.
+    pub fn touch(&self, path: impl Into<PathBuf>) {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(path.into());
+    }
+}
+
+impl ChangeSource for ManualSource {
+    fn watch(&mut self, _path: &Path) {}
+
+    fn changed(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut *self.pending.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    shader: CompiledShader,
+    version: u64,
+    last_error: Option<Error>,
+}
+
+/// The current version of a watched shader.
+///
+/// Cloning is cheap and yields another handle to the same slot.
+#[derive(Debug, Clone)]
+pub struct ShaderHandle(Arc<Mutex<Slot>>);
+
+impl ShaderHandle {
+    fn slot(&self) -> std::sync::MutexGuard<'_, Slot> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The latest successfully compiled version.
+    #[must_use]
+    pub fn current(&self) -> CompiledShader {
+        self.slot().shader.clone()
+    }
+
+    /// Number of successful reloads since the shader was first compiled.
+    #[must_use]
+    pub fn version(&self) -> u64 {
+        self.slot().version
+    }
+
+    /// Error from the most recent reload, if it failed.
+    #[must_use]
+    pub fn last_error(&self) -> Option<Error> {
+        self.slot().last_error.clone()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    file: String,
+    function: String,
+    paths: Vec<PathBuf>,
+    handle: ShaderHandle,
+}
+
+/// Recompiles shaders when their source files change.
+///
+/// See the [module documentation](self).
+pub struct ShaderWatcher<'a, S: ChangeSource = PollingSource> {
+    compute: &'a MetalCompute,
+    loader: ShaderLoader,
+    source: S,
+    entries: Vec<Entry>,
+}
+
+impl<'a> ShaderWatcher<'a> {
+    /// Create a watcher that polls file modification times.
+    #[must_use]
+    pub fn new(compute: &'a MetalCompute, loader: ShaderLoader) -> Self {
+        Self::with_source(compute, loader, PollingSource::new())
+    }
+}
+
+impl<'a, S: ChangeSource> ShaderWatcher<'a, S> {
+    /// Create a watcher that learns about changes from `source`.
+    pub const fn with_source(compute: &'a MetalCompute, loader: ShaderLoader, source: S) -> Self {
+        Self {
+            compute,
+            loader,
+            source,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Load `file` through the loader, compile `function` and watch every
+    /// file on disk it was assembled from.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - Shader file name, resolved as by [`ShaderLoader::load`]
+    /// * `function` - Name of the kernel function to compile
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be loaded or compiled.
+    pub fn watch(&mut self, file: &str, function: &str) -> Result<ShaderHandle> {
+        let source = self.loader.load(file)?;
+        let shader = self.compile(&source, function)?;
+        let handle = ShaderHandle(Arc::new(Mutex::new(Slot {
+            shader,
+            version: 0,
+            last_error: None,
+        })));
+        for path in source.paths() {
+            self.source.watch(path);
+        }
+        self.entries.push(Entry {
+            file: file.to_string(),
+            function: function.to_string(),
+            paths: source.paths().to_vec(),
+            handle: handle.clone(),
+        });
+        Ok(handle)
+    }
+
+    /// Every watched file, deduplicated and sorted.
+    #[must_use]
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = self
+            .entries
+            .iter()
+            .flat_map(|entry| entry.paths.iter().cloned())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Recompile the shaders whose files changed since the last poll.
+    ///
+    /// Shaders whose assembled source is unchanged are not recompiled.
+    /// Returns the number of shaders that were replaced.
+    pub fn poll(&mut self) -> usize {
+        let changed: Vec<_> = self
+            .source
+            .changed()
+            .into_iter()
+            .map(|path| path.canonicalize().unwrap_or(path))
+            .collect();
+        if changed.is_empty() {
+            return 0;
+        }
+
+        let mut swapped = 0;
+        for index in 0..self.entries.len() {
+            if self.entries[index]
+                .paths
+                .iter()
+                .any(|p| changed.contains(p))
+                && self.reload(index)
+            {
+                swapped += 1;
+            }
+        }
+        swapped
+    }
+
+    /// Reload one entry. Returns `true` if its pipeline was replaced.
+    fn reload(&mut self, index: usize) -> bool {
+        let entry = &self.entries[index];
+        let loaded = self.loader.load(&entry.file);
+        let previous = entry.handle.current();
+        let result = loaded.as_ref().map_err(Clone::clone).and_then(|source| {
+            if source.text().as_bytes() == &*previous.source {
+                return Ok(None);
+            }
+            self.compile(source, &entry.function).map(Some)
+        });
+
+        let replaced = matches!(result, Ok(Some(_)));
+        let mut slot = entry.handle.slot();
+        match result {
+            Ok(Some(mut shader)) => {
+                if shader.cpu_kernel.is_none() {
+                    shader.cpu_kernel = previous.cpu_kernel;
+                }
+                slot.shader = shader;
+                slot.version += 1;
+                slot.last_error = None;
+                info!(
+                    file = %entry.file,
+                    function = %entry.function,
+                    version = slot.version,
+                    "reloaded shader"
+                );
+            }
+            Ok(None) => slot.last_error = None,
+            Err(e) => {
+                warn!(
+                    file = %entry.file,
+                    function = %entry.function,
+                    error = %e,
+                    "shader reload failed; keeping previous version"
+                );
+                slot.last_error = Some(e);
+            }
+        }
+        drop(slot);
+
+        // Includes may have been added or removed.
+        if let Ok(source) = loaded {
+            for path in source.paths() {
+                self.source.watch(path);
+            }
+            self.entries[index].paths = source.paths().to_vec();
+        }
+        replaced
+    }
+
+    fn compile(&self, source: &ShaderSource, function: &str) -> Result<CompiledShader> {
+        self.compute
+            .compile_shader(source.text(), function)
+            .map_err(|e| match e {
+                Error::Metal { .. } => Error::metal(source.remap_diagnostic(&e.to_string())),
+                e => e,
+            })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::metal::CpuKernel;
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("manzana-watch-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&root).unwrap();
+            Self(root)
+        }
+
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn loader(dir: &Dir) -> ShaderLoader {
+        ShaderLoader::new().with_search_path(&dir.0)
+    }
+
+    #[test]
+    fn test_reload_swaps_pipeline() {
+        let dir = Dir::new("swap");
+        let header = dir.write("common.h", "constant uint SCALE = 2;");
+        let main = dir.write(
+            "scale.metal",
+            "#include \"common.h\"\nkernel void scale() {}",
+        );
+        dir.write("other.metal", "kernel void other() {}");
+
+        let compute = MetalCompute::cpu();
+        let changes = ManualSource::new();
+        let mut watcher = ShaderWatcher::with_source(&compute, loader(&dir), changes.clone());
+        let scale = watcher.watch("scale.metal", "scale").unwrap();
+        let other = watcher.watch("other.metal", "other").unwrap();
+        assert_eq!(watcher.watched_files().len(), 3);
+        assert_eq!(watcher.poll(), 0);
+
+        // Touching without editing does not recompile.
+        changes.touch(&main);
+        assert_eq!(watcher.poll(), 0);
+        assert_eq!(scale.version(), 0);
+
+        let first = scale.current();
+        dir.write("common.h", "constant uint SCALE = 3;");
+        changes.touch(&header);
+        assert_eq!(watcher.poll(), 1);
+        assert_eq!(scale.version(), 1);
+        assert_eq!(other.version(), 0);
+        assert_ne!(scale.current().source, first.source);
+        assert_eq!(scale.current().name(), "scale");
+
+        // An edit to text that hashes the same ("Aa" and "BB" under a
+        // polynomial-31 hash) is still a change.
+        let edited = dir.write("other.metal", "kernel void other() {} // Aa");
+        changes.touch(&edited);
+        assert_eq!(watcher.poll(), 1);
+        dir.write("other.metal", "kernel void other() {} // BB");
+        changes.touch(&edited);
+        assert_eq!(watcher.poll(), 1);
+        assert_eq!(other.version(), 2);
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_previous_version() {
+        let dir = Dir::new("fail");
+        let main = dir.write("double.metal", "kernel void double_it() {}");
+
+        let compute = MetalCompute::cpu();
+        let changes = ManualSource::new();
+        let mut watcher = ShaderWatcher::with_source(&compute, loader(&dir), changes.clone());
+        let handle = watcher.watch("double.metal", "double_it").unwrap();
+        let kernel = CpuKernel::new(|ctx| {
+            let i = ctx.thread_position().0 as usize;
+            ctx.write_f32(0, i, ctx.read_f32(0, i) * 2.0);
+        });
+        {
+            let mut slot = handle.slot();
+            slot.shader = slot.shader.clone().with_cpu_kernel(kernel);
+        }
+        let original = handle.current();
+
+        // A function constant without a value fails to compile.
+        dir.write(
+            "double.metal",
+            "constant bool FAST [[function_constant(0)]];\nkernel void double_it() { if (FAST) {} }",
+        );
+        changes.touch(&main);
+        assert_eq!(watcher.poll(), 0);
+        assert!(handle.last_error().is_some());
+        assert_eq!(handle.current().source, original.source);
+
+        let buffer = compute.allocate_buffer(8).unwrap();
+        buffer.write(0, &[1.0f32, 2.0]).unwrap();
+        compute
+            .dispatch(&handle.current(), &[&buffer], (2, 1, 1), (2, 1, 1))
+            .unwrap();
+        assert_eq!(buffer.read::<f32>().unwrap(), vec![2.0, 4.0]);
+
+        // Fixing the file reloads it and keeps the host kernel.
+        dir.write("double.metal", "kernel void double_it() { }");
+        changes.touch(&main);
+        assert_eq!(watcher.poll(), 1);
+        assert!(handle.last_error().is_none());
+        assert_eq!(handle.version(), 1);
+        compute
+            .dispatch(&handle.current(), &[&buffer], (2, 1, 1), (2, 1, 1))
+            .unwrap();
+        assert_eq!(buffer.read::<f32>().unwrap(), vec![4.0, 8.0]);
+    }
+
+    #[test]
+    fn test_include_set_follows_edits() {
+        let dir = Dir::new("includes");
+        dir.write("a.h", "int a;");
+        let b = dir.write("b.h", "int b;");
+        let main = dir.write("main.metal", "#include \"a.h\"\nkernel void k() {}");
+
+        let compute = MetalCompute::cpu();
+        let changes = ManualSource::new();
+        let mut watcher = ShaderWatcher::with_source(&compute, loader(&dir), changes.clone());
+        let handle = watcher.watch("main.metal", "k").unwrap();
+        assert_eq!(watcher.watched_files().len(), 2);
+
+        dir.write("main.metal", "#include \"b.h\"\nkernel void k() {}");
+        changes.touch(&main);
+        assert_eq!(watcher.poll(), 1);
+        assert!(watcher.watched_files().contains(&b.canonicalize().unwrap()));
+
+        // A missing include is a failed reload.
+        dir.write("main.metal", "#include \"gone.h\"\nkernel void k() {}");
+        changes.touch(&main);
+        assert_eq!(watcher.poll(), 0);
+        assert!(handle.last_error().unwrap().to_string().contains("gone.h"));
+        assert_eq!(handle.version(), 1);
+    }
+
+    #[test]
+    fn test_polling_source() {
+        let dir = Dir::new("poll");
+        let path = dir.write("k.metal", "kernel void k() {}");
+        let mut source = PollingSource::new();
+        source.watch(&path);
+        assert!(source.changed().is_empty());
+
+        dir.write("k.metal", "kernel void k() { /* longer */ }");
+        assert_eq!(source.changed(), vec![path.clone()]);
+        assert!(source.changed().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(source.changed(), vec![path]);
+    }
+}
@@ -0,0 +1,950 @@
+//! Inspection of CoreML models in every packaging.
+//!
+//! CoreML models ship in three forms, and [`ModelInfo::inspect`] reads
+//! each into one summary:
+//!
+//! - **`.mlmodel`**: a single protobuf file, decoded by
+//!   [`ModelSpec`]. Weights are embedded in the file.
+//! - **`.mlpackage`**: a directory whose `Manifest.json` lists items under
+//!   `Data/`. The root item is the `.mlmodel` specification; other items,
+//!   such as `weights/`, hold the weight files of ML programs.
+//! - **`.mlmodelc`**: a model compiled by Xcode or `coremlcompiler`. Its
+//!   `metadata.json` describes the interface, metadata and compute
+//!   precision; ML programs also carry their `model.mil` source and a
+//!   `weights/` directory, and neural networks `model.espresso.*` files.
+//!   `coremldata.bin` marks the directory as a compiled model; its layout
+//!   is undocumented, so it is decoded only when it holds a plain
+//!   `Model` message. Without `metadata.json`, the interface and precision
+//!   come from `model.mil`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::neural_engine::bundle::ModelInfo;
+//!
+//! let info = ModelInfo::inspect("Classifier.mlmodelc")?;
+//! println!("{:?} by {}", info.model_type, info.description.metadata.author);
+//! for input in &info.description.inputs {
+//!     println!("input {}: {:?}", input.name, input.feature_type);
+//! }
+//! println!(
+//!     "precision {:?}, {} bytes of weights",
+//!     info.compute_precision,
+//!     info.weights_size()
+//! );
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - All three packagings of one model report the same inputs and outputs
+//! - Weight sizes match the files on disk
+//! - Manifest paths cannot point outside the bundle
+
+use super::json::Json;
+use super::mlmodel::{
+    ArrayDataType, ArrayFeature, ArrayShapes, ColorSpace, ElementType, FeatureDescription,
+    FeatureType, ImageFeature, ImageSizes, Metadata, ModelDescription, ModelSpec, ModelType,
+    SizeRange,
+};
+use super::protobuf::Parse;
+use crate::error::{Error, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+/// How a model is packaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelFormat {
+    /// Single `.mlmodel` specification file.
+    MlModel,
+    /// `.mlpackage` directory with a manifest.
+    MlPackage,
+    /// Compiled `.mlmodelc` directory.
+    MlModelC,
+}
+
+impl ModelFormat {
+    /// The format named by the extension of `path`.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "mlmodel" => Some(Self::MlModel),
+            "mlpackage" => Some(Self::MlPackage),
+            "mlmodelc" => Some(Self::MlModelC),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ModelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MlModel => write!(f, ".mlmodel"),
+            Self::MlPackage => write!(f, ".mlpackage"),
+            Self::MlModelC => write!(f, ".mlmodelc"),
+        }
+    }
+}
+
+/// A weight file inside a bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightFile {
+    /// Path relative to the bundle.
+    pub path: PathBuf,
+    /// Size in bytes.
+    pub size: u64,
+}
+
+/// Summary of a model, whatever its packaging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelInfo {
+    /// Packaging.
+    pub format: ModelFormat,
+    /// Path the model was inspected at.
+    pub path: PathBuf,
+    /// Specification version, if recorded.
+    pub specification_version: Option<u32>,
+    /// Kind of model, if recorded.
+    pub model_type: Option<ModelType>,
+    /// Inputs, outputs and author, license and version metadata.
+    pub description: ModelDescription,
+    /// Precision of compiled models, such as `Float16` or
+    /// `Mixed (Float16, Float32)`; `None` for uncompiled models, whose
+    /// precision is chosen when they are compiled.
+    pub compute_precision: Option<String>,
+    /// Separate weight files, sorted by path; empty when weights are
+    /// embedded in the specification.
+    pub weights: Vec<WeightFile>,
+}
+
+impl ModelInfo {
+    /// Inspect the model at `path`, choosing the reader by extension.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the path doesn't exist or has an unknown
+    /// extension, a required file of the bundle is missing, or a
+    /// specification, manifest, `metadata.json` or `model.mil` is
+    /// malformed.
+    pub fn inspect(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Error::not_found(format!("model: {}", path.display())));
+        }
+        let format = ModelFormat::from_path(path).ok_or_else(|| {
+            Error::invalid_input(format!(
+                "unsupported model format: {} (expected .mlmodel, .mlpackage or .mlmodelc)",
+                path.display()
+            ))
+        })?;
+        let info = match format {
+            ModelFormat::MlModel => Self::from_spec(format, path, ModelSpec::open(path)?),
+            ModelFormat::MlPackage => inspect_package(path)?,
+            ModelFormat::MlModelC => inspect_compiled(path)?,
+        };
+        debug!(
+            path = %path.display(),
+            %format,
+            inputs = info.description.inputs.len(),
+            weights = info.weights.len(),
+            "inspected CoreML model"
+        );
+        Ok(info)
+    }
+
+    fn from_spec(format: ModelFormat, path: &Path, spec: ModelSpec) -> Self {
+        Self {
+            format,
+            path: path.to_path_buf(),
+            specification_version: Some(spec.specification_version),
+            model_type: Some(spec.model_type),
+            description: spec.description,
+            compute_precision: None,
+            weights: Vec::new(),
+        }
+    }
+
+    /// Total size of the weight files in bytes.
+    #[must_use]
+    pub fn weights_size(&self) -> u64 {
+        self.weights.iter().map(|weight| weight.size).sum()
+    }
+}
+
+/// An error about `file` inside the bundle at `root`.
+fn malformed(root: &Path, file: &str, what: impl fmt::Display) -> Error {
+    Error::coreml(format!("{}: {file}: {what}", root.display()))
+}
+
+fn read_json(root: &Path, file: &str) -> Result<Json> {
+    let text = std::fs::read_to_string(root.join(file))
+        .map_err(|e| malformed(root, file, format!("failed to read: {e}")))?;
+    Json::parse(&text).map_err(|what| malformed(root, file, what))
+}
+
+/// Files under `dir`, recursively and sorted, without following links.
+fn files(dir: &Path, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(std::fs::DirEntry::file_name);
+    for entry in entries {
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            files(&entry.path(), out)?;
+        } else if kind.is_file() {
+            out.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Weight files at `paths`, relative to `root`.
+fn weight_files(root: &Path, paths: Vec<PathBuf>) -> std::io::Result<Vec<WeightFile>> {
+    paths
+        .into_iter()
+        .map(|path| {
+            Ok(WeightFile {
+                size: std::fs::metadata(&path)?.len(),
+                path: path
+                    .strip_prefix(root)
+                    .map_or_else(|_| path.clone(), Path::to_path_buf),
+            })
+        })
+        .collect()
+}
+
+fn inspect_package(root: &Path) -> Result<ModelInfo> {
+    const MANIFEST: &str = "Manifest.json";
+    let manifest = read_json(root, MANIFEST)?;
+    let bad = |what: &str| malformed(root, MANIFEST, what);
+    let root_id = manifest
+        .get("rootModelIdentifier")
+        .and_then(Json::as_str)
+        .ok_or_else(|| bad("missing rootModelIdentifier"))?;
+    let entries = manifest
+        .get("itemInfoEntries")
+        .map(Json::members)
+        .ok_or_else(|| bad("missing itemInfoEntries"))?;
+    // Items are checked after resolving links, so a symlink under `Data/`
+    // cannot lead outside the package either.
+    let package = root
+        .canonicalize()
+        .map_err(|e| bad(&format!("failed to resolve package: {e}")))?;
+
+    let mut spec_path = None;
+    let mut weights = Vec::new();
+    for (id, entry) in entries {
+        let relative = entry
+            .get("path")
+            .and_then(Json::as_str)
+            .ok_or_else(|| bad(&format!("item {id} has no path")))?;
+        let relative = Path::new(relative);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(bad(&format!(
+                "item {id} path {} leaves the package",
+                relative.display()
+            )));
+        }
+        let item = root.join("Data").join(relative);
+        let resolved = item
+            .canonicalize()
+            .map_err(|_| bad(&format!("item {id} is missing: {}", item.display())))?;
+        if !resolved.starts_with(&package) {
+            return Err(bad(&format!(
+                "item {id} path {} leaves the package",
+                relative.display()
+            )));
+        }
+        if id == root_id {
+            spec_path = Some(item);
+        } else if item.is_dir() {
+            files(&item, &mut weights)
+                .map_err(|e| bad(&format!("failed to list item {id}: {e}")))?;
+        } else {
+            weights.push(item);
+        }
+    }
+    let spec_path = spec_path.ok_or_else(|| bad(&format!("root model {root_id} is not listed")))?;
+    let mut info = ModelInfo::from_spec(ModelFormat::MlPackage, root, ModelSpec::open(spec_path)?);
+    weights.sort();
+    info.weights = weight_files(root, weights)
+        .map_err(|e| malformed(root, "Data", format!("failed to read weights: {e}")))?;
+    Ok(info)
+}
+
+fn inspect_compiled(root: &Path) -> Result<ModelInfo> {
+    let data_path = root.join("coremldata.bin");
+    let data = std::fs::read(&data_path)
+        .map_err(|e| malformed(root, "coremldata.bin", format!("not a compiled model: {e}")))?;
+    let spec = ModelSpec::parse(&data).ok();
+
+    let mut info = ModelInfo {
+        format: ModelFormat::MlModelC,
+        path: root.to_path_buf(),
+        specification_version: spec.as_ref().map(|spec| spec.specification_version),
+        model_type: spec.as_ref().map(|spec| spec.model_type),
+        description: spec.map(|spec| spec.description).unwrap_or_default(),
+        compute_precision: None,
+        weights: Vec::new(),
+    };
+
+    let mil_path = root.join("model.mil");
+    let mil = if mil_path.is_file() {
+        let text = std::fs::read_to_string(&mil_path)
+            .map_err(|e| malformed(root, "model.mil", format!("failed to read: {e}")))?;
+        Some(scan_mil(&text).map_err(|what| malformed(root, "model.mil", what))?)
+    } else {
+        None
+    };
+
+    if root.join("metadata.json").is_file() {
+        let metadata = read_json(root, "metadata.json")?;
+        // Compiled models write a one-element array.
+        let metadata = metadata.elements().first().unwrap_or(&metadata);
+        apply_metadata(&mut info, metadata)
+            .map_err(|what| malformed(root, "metadata.json", what))?;
+    }
+    if let Some(mil) = mil {
+        info.model_type.get_or_insert(ModelType::MlProgram);
+        if info.description.inputs.is_empty() && info.description.outputs.is_empty() {
+            info.description.inputs = mil.inputs;
+            info.description.outputs = mil.outputs;
+        }
+        if info.compute_precision.is_none() {
+            info.compute_precision = precision(&mil.dtypes);
+        }
+    }
+
+    let mut all = Vec::new();
+    files(root, &mut all)
+        .map_err(|e| malformed(root, ".", format!("failed to list files: {e}")))?;
+    let weights = all
+        .into_iter()
+        .filter(|path| {
+            let relative = path.strip_prefix(root).unwrap_or(path);
+            relative.extension().is_some_and(|ext| ext == "weights")
+                || relative
+                    .parent()
+                    .is_some_and(|dir| dir.components().any(|c| c.as_os_str() == "weights"))
+        })
+        .collect();
+    info.weights = weight_files(root, weights)
+        .map_err(|e| malformed(root, "weights", format!("failed to read: {e}")))?;
+    Ok(info)
+}
+
+/// Fill `info` from the object in a compiled model's `metadata.json`.
+fn apply_metadata(info: &mut ModelInfo, metadata: &Json) -> Parse<()> {
+    let text = |key: &str| metadata.get(key).and_then(Json::text);
+    if let Some(version) = text("specificationVersion") {
+        info.specification_version = Some(
+            version
+                .parse()
+                .map_err(|_| format!("invalid specificationVersion '{version}'"))?,
+        );
+    }
+    if let Some(name) = metadata
+        .get("modelType")
+        .and_then(|kind| kind.get("name"))
+        .and_then(Json::as_str)
+    {
+        info.model_type = Some(model_type(name));
+    }
+    if let Some(precision) = text("computePrecision") {
+        info.compute_precision = Some(precision);
+    }
+
+    let description = &mut info.description;
+    for (key, list) in [
+        ("inputSchema", &mut description.inputs),
+        ("outputSchema", &mut description.outputs),
+    ] {
+        if let Some(schema) = metadata.get(key) {
+            *list = schema
+                .elements()
+                .iter()
+                .enumerate()
+                .map(|(index, feature)| {
+                    schema_feature(feature).map_err(|what| format!("{key}[{index}]: {what}"))
+                })
+                .collect::<Parse<_>>()?;
+        }
+    }
+
+    let fields = Metadata {
+        short_description: text("shortDescription").unwrap_or_default(),
+        version: text("version").unwrap_or_default(),
+        author: text("author").unwrap_or_default(),
+        license: text("license").unwrap_or_default(),
+        user_defined: metadata
+            .get("userDefinedMetadata")
+            .map(Json::members)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.text()?)))
+            .collect(),
+    };
+    if fields != Metadata::default() {
+        description.metadata = fields;
+    }
+    Ok(())
+}
+
+/// The model type named in `metadata.json`, such as
+/// `MLModelType_mlProgram`.
+fn model_type(name: &str) -> ModelType {
+    ModelType::from_name(name.strip_prefix("MLModelType_").unwrap_or(name))
+        .unwrap_or(ModelType::Other(0))
+}
+
+/// Dimensions of a shape written as `[1, 3, 224, 224]`.
+fn parse_dims(shape: &str) -> Parse<Vec<usize>> {
+    let inner = shape
+        .trim()
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .ok_or_else(|| format!("invalid shape '{shape}'"))?;
+    inner
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| {
+            dim.parse()
+                .map_err(|_| format!("invalid dimension '{dim}' in '{shape}'"))
+        })
+        .collect()
+}
+
+const fn unbounded() -> SizeRange {
+    SizeRange {
+        lower: 0,
+        upper: None,
+    }
+}
+
+fn array_data_type(name: &str) -> ArrayDataType {
+    match name {
+        "Float16" => ArrayDataType::Float16,
+        "Float32" => ArrayDataType::Float32,
+        "Double" | "Float64" => ArrayDataType::Double,
+        "Int32" => ArrayDataType::Int32,
+        "Int8" => ArrayDataType::Int8,
+        _ => ArrayDataType::Other(0),
+    }
+}
+
+/// One entry of `inputSchema` or `outputSchema`.
+fn schema_feature(feature: &Json) -> Parse<FeatureDescription> {
+    let text = |key: &str| feature.get(key).and_then(Json::text);
+    let flag = |key: &str| text(key).is_some_and(|value| value == "1" || value == "true");
+    let name = text("name").ok_or("feature has no name")?;
+    let kind = text("type").ok_or_else(|| format!("feature '{name}' has no type"))?;
+    let data_type = text("dataType").unwrap_or_default();
+    let flexible = flag("hasShapeFlexibility");
+    let array = || -> Parse<ArrayFeature> {
+        let shape = text("shape").map(|shape| parse_dims(&shape)).transpose()?;
+        let shape = shape.unwrap_or_default();
+        Ok(ArrayFeature {
+            shapes: if flexible {
+                // The accepted range is not recorded; allow any size.
+                ArrayShapes::Ranges(vec![unbounded(); shape.len()])
+            } else {
+                ArrayShapes::Fixed
+            },
+            shape,
+            data_type: array_data_type(&data_type),
+        })
+    };
+    let element = if data_type.contains("Int64") {
+        ElementType::Int64
+    } else {
+        ElementType::String
+    };
+    let feature_type = match kind.as_str() {
+        "MultiArray" => FeatureType::MultiArray(array()?),
+        "State" => FeatureType::State(array()?),
+        "Int64" => FeatureType::Int64,
+        "Double" => FeatureType::Double,
+        "String" => FeatureType::String,
+        "Dictionary" => FeatureType::Dictionary { key: element },
+        "Sequence" => FeatureType::Sequence {
+            element,
+            length: unbounded(),
+        },
+        "Image" => {
+            let size = |key: &str| text(key).and_then(|value| value.parse().ok());
+            let (width, height) = (size("width"), size("height"));
+            FeatureType::Image(ImageFeature {
+                width: width.unwrap_or(0),
+                height: height.unwrap_or(0),
+                color_space: match text("colorspace").as_deref() {
+                    Some("Grayscale") => ColorSpace::Grayscale,
+                    Some("RGB") => ColorSpace::Rgb,
+                    Some("BGR") => ColorSpace::Bgr,
+                    _ => ColorSpace::Other(0),
+                },
+                sizes: if flexible || width.is_none() || height.is_none() {
+                    ImageSizes::Range {
+                        width: unbounded(),
+                        height: unbounded(),
+                    }
+                } else {
+                    ImageSizes::Fixed
+                },
+            })
+        }
+        other => return Err(format!("feature '{name}' has unknown type '{other}'")),
+    };
+    Ok(FeatureDescription {
+        short_description: text("shortDescription").unwrap_or_default(),
+        optional: flag("isOptional"),
+        name,
+        feature_type,
+    })
+}
+
+/// What inspection reads from a `model.mil` program.
+#[derive(Debug)]
+struct MilSummary {
+    inputs: Vec<FeatureDescription>,
+    outputs: Vec<FeatureDescription>,
+    /// Element types of every tensor in the program.
+    dtypes: BTreeSet<String>,
+}
+
+/// Element types that count towards compute precision, in report order.
+const PRECISIONS: [(&str, &str); 9] = [
+    ("fp16", "Float16"),
+    ("bf16", "BFloat16"),
+    ("fp32", "Float32"),
+    ("fp64", "Float64"),
+    ("int8", "Int8"),
+    ("uint8", "UInt8"),
+    ("int16", "Int16"),
+    ("int32", "Int32"),
+    ("int64", "Int64"),
+];
+
+/// Precision in `metadata.json` style, from MIL element types.
+fn precision(dtypes: &BTreeSet<String>) -> Option<String> {
+    let used: Vec<_> = PRECISIONS
+        .iter()
+        .filter(|(dtype, _)| dtypes.contains(*dtype))
+        .map(|(_, name)| *name)
+        .collect();
+    match used.as_slice() {
+        [] => None,
+        [only] => Some((*only).to_string()),
+        many => Some(format!("Mixed ({})", many.join(", "))),
+    }
+}
+
+/// Split `text` at commas outside brackets.
+fn split_top_level(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0i32, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '<' | '[' | '(' => depth += 1,
+            '>' | ']' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(text[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+/// Index of the bracket closing the one opened just before `text`.
+fn closing(text: &str, open: char, close: char) -> Option<usize> {
+    let mut depth = 1;
+    for (i, c) in text.char_indices() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+/// A MIL value type, `tensor<fp32, [1, 3]>` or `state<tensor<...>>`, as
+/// a feature type.
+fn mil_type(text: &str) -> Parse<FeatureType> {
+    let text = text.trim();
+    if let Some(inner) = text
+        .strip_prefix("state<")
+        .and_then(|t| t.strip_suffix('>'))
+    {
+        return match mil_type(inner)? {
+            FeatureType::MultiArray(array) => Ok(FeatureType::State(array)),
+            _ => Err(format!("invalid state type '{text}'")),
+        };
+    }
+    let inner = text
+        .strip_prefix("tensor<")
+        .and_then(|t| t.strip_suffix('>'))
+        .ok_or_else(|| format!("unsupported type '{text}'"))?;
+    let (dtype, shape) = inner
+        .split_once(',')
+        .ok_or_else(|| format!("invalid tensor type '{text}'"))?;
+    let dims: Vec<_> = split_top_level(
+        shape
+            .trim()
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .ok_or_else(|| format!("invalid tensor shape in '{text}'"))?,
+    )
+    .into_iter()
+    .map(|dim| dim.parse::<usize>().ok())
+    .collect();
+    // Symbolic dimensions make the shape flexible.
+    let flexible = dims.iter().any(Option::is_none);
+    let data_type = match dtype.trim() {
+        "fp16" => ArrayDataType::Float16,
+        "fp32" => ArrayDataType::Float32,
+        "fp64" => ArrayDataType::Double,
+        "int32" => ArrayDataType::Int32,
+        "int8" => ArrayDataType::Int8,
+        _ => ArrayDataType::Other(0),
+    };
+    Ok(FeatureType::MultiArray(if flexible {
+        ArrayFeature {
+            shapes: ArrayShapes::Ranges(
+                dims.iter()
+                    .map(|dim| {
+                        dim.map_or(unbounded(), |d| SizeRange {
+                            lower: d,
+                            upper: Some(d),
+                        })
+                    })
+                    .collect(),
+            ),
+            shape: Vec::new(),
+            data_type,
+        }
+    } else {
+        ArrayFeature {
+            shape: dims.into_iter().flatten().collect(),
+            data_type,
+            shapes: ArrayShapes::Fixed,
+        }
+    }))
+}
+
+/// A feature named `name` of MIL type `ty`.
+fn mil_feature(name: &str, ty: &str) -> Parse<FeatureDescription> {
+    Ok(FeatureDescription {
+        name: name.to_string(),
+        short_description: String::new(),
+        feature_type: mil_type(ty).map_err(|what| format!("'{name}': {what}"))?,
+        optional: false,
+    })
+}
+
+/// Read the signature of the `main` function and the element types used
+/// by a MIL program.
+fn scan_mil(text: &str) -> Parse<MilSummary> {
+    if !text.trim_start().starts_with("program(") {
+        return Err("does not start with a program".to_string());
+    }
+    let func = text.find("func main").ok_or("has no main function")?;
+    let params_start = text[func..]
+        .find('(')
+        .map(|i| func + i + 1)
+        .ok_or("main function has no parameter list")?;
+    let params_end = closing(&text[params_start..], '(', ')')
+        .map(|i| params_start + i)
+        .ok_or("main function parameter list is not closed")?;
+    let inputs = split_top_level(&text[params_start..params_end])
+        .into_iter()
+        .map(|param| {
+            let (ty, name) = param
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| format!("invalid parameter '{param}'"))?;
+            mil_feature(name, ty)
+        })
+        .collect::<Parse<Vec<_>>>()?;
+
+    let body_start = text[params_end..]
+        .find('{')
+        .map(|i| params_end + i + 1)
+        .ok_or("main function has no body")?;
+    let body_end = closing(&text[body_start..], '{', '}')
+        .map(|i| body_start + i)
+        .ok_or("main function body is not closed")?;
+    let body = &text[body_start..body_end];
+    let returns = text[body_end + 1..]
+        .trim_start()
+        .strip_prefix("->")
+        .and_then(|rest| rest.trim_start().strip_prefix('('))
+        .and_then(|rest| rest.find(')').map(|end| &rest[..end]))
+        .ok_or("main function has no return list")?;
+    let outputs = split_top_level(returns)
+        .into_iter()
+        .map(|name| {
+            let ty = body
+                .lines()
+                .map(str::trim)
+                .find_map(|line| {
+                    let (ty, rest) = split_type(line)?;
+                    rest.trim_start()
+                        .strip_prefix(name)
+                        .filter(|rest| rest.trim_start().starts_with('='))
+                        .map(|_| ty)
+                })
+                .ok_or_else(|| format!("output '{name}' is never assigned"))?;
+            mil_feature(name, ty)
+        })
+        .collect::<Parse<Vec<_>>>()?;
+
+    let dtypes = text
+        .match_indices("tensor<")
+        .filter_map(|(at, tag)| {
+            let rest = &text[at + tag.len()..];
+            let end = rest.find([',', '>'])?;
+            Some(rest[..end].trim().to_string())
+        })
+        .collect();
+    Ok(MilSummary {
+        inputs,
+        outputs,
+        dtypes,
+    })
+}
+
+/// Split a statement into its leading type and the rest.
+fn split_type(line: &str) -> Option<(&str, &str)> {
+    let open = line.find('<')?;
+    let close = open + 1 + closing(&line[open + 1..], '<', '>')?;
+    Some((&line[..=close], &line[close + 1..]))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::neural_engine::mlmodel::fixture::classifier;
+
+    /// An empty scratch directory for one test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("manzana-bundle-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: impl AsRef<[u8]>) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    const MANIFEST: &str = r#"{
+        "fileFormatVersion": "1.0.0",
+        "itemInfoEntries": {
+            "A1": {"author": "com.apple.CoreML", "name": "model.mlmodel",
+                   "path": "com.apple.CoreML/model.mlmodel"},
+            "B2": {"author": "com.apple.CoreML", "name": "weights",
+                   "path": "com.apple.CoreML/weights"}
+        },
+        "rootModelIdentifier": "A1"
+    }"#;
+
+    const METADATA: &str = r#"[{
+        "specificationVersion": 5,
+        "modelType": {"name": "MLModelType_neuralNetwork"},
+        "computePrecision": "Float16",
+        "author": "manzana", "license": "MIT", "version": "1.0",
+        "shortDescription": "Test model",
+        "userDefinedMetadata": {"com.github.apple.coremltools.version": "7.1"},
+        "inputSchema": [{"name": "image", "shortDescription": "a feature",
+            "type": "MultiArray", "dataType": "Float32", "shape": "[1, 3, 224, 224]",
+            "isOptional": "0", "hasShapeFlexibility": "0"}],
+        "outputSchema": [{"name": "logits", "shortDescription": "a feature",
+            "type": "MultiArray", "dataType": "Float32", "shape": "[1, 1000]",
+            "isOptional": "0"}]
+    }]"#;
+
+    const MIL: &str = "program(1.0)
+[buildInfo = dict<tensor<string, []>, tensor<string, []>>({{\"coremltools-version\", \"7.1\"}})]
+{
+    func main<ios16>(tensor<fp32, [1, 3, 224, 224]> image, tensor<int32, [1, ?]> tokens) {
+            tensor<fp16, [1, 3, 224, 224]> image_16 = cast(dtype = \"fp16\", x = image);
+            tensor<fp16, [1, 1000]> logits_16 = linear(x = image_16);
+            tensor<fp32, [1, 1000]> logits = cast(dtype = \"fp32\", x = logits_16);
+        } -> (logits);
+}
+";
+
+    #[test]
+    fn test_packagings_agree() {
+        let dir = scratch("agree");
+        let spec = ModelSpec::parse(&classifier()).unwrap();
+
+        let model = dir.join("Classifier.mlmodel");
+        write(&model, classifier());
+
+        let package = dir.join("Classifier.mlpackage");
+        write(&package.join("Manifest.json"), MANIFEST);
+        let data = package.join("Data/com.apple.CoreML");
+        write(&data.join("model.mlmodel"), classifier());
+        write(&data.join("weights/weight.bin"), [0u8; 100]);
+
+        let compiled = dir.join("Classifier.mlmodelc");
+        write(&compiled.join("coremldata.bin"), b"not a model message");
+        write(&compiled.join("metadata.json"), METADATA);
+        write(&compiled.join("model.espresso.net"), b"{}");
+        write(&compiled.join("model.espresso.weights"), [0u8; 64]);
+
+        for (path, format) in [
+            (&model, ModelFormat::MlModel),
+            (&package, ModelFormat::MlPackage),
+            (&compiled, ModelFormat::MlModelC),
+        ] {
+            let info = ModelInfo::inspect(path).unwrap();
+            assert_eq!(info.format, format);
+            assert_eq!(info.specification_version, Some(5), "{format}");
+            assert_eq!(info.model_type, Some(ModelType::NeuralNetwork), "{format}");
+            assert_eq!(info.description.inputs, spec.description.inputs, "{format}");
+            assert_eq!(
+                info.description.outputs, spec.description.outputs,
+                "{format}"
+            );
+            assert_eq!(
+                info.description.metadata, spec.description.metadata,
+                "{format}"
+            );
+        }
+
+        let info = ModelInfo::inspect(&package).unwrap();
+        assert_eq!(
+            info.weights,
+            [WeightFile {
+                path: PathBuf::from("Data/com.apple.CoreML/weights/weight.bin"),
+                size: 100,
+            }]
+        );
+        assert_eq!(info.compute_precision, None);
+
+        let info = ModelInfo::inspect(&compiled).unwrap();
+        assert_eq!(info.compute_precision.as_deref(), Some("Float16"));
+        assert_eq!(info.weights_size(), 64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compiled_program_without_metadata() {
+        let dir = scratch("mil");
+        let compiled = dir.join("Program.mlmodelc");
+        write(&compiled.join("coremldata.bin"), [0u8; 8]);
+        write(&compiled.join("model.mil"), MIL);
+        write(&compiled.join("weights/weight.bin"), [0u8; 32]);
+
+        let info = ModelInfo::inspect(&compiled).unwrap();
+        assert_eq!(info.model_type, Some(ModelType::MlProgram));
+        assert_eq!(info.specification_version, None);
+        assert_eq!(
+            info.compute_precision.as_deref(),
+            Some("Mixed (Float16, Float32, Int32)")
+        );
+        let inputs = &info.description.inputs;
+        assert_eq!(inputs.len(), 2);
+        assert!(inputs[0].check_shape(&[1, 3, 224, 224]).is_ok());
+        // `?` dimensions accept any size.
+        assert!(inputs[1].check_shape(&[1, 77]).is_ok());
+        assert!(inputs[1].check_shape(&[2, 77]).is_err());
+        let FeatureType::MultiArray(output) = &info.description.outputs[0].feature_type else {
+            unreachable!("{:?}", info.description.outputs)
+        };
+        assert_eq!(output.shape, [1, 1000]);
+        assert_eq!(output.data_type, ArrayDataType::Float32);
+        assert_eq!(info.weights_size(), 32);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_bundles() {
+        let dir = scratch("malformed");
+        let error = |path: &Path| ModelInfo::inspect(path).unwrap_err().to_string();
+
+        let missing = dir.join("Missing.mlmodelc");
+        assert!(matches!(
+            ModelInfo::inspect(&missing).unwrap_err(),
+            Error::NotFound { .. }
+        ));
+
+        let bare = dir.join("Bare.mlmodelc");
+        std::fs::create_dir_all(&bare).unwrap();
+        assert!(error(&bare).contains("coremldata.bin"));
+
+        let compiled = dir.join("Bad.mlmodelc");
+        write(&compiled.join("coremldata.bin"), [0u8; 8]);
+        write(&compiled.join("metadata.json"), "[{\"inputSchema\": [}]");
+        assert!(error(&compiled).contains("metadata.json: unexpected '}' at byte 18"));
+        write(
+            &compiled.join("metadata.json"),
+            "[{\"inputSchema\": [{\"name\": \"x\"}]}]",
+        );
+        assert!(error(&compiled).contains("inputSchema[0]: feature 'x' has no type"));
+        std::fs::remove_file(compiled.join("metadata.json")).unwrap();
+        write(&compiled.join("model.mil"), "program(1.0) {}");
+        assert!(error(&compiled).contains("model.mil: has no main function"));
+
+        let package = dir.join("Escape.mlpackage");
+        write(
+            &package.join("Manifest.json"),
+            MANIFEST.replace("com.apple.CoreML/weights", "../../secrets"),
+        );
+        write(
+            &package.join("Data/com.apple.CoreML/model.mlmodel"),
+            classifier(),
+        );
+        assert!(error(&package).contains("leaves the package"));
+        write(
+            &package.join("Manifest.json"),
+            MANIFEST.replace(
+                "\"rootModelIdentifier\": \"A1\"",
+                "\"rootModelIdentifier\": \"C3\"",
+            ),
+        );
+        write(&package.join("Data/com.apple.CoreML/weights/w.bin"), [0u8]);
+        assert!(error(&package).contains("root model C3 is not listed"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_item_cannot_leave_package() {
+        let dir = scratch("symlink");
+        let outside = dir.join("secrets");
+        write(&outside.join("key.bin"), [0u8; 32]);
+
+        let package = dir.join("Linked.mlpackage");
+        write(&package.join("Manifest.json"), MANIFEST);
+        let data = package.join("Data/com.apple.CoreML");
+        write(&data.join("model.mlmodel"), classifier());
+        std::os::unix::fs::symlink(&outside, data.join("weights")).unwrap();
+        let err = ModelInfo::inspect(&package).unwrap_err().to_string();
+        assert!(err.contains("item B2 path com.apple.CoreML/weights leaves the package"));
+
+        // Links that stay inside the package are followed.
+        std::fs::remove_file(data.join("weights")).unwrap();
+        write(&data.join("real/weight.bin"), [0u8; 16]);
+        std::os::unix::fs::symlink(data.join("real"), data.join("weights")).unwrap();
+        let info = ModelInfo::inspect(&package).unwrap();
+        assert_eq!(info.weights_size(), 16);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
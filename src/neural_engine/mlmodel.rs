@@ -0,0 +1,1327 @@
+//! CoreML model specifications (`.mlmodel`).
+//!
+//! A `.mlmodel` file is one `Model` message of CoreML's protobuf
+//! specification (`Model.proto` in coremltools). [`ModelSpec`] decodes the
+//! parts that describe a model, without Apple frameworks:
+//!
+//! - the specification version
+//! - the model type, from whichever field of the `Type` oneof is set;
+//!   the sub-models of pipelines are decoded too
+//! - input and output feature descriptions, with their types and shapes
+//! - metadata: description, author, license, version and user-defined
+//!   entries
+//!
+//! Weights, layers and other type-specific payloads are skipped, as are
+//! fields this decoder does not know.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use manzana::neural_engine::mlmodel::{FeatureType, ModelSpec};
+//!
+//! let spec = ModelSpec::open("MobileNetV2.mlmodel")?;
+//! println!("{} model, specification version {}", spec.model_type, spec.specification_version);
+//! for input in &spec.description.inputs {
+//!     if let FeatureType::MultiArray(array) = &input.feature_type {
+//!         println!("{}: {:?} {:?}", input.name, array.data_type, array.shape);
+//!     }
+//! }
+//! # Ok::<(), manzana::Error>(())
+//! ```
+//!
+//! # Falsification Claims
+//!
+//! - F039: Truncated or corrupt files are rejected with an error naming
+//!   the offending field and byte offset, never a panic
+//! - A strict prefix of a well-formed model is rejected if it cuts a
+//!   field short or drops the specification version, description or
+//!   model type. Protobuf has no end marker, so a prefix that ends
+//!   between fields after all three parses, without the optional fields
+//!   that followed
+//! - Feature shapes and types round-trip exactly
+
+use super::protobuf::{Field, Message, Parse};
+use crate::error::{Error, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+/// The kind of model, from the field of `Model.Type` that is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelType {
+    /// Sequence of models ending in a classifier.
+    PipelineClassifier,
+    /// Sequence of models ending in a regressor.
+    PipelineRegressor,
+    /// Sequence of models.
+    Pipeline,
+    /// Generalized linear regressor.
+    GlmRegressor,
+    /// Support vector regressor.
+    SupportVectorRegressor,
+    /// Tree ensemble regressor.
+    TreeEnsembleRegressor,
+    /// Neural network regressor.
+    NeuralNetworkRegressor,
+    /// Bayesian probit regressor.
+    BayesianProbitRegressor,
+    /// Generalized linear classifier.
+    GlmClassifier,
+    /// Support vector classifier.
+    SupportVectorClassifier,
+    /// Tree ensemble classifier.
+    TreeEnsembleClassifier,
+    /// Neural network classifier.
+    NeuralNetworkClassifier,
+    /// k-nearest neighbors classifier.
+    KNearestNeighborsClassifier,
+    /// Neural network (layer graph).
+    NeuralNetwork,
+    /// Item similarity recommender.
+    ItemSimilarityRecommender,
+    /// ML program (MIL operations, iOS 15 and later).
+    MlProgram,
+    /// Model implemented by an app-provided class.
+    CustomModel,
+    /// Reference to a compiled model stored elsewhere.
+    LinkedModel,
+    /// One-hot encoder.
+    OneHotEncoder,
+    /// Missing value imputer.
+    Imputer,
+    /// Feature vectorizer.
+    FeatureVectorizer,
+    /// Dictionary vectorizer.
+    DictVectorizer,
+    /// Scaler.
+    Scaler,
+    /// Categorical mapping.
+    CategoricalMapping,
+    /// Normalizer.
+    Normalizer,
+    /// Array feature extractor.
+    ArrayFeatureExtractor,
+    /// Non-maximum suppression.
+    NonMaximumSuppression,
+    /// Identity.
+    Identity,
+    /// Create ML text classifier.
+    TextClassifier,
+    /// Create ML word tagger.
+    WordTagger,
+    /// Vision feature print.
+    VisionFeaturePrint,
+    /// Sound analysis preprocessing.
+    SoundAnalysisPreprocessing,
+    /// Gazetteer.
+    Gazetteer,
+    /// Word embedding.
+    WordEmbedding,
+    /// Audio feature print.
+    AudioFeaturePrint,
+    /// Encrypted or otherwise serialized model.
+    SerializedModel,
+    /// A model type this decoder does not know, by field number.
+    Other(u32),
+}
+
+/// Field number and name of every known model type.
+#[rustfmt::skip]
+const MODEL_TYPES: [(u32, ModelType, &str); 36] = [
+    (200, ModelType::PipelineClassifier, "PipelineClassifier"),
+    (201, ModelType::PipelineRegressor, "PipelineRegressor"),
+    (202, ModelType::Pipeline, "Pipeline"),
+    (300, ModelType::GlmRegressor, "GLMRegressor"),
+    (301, ModelType::SupportVectorRegressor, "SupportVectorRegressor"),
+    (302, ModelType::TreeEnsembleRegressor, "TreeEnsembleRegressor"),
+    (303, ModelType::NeuralNetworkRegressor, "NeuralNetworkRegressor"),
+    (304, ModelType::BayesianProbitRegressor, "BayesianProbitRegressor"),
+    (400, ModelType::GlmClassifier, "GLMClassifier"),
+    (401, ModelType::SupportVectorClassifier, "SupportVectorClassifier"),
+    (402, ModelType::TreeEnsembleClassifier, "TreeEnsembleClassifier"),
+    (403, ModelType::NeuralNetworkClassifier, "NeuralNetworkClassifier"),
+    (404, ModelType::KNearestNeighborsClassifier, "KNearestNeighborsClassifier"),
+    (500, ModelType::NeuralNetwork, "NeuralNetwork"),
+    (501, ModelType::ItemSimilarityRecommender, "ItemSimilarityRecommender"),
+    (502, ModelType::MlProgram, "MLProgram"),
+    (555, ModelType::CustomModel, "CustomModel"),
+    (556, ModelType::LinkedModel, "LinkedModel"),
+    (600, ModelType::OneHotEncoder, "OneHotEncoder"),
+    (601, ModelType::Imputer, "Imputer"),
+    (602, ModelType::FeatureVectorizer, "FeatureVectorizer"),
+    (603, ModelType::DictVectorizer, "DictVectorizer"),
+    (604, ModelType::Scaler, "Scaler"),
+    (606, ModelType::CategoricalMapping, "CategoricalMapping"),
+    (607, ModelType::Normalizer, "Normalizer"),
+    (609, ModelType::ArrayFeatureExtractor, "ArrayFeatureExtractor"),
+    (610, ModelType::NonMaximumSuppression, "NonMaximumSuppression"),
+    (900, ModelType::Identity, "Identity"),
+    (2000, ModelType::TextClassifier, "TextClassifier"),
+    (2001, ModelType::WordTagger, "WordTagger"),
+    (2002, ModelType::VisionFeaturePrint, "VisionFeaturePrint"),
+    (2003, ModelType::SoundAnalysisPreprocessing, "SoundAnalysisPreprocessing"),
+    (2004, ModelType::Gazetteer, "Gazetteer"),
+    (2005, ModelType::WordEmbedding, "WordEmbedding"),
+    (2006, ModelType::AudioFeaturePrint, "AudioFeaturePrint"),
+    (3000, ModelType::SerializedModel, "SerializedModel"),
+];
+
+/// Lowest field number of the `Model.Type` oneof.
+const FIRST_TYPE_FIELD: u32 = 200;
+
+impl ModelType {
+    /// The model type stored in field `number` of `Model`.
+    #[must_use]
+    pub fn from_field(number: u32) -> Self {
+        MODEL_TYPES
+            .iter()
+            .find(|(field, _, _)| *field == number)
+            .map_or(Self::Other(number), |(_, kind, _)| *kind)
+    }
+
+    /// The model type with `name`, such as `MLProgram`, ignoring case.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        MODEL_TYPES
+            .iter()
+            .find(|(_, _, known)| known.eq_ignore_ascii_case(name))
+            .map(|(_, kind, _)| *kind)
+    }
+
+    /// Field number of this type in `Model`.
+    #[must_use]
+    pub fn field_number(self) -> u32 {
+        match self {
+            Self::Other(number) => number,
+            kind => MODEL_TYPES
+                .iter()
+                .find(|(_, known, _)| *known == kind)
+                .map_or(0, |(field, _, _)| *field),
+        }
+    }
+
+    /// True for the pipeline types, which contain sub-models.
+    #[must_use]
+    pub const fn is_pipeline(self) -> bool {
+        matches!(
+            self,
+            Self::Pipeline | Self::PipelineClassifier | Self::PipelineRegressor
+        )
+    }
+}
+
+impl fmt::Display for ModelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match MODEL_TYPES.iter().find(|(_, kind, _)| kind == self) {
+            Some((_, _, name)) => write!(f, "{name}"),
+            None => write!(f, "unknown model type (field {})", self.field_number()),
+        }
+    }
+}
+
+/// Element type of a multi-array feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArrayDataType {
+    /// 16-bit float.
+    Float16,
+    /// 32-bit float.
+    Float32,
+    /// 64-bit float.
+    Double,
+    /// 32-bit signed integer.
+    Int32,
+    /// 8-bit signed integer.
+    Int8,
+    /// A type this decoder does not know, or unset.
+    Other(u64),
+}
+
+impl From<u64> for ArrayDataType {
+    fn from(raw: u64) -> Self {
+        match raw {
+            0x1_0010 => Self::Float16,
+            0x1_0020 => Self::Float32,
+            0x1_0040 => Self::Double,
+            0x2_0020 => Self::Int32,
+            0x2_0008 => Self::Int8,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Pixel layout of an image feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    /// 8-bit grayscale.
+    Grayscale,
+    /// 8-bit RGB.
+    Rgb,
+    /// 8-bit BGR.
+    Bgr,
+    /// 16-bit float grayscale.
+    GrayscaleFloat16,
+    /// A color space this decoder does not know, or unset.
+    Other(u64),
+}
+
+impl ColorSpace {
+    /// Number of channels, if known.
+    #[must_use]
+    pub const fn channels(self) -> Option<usize> {
+        match self {
+            Self::Grayscale | Self::GrayscaleFloat16 => Some(1),
+            Self::Rgb | Self::Bgr => Some(3),
+            Self::Other(_) => None,
+        }
+    }
+}
+
+impl From<u64> for ColorSpace {
+    fn from(raw: u64) -> Self {
+        match raw {
+            10 => Self::Grayscale,
+            20 => Self::Rgb,
+            30 => Self::Bgr,
+            40 => Self::GrayscaleFloat16,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Inclusive range of sizes; an unbounded range has no upper bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct SizeRange {
+    /// Smallest allowed size.
+    pub lower: usize,
+    /// Largest allowed size, or `None` for no limit.
+    pub upper: Option<usize>,
+}
+
+impl SizeRange {
+    /// Check if `size` is in the range.
+    #[must_use]
+    pub fn contains(&self, size: usize) -> bool {
+        size >= self.lower && self.upper.map_or(true, |upper| size <= upper)
+    }
+}
+
+impl fmt::Display for SizeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.upper {
+            Some(upper) => write!(f, "{}..={upper}", self.lower),
+            None => write!(f, "{}..", self.lower),
+        }
+    }
+}
+
+/// Shapes a multi-array feature accepts besides its default shape.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ArrayShapes {
+    /// Only the default shape.
+    #[default]
+    Fixed,
+    /// Any of these shapes.
+    Enumerated(Vec<Vec<usize>>),
+    /// Any shape with one dimension per range, each within its range.
+    Ranges(Vec<SizeRange>),
+}
+
+/// A multi-array (tensor) feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArrayFeature {
+    /// Default shape; empty if the model does not fix one.
+    pub shape: Vec<usize>,
+    /// Element type.
+    pub data_type: ArrayDataType,
+    /// Other accepted shapes.
+    pub shapes: ArrayShapes,
+}
+
+impl ArrayFeature {
+    /// Check `shape` against the feature, describing what is expected if
+    /// it does not fit.
+    fn accepts(&self, shape: &[usize]) -> std::result::Result<(), String> {
+        match &self.shapes {
+            _ if self.shape == shape => Ok(()),
+            ArrayShapes::Fixed if self.shape.is_empty() => Ok(()),
+            ArrayShapes::Fixed => Err(format!("shape {:?}", self.shape)),
+            ArrayShapes::Enumerated(shapes) if shapes.iter().any(|s| s == shape) => Ok(()),
+            ArrayShapes::Enumerated(shapes) => Err(format!("one of the shapes {shapes:?}")),
+            ArrayShapes::Ranges(ranges)
+                if ranges.len() == shape.len()
+                    && ranges
+                        .iter()
+                        .zip(shape)
+                        .all(|(range, &dim)| range.contains(dim)) =>
+            {
+                Ok(())
+            }
+            ArrayShapes::Ranges(ranges) => {
+                let ranges: Vec<_> = ranges.iter().map(ToString::to_string).collect();
+                Err(format!("a shape within [{}]", ranges.join(", ")))
+            }
+        }
+    }
+}
+
+/// Sizes an image feature accepts besides its default size.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ImageSizes {
+    /// Only the default size.
+    #[default]
+    Fixed,
+    /// Any of these `(width, height)` sizes.
+    Enumerated(Vec<(usize, usize)>),
+    /// Any size within these ranges.
+    Range {
+        /// Accepted widths.
+        width: SizeRange,
+        /// Accepted heights.
+        height: SizeRange,
+    },
+}
+
+/// An image feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageFeature {
+    /// Default width in pixels.
+    pub width: usize,
+    /// Default height in pixels.
+    pub height: usize,
+    /// Pixel layout.
+    pub color_space: ColorSpace,
+    /// Other accepted sizes.
+    pub sizes: ImageSizes,
+}
+
+impl ImageFeature {
+    /// Check a `[channels, height, width]` shape, optionally with a
+    /// leading batch dimension of 1.
+    fn accepts(&self, shape: &[usize]) -> std::result::Result<(), String> {
+        let channels = self
+            .color_space
+            .channels()
+            .map_or_else(|| "c".to_string(), |c| c.to_string());
+        let expected = || match &self.sizes {
+            ImageSizes::Fixed => format!("shape [{channels}, {}, {}]", self.height, self.width),
+            ImageSizes::Enumerated(sizes) => {
+                format!("a {channels}-channel image with (width, height) in {sizes:?}")
+            }
+            ImageSizes::Range { width, height } => {
+                format!("a {channels}-channel image {width} wide and {height} high")
+            }
+        };
+        let dims = match shape {
+            [1, c, h, w] | [c, h, w] => (*c, *h, *w),
+            _ => return Err(expected()),
+        };
+        let (c, h, w) = dims;
+        let size_ok = (w, h) == (self.width, self.height)
+            || match &self.sizes {
+                ImageSizes::Fixed => false,
+                ImageSizes::Enumerated(sizes) => sizes.contains(&(w, h)),
+                ImageSizes::Range { width, height } => width.contains(w) && height.contains(h),
+            };
+        if self.color_space.channels().map_or(true, |n| n == c) && size_ok {
+            Ok(())
+        } else {
+            Err(expected())
+        }
+    }
+}
+
+/// Element or key type of sequence and dictionary features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ElementType {
+    /// 64-bit integers.
+    Int64,
+    /// Strings.
+    String,
+}
+
+/// Type of a model input or output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeatureType {
+    /// A 64-bit integer.
+    Int64,
+    /// A 64-bit float.
+    Double,
+    /// A string.
+    String,
+    /// An image.
+    Image(ImageFeature),
+    /// A multi-array (tensor).
+    MultiArray(ArrayFeature),
+    /// A dictionary with numeric values.
+    Dictionary {
+        /// Key type.
+        key: ElementType,
+    },
+    /// A sequence.
+    Sequence {
+        /// Element type.
+        element: ElementType,
+        /// Accepted lengths.
+        length: SizeRange,
+    },
+    /// State kept between predictions (iOS 18 and later).
+    State(ArrayFeature),
+}
+
+impl FeatureType {
+    fn accepts(&self, shape: &[usize]) -> std::result::Result<(), String> {
+        match self {
+            Self::Int64 | Self::Double if shape.iter().product::<usize>() == 1 => Ok(()),
+            Self::Int64 | Self::Double => Err("a scalar".to_string()),
+            Self::MultiArray(array) | Self::State(array) => array.accepts(shape),
+            Self::Image(image) => image.accepts(shape),
+            Self::String => Err("a string, which a tensor cannot hold".to_string()),
+            Self::Dictionary { .. } => Err("a dictionary, which a tensor cannot hold".to_string()),
+            Self::Sequence { .. } => Err("a sequence, which a tensor cannot hold".to_string()),
+        }
+    }
+}
+
+/// A named model input or output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureDescription {
+    /// Feature name.
+    pub name: String,
+    /// Human-readable description; empty if none.
+    pub short_description: String,
+    /// Feature type.
+    pub feature_type: FeatureType,
+    /// True if the feature may be omitted.
+    pub optional: bool,
+}
+
+impl FeatureDescription {
+    /// Check that a tensor of `shape` can be passed as this feature.
+    ///
+    /// Images take `[channels, height, width]`, with or without a leading
+    /// batch dimension of 1; scalars take any shape with one element.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the feature and the expected shape if
+    /// `shape` does not fit, or if the feature can't be held in a tensor.
+    pub fn check_shape(&self, shape: &[usize]) -> Result<()> {
+        self.feature_type.accepts(shape).map_err(|expected| {
+            Error::invalid_input(format!(
+                "input '{}' expects {expected}, got shape {shape:?}",
+                self.name
+            ))
+        })
+    }
+}
+
+/// Descriptive metadata of a model.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Summary of what the model does.
+    pub short_description: String,
+    /// Model version.
+    pub version: String,
+    /// Author.
+    pub author: String,
+    /// License.
+    pub license: String,
+    /// Creator-defined entries, such as the converter version.
+    pub user_defined: BTreeMap<String, String>,
+}
+
+/// Interface of a model: its features and metadata.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelDescription {
+    /// Inputs, in declaration order.
+    pub inputs: Vec<FeatureDescription>,
+    /// Outputs, in declaration order.
+    pub outputs: Vec<FeatureDescription>,
+    /// Inputs used only for on-device training.
+    pub training_inputs: Vec<FeatureDescription>,
+    /// Output holding a classifier's predicted label.
+    pub predicted_feature_name: Option<String>,
+    /// Output holding a classifier's label probabilities.
+    pub predicted_probabilities_name: Option<String>,
+    /// Metadata.
+    pub metadata: Metadata,
+}
+
+impl ModelDescription {
+    /// The input called `name`.
+    #[must_use]
+    pub fn input(&self, name: &str) -> Option<&FeatureDescription> {
+        self.inputs.iter().find(|feature| feature.name == name)
+    }
+
+    /// The output called `name`.
+    #[must_use]
+    pub fn output(&self, name: &str) -> Option<&FeatureDescription> {
+        self.outputs.iter().find(|feature| feature.name == name)
+    }
+}
+
+/// A decoded CoreML `Model` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelSpec {
+    /// Specification version the model was written for.
+    pub specification_version: u32,
+    /// Inputs, outputs and metadata.
+    pub description: ModelDescription,
+    /// Kind of model.
+    pub model_type: ModelType,
+    /// True if the model supports on-device training.
+    pub is_updatable: bool,
+    /// Models of a pipeline, in order; empty for other types.
+    pub sub_models: Vec<Self>,
+}
+
+impl ModelSpec {
+    /// Read and parse the `.mlmodel` file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not a well-formed
+    /// model.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .map_err(|e| Error::coreml(format!("failed to read {}: {e}", path.display())))?;
+        parse_model(Message::new(&bytes), 0)
+            .map_err(|what| Error::coreml(format!("malformed model {}: {what}", path.display())))
+    }
+
+    /// Parse a model held in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data is truncated or is not valid protobuf,
+    /// a field has the wrong encoding, the specification version or model
+    /// type is missing, or feature names are empty or repeated.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        parse_model(Message::new(bytes), 0)
+            .map_err(|what| Error::coreml(format!("malformed model: {what}")))
+    }
+}
+
+/// Prefix errors from `result` with the schema path `context`.
+fn within<T>(context: impl fmt::Display, result: Parse<T>) -> Parse<T> {
+    result.map_err(|what| format!("{context}: {what}"))
+}
+
+/// A non-negative size stored as `int64` or `uint64`.
+fn size(field: &Field<'_>, value: i64) -> Parse<usize> {
+    usize::try_from(value).map_err(|_| {
+        format!(
+            "field {} at byte {} holds invalid size {value}",
+            field.number, field.offset
+        )
+    })
+}
+
+/// Deepest nesting of pipelines accepted, so that hostile files can't
+/// exhaust the stack.
+const MAX_PIPELINE_DEPTH: usize = 64;
+
+fn parse_model(message: Message<'_>, depth: usize) -> Parse<ModelSpec> {
+    let mut version = None;
+    let mut description = None;
+    let mut is_updatable = false;
+    let mut model_type: Option<(ModelType, usize)> = None;
+    let mut sub_models = Vec::new();
+    for field in message.fields() {
+        let field = field?;
+        match field.number {
+            1 => version = Some(field.varint()?),
+            2 => {
+                description = Some(within("description", parse_description(field.message()?))?);
+            }
+            10 => is_updatable = field.bool()?,
+            number if number >= FIRST_TYPE_FIELD => {
+                let kind = ModelType::from_field(number);
+                if let Some((previous, at)) = model_type {
+                    return Err(format!(
+                        "model type is set twice: {previous} at byte {at} and {kind} at byte {}",
+                        field.offset
+                    ));
+                }
+                let payload = field.message()?;
+                sub_models = within(kind, parse_type(kind, payload, depth))?;
+                model_type = Some((kind, field.offset));
+            }
+            _ => {}
+        }
+    }
+    let specification_version = version
+        .filter(|&version| version > 0)
+        .ok_or_else(|| "missing specification version".to_string())?;
+    let specification_version = u32::try_from(specification_version)
+        .map_err(|_| format!("specification version {specification_version} is out of range"))?;
+    Ok(ModelSpec {
+        specification_version,
+        description: description.ok_or_else(|| "missing model description".to_string())?,
+        model_type: model_type
+            .map(|(kind, _)| kind)
+            .ok_or_else(|| "no model type is set".to_string())?,
+        is_updatable,
+        sub_models,
+    })
+}
+
+/// Sub-models of a pipeline type; other payloads are skipped.
+fn parse_type(kind: ModelType, payload: Message<'_>, depth: usize) -> Parse<Vec<ModelSpec>> {
+    match kind {
+        ModelType::Pipeline => parse_pipeline(payload, depth),
+        ModelType::PipelineClassifier | ModelType::PipelineRegressor => {
+            let mut models = Vec::new();
+            for field in payload.fields() {
+                let field = field?;
+                if field.number == 1 {
+                    models = within("pipeline", parse_pipeline(field.message()?, depth))?;
+                }
+            }
+            Ok(models)
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn parse_pipeline(message: Message<'_>, depth: usize) -> Parse<Vec<ModelSpec>> {
+    if depth >= MAX_PIPELINE_DEPTH {
+        return Err(format!(
+            "pipeline nesting deeper than {MAX_PIPELINE_DEPTH} at byte {}",
+            message.offset()
+        ));
+    }
+    let mut models = Vec::new();
+    for field in message.fields() {
+        let field = field?;
+        if field.number == 1 {
+            let index = models.len();
+            models.push(within(
+                format_args!("models[{index}]"),
+                parse_model(field.message()?, depth + 1),
+            )?);
+        }
+    }
+    Ok(models)
+}
+
+fn parse_description(message: Message<'_>) -> Parse<ModelDescription> {
+    let mut description = ModelDescription::default();
+    for field in message.fields() {
+        let field = field?;
+        let (name, list) = match field.number {
+            1 => ("input", &mut description.inputs),
+            10 => ("output", &mut description.outputs),
+            50 => ("trainingInput", &mut description.training_inputs),
+            11 => {
+                description.predicted_feature_name = Some(field.string()?);
+                continue;
+            }
+            12 => {
+                description.predicted_probabilities_name = Some(field.string()?);
+                continue;
+            }
+            100 => {
+                description.metadata = within("metadata", parse_metadata(field.message()?))?;
+                continue;
+            }
+            _ => continue,
+        };
+        let index = list.len();
+        list.push(within(
+            format_args!("{name}[{index}]"),
+            parse_feature(field.message()?),
+        )?);
+    }
+    for (name, list) in [
+        ("input", &description.inputs),
+        ("output", &description.outputs),
+    ] {
+        let mut seen = HashSet::new();
+        if let Some(repeated) = list.iter().find(|feature| !seen.insert(&feature.name)) {
+            return Err(format!("{name} '{}' is declared twice", repeated.name));
+        }
+    }
+    Ok(description)
+}
+
+fn parse_metadata(message: Message<'_>) -> Parse<Metadata> {
+    let mut metadata = Metadata::default();
+    for field in message.fields() {
+        let field = field?;
+        match field.number {
+            1 => metadata.short_description = field.string()?,
+            2 => metadata.version = field.string()?,
+            3 => metadata.author = field.string()?,
+            4 => metadata.license = field.string()?,
+            100 => {
+                let (mut key, mut value) = (String::new(), String::new());
+                for entry in field.message()?.fields() {
+                    let entry = entry?;
+                    match entry.number {
+                        1 => key = entry.string()?,
+                        2 => value = entry.string()?,
+                        _ => {}
+                    }
+                }
+                metadata.user_defined.insert(key, value);
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+fn parse_feature(message: Message<'_>) -> Parse<FeatureDescription> {
+    let mut name = String::new();
+    let mut short_description = String::new();
+    let mut feature_type = None;
+    for field in message.fields() {
+        let field = field?;
+        match field.number {
+            1 => name = field.string()?,
+            2 => short_description = field.string()?,
+            3 => feature_type = Some(within("type", parse_feature_type(field.message()?))?),
+            _ => {}
+        }
+    }
+    if name.is_empty() {
+        return Err(format!("feature at byte {} has no name", message.offset()));
+    }
+    let (feature_type, optional) =
+        feature_type.ok_or_else(|| format!("feature '{name}' has no type"))?;
+    Ok(FeatureDescription {
+        name,
+        short_description,
+        feature_type,
+        optional,
+    })
+}
+
+fn parse_feature_type(message: Message<'_>) -> Parse<(FeatureType, bool)> {
+    let mut feature_type = None;
+    let mut optional = false;
+    for field in message.fields() {
+        let field = field?;
+        let kind = match field.number {
+            1 => FeatureType::Int64,
+            2 => FeatureType::Double,
+            3 => FeatureType::String,
+            4 => FeatureType::Image(within("imageType", parse_image(field.message()?))?),
+            5 => FeatureType::MultiArray(within("multiArrayType", parse_array(field.message()?))?),
+            6 => within("dictionaryType", parse_dictionary(field.message()?))?,
+            7 => within("sequenceType", parse_sequence(field.message()?))?,
+            8 => within("stateType", parse_state(field.message()?))?,
+            1000 => {
+                optional = field.bool()?;
+                continue;
+            }
+            number => {
+                return Err(format!(
+                    "unsupported feature type (field {number}) at byte {}",
+                    field.offset
+                ))
+            }
+        };
+        // Scalar types are empty messages; check their encoding anyway.
+        field.message()?;
+        feature_type = Some(kind);
+    }
+    feature_type
+        .map(|kind| (kind, optional))
+        .ok_or_else(|| format!("no type is set at byte {}", message.offset()))
+}
+
+fn parse_array(message: Message<'_>) -> Parse<ArrayFeature> {
+    let mut shape = Vec::new();
+    let mut data_type = ArrayDataType::Other(0);
+    let mut shapes = ArrayShapes::Fixed;
+    for field in message.fields() {
+        let field = field?;
+        match field.number {
+            1 => {
+                for dim in field.int64s()? {
+                    shape.push(size(&field, dim)?);
+                }
+            }
+            2 => data_type = ArrayDataType::from(field.varint()?),
+            21 => {
+                let mut enumerated = Vec::new();
+                for entry in field.message()?.fields() {
+                    let entry = entry?;
+                    if entry.number == 1 {
+                        enumerated.push(within("enumeratedShapes", parse_shape(entry.message()?))?);
+                    }
+                }
+                shapes = ArrayShapes::Enumerated(enumerated);
+            }
+            31 => {
+                let mut ranges = Vec::new();
+                for entry in field.message()?.fields() {
+                    let entry = entry?;
+                    if entry.number == 1 {
+                        ranges.push(within("shapeRange", parse_size_range(entry.message()?))?);
+                    }
+                }
+                shapes = ArrayShapes::Ranges(ranges);
+            }
+            _ => {}
+        }
+    }
+    Ok(ArrayFeature {
+        shape,
+        data_type,
+        shapes,
+    })
+}
+
+fn parse_shape(message: Message<'_>) -> Parse<Vec<usize>> {
+    let mut shape = Vec::new();
+    for field in message.fields() {
+        let field = field?;
+        if field.number == 1 {
+            for dim in field.int64s()? {
+                shape.push(size(&field, dim)?);
+            }
+        }
+    }
+    Ok(shape)
+}
+
+fn parse_size_range(message: Message<'_>) -> Parse<SizeRange> {
+    let mut range = SizeRange::default();
+    for field in message.fields() {
+        let field = field?;
+        match field.number {
+            1 => range.lower = size(&field, field.int64()?)?,
+            // Negative upper bounds mean unbounded.
+            2 => range.upper = usize::try_from(field.int64()?).ok(),
+            _ => {}
+        }
+    }
+    match range.upper {
+        Some(upper) if upper < range.lower => Err(format!(
+            "range at byte {} ends before it starts ({}..={upper})",
+            message.offset(),
+            range.lower
+        )),
+        _ => Ok(range),
+    }
+}
+
+fn parse_image(message: Message<'_>) -> Parse<ImageFeature> {
+    let mut image = ImageFeature {
+        width: 0,
+        height: 0,
+        color_space: ColorSpace::Other(0),
+        sizes: ImageSizes::Fixed,
+    };
+    for field in message.fields() {
+        let field = field?;
+        match field.number {
+            1 => image.width = size(&field, field.int64()?)?,
+            2 => image.height = size(&field, field.int64()?)?,
+            3 => image.color_space = ColorSpace::from(field.varint()?),
+            21 => {
+                let mut sizes = Vec::new();
+                for entry in field.message()?.fields() {
+                    let entry = entry?;
+                    if entry.number == 1 {
+                        let (mut width, mut height) = (0, 0);
+                        for dim in entry.message()?.fields() {
+                            let dim = dim?;
+                            match dim.number {
+                                1 => width = size(&dim, dim.int64()?)?,
+                                2 => height = size(&dim, dim.int64()?)?,
+                                _ => {}
+                            }
+                        }
+                        sizes.push((width, height));
+                    }
+                }
+                image.sizes = ImageSizes::Enumerated(sizes);
+            }
+            31 => {
+                let (mut width, mut height) = (SizeRange::default(), SizeRange::default());
+                for entry in field.message()?.fields() {
+                    let entry = entry?;
+                    match entry.number {
+                        1 => width = within("widthRange", parse_size_range(entry.message()?))?,
+                        2 => height = within("heightRange", parse_size_range(entry.message()?))?,
+                        _ => {}
+                    }
+                }
+                image.sizes = ImageSizes::Range { width, height };
+            }
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+fn parse_dictionary(message: Message<'_>) -> Parse<FeatureType> {
+    let mut key = None;
+    for field in message.fields() {
+        match field?.number {
+            1 => key = Some(ElementType::Int64),
+            2 => key = Some(ElementType::String),
+            _ => {}
+        }
+    }
+    key.map(|key| FeatureType::Dictionary { key })
+        .ok_or_else(|| format!("no key type is set at byte {}", message.offset()))
+}
+
+fn parse_sequence(message: Message<'_>) -> Parse<FeatureType> {
+    let mut element = None;
+    let mut length = SizeRange::default();
+    for field in message.fields() {
+        let field = field?;
+        match field.number {
+            1 => element = Some(ElementType::Int64),
+            3 => element = Some(ElementType::String),
+            101 => length = within("sizeRange", parse_size_range(field.message()?))?,
+            _ => {}
+        }
+    }
+    element
+        .map(|element| FeatureType::Sequence { element, length })
+        .ok_or_else(|| format!("no element type is set at byte {}", message.offset()))
+}
+
+fn parse_state(message: Message<'_>) -> Parse<FeatureType> {
+    let mut array = None;
+    for field in message.fields() {
+        let field = field?;
+        if field.number == 1 {
+            array = Some(within("arrayType", parse_array(field.message()?))?);
+        }
+    }
+    array
+        .map(FeatureType::State)
+        .ok_or_else(|| format!("state has no array type at byte {}", message.offset()))
+}
+
+/// Writer for test models.
+#[cfg(test)]
+#[allow(clippy::must_use_candidate)]
+pub(crate) mod fixture {
+    use crate::neural_engine::protobuf::writer::Writer;
+
+    /// `ArrayFeatureType.FLOAT32`.
+    pub const FLOAT32: u64 = 0x1_0020;
+
+    /// A multi-array feature description.
+    pub fn array(name: &str, shape: &[i64]) -> Writer {
+        let array = Writer::new().packed(1, shape).varint(2, FLOAT32);
+        feature(name, Writer::new().message(5, array))
+    }
+
+    /// A feature description of the given `FeatureType`.
+    pub fn feature(name: &str, feature_type: Writer) -> Writer {
+        Writer::new()
+            .string(1, name)
+            .string(2, "a feature")
+            .message(3, feature_type)
+    }
+
+    /// A `Model` message of `model_type` (a `Model.Type` field number).
+    pub fn model_of(
+        model_type: u32,
+        payload: Writer,
+        inputs: Vec<Writer>,
+        outputs: Vec<Writer>,
+    ) -> Writer {
+        let mut description = Writer::new();
+        for input in inputs {
+            description = description.message(1, input);
+        }
+        for output in outputs {
+            description = description.message(10, output);
+        }
+        let metadata = Writer::new()
+            .string(1, "Test model")
+            .string(2, "1.0")
+            .string(3, "manzana")
+            .string(4, "MIT")
+            .message(
+                100,
+                Writer::new()
+                    .string(1, "com.github.apple.coremltools.version")
+                    .string(2, "7.1"),
+            );
+        description = description.message(100, metadata);
+        Writer::new()
+            .varint(1, 5)
+            .message(2, description)
+            .message(model_type, payload)
+    }
+
+    /// A neural network from one `[1, 3, 224, 224]` input to a
+    /// `[1, 1000]` output.
+    pub fn classifier() -> Vec<u8> {
+        model_of(
+            500,
+            Writer::new().message(1, Writer::new().string(1, "dense")),
+            vec![array("image", &[1, 3, 224, 224])],
+            vec![array("logits", &[1, 1000])],
+        )
+        .finish()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::fixture::{array, classifier, feature, model_of};
+    use super::*;
+    use crate::neural_engine::protobuf::writer::Writer;
+
+    #[test]
+    fn test_parse_model() {
+        let spec = ModelSpec::parse(&classifier()).unwrap();
+        assert_eq!(spec.specification_version, 5);
+        assert_eq!(spec.model_type, ModelType::NeuralNetwork);
+        assert_eq!(spec.model_type.to_string(), "NeuralNetwork");
+        assert!(!spec.is_updatable);
+        assert!(spec.sub_models.is_empty());
+
+        let description = &spec.description;
+        let input = description.input("image").unwrap();
+        assert_eq!(input.short_description, "a feature");
+        assert!(!input.optional);
+        assert_eq!(
+            input.feature_type,
+            FeatureType::MultiArray(ArrayFeature {
+                shape: vec![1, 3, 224, 224],
+                data_type: ArrayDataType::Float32,
+                shapes: ArrayShapes::Fixed,
+            })
+        );
+        assert!(description.output("logits").is_some());
+        assert_eq!(description.metadata.author, "manzana");
+        assert_eq!(description.metadata.license, "MIT");
+        assert_eq!(
+            description.metadata.user_defined["com.github.apple.coremltools.version"],
+            "7.1"
+        );
+
+        input.check_shape(&[1, 3, 224, 224]).unwrap();
+        let error = input.check_shape(&[1, 3, 112, 112]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("input 'image' expects shape [1, 3, 224, 224]"),
+            "{error}"
+        );
+        assert_eq!(ModelType::from_field(502), ModelType::MlProgram);
+        assert_eq!(ModelType::from_field(9999), ModelType::Other(9999));
+        assert_eq!(ModelType::MlProgram.field_number(), 502);
+    }
+
+    /// A pipeline classifier of two models whose inputs are a ranged
+    /// array, an enumerated array, a flexible image and an optional
+    /// scalar.
+    fn flexible_pipeline() -> Vec<u8> {
+        let ranged = Writer::new()
+            .packed(1, &[1, 8])
+            .varint(2, 0x2_0020)
+            .message(
+                31,
+                Writer::new()
+                    .message(1, Writer::new().varint(1, 1).int64(2, 1))
+                    .message(1, Writer::new().varint(1, 1).int64(2, -1)),
+            );
+        let enumerated = Writer::new().packed(1, &[2, 2]).message(
+            21,
+            Writer::new()
+                .message(1, Writer::new().packed(1, &[2, 2]))
+                .message(1, Writer::new().packed(1, &[4, 4])),
+        );
+        let image = Writer::new()
+            .int64(1, 64)
+            .int64(2, 32)
+            .varint(3, 30)
+            .message(
+                31,
+                Writer::new()
+                    .message(1, Writer::new().varint(1, 16).int64(2, 128))
+                    .message(2, Writer::new().varint(1, 16).int64(2, 128)),
+            );
+        let inner = model_of(
+            500,
+            Writer::new(),
+            vec![
+                feature("tokens", Writer::new().message(5, ranged)),
+                feature("grid", Writer::new().message(5, enumerated)),
+                feature("photo", Writer::new().message(4, image)),
+                feature(
+                    "count",
+                    Writer::new().message(1, Writer::new()).varint(1000, 1),
+                ),
+            ],
+            vec![feature("label", Writer::new().message(3, Writer::new()))],
+        );
+        model_of(
+            200,
+            Writer::new().message(1, Writer::new().message(1, inner.clone()).message(1, inner)),
+            vec![array("x", &[3])],
+            vec![feature("label", Writer::new().message(3, Writer::new()))],
+        )
+        .finish()
+    }
+
+    #[test]
+    fn test_pipelines_and_flexible_features() {
+        let spec = ModelSpec::parse(&flexible_pipeline()).unwrap();
+        assert_eq!(spec.model_type, ModelType::PipelineClassifier);
+        assert!(spec.model_type.is_pipeline());
+        assert_eq!(spec.sub_models.len(), 2);
+        let inner = &spec.sub_models[1].description;
+
+        let tokens = inner.input("tokens").unwrap();
+        assert_eq!(
+            tokens.feature_type,
+            FeatureType::MultiArray(ArrayFeature {
+                shape: vec![1, 8],
+                data_type: ArrayDataType::Int32,
+                shapes: ArrayShapes::Ranges(vec![
+                    SizeRange {
+                        lower: 1,
+                        upper: Some(1)
+                    },
+                    SizeRange {
+                        lower: 1,
+                        upper: None
+                    },
+                ]),
+            })
+        );
+        tokens.check_shape(&[1, 500]).unwrap();
+        let error = tokens.check_shape(&[2, 5]).unwrap_err();
+        assert!(error.to_string().contains("within [1..=1, 1..]"), "{error}");
+
+        let grid = inner.input("grid").unwrap();
+        grid.check_shape(&[4, 4]).unwrap();
+        assert!(grid.check_shape(&[3, 3]).is_err());
+
+        let photo = inner.input("photo").unwrap();
+        assert_eq!(
+            photo.feature_type,
+            FeatureType::Image(ImageFeature {
+                width: 64,
+                height: 32,
+                color_space: ColorSpace::Bgr,
+                sizes: ImageSizes::Range {
+                    width: SizeRange {
+                        lower: 16,
+                        upper: Some(128)
+                    },
+                    height: SizeRange {
+                        lower: 16,
+                        upper: Some(128)
+                    },
+                },
+            })
+        );
+        photo.check_shape(&[3, 32, 64]).unwrap();
+        photo.check_shape(&[1, 3, 100, 20]).unwrap();
+        assert!(photo.check_shape(&[1, 3, 200, 20]).is_err());
+        assert!(photo.check_shape(&[1, 32, 64]).is_err());
+
+        let count = inner.input("count").unwrap();
+        assert!(count.optional);
+        count.check_shape(&[1]).unwrap();
+        assert!(count.check_shape(&[2]).is_err());
+        let error = inner
+            .output("label")
+            .unwrap()
+            .check_shape(&[1])
+            .unwrap_err();
+        assert!(error.to_string().contains("a string"), "{error}");
+    }
+
+    #[test]
+    fn test_corrupt_models_rejected() {
+        let good = classifier();
+        for len in 0..good.len() {
+            assert!(ModelSpec::parse(&good[..len]).is_err(), "length {len}");
+        }
+
+        // Only the optional `isUpdatable` follows the type here, so the
+        // prefix ending before it is itself a well-formed model.
+        let updatable = [good.clone(), Writer::new().varint(10, 1).finish()].concat();
+        for len in good.len() + 1..updatable.len() {
+            assert!(ModelSpec::parse(&updatable[..len]).is_err(), "length {len}");
+        }
+        let spec = ModelSpec::parse(&updatable[..good.len()]).unwrap();
+        assert!(!spec.is_updatable);
+        assert!(ModelSpec::parse(&updatable).unwrap().is_updatable);
+
+        let message = |bytes: Vec<u8>| ModelSpec::parse(&bytes).unwrap_err().to_string();
+        let truncated = message(good[..good.len() - 3].to_vec());
+        assert!(truncated.contains("field 500"), "{truncated}");
+
+        let error = message(
+            Writer::new()
+                .varint(1, 5)
+                .message(2, Writer::new())
+                .finish(),
+        );
+        assert!(error.contains("no model type"), "{error}");
+        let error = message(
+            Writer::new()
+                .varint(1, 5)
+                .message(2, Writer::new())
+                .message(500, Writer::new())
+                .message(502, Writer::new())
+                .finish(),
+        );
+        assert!(error.contains("set twice: NeuralNetwork"), "{error}");
+        let error = message(Writer::new().varint(1, 5).varint(2, 7).finish());
+        assert!(
+            error.contains("field 2 at byte 2 should be a message"),
+            "{error}"
+        );
+
+        let negative = model_of(500, Writer::new(), vec![array("x", &[1, -3])], vec![]).finish();
+        let error = message(negative);
+        assert!(
+            error.contains("description: input[0]: type: multiArrayType: field 1"),
+            "{error}"
+        );
+        assert!(error.contains("invalid size -3"), "{error}");
+
+        let twice = model_of(
+            500,
+            Writer::new(),
+            vec![array("x", &[1]), array("x", &[2])],
+            vec![],
+        )
+        .finish();
+        assert!(message(twice).contains("input 'x' is declared twice"));
+
+        let untyped = model_of(
+            500,
+            Writer::new(),
+            vec![Writer::new().string(1, "x")],
+            vec![],
+        );
+        assert!(message(untyped.finish()).contains("feature 'x' has no type"));
+
+        let error = ModelSpec::open("/nonexistent/model.mlmodel").unwrap_err();
+        assert!(matches!(error, Error::CoreMl { .. }));
+    }
+
+    #[test]
+    fn test_deep_pipeline_nesting_rejected() {
+        let mut model = Writer::new();
+        for _ in 0..1000 {
+            let pipeline = Writer::new().message(1, model);
+            model = Writer::new().varint(1, 5).message(202, pipeline);
+        }
+        let error = ModelSpec::parse(&model.finish()).unwrap_err().to_string();
+        assert!(error.contains("pipeline nesting deeper than 64"), "{error}");
+    }
+}
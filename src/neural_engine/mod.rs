@@ -24,8 +24,17 @@
 //! - F031: ANE detected on Apple Silicon
 //! - F032: Returns None on Intel Mac
 //! - F033: CoreML model loads successfully
+//! - F039: Corrupted model returns Error
 //! - F040: TOPS matches Apple spec (±10%)
 
+pub mod bundle;
+mod json;
+pub mod mlmodel;
+mod protobuf;
+
+pub use bundle::{ModelFormat, ModelInfo, WeightFile};
+pub use mlmodel::{FeatureDescription, FeatureType, ModelDescription, ModelSpec, ModelType};
+
 use crate::error::{Error, Result};
 use std::path::Path;
 
@@ -164,6 +173,7 @@ impl Tensor {
 #[derive(Debug)]
 pub struct NeuralEngineSession {
     model_path: String,
+    info: ModelInfo,
     _not_send_sync: std::marker::PhantomData<*const ()>,
 }
 
@@ -201,15 +211,20 @@ impl NeuralEngineSession {
 
     /// Load a CoreML model for inference.
     ///
+    /// The model is inspected (see [`bundle`]) so that corrupt models are
+    /// rejected here and [`infer`](Self::infer) can check its input
+    /// against the model description.
+    ///
     /// # Arguments
     ///
-    /// * `model_path` - Path to a `.mlmodel` or `.mlmodelc` file
+    /// * `model_path` - Path to a `.mlmodel` file, or a `.mlpackage` or
+    ///   `.mlmodelc` directory
     ///
     /// # Errors
     ///
     /// Returns an error if:
-    /// - The model file doesn't exist
-    /// - The model is corrupted
+    /// - The model doesn't exist
+    /// - The model, its manifest or its metadata is truncated or corrupted
     /// - The model format is unsupported
     ///
     /// # Example
@@ -222,28 +237,10 @@ impl NeuralEngineSession {
     /// # Ok::<(), manzana::Error>(())
     /// ```
     pub fn load(model_path: &Path) -> Result<Self> {
-        // Validate path exists
-        if !model_path.exists() {
-            return Err(Error::not_found(format!(
-                "model file: {}",
-                model_path.display()
-            )));
-        }
-
-        // Validate extension
-        let ext = model_path
-            .extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("");
-
-        if ext != "mlmodel" && ext != "mlmodelc" {
-            return Err(Error::invalid_input(format!(
-                "unsupported model format: .{ext} (expected .mlmodel or .mlmodelc)"
-            )));
-        }
-
+        let info = ModelInfo::inspect(model_path)?;
         Ok(Self {
             model_path: model_path.to_string_lossy().into_owned(),
+            info,
             _not_send_sync: std::marker::PhantomData,
         })
     }
@@ -257,18 +254,45 @@ impl NeuralEngineSession {
     /// # Errors
     ///
     /// Returns an error if:
+    /// - The model does not have exactly one input
     /// - Input shape doesn't match model requirements
     /// - Inference fails
     ///
     /// # Note
     ///
     /// This is a stub implementation. Full implementation requires
-    /// CoreML framework bindings.
+    /// CoreML framework bindings. The input is checked against the model
+    /// description when inspection found inputs; the result is zeros, shaped like
+    /// the model's first output if that has a fixed shape, and like the
+    /// input otherwise.
     pub fn infer(&self, input: &Tensor) -> Result<Tensor> {
-        // Stub: return zeros with same shape as input
-        // Full implementation would use CoreML framework
-        let _ = &self.model_path; // Suppress unused warning
-        Ok(Tensor::zeros(input.shape.clone()))
+        let description = &self.info.description;
+        if description.inputs.is_empty() {
+            return Ok(Tensor::zeros(input.shape.clone()));
+        }
+        let [feature] = description.inputs.as_slice() else {
+            return Err(Error::invalid_input(format!(
+                "model has {} inputs; infer takes exactly one",
+                description.inputs.len()
+            )));
+        };
+        feature.check_shape(&input.shape)?;
+        let shape = match description.outputs.first().map(|f| &f.feature_type) {
+            Some(FeatureType::MultiArray(array))
+                if !array.shape.is_empty() && array.shapes == mlmodel::ArrayShapes::Fixed =>
+            {
+                array.shape.clone()
+            }
+            _ => input.shape.clone(),
+        };
+        Ok(Tensor::zeros(shape))
+    }
+
+    /// What inspecting the model found: its interface, metadata, compute
+    /// precision and weight files.
+    #[must_use]
+    pub const fn info(&self) -> &ModelInfo {
+        &self.info
     }
 
     /// Get the model path.
@@ -363,6 +387,32 @@ mod tests {
         assert!(matches!(err, Error::NotFound { .. }));
     }
 
+    #[test]
+    fn test_load_parses_and_infer_checks_shape() {
+        let dir = std::env::temp_dir().join(format!("manzana-ane-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("classifier.mlmodel");
+        let bytes = mlmodel::fixture::classifier();
+        std::fs::write(&good, &bytes).unwrap();
+        let truncated = dir.join("truncated.mlmodel");
+        std::fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+
+        let session = NeuralEngineSession::load(&good).unwrap();
+        assert_eq!(session.info().model_type, Some(ModelType::NeuralNetwork));
+        let output = session.infer(&Tensor::zeros(vec![1, 3, 224, 224])).unwrap();
+        assert_eq!(output.shape, [1, 1000]);
+        let error = session
+            .infer(&Tensor::zeros(vec![1, 3, 32, 32]))
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidInput { .. }), "{error}");
+
+        // F039: a truncated model is rejected when loaded.
+        let error = NeuralEngineSession::load(&truncated).unwrap_err();
+        assert!(matches!(error, Error::CoreMl { .. }), "{error}");
+        assert!(error.to_string().contains("truncated.mlmodel"), "{error}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_convenience_function() {
         assert_eq!(is_available(), NeuralEngineSession::is_available());
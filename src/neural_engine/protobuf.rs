@@ -0,0 +1,355 @@
+//! Protocol Buffers wire format reader.
+//!
+//! Just enough of the encoding to walk CoreML's `Model.proto` without
+//! generated code. A message is a sequence of fields, each a varint key
+//! (`number << 3 | wire type`) followed by a varint, a fixed 4 or 8 byte
+//! value, or a varint length and that many bytes. Length-delimited
+//! payloads hold strings, packed repeated scalars and nested messages.
+//!
+//! Every read is bounds-checked, and errors name the byte offset within
+//! the outermost message.
+
+/// Parse errors, before they are wrapped in [`Error`](crate::Error).
+pub type Parse<T> = std::result::Result<T, String>;
+
+/// Longest valid varint encoding.
+const MAX_VARINT_LEN: usize = 10;
+
+/// An encoded message, or any other length-delimited payload.
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+    bytes: &'a [u8],
+    /// Offset of `bytes` within the outermost message.
+    base: usize,
+}
+
+impl<'a> Message<'a> {
+    /// The outermost message of a file.
+    pub const fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, base: 0 }
+    }
+
+    /// The fields of the message, in encoded order.
+    pub const fn fields(self) -> Fields<'a> {
+        Fields {
+            message: self,
+            pos: 0,
+        }
+    }
+
+    /// Offset of the payload within the outermost message.
+    pub const fn offset(&self) -> usize {
+        self.base
+    }
+
+    /// Read a varint at `pos`, returning it and the position after it.
+    fn varint(&self, pos: usize) -> Parse<(u64, usize)> {
+        let mut value = 0u64;
+        for (i, &byte) in self.bytes.iter().skip(pos).take(MAX_VARINT_LEN).enumerate() {
+            value |= u64::from(byte & 0x7F) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok((value, pos + i + 1));
+            }
+        }
+        if self.bytes.len() - pos.min(self.bytes.len()) >= MAX_VARINT_LEN {
+            Err(format!("varint at byte {} is too long", self.base + pos))
+        } else {
+            Err(format!("truncated varint at byte {}", self.base + pos))
+        }
+    }
+
+    /// `len` bytes at `pos`.
+    fn take(&self, pos: usize, len: u64, what: &str) -> Parse<(&'a [u8], usize)> {
+        let left = self.bytes.len().saturating_sub(pos);
+        usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= left)
+            .map(|len| (&self.bytes[pos..pos + len], pos + len))
+            .ok_or_else(|| {
+                format!(
+                    "{what} at byte {} needs {len} bytes but {left} remain",
+                    self.base + pos
+                )
+            })
+    }
+}
+
+/// Iterator over the fields of a [`Message`].
+///
+/// Stops after the first error.
+pub struct Fields<'a> {
+    message: Message<'a>,
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn read(&mut self) -> Parse<Field<'a>> {
+        let message = self.message;
+        let offset = message.base + self.pos;
+        let (key, pos) = message.varint(self.pos)?;
+        let number = u32::try_from(key >> 3)
+            .ok()
+            .filter(|&number| number > 0 && number < 1 << 29)
+            .ok_or_else(|| format!("invalid field number {} at byte {offset}", key >> 3))?;
+        let (value, end) = match key & 0x7 {
+            0 => {
+                let (value, end) = message.varint(pos)?;
+                (Value::Varint(value), end)
+            }
+            1 => {
+                let (_, end) = message.take(pos, 8, &format!("field {number}"))?;
+                (Value::Fixed64, end)
+            }
+            2 => {
+                let (len, start) = message.varint(pos)?;
+                let (bytes, end) = message.take(start, len, &format!("field {number}"))?;
+                let payload = Message {
+                    bytes,
+                    base: message.base + start,
+                };
+                (Value::Bytes(payload), end)
+            }
+            5 => {
+                let (_, end) = message.take(pos, 4, &format!("field {number}"))?;
+                (Value::Fixed32, end)
+            }
+            wire => {
+                return Err(format!(
+                    "field {number} at byte {offset} has unsupported wire type {wire}"
+                ))
+            }
+        };
+        self.pos = end;
+        Ok(Field {
+            number,
+            offset,
+            value,
+        })
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Parse<Field<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.message.bytes.len() {
+            return None;
+        }
+        let field = self.read();
+        if field.is_err() {
+            self.pos = self.message.bytes.len();
+        }
+        Some(field)
+    }
+}
+
+/// The encoded value of a field. No CoreML field read here is a
+/// fixed-width number, so those are only skipped.
+#[derive(Debug, Clone, Copy)]
+enum Value<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(Message<'a>),
+    Fixed32,
+}
+
+/// One field of a [`Message`].
+#[derive(Debug, Clone, Copy)]
+pub struct Field<'a> {
+    /// Field number from the schema.
+    pub number: u32,
+    /// Offset of the field's key within the outermost message.
+    pub offset: usize,
+    value: Value<'a>,
+}
+
+impl<'a> Field<'a> {
+    fn mismatch(&self, expected: &str) -> String {
+        let found = match self.value {
+            Value::Varint(_) => "a varint",
+            Value::Fixed64 => "a 64-bit value",
+            Value::Bytes(_) => "length-delimited data",
+            Value::Fixed32 => "a 32-bit value",
+        };
+        format!(
+            "field {} at byte {} should be {expected} but is {found}",
+            self.number, self.offset
+        )
+    }
+
+    /// An unsigned integer, bool or enum.
+    pub fn varint(&self) -> Parse<u64> {
+        match self.value {
+            Value::Varint(value) => Ok(value),
+            _ => Err(self.mismatch("a varint")),
+        }
+    }
+
+    /// A signed `int64` or `int32`.
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn int64(&self) -> Parse<i64> {
+        self.varint().map(|value| value as i64)
+    }
+
+    /// A `bool`.
+    pub fn bool(&self) -> Parse<bool> {
+        self.varint().map(|value| value != 0)
+    }
+
+    /// A nested message.
+    pub fn message(&self) -> Parse<Message<'a>> {
+        match self.value {
+            Value::Bytes(message) => Ok(message),
+            _ => Err(self.mismatch("a message")),
+        }
+    }
+
+    /// A UTF-8 `string`.
+    pub fn string(&self) -> Parse<String> {
+        let message = self.message()?;
+        std::str::from_utf8(message.bytes)
+            .map(str::to_string)
+            .map_err(|e| {
+                format!(
+                    "field {} at byte {} is not UTF-8: {e}",
+                    self.number, self.offset
+                )
+            })
+    }
+
+    /// The elements of a repeated signed integer field, packed or not.
+    ///
+    /// An unpacked field holds one element per occurrence; the caller
+    /// concatenates them.
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn int64s(&self) -> Parse<Vec<i64>> {
+        match self.value {
+            Value::Varint(value) => Ok(vec![value as i64]),
+            Value::Bytes(packed) => {
+                let mut values = Vec::new();
+                let mut pos = 0;
+                while pos < packed.bytes.len() {
+                    let (value, next) = packed.varint(pos)?;
+                    values.push(value as i64);
+                    pos = next;
+                }
+                Ok(values)
+            }
+            _ => Err(self.mismatch("repeated integers")),
+        }
+    }
+}
+
+/// Encoder for building test messages.
+#[cfg(test)]
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::needless_pass_by_value
+)]
+pub mod writer {
+    /// A message under construction.
+    #[derive(Debug, Default, Clone)]
+    pub struct Writer(Vec<u8>);
+
+    impl Writer {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        fn raw_varint(&mut self, mut value: u64) {
+            while value >= 0x80 {
+                self.0.push((value as u8) | 0x80);
+                value >>= 7;
+            }
+            self.0.push(value as u8);
+        }
+
+        fn key(&mut self, number: u32, wire: u8) {
+            self.raw_varint(u64::from(number) << 3 | u64::from(wire));
+        }
+
+        pub fn varint(mut self, number: u32, value: u64) -> Self {
+            self.key(number, 0);
+            self.raw_varint(value);
+            self
+        }
+
+        pub fn int64(self, number: u32, value: i64) -> Self {
+            self.varint(number, value as u64)
+        }
+
+        pub fn bytes(mut self, number: u32, bytes: &[u8]) -> Self {
+            self.key(number, 2);
+            self.raw_varint(bytes.len() as u64);
+            self.0.extend_from_slice(bytes);
+            self
+        }
+
+        pub fn string(self, number: u32, text: &str) -> Self {
+            self.bytes(number, text.as_bytes())
+        }
+
+        pub fn message(self, number: u32, message: Self) -> Self {
+            self.bytes(number, &message.0)
+        }
+
+        pub fn packed(self, number: u32, values: &[i64]) -> Self {
+            let mut packed = Self::new();
+            for &value in values {
+                packed.raw_varint(value as u64);
+            }
+            self.bytes(number, &packed.0)
+        }
+
+        pub fn finish(self) -> Vec<u8> {
+            self.0
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::writer::Writer;
+    use super::*;
+
+    #[test]
+    fn test_read_fields() {
+        let bytes = Writer::new()
+            .varint(1, 300)
+            .int64(2, -5)
+            .string(3, "hi")
+            .packed(4, &[1, 224, -1])
+            .message(5, Writer::new().varint(1, 7))
+            .finish();
+        let fields: Vec<_> = Message::new(&bytes).fields().collect::<Parse<_>>().unwrap();
+        assert_eq!(fields.len(), 5);
+        assert_eq!(fields[0].varint().unwrap(), 300);
+        assert_eq!(fields[1].int64().unwrap(), -5);
+        assert_eq!(fields[2].string().unwrap(), "hi");
+        assert_eq!(fields[3].int64s().unwrap(), [1, 224, -1]);
+        assert_eq!(fields[0].int64s().unwrap(), [300]);
+        let nested = fields[4].message().unwrap();
+        let inner = nested.fields().next().unwrap().unwrap();
+        assert_eq!(inner.varint().unwrap(), 7);
+        assert_eq!(inner.offset, nested.offset());
+        assert!(fields[2].varint().unwrap_err().contains("field 3"));
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        let error = |bytes: &[u8]| Message::new(bytes).fields().find_map(Result::err).unwrap();
+        assert!(error(&[0x08, 0x80]).contains("truncated varint at byte 1"));
+        assert!(error(&[0x08; 12][..1]).contains("truncated"));
+        assert!(
+            error(&[0x08, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01])
+                .contains("too long")
+        );
+        assert!(error(&[0x12, 0x05, b'a']).contains("needs 5 bytes but 1 remain"));
+        assert!(error(&[0x0B]).contains("wire type 3"));
+        assert!(error(&[0x00, 0x00]).contains("field number 0"));
+        // Iteration stops at the first error.
+        assert_eq!(Message::new(&[0x0B, 0x08, 0x01]).fields().count(), 1);
+    }
+}
@@ -26,12 +26,16 @@
 //! # Falsification Claims
 //!
 //! - F071: UMA buffer allocation succeeds
+//! - F073: GPU access without copies, via
+//!   [`MetalCompute::wrap_uma_buffer`](crate::metal::MetalCompute::wrap_uma_buffer)
 //! - F074: Zero-copy verified
 //! - F076: Alignment correct for Metal
 
 use crate::error::{Error, Result};
 use std::alloc::{alloc, dealloc, Layout};
+use std::cell::RefCell;
 use std::ptr::NonNull;
+use std::sync::{Arc, Weak};
 
 /// Page size for Metal buffer alignment (4096 bytes).
 pub const PAGE_SIZE: usize = 4096;
@@ -58,6 +62,42 @@ pub struct UmaBuffer {
     ptr: NonNull<u8>,
     len: usize,
     layout: Layout,
+    /// GPU views of the memory, detached before it is freed or mutably
+    /// borrowed.
+    views: RefCell<Vec<Weak<dyn Fn() + Send + Sync>>>,
+}
+
+/// Callback that detaches a GPU view from a [`UmaBuffer`]'s memory by
+/// dropping its [`UmaRegion`].
+pub(crate) type Detach = Arc<dyn Fn() + Send + Sync>;
+
+/// A [`UmaBuffer`]'s page-rounded allocation, accessed in place by GPU
+/// storage; see [`UmaBuffer::gpu_region`].
+pub(crate) struct UmaRegion {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the region's owner serializes access to it (GPU storage keeps it
+// behind a mutex), and the memory stays allocated while the region exists:
+// the `UmaBuffer` detaches its views before freeing the memory.
+unsafe impl Send for UmaRegion {}
+
+impl UmaRegion {
+    /// The whole allocation, including the zeroed padding past `len`.
+    #[allow(clippy::missing_const_for_fn)] // slice::from_raw_parts is not const-stable
+    pub(crate) fn bytes(&self) -> &[u8] {
+        // SAFETY: the allocation outlives the region, and `new` initialized
+        // the padding.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// The whole allocation, mutably.
+    pub(crate) fn bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above; the `UmaBuffer` hands out no `&mut` access
+        // while the region exists.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
 }
 
 // SAFETY: UmaBuffer owns its memory and uses NonNull for the pointer.
@@ -115,7 +155,19 @@ impl UmaBuffer {
             Error::internal(format!("memory allocation failed for {aligned_len} bytes"))
         })?;
 
-        Ok(Self { ptr, len, layout })
+        // Zero the padding so GPU views of the whole allocation never see
+        // uninitialized bytes.
+        // SAFETY: the padding lies inside the allocation.
+        unsafe {
+            std::ptr::write_bytes(ptr.as_ptr().add(len), 0, aligned_len - len);
+        }
+
+        Ok(Self {
+            ptr,
+            len,
+            layout,
+            views: RefCell::default(),
+        })
     }
 
     /// Allocate a zeroed unified memory buffer.
@@ -178,6 +230,7 @@ impl UmaBuffer {
     /// is not accessed after the `UmaBuffer` is dropped.
     #[must_use]
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.detach_views();
         self.ptr.as_ptr()
     }
 
@@ -192,6 +245,7 @@ impl UmaBuffer {
     /// Get a mutable slice view of the buffer.
     #[must_use]
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.detach_views();
         // SAFETY: ptr is valid, we have exclusive access
         unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
     }
@@ -220,6 +274,32 @@ impl UmaBuffer {
         Ok(())
     }
 
+    /// The whole page-rounded allocation, for a GPU view to access in
+    /// place.
+    ///
+    /// `detach` must drop the returned region. It runs before the memory
+    /// is freed or mutably borrowed, so a view that is leaked instead of
+    /// dropped cannot outlive the memory. A view that drops `detach` must
+    /// have dropped the region first.
+    pub(crate) fn gpu_region(&self, detach: &Detach) -> UmaRegion {
+        let mut views = self.views.borrow_mut();
+        views.retain(|view| view.strong_count() > 0);
+        views.push(Arc::downgrade(detach));
+        UmaRegion {
+            ptr: self.ptr,
+            len: self.layout.size(),
+        }
+    }
+
+    /// Detach every GPU view that still holds a region of the memory.
+    fn detach_views(&mut self) {
+        for view in self.views.get_mut().drain(..) {
+            if let Some(detach) = view.upgrade() {
+                detach();
+            }
+        }
+    }
+
     /// Check if UMA is available on this system.
     ///
     /// Returns `true` on Apple Silicon, `false` on Intel Macs.
@@ -239,6 +319,7 @@ impl UmaBuffer {
 
 impl Drop for UmaBuffer {
     fn drop(&mut self) {
+        self.detach_views();
         // SAFETY: ptr was allocated with the same layout
         unsafe {
             dealloc(self.ptr.as_ptr(), self.layout);
@@ -382,6 +463,38 @@ mod tests {
         assert_eq!(ptr, mut_ptr);
     }
 
+    #[test]
+    fn test_gpu_region_covers_zeroed_padding() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut buffer = UmaBuffer::new(10).unwrap();
+        buffer.copy_from_slice(&[7; 10]).unwrap();
+        let detached = Arc::new(AtomicUsize::new(0));
+        let detach: Detach = {
+            let detached = Arc::clone(&detached);
+            Arc::new(move || {
+                detached.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        {
+            let mut region = buffer.gpu_region(&detach);
+            assert_eq!(region.bytes().len(), PAGE_SIZE);
+            assert_eq!(&region.bytes()[8..12], &[7, 7, 0, 0]);
+            assert!(region.bytes()[10..].iter().all(|&b| b == 0));
+            region.bytes_mut()[0] = 1;
+        }
+        assert_eq!(buffer.as_slice()[0], 1);
+
+        // Mutable access detaches views once; dropped views are skipped.
+        buffer.as_mut_slice()[0] = 2;
+        buffer.as_mut_slice()[0] = 3;
+        assert_eq!(detached.load(Ordering::SeqCst), 1);
+        let _ = buffer.gpu_region(&detach);
+        drop(detach);
+        drop(buffer);
+        assert_eq!(detached.load(Ordering::SeqCst), 1);
+    }
+
     #[test]
     fn test_large_allocation() {
         // 1 MB allocation
@@ -295,6 +295,72 @@ fn test_f047_device_properties() {
     }
 }
 
+// F052: Buffer overflow prevented (trapped under validation)
+#[test]
+fn test_f052_out_of_bounds_access_caught() {
+    use manzana::metal::{CpuKernel, Validation, ViolationKind};
+
+    let validation = Validation::all();
+    let compute = MetalCompute::cpu().with_validation(validation.clone());
+    let shader = compute
+        .compile_shader("kernel void overflow() {}", "overflow")
+        .unwrap()
+        .with_cpu_kernel(CpuKernel::new(|ctx| {
+            let i = ctx.thread_position().0 as usize;
+            ctx.write_u32(0, i * 2, 1);
+        }));
+    let buffer = compute.allocate_buffer(16).unwrap();
+
+    let err = compute
+        .dispatch(&shader, &[&buffer], (4, 1, 1), (4, 1, 1))
+        .unwrap_err();
+    assert!(err.to_string().contains("out-of-bounds write"));
+    let threads: Vec<_> = validation
+        .violations()
+        .iter()
+        .filter(|v| matches!(v.kind, ViolationKind::OutOfBounds { .. }))
+        .map(|v| (v.thread, v.binding))
+        .collect();
+    assert_eq!(threads, vec![((2, 0, 0), 0), ((3, 0, 0), 0)]);
+}
+
+// Precompiled libraries load from a .metallib archive
+#[test]
+fn test_metallib_fixture_loads() {
+    use manzana::metal::metallib::{FunctionType, LibraryType, TargetOs, Version};
+    use manzana::metal::MetalLibrary;
+
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/kernels.metallib"
+    );
+    let library = MetalLibrary::open(path).unwrap();
+    assert_eq!(library.header().library_type, LibraryType::Executable);
+    assert_eq!(library.header().target_os, TargetOs::MacOs);
+    assert_eq!(library.header().os_version, Version::new(14, 0));
+
+    let names: Vec<_> = library
+        .functions()
+        .iter()
+        .map(|f| f.name.as_str())
+        .collect();
+    assert_eq!(names, ["vector_add", "vector_scale", "blit_fragment"]);
+    let scale = library.function("vector_scale").unwrap();
+    assert_eq!(scale.language_version, Version::new(3, 1));
+    assert!(scale.reflection.get(b"CNST").is_some());
+    assert_eq!(
+        library.function("blit_fragment").unwrap().function_type,
+        FunctionType::Fragment
+    );
+
+    let shaders = MetalCompute::cpu().load_library(path).unwrap();
+    let names: Vec<_> = shaders
+        .iter()
+        .map(manzana::metal::CompiledShader::name)
+        .collect();
+    assert_eq!(names, ["vector_add", "vector_scale"]);
+}
+
 // F060: Threadgroup size limits enforced
 #[test]
 #[cfg(target_os = "macos")]
@@ -337,6 +403,29 @@ fn test_f038_invalid_model_path() {
     assert!(result.is_err(), "Should reject nonexistent model path");
 }
 
+// F039: Corrupted model returns Error
+#[test]
+fn test_f039_corrupted_model() {
+    let path = std::env::temp_dir().join(format!("manzana-f039-{}.mlmodel", std::process::id()));
+    // A description field whose length runs past the end of the file.
+    std::fs::write(&path, [0x08, 0x05, 0x12, 0x40, 0x0A]).expect("write model");
+    let result = NeuralEngineSession::load(&path);
+    std::fs::remove_file(&path).expect("remove model");
+    assert!(result.is_err(), "Should reject truncated model");
+}
+
+// F039: A compiled model with corrupt metadata returns Error
+#[test]
+fn test_f039_corrupted_compiled_model() {
+    let path = std::env::temp_dir().join(format!("manzana-f039-{}.mlmodelc", std::process::id()));
+    std::fs::create_dir_all(&path).expect("create bundle");
+    std::fs::write(path.join("coremldata.bin"), [0u8; 8]).expect("write data");
+    std::fs::write(path.join("metadata.json"), "[{\"author\": ").expect("write metadata");
+    let result = NeuralEngineSession::load(&path);
+    std::fs::remove_dir_all(&path).expect("remove bundle");
+    assert!(result.is_err(), "Should reject corrupt metadata");
+}
+
 // =============================================================================
 // Unified Memory API tests (F071-F080)
 // =============================================================================
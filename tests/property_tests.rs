@@ -8,10 +8,19 @@
 
 use manzana::afterburner::{AfterburnerStats, ProResCodec};
 use manzana::error::{Error, Subsystem};
+use manzana::metal::dispatcher::{
+    Capabilities, DispatchTask, Dispatcher, Precision, SelectionReason,
+};
+use manzana::metal::graph::{ComputeGraph, TensorId};
+use manzana::metal::kernels::{cpu_reference, BinaryOp, GemmShape, KernelLibrary, NormShape};
+use manzana::metal::{
+    Backend, CpuKernel, DType, Expr, HostData, MetalBuffer, MetalCompute, MetalDevice, MetalLibrary,
+};
 use manzana::secure_enclave::{AccessControl, Algorithm, KeyConfig, PublicKey, Signature};
 use manzana::unified_memory::UmaBuffer;
 use proptest::prelude::*;
 use std::collections::HashMap;
+use std::time::Duration;
 
 // Strategy for generating ProResCodec values
 fn prores_codec_strategy() -> impl Strategy<Value = ProResCodec> {
@@ -327,6 +336,401 @@ proptest! {
     }
 }
 
+// Strategy for small matrices: (rows, cols, row-major values)
+fn matrix_strategy() -> impl Strategy<Value = (usize, usize, Vec<f32>)> {
+    (1usize..6, 1usize..6).prop_flat_map(|(rows, cols)| {
+        (
+            Just(rows),
+            Just(cols),
+            prop::collection::vec(-10.0f32..10.0, rows * cols),
+        )
+    })
+}
+
+fn transpose(data: &[f32], rows: usize, cols: usize) -> Vec<f32> {
+    (0..cols)
+        .flat_map(|c| (0..rows).map(move |r| data[r * cols + c]))
+        .collect()
+}
+
+fn upload(compute: &MetalCompute, data: &[f32]) -> MetalBuffer {
+    let buffer = compute.allocate_buffer(data.len().max(1) * 4).unwrap();
+    buffer.write(0, data).unwrap();
+    buffer
+}
+
+// Oracles for the CPU kernels, computed in f64 with plain loops so they
+// share no code with the library's reference implementations
+fn oracle_softmax(data: &[f32], cols: usize) -> Vec<f64> {
+    let mut out = Vec::with_capacity(data.len());
+    for row in data.chunks(cols) {
+        let mut max = f64::NEG_INFINITY;
+        for &x in row {
+            max = max.max(f64::from(x));
+        }
+        let mut sum = 0.0;
+        for &x in row {
+            sum += (f64::from(x) - max).exp();
+        }
+        for &x in row {
+            out.push((f64::from(x) - max).exp() / sum);
+        }
+    }
+    out
+}
+
+fn oracle_rms_norm(data: &[f32], gamma: f32, cols: usize, eps: f32) -> Vec<f64> {
+    let mut out = Vec::with_capacity(data.len());
+    for row in data.chunks(cols) {
+        let mut sum_squares = 0.0;
+        for &x in row {
+            sum_squares += f64::from(x) * f64::from(x);
+        }
+        let n = f64::from(u32::try_from(cols).unwrap());
+        let scale = 1.0 / (sum_squares / n + f64::from(eps)).sqrt();
+        for &x in row {
+            out.push(f64::from(x) * scale * f64::from(gamma));
+        }
+    }
+    out
+}
+
+fn oracle_row_broadcast(op: BinaryOp, data: &[f32], row: &[f32]) -> Vec<f64> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &a) in data.iter().enumerate() {
+        let (a, b) = (f64::from(a), f64::from(row[i % row.len()]));
+        out.push(match op {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+            BinaryOp::Max => a.max(b),
+            BinaryOp::Min => a.min(b),
+        });
+    }
+    out
+}
+
+// Whether f32 results agree with an f64 oracle to within f32 rounding
+fn close(actual: &[f32], expected: &[f64]) -> bool {
+    actual.len() == expected.len()
+        && actual.iter().zip(expected).all(|(&a, &e)| {
+            let a = f64::from(a);
+            a.total_cmp(&e).is_eq()
+                || (a.is_nan() && e.is_nan())
+                || (a - e).abs() <= 1e-5 * e.abs().max(1.0)
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(100))]
+
+    // Property: softmax rows are probability distributions
+    #[test]
+    fn prop_softmax_rows_sum_to_one((rows, cols, data) in matrix_strategy()) {
+        let out = cpu_reference::softmax(&data, cols);
+        prop_assert_eq!(out.len(), rows * cols);
+        for row in out.chunks(cols) {
+            prop_assert!(row.iter().all(|&p| (0.0..=1.0).contains(&p)));
+            prop_assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    // Property: transposed GEMM equals GEMM on explicitly transposed inputs
+    #[test]
+    fn prop_gemm_transpose_consistent(
+        (m, k, a) in matrix_strategy(),
+        n in 1usize..6,
+        ta in any::<bool>(),
+        tb in any::<bool>(),
+        b_pool in prop::collection::vec(-10.0f32..10.0, 36),
+    ) {
+        let b = b_pool[..k * n].to_vec();
+        let plain = cpu_reference::gemm(&a, &b, &GemmShape::new(m, n, k));
+        let a_stored = if ta { transpose(&a, m, k) } else { a };
+        let b_stored = if tb { transpose(&b, k, n) } else { b };
+        let shape = GemmShape::new(m, n, k).with_transpose_a(ta).with_transpose_b(tb);
+        prop_assert_eq!(cpu_reference::gemm(&a_stored, &b_stored, &shape), plain);
+    }
+
+    // Property: CPU backend kernels match naive f64 oracles
+    #[test]
+    fn prop_cpu_kernels_match_oracles((rows, cols, data) in matrix_strategy()) {
+        let compute = MetalCompute::cpu();
+        let kernels = KernelLibrary::new(&compute);
+        let input = upload(&compute, &data);
+        let output = compute.allocate_buffer(data.len() * 4).unwrap();
+
+        kernels.softmax(&input, &output, rows, cols).unwrap();
+        let actual = output.read::<f32>().unwrap();
+        let expected = oracle_softmax(&data, cols);
+        prop_assert!(close(&actual, &expected), "softmax {:?} vs {:?}", actual, expected);
+
+        let gamma = upload(&compute, &vec![1.5; cols]);
+        let shape = NormShape::new(rows, cols);
+        kernels.rms_norm(&input, &gamma, &output, &shape).unwrap();
+        let actual = output.read::<f32>().unwrap();
+        let expected = oracle_rms_norm(&data, 1.5, cols, shape.eps);
+        prop_assert!(close(&actual, &expected), "rms_norm {:?} vs {:?}", actual, expected);
+
+        let row_bias = data[..cols].to_vec();
+        for op in BinaryOp::ALL {
+            let out_shape = kernels
+                .binary(op, &input, &[rows, cols], &upload(&compute, &row_bias), &[cols], &output)
+                .unwrap();
+            prop_assert_eq!(out_shape, vec![rows, cols]);
+            let actual = output.read::<f32>().unwrap();
+            let expected = oracle_row_broadcast(op, &data, &row_bias);
+            prop_assert!(close(&actual, &expected), "{:?} {:?} vs {:?}", op, actual, expected);
+        }
+    }
+
+    // Property: fused binary expressions broadcast like the reference
+    // kernels, on the CPU backend and on the host
+    #[test]
+    fn prop_fused_binary_matches_reference(
+        (rows, cols, data) in matrix_strategy(),
+        bias_rows in any::<bool>(),
+    ) {
+        let compute = MetalCompute::cpu();
+        let bias_shape = if bias_rows { vec![rows, 1] } else { vec![cols] };
+        let bias: Vec<f32> = data.iter().copied().cycle().skip(1).take(bias_shape.iter().product()).collect();
+        let input = upload(&compute, &data);
+        let output = compute.allocate_buffer(data.len() * 4).unwrap();
+
+        for op in BinaryOp::ALL {
+            let (a, b) = (
+                Expr::input("a", DType::F32, &[rows, cols]),
+                Expr::input("b", DType::F32, &bias_shape),
+            );
+            let expr = match op {
+                BinaryOp::Add => a + b,
+                BinaryOp::Sub => a - b,
+                BinaryOp::Mul => a * b,
+                BinaryOp::Div => a / b,
+                BinaryOp::Max => a.max(b),
+                BinaryOp::Min => a.min(b),
+            };
+            let kernel = compute.compile_fused("fused", &expr).unwrap();
+            kernel.run(&[&input, &upload(&compute, &bias)], &output).unwrap();
+            let (expected, expected_shape) =
+                cpu_reference::binary(op, &data, &[rows, cols], &bias, &bias_shape).unwrap();
+            prop_assert_eq!(kernel.output_shape(), &expected_shape[..]);
+            prop_assert_eq!(output.read::<f32>().unwrap(), expected.clone());
+            let host = kernel
+                .evaluate(&[HostData::from(data.clone()), HostData::from(bias.clone())])
+                .unwrap();
+            prop_assert_eq!(host, HostData::F32(expected));
+        }
+    }
+
+    // Property: layer norm output has zero mean per row
+    #[test]
+    fn prop_layer_norm_zero_mean((_rows, cols, data) in matrix_strategy()) {
+        let ones = vec![1.0; cols];
+        let zeros = vec![0.0; cols];
+        let out = cpu_reference::layer_norm(&data, &ones, &zeros, cols, 1e-5);
+        for row in out.chunks(cols) {
+            prop_assert!(row.iter().sum::<f32>().abs() < 1e-3);
+        }
+    }
+}
+
+// Strategy for Metal device lists of up to four devices
+fn devices_strategy() -> impl Strategy<Value = Vec<MetalDevice>> {
+    prop::collection::vec((0u64..1 << 36, any::<bool>(), any::<bool>()), 0..4).prop_map(|specs| {
+        specs
+            .into_iter()
+            .enumerate()
+            .map(
+                |(index, (capacity, is_low_power, is_headless))| MetalDevice {
+                    name: format!("GPU {index}"),
+                    registry_id: index as u64 + 1,
+                    device_id: None,
+                    peer_group_id: 0,
+                    is_low_power,
+                    is_headless,
+                    max_threads_per_threadgroup: 1024,
+                    max_buffer_length: capacity,
+                    vram_bytes: 0,
+                    has_unified_memory: is_low_power,
+                    index,
+                },
+            )
+            .collect()
+    })
+}
+
+// Strategy for dispatch tasks of any kind, size, precision and latency
+fn dispatch_task_strategy() -> impl Strategy<Value = DispatchTask> {
+    (
+        any::<bool>(),
+        0u64..1 << 37,
+        prop_oneof![
+            Just(Precision::F16),
+            Just(Precision::F32),
+            Just(Precision::F64)
+        ],
+        prop::option::of(0u64..1000),
+    )
+        .prop_map(|(render, bytes, precision, latency_us)| {
+            let task = if render {
+                DispatchTask::render("k", bytes)
+            } else {
+                DispatchTask::compute("k", bytes)
+            };
+            let task = task.with_precision(precision);
+            match latency_us {
+                Some(us) => task.with_latency_target(Duration::from_micros(us)),
+                None => task,
+            }
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(200))]
+
+    // FALSIFY-DISPATCH-001: dispatch(task) ∈ {Metal, CPU}, and a Metal
+    // choice names a detected device that can hold the task's data
+    #[test]
+    fn prop_dispatch_always_selects_valid_backend(
+        devices in devices_strategy(),
+        task in dispatch_task_strategy(),
+    ) {
+        let dispatcher = Dispatcher::new(Capabilities::from_devices(devices.clone()));
+        let selection = dispatcher.select(&task);
+        match selection.backend() {
+            Backend::Metal => {
+                let index = selection.device_index().unwrap();
+                let device = devices.iter().find(|d| d.index == index).unwrap();
+                prop_assert!(device.max_buffer_length >= task.data_bytes());
+                prop_assert!(task.precision() != Precision::F64);
+                let is_metal = matches!(selection.reason(), SelectionReason::MetalDevice { .. });
+                prop_assert!(is_metal);
+            }
+            Backend::Cpu => {
+                prop_assert_eq!(selection.device_index(), None);
+                let is_metal = matches!(selection.reason(), SelectionReason::MetalDevice { .. });
+                prop_assert!(!is_metal);
+            }
+        }
+        prop_assert!(!selection.reason().to_string().is_empty());
+    }
+
+    // Contract invariant: fallback to CPU when Metal is unavailable
+    #[test]
+    fn prop_dispatch_falls_back_to_cpu_without_metal(task in dispatch_task_strategy()) {
+        let selection = Dispatcher::new(Capabilities::cpu_only()).select(&task);
+        prop_assert_eq!(selection.backend(), Backend::Cpu);
+        prop_assert_eq!(selection.reason(), &SelectionReason::NoMetalDevice);
+        prop_assert_eq!(selection.create_pipeline().unwrap().backend(), Backend::Cpu);
+    }
+}
+
+// Strategy for random DAGs: node i writes temporary i and reads up to
+// three earlier temporaries, given as (size, reads) per node
+fn dag_strategy() -> impl Strategy<Value = Vec<(usize, Vec<usize>)>> {
+    prop::collection::vec(
+        (1usize..2000, prop::collection::vec(any::<usize>(), 0..3)),
+        1..12,
+    )
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(100))]
+
+    // Property: compiled plans respect dependencies, and temporaries that
+    // are live at the same time never share arena bytes
+    #[test]
+    fn prop_graph_plan_orders_and_separates_live_tensors(dag in dag_strategy()) {
+        let compute = MetalCompute::cpu();
+        let shader = compute
+            .compile_shader("kernel void k() {}", "k")
+            .unwrap()
+            .with_cpu_kernel(CpuKernel::new(|_| {}));
+
+        let mut graph = ComputeGraph::new();
+        let temps: Vec<TensorId> = dag
+            .iter()
+            .enumerate()
+            .map(|(i, (bytes, _))| graph.temporary(&format!("t{i}"), *bytes))
+            .collect();
+        let out = graph.output("out", 4);
+        // Added in reverse so ordering is the compiler's job.
+        let mut reads_of = vec![Vec::new(); dag.len()];
+        let mut nodes = vec![None; dag.len()];
+        for (i, (_, picks)) in dag.iter().enumerate().rev() {
+            let mut reads: Vec<usize> = if i == 0 {
+                Vec::new()
+            } else {
+                picks.iter().map(|p| p % i).collect()
+            };
+            reads.sort_unstable();
+            reads.dedup();
+            let tensors: Vec<TensorId> = reads.iter().map(|&r| temps[r]).collect();
+            nodes[i] = Some(
+                graph
+                    .add_node(&shader, &tensors, &[temps[i]], (1, 1, 1), (1, 1, 1))
+                    .unwrap(),
+            );
+            reads_of[i] = reads;
+        }
+        // The sink reads everything, so every temporary is read.
+        let sink = graph
+            .add_node(&shader, &temps, &[out], (1, 1, 1), (1, 1, 1))
+            .unwrap();
+
+        let plan = graph.compile().unwrap();
+        let order: Vec<_> = plan.order().collect();
+        prop_assert_eq!(order.len(), dag.len() + 1);
+        prop_assert_eq!(order.last(), Some(&sink));
+        let position: Vec<usize> = nodes
+            .iter()
+            .map(|node| order.iter().position(|n| Some(*n) == *node).unwrap())
+            .collect();
+        for (i, reads) in reads_of.iter().enumerate() {
+            for &r in reads {
+                prop_assert!(position[r] < position[i]);
+            }
+        }
+
+        // Every temporary lives from its producer to the sink.
+        let span = |t: usize| {
+            let offset = plan.arena_offset(temps[t]).unwrap();
+            offset..offset + dag[t].0
+        };
+        for a in 0..dag.len() {
+            prop_assert!(span(a).end <= plan.arena_bytes());
+            for b in a + 1..dag.len() {
+                let (sa, sb) = (span(a), span(b));
+                prop_assert!(sa.end <= sb.start || sb.end <= sa.start);
+            }
+        }
+    }
+}
+
+proptest! {
+    // Property: corrupting any bytes of an archive yields an error or a
+    // library, never a panic
+    #[test]
+    fn prop_metallib_parse_never_panics(
+        edits in prop::collection::vec((any::<usize>(), any::<u8>()), 1..8),
+        truncate in any::<Option<usize>>(),
+    ) {
+        let mut bytes = include_bytes!("fixtures/kernels.metallib").to_vec();
+        for (at, value) in edits {
+            let at = at % bytes.len();
+            bytes[at] = value;
+        }
+        if let Some(len) = truncate {
+            bytes.truncate(len % bytes.len());
+        }
+        if let Ok(library) = MetalLibrary::parse(&bytes) {
+            prop_assert!(library.functions().len() <= bytes.len());
+        }
+    }
+}
+
 #[cfg(test)]
 mod determinism_tests {
     use super::*;